@@ -1,8 +1,11 @@
 extern crate rustc_version;
-use rustc_version::{version, Version};
+use rustc_version::version;
 
 fn main() {
-    if version().unwrap() >= Version::parse("1.19.0").unwrap() {
-        println!("cargo:rustc-cfg=HAS_EPRINTLN");
-    }
+    let version = version().unwrap();
+
+    // For --version-info
+    println!("cargo:rustc-env=SUBMERGE_RUSTC_VERSION={}", version);
+    println!("cargo:rustc-env=SUBMERGE_TARGET={}",
+             std::env::var("TARGET").unwrap_or(String::from("unknown")));
 }
@@ -0,0 +1,112 @@
+// With --migration-checklist, git-submerge lists what the rewrite means for everyone else who has
+// a copy of the repo: which branches changed identity, how far back, and which of them were
+// published (as far as the remote-tracking branches can tell). That's the raw material for the
+// announcement that has to go out before the rewritten branches are pushed.
+//
+// We can't see other people's clones, of course, so the number of people affected is only
+// estimated from the authors of the rewritten commits on published branches.
+
+use git2::{Repository, Oid};
+use std::collections::{HashMap, HashSet};
+
+use super::{Options, abbreviate, format_date, get_branch_to_id_map, is_branch_excluded};
+
+// Has to be called before the branches are moved
+pub fn print(repo: &Repository, old_id_to_new: &HashMap<Oid, Oid>, options: &Options) {
+    let is_rewritten = |id: &Oid| old_id_to_new.get(id).map_or(false, |new_id| new_id != id);
+
+    let mut branches: Vec<(String, Oid)> = get_branch_to_id_map(repo, options)
+        .into_iter()
+        .filter(|&(ref name, id)| !is_branch_excluded(name, options) && is_rewritten(&id))
+        .collect();
+    branches.sort();
+
+    // Remote-tracking branches, by the name of the branch they track
+    let mut remote_branches: HashMap<String, Vec<String>> = HashMap::new();
+    if let Ok(references) = repo.references_glob("refs/remotes/*") {
+        for reference in references.filter_map(|r| r.ok()) {
+            let name = match reference.shorthand() {
+                Some(name) if !name.ends_with("/HEAD") => String::from(name),
+                _ => continue,
+            };
+            let is_stale = reference.target().map_or(true, |id| !is_rewritten(&id));
+            if let (Some(slash), false) = (name.find('/'), is_stale) {
+                remote_branches.entry(String::from(&name[slash + 1..]))
+                    .or_insert_with(Vec::new)
+                    .push(name);
+            }
+        }
+    }
+
+    println!("Migration checklist:");
+    let mut published = 0;
+    let mut authors = HashSet::new();
+    for &(ref name, tip) in &branches {
+        let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
+        revwalk.set_sorting(::git2::SORT_TOPOLOGICAL);
+        revwalk.push(tip).expect("Couldn't add branch's tip to RevWalk");
+        let remotes = remote_branches.get(name);
+
+        // The oldest commit that changed its ID is where the branch stops being what people have.
+        // Children come before their parents, so on a tie, the parent wins.
+        let mut rewritten = 0;
+        let mut oldest: Option<(i64, Oid)> = None;
+        for id in revwalk.filter_map(|id| id.ok()).filter(|id| is_rewritten(id)) {
+            let commit = match repo.find_commit(id) {
+                Ok(commit) => commit,
+                Err(_) => continue,
+            };
+            rewritten += 1;
+            let time = commit.committer().when().seconds();
+            if oldest.map_or(true, |(oldest_time, _)| time <= oldest_time) {
+                oldest = Some((time, id));
+            }
+            if remotes.is_some() {
+                if let Some(email) = commit.author().email() {
+                    authors.insert(String::from(email));
+                }
+            }
+        }
+        let since = oldest.map_or(String::new(), |(time, id)| {
+            format!(", going back to {} ({})",
+                    format_date(time),
+                    abbreviate(repo, id, options.abbrev))
+        });
+        println!("    {}: {} commit(s) rewritten{}",
+                 options.colors.branch(name),
+                 rewritten,
+                 since);
+
+        match remotes {
+            Some(remotes) => {
+                published += 1;
+                for remote in remotes {
+                    println!("        published as {}{}", remote, last_fetched(repo, remote));
+                }
+            }
+            None => println!("        not published"),
+        }
+    }
+
+    if branches.is_empty() {
+        println!("    No branch changed its identity, so nobody's clone is affected.");
+    } else if published == 0 {
+        println!("None of the rewritten branches are on any remote we know of, so other clones \
+                  are probably unaffected.");
+    } else {
+        println!("{} published branch(es) changed identity. {} author(s) have commits on them; \
+                  each of their clones has to fetch the new history, and work based on the \
+                  old one has to be rebased onto it.",
+                 published,
+                 authors.len());
+    }
+}
+
+// When the remote-tracking branch was last updated, according to its reflog
+fn last_fetched(repo: &Repository, remote_branch: &str) -> String {
+    repo.reflog(&format!("refs/remotes/{}", remote_branch))
+        .ok()
+        .and_then(|reflog| reflog.get(0).map(|entry| entry.committer().when().seconds()))
+        .map_or(String::new(),
+                |time| format!(" (last fetched {})", format_date(time)))
+}
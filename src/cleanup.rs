@@ -0,0 +1,789 @@
+// With --cleanup-remove and --cleanup-patch, each rewritten branch gets one more commit on top,
+// with whatever only made sense while the submodule was a submodule taken out: scripts that update
+// it, CI steps that check it out, and so on. That way, the migration and the cleanup land together,
+// and nobody checks out a branch that still tries to run `git submodule update'.
+//
+// The patch is the kind `git diff' makes, and is applied to every branch the same way `git apply'
+// would, i.e. hunks can move around a bit, but their context has to match. Binary patches aren't
+// supported.
+
+use git2::{Repository, Tree, Oid};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::{Options, create_commit, glob_matches, insert_blob, replace_subdir, warn};
+
+pub struct Patch {
+    // The file it was read from, for the commit message
+    name: String,
+    files: Vec<FilePatch>,
+}
+
+struct FilePatch {
+    // None for new files
+    old_path: Option<String>,
+    // None for deleted files
+    new_path: Option<String>,
+    new_mode: Option<i32>,
+    hunks: Vec<Hunk>,
+}
+
+struct Hunk {
+    // 1-based, as in the hunk's header
+    old_start: usize,
+    // Lines with their newlines (except for the last line of a file that has none)
+    old_lines: Vec<Vec<u8>>,
+    new_lines: Vec<Vec<u8>>,
+}
+
+pub fn read_patch(path: &str) -> Result<Patch, String> {
+    let mut contents = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut contents))
+        .map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+    let lines = split_lines(&contents);
+
+    let mut files: Vec<FilePatch> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = trim_newline(lines[i]);
+        i += 1;
+        if line.starts_with(b"diff --git ") {
+            files.push(FilePatch {
+                old_path: None,
+                new_path: None,
+                new_mode: None,
+                hunks: Vec::new(),
+            });
+            // The paths are only given here for changes that have no hunks, e.g. renames
+            let paths = String::from_utf8_lossy(&line[b"diff --git ".len()..]).into_owned();
+            if let Some(middle) = paths.find(" b/") {
+                let file = files.last_mut().expect("A file was just added");
+                file.old_path = parse_path(&paths[..middle]);
+                file.new_path = parse_path(&paths[middle + 1..]);
+            }
+            continue;
+        }
+        if line.starts_with(b"--- ") && i < lines.len() && lines[i].starts_with(b"+++ ") {
+            // Patches made by plain `diff -u' have no "diff --git" lines
+            if files.last().map_or(true, |file| !file.hunks.is_empty()) {
+                files.push(FilePatch {
+                    old_path: None,
+                    new_path: None,
+                    new_mode: None,
+                    hunks: Vec::new(),
+                });
+            }
+            let file = files.last_mut().expect("A file was just added");
+            file.old_path = parse_path(&String::from_utf8_lossy(&line[4..]));
+            file.new_path = parse_path(&String::from_utf8_lossy(trim_newline(&lines[i][4..])));
+            i += 1;
+            continue;
+        }
+        if line.starts_with(b"GIT binary patch") || line.starts_with(b"Binary files ") {
+            return Err(format!("{} contains a binary patch, which isn't supported", path));
+        }
+
+        let file = match files.last_mut() {
+            Some(file) => file,
+            // Whatever comes before the first file, e.g. the commit message of `git format-patch'
+            None => continue,
+        };
+        let text = String::from_utf8_lossy(line).into_owned();
+        if text.starts_with("new file mode ") {
+            file.old_path = None;
+            file.new_mode = parse_mode(&text["new file mode ".len()..]);
+        } else if text.starts_with("new mode ") {
+            file.new_mode = parse_mode(&text["new mode ".len()..]);
+        } else if text.starts_with("deleted file mode ") {
+            file.new_path = None;
+        } else if text.starts_with("rename from ") {
+            file.old_path = Some(String::from(&text["rename from ".len()..]));
+        } else if text.starts_with("rename to ") {
+            file.new_path = Some(String::from(&text["rename to ".len()..]));
+        } else if text.starts_with("@@ ") {
+            let (old_start, old_count, new_count) = match parse_hunk_header(&text) {
+                Some(header) => header,
+                None => return Err(format!("{}:{}: malformed hunk header", path, i)),
+            };
+            let mut hunk = Hunk {
+                old_start: old_start,
+                old_lines: Vec::new(),
+                new_lines: Vec::new(),
+            };
+            // Which sides the last line went to, for "\ No newline at end of file"
+            let mut last = (false, false);
+            while i < lines.len() &&
+                  (hunk.old_lines.len() < old_count || hunk.new_lines.len() < new_count ||
+                   lines[i].starts_with(b"\\")) {
+                let line = lines[i];
+                i += 1;
+                match line.first() {
+                    Some(&b' ') => {
+                        hunk.old_lines.push(line[1..].to_vec());
+                        hunk.new_lines.push(line[1..].to_vec());
+                        last = (true, true);
+                    }
+                    // Editors like to strip the space off empty context lines
+                    Some(&b'\n') => {
+                        hunk.old_lines.push(line.to_vec());
+                        hunk.new_lines.push(line.to_vec());
+                        last = (true, true);
+                    }
+                    Some(&b'-') => {
+                        hunk.old_lines.push(line[1..].to_vec());
+                        last = (true, false);
+                    }
+                    Some(&b'+') => {
+                        hunk.new_lines.push(line[1..].to_vec());
+                        last = (false, true);
+                    }
+                    Some(&b'\\') => {
+                        if last.0 {
+                            strip_newline(hunk.old_lines.last_mut());
+                        }
+                        if last.1 {
+                            strip_newline(hunk.new_lines.last_mut());
+                        }
+                    }
+                    _ => return Err(format!("{}:{}: unexpected line in a hunk", path, i)),
+                }
+            }
+            if hunk.old_lines.len() != old_count || hunk.new_lines.len() != new_count {
+                return Err(format!("{}: the patch ends in the middle of a hunk", path));
+            }
+            file.hunks.push(hunk);
+        }
+    }
+
+    if files.is_empty() {
+        return Err(format!("{} doesn't contain a patch", path));
+    }
+    let name = Path::new(path)
+        .file_name()
+        .map_or(String::from(path), |name| name.to_string_lossy().into_owned());
+    Ok(Patch {
+        name: name,
+        files: files,
+    })
+}
+
+// Puts the cleanup commit on top of the rewritten branch. Returns the ID of the new branch tip,
+// which is the old one if there was nothing to clean up, or the patch didn't apply.
+pub fn add_cleanup_commit(repo: &Repository,
+                          name: &str,
+                          new_tip_id: Oid,
+                          options: &Options)
+                          -> Oid {
+    let new_tip = repo.find_commit(new_tip_id).expect("Couldn't find rewritten branch's tip");
+    let tree = new_tip.tree().expect("Couldn't obtain commit's tree");
+
+    let mut removed = Vec::new();
+    collect_removed_paths(repo, &tree, "", &options.cleanup_remove, &mut removed);
+    let mut tree_id = tree.id();
+    for path in &removed {
+        let tree = repo.find_tree(tree_id).expect("Couldn't retrieve the tree we just created");
+        tree_id = replace_subdir(repo, Some(&tree), Path::new(path), None, None)
+            .unwrap_or_else(|| empty_tree(repo));
+    }
+
+    if let Some(ref patch) = options.cleanup_patch {
+        let tree = repo.find_tree(tree_id).expect("Couldn't retrieve the tree we just created");
+        match apply_patch(repo, &tree, patch) {
+            Ok(id) => tree_id = id,
+            Err(message) => {
+                warn(options,
+                     "cleanup",
+                     format!("{} doesn't apply to branch {}: {}. The branch was left without the \
+                              cleanup commit.",
+                             patch.name,
+                             name,
+                             message));
+                return new_tip_id;
+            }
+        }
+    }
+    if tree_id == tree.id() {
+        return new_tip_id;
+    }
+
+    let mut paragraphs = vec![format!("Clean up after merging submodule '{}'",
+                                      options.submodule_dir)];
+    if !removed.is_empty() {
+        paragraphs.push(format!("Remove what only made sense while {} was a submodule:",
+                                options.submodule_dir));
+        let paths: Vec<String> = removed.iter().map(|path| format!("    {}", path)).collect();
+        paragraphs.push(paths.join("\n"));
+    }
+    if let Some(ref patch) = options.cleanup_patch {
+        paragraphs.push(format!("Apply {}.", patch.name));
+    }
+    let message = paragraphs.join("\n\n") + "\n";
+
+    let new_tree = repo.find_tree(tree_id).expect("Couldn't retrieve the tree we just created");
+    let signature = repo.signature().expect("Couldn't obtain user's signature from Git config");
+    create_commit(repo,
+                  None,
+                  &signature,
+                  &signature,
+                  &message,
+                  &new_tree,
+                  &[&new_tip],
+                  options)
+}
+
+// Files and directories matching any of the globs. Directories are removed as a whole.
+fn collect_removed_paths(repo: &Repository,
+                         tree: &Tree,
+                         dir: &str,
+                         globs: &[String],
+                         removed: &mut Vec<String>) {
+    for entry in tree.iter() {
+        let path = format!("{}{}", dir, String::from_utf8_lossy(entry.name_bytes()));
+        if globs.iter().any(|glob| glob_matches(glob.as_bytes(), path.as_bytes())) {
+            removed.push(path);
+        } else if entry.filemode() == 0o040000 {
+            if let Ok(subtree) = repo.find_tree(entry.id()) {
+                collect_removed_paths(repo, &subtree, &format!("{}/", path), globs, removed);
+            }
+        }
+    }
+}
+
+// Returns the ID of the patched tree, or what went wrong
+fn apply_patch(repo: &Repository, tree: &Tree, patch: &Patch) -> Result<Oid, String> {
+    let mut tree_id = tree.id();
+    for file in &patch.files {
+        let tree = repo.find_tree(tree_id).expect("Couldn't retrieve the tree we just created");
+        let (contents, mode) = match file.old_path {
+            Some(ref path) => {
+                let entry = tree.get_path(Path::new(path))
+                    .map_err(|_| format!("{} doesn't exist", path))?;
+                let blob = repo.find_blob(entry.id())
+                    .map_err(|_| format!("{} is not a file", path))?;
+                (blob.content().to_vec(), entry.filemode())
+            }
+            None => {
+                match file.new_path {
+                    Some(ref path) if tree.get_path(Path::new(path)).is_ok() => {
+                        return Err(format!("{} already exists", path));
+                    }
+                    _ => (Vec::new(), 0o100644),
+                }
+            }
+        };
+        let contents = apply_hunks(&contents, &file.hunks).map_err(|number| {
+            format!("hunk #{} of {} doesn't match",
+                    number + 1,
+                    file.new_path.as_ref().or(file.old_path.as_ref()).map_or("", |path| &path[..]))
+        })?;
+
+        if let Some(ref path) = file.old_path {
+            if file.new_path.as_ref() != Some(path) {
+                tree_id = replace_subdir(repo, Some(&tree), Path::new(path), None, None)
+                    .unwrap_or_else(|| empty_tree(repo));
+            }
+        }
+        if let Some(ref path) = file.new_path {
+            let tree = repo.find_tree(tree_id).expect("Couldn't retrieve the tree we just created");
+            let blob_id = repo.blob(&contents)
+                .expect("Couldn't write a patched file into the repo");
+            tree_id = insert_blob(repo,
+                                  Some(&tree),
+                                  Path::new(path),
+                                  blob_id,
+                                  file.new_mode.unwrap_or(mode));
+        }
+    }
+    Ok(tree_id)
+}
+
+// Returns the patched contents, or the index of the hunk that doesn't match
+fn apply_hunks(contents: &[u8], hunks: &[Hunk]) -> Result<Vec<u8>, usize> {
+    let lines = split_lines(contents);
+    let mut result = Vec::new();
+    // Lines before this one are already in `result'
+    let mut position = 0;
+    // How far the hunks turned out to be from where their headers say they are
+    let mut offset: isize = 0;
+    for (number, hunk) in hunks.iter().enumerate() {
+        let length = hunk.old_lines.len();
+        // A hunk that only adds lines starts right after the line in its header
+        let old_index = if length == 0 {
+            hunk.old_start
+        } else {
+            hunk.old_start.saturating_sub(1)
+        };
+        let matches = |start: usize| {
+            start + length <= lines.len() &&
+            lines[start..start + length].iter().zip(&hunk.old_lines).all(|(a, b)| *a == &b[..])
+        };
+        let expected = ::std::cmp::max(old_index as isize + offset, position as isize) as usize;
+        let start = (0..lines.len() + 1)
+            .flat_map(|distance| {
+                let before = if distance > 0 && expected >= position + distance {
+                    Some(expected - distance)
+                } else {
+                    None
+                };
+                Some(expected + distance).into_iter().chain(before)
+            })
+            .find(|start| matches(*start))
+            .ok_or(number)?;
+
+        for line in &lines[position..start] {
+            result.extend_from_slice(line);
+        }
+        for line in &hunk.new_lines {
+            result.extend_from_slice(line);
+        }
+        position = start + length;
+        offset = start as isize - old_index as isize;
+    }
+    for line in &lines[position..] {
+        result.extend_from_slice(line);
+    }
+    Ok(result)
+}
+
+// Lines with their newlines
+fn split_lines(contents: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, byte) in contents.iter().enumerate() {
+        if *byte == b'\n' {
+            lines.push(&contents[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < contents.len() {
+        lines.push(&contents[start..]);
+    }
+    lines
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    if line.ends_with(b"\n") {
+        &line[..line.len() - 1]
+    } else {
+        line
+    }
+}
+
+fn strip_newline(line: Option<&mut Vec<u8>>) {
+    if let Some(line) = line {
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+    }
+}
+
+// "a/foo" and "b/foo" become "foo", /dev/null becomes None; `diff -u' puts a timestamp after a tab
+fn parse_path(text: &str) -> Option<String> {
+    let path = text.split('\t').next().unwrap_or(text).trim_right();
+    if path == "/dev/null" {
+        None
+    } else if path.starts_with("a/") || path.starts_with("b/") {
+        Some(String::from(&path[2..]))
+    } else {
+        Some(String::from(path))
+    }
+}
+
+fn parse_mode(text: &str) -> Option<i32> {
+    i32::from_str_radix(text.trim(), 8).ok()
+}
+
+// "@@ -<start>[,<count>] +<start>[,<count>] @@" into the old start and both counts
+fn parse_hunk_header(header: &str) -> Option<(usize, usize, usize)> {
+    let mut ranges = header.split(' ').skip(1).take(2);
+    let parse_range = |range: Option<&str>, sign: char| -> Option<(usize, usize)> {
+        let range = range.and_then(|range| if range.starts_with(sign) {
+            Some(&range[1..])
+        } else {
+            None
+        });
+        let mut numbers = match range {
+            Some(range) => range.split(','),
+            None => return None,
+        };
+        let start = numbers.next().and_then(|number| number.parse().ok());
+        let count = match numbers.next() {
+            Some(number) => number.parse().ok(),
+            None => Some(1),
+        };
+        match (start, count) {
+            (Some(start), Some(count)) => Some((start, count)),
+            _ => None,
+        }
+    };
+    match (parse_range(ranges.next(), '-'), parse_range(ranges.next(), '+')) {
+        (Some((old_start, old_count)), Some((_, new_count))) => {
+            Some((old_start, old_count, new_count))
+        }
+        _ => None,
+    }
+}
+
+fn empty_tree(repo: &Repository) -> Oid {
+    repo.treebuilder(None)
+        .and_then(|builder| builder.write())
+        .expect("Couldn't write an empty tree")
+}
+
+// Unlike the rest of the merge, the cleanup commit changes files outside of the submodule's
+// directory, so they have to be checked out too. `old_tree' is what the working directory had
+// before the merge.
+pub fn update_worktree(repo: &Repository, old_tree: &Tree, options: &Options) {
+    let head_tree = repo.head()
+        .and_then(|head| head.peel(::git2::ObjectType::Tree))
+        .expect("Couldn't obtain the tree of HEAD");
+    let head_tree = head_tree.as_tree().expect("HEAD's tree is not a tree");
+    let diff = repo.diff_tree_to_tree(Some(old_tree), Some(head_tree), None)
+        .expect("Couldn't compare the trees before and after the cleanup");
+
+    let submodule_dir = Path::new(&options.submodule_dir);
+    let mut checkout = ::git2::build::CheckoutBuilder::new();
+    checkout.force();
+    let mut has_paths = false;
+    for delta in diff.deltas() {
+        let path = match delta.new_file().path().or(delta.old_file().path()) {
+            Some(path) if !path.starts_with(submodule_dir) && path != Path::new(".gitmodules") => {
+                path
+            }
+            _ => continue,
+        };
+        if delta.status() == ::git2::Delta::Deleted {
+            if path.exists() {
+                ::std::fs::remove_file(path)
+                    .expect(&format!("Couldn't remove {}", path.display()));
+            }
+        } else {
+            checkout.path(path);
+            has_paths = true;
+        }
+    }
+    if has_paths {
+        repo.checkout_head(Some(&mut checkout))
+            .expect("Couldn't check out the files changed by the cleanup commit");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{Oid, Tree};
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::{Patch, apply_hunks, apply_patch, insert_blob, parse_hunk_header, read_patch};
+    use tests::TestRepo;
+
+    // Writes the patch into a temporary file and reads it back
+    fn read(name: &str, text: &str) -> Result<Patch, String> {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+        let path = ::std::env::temp_dir()
+            .join(format!("git-submerge-test-{}-{}.patch", name, nanos));
+        File::create(&path).unwrap().write_all(text.as_bytes()).unwrap();
+        let result = read_patch(path.to_str().unwrap());
+        ::std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn apply(text: &str, contents: &str) -> Result<String, usize> {
+        let patch = read("apply", text).unwrap();
+        assert_eq!(patch.files.len(), 1);
+        apply_hunks(contents.as_bytes(), &patch.files[0].hunks)
+            .map(|result| String::from_utf8(result).unwrap())
+    }
+
+    fn lines(lines: &[Vec<u8>]) -> Vec<String> {
+        lines.iter().map(|line| String::from_utf8(line.clone()).unwrap()).collect()
+    }
+
+    fn tree_with(test: &TestRepo, files: &[(&str, &str)]) -> Oid {
+        let mut tree_id = test.repo.treebuilder(None).unwrap().write().unwrap();
+        for &(path, contents) in files {
+            let tree = test.repo.find_tree(tree_id).unwrap();
+            let blob_id = test.repo.blob(contents.as_bytes()).unwrap();
+            tree_id = insert_blob(&test.repo, Some(&tree), Path::new(path), blob_id, 0o100644);
+        }
+        tree_id
+    }
+
+    fn contents_of(test: &TestRepo, tree: &Tree, path: &str) -> Option<String> {
+        tree.get_path(Path::new(path)).ok().map(|entry| {
+            let blob = test.repo.find_blob(entry.id()).unwrap();
+            String::from_utf8(blob.content().to_vec()).unwrap()
+        })
+    }
+
+    #[test]
+    fn hunk_headers_are_parsed() {
+        assert_eq!(parse_hunk_header("@@ -1,3 +1,4 @@"), Some((1, 3, 4)));
+        // The count is 1 if it's left out, and the function name doesn't matter
+        assert_eq!(parse_hunk_header("@@ -5 +6 @@ fn main() {"), Some((5, 1, 1)));
+        assert_eq!(parse_hunk_header("@@ -0,0 +1,2 @@"), Some((0, 0, 2)));
+        assert_eq!(parse_hunk_header("@@ -3,2 +2,0 @@"), Some((3, 2, 0)));
+    }
+
+    #[test]
+    fn malformed_hunk_headers_are_rejected() {
+        assert_eq!(parse_hunk_header("@@ -a,1 +1 @@"), None);
+        assert_eq!(parse_hunk_header("@@ -1,b +1 @@"), None);
+        assert_eq!(parse_hunk_header("@@ 1,2 +1,2 @@"), None);
+        assert_eq!(parse_hunk_header("@@ -1,2 1,2 @@"), None);
+        assert_eq!(parse_hunk_header("@@ -1,2 @@"), None);
+        assert_eq!(parse_hunk_header("@@"), None);
+    }
+
+    #[test]
+    fn git_patches_are_read() {
+        let patch = read("git",
+                         "From 0123456789abcdef Mon Sep 17 00:00:00 2001\n\
+                          Subject: [PATCH] Stop updating the submodule\n\
+                          \n\
+                          diff --git a/ci.yml b/ci.yml\n\
+                          index 1111111..2222222 100644\n\
+                          --- a/ci.yml\n\
+                          +++ b/ci.yml\n\
+                          @@ -1,3 +1,2 @@\n \
+                          build:\n\
+                          -git submodule update\n \
+                          make\n\
+                          diff --git a/old.sh b/new.sh\n\
+                          similarity index 100%\n\
+                          rename from old.sh\n\
+                          rename to new.sh\n\
+                          diff --git a/NOTES b/NOTES\n\
+                          new file mode 100644\n\
+                          index 0000000..3333333\n\
+                          --- /dev/null\n\
+                          +++ b/NOTES\n\
+                          @@ -0,0 +1 @@\n\
+                          +merged\n\
+                          diff --git a/update.sh b/update.sh\n\
+                          deleted file mode 100755\n\
+                          index 4444444..0000000\n\
+                          --- a/update.sh\n\
+                          +++ /dev/null\n\
+                          @@ -1 +0,0 @@\n\
+                          -git submodule update\n")
+            .unwrap();
+        assert_eq!(patch.files.len(), 4);
+
+        let ci = &patch.files[0];
+        assert_eq!(ci.old_path, Some(String::from("ci.yml")));
+        assert_eq!(ci.new_path, Some(String::from("ci.yml")));
+        assert_eq!(ci.hunks.len(), 1);
+        assert_eq!(ci.hunks[0].old_start, 1);
+        assert_eq!(lines(&ci.hunks[0].old_lines),
+                   vec!["build:\n", "git submodule update\n", "make\n"]);
+        assert_eq!(lines(&ci.hunks[0].new_lines), vec!["build:\n", "make\n"]);
+
+        let renamed = &patch.files[1];
+        assert_eq!(renamed.old_path, Some(String::from("old.sh")));
+        assert_eq!(renamed.new_path, Some(String::from("new.sh")));
+        assert!(renamed.hunks.is_empty());
+
+        let added = &patch.files[2];
+        assert_eq!(added.old_path, None);
+        assert_eq!(added.new_path, Some(String::from("NOTES")));
+        assert_eq!(added.new_mode, Some(0o100644));
+        assert_eq!(lines(&added.hunks[0].new_lines), vec!["merged\n"]);
+
+        let deleted = &patch.files[3];
+        assert_eq!(deleted.old_path, Some(String::from("update.sh")));
+        assert_eq!(deleted.new_path, None);
+        assert!(deleted.hunks[0].new_lines.is_empty());
+    }
+
+    #[test]
+    fn plain_diff_patches_are_read() {
+        let patch = read("plain",
+                         "--- Makefile\t2018-01-01 00:00:00.000000000 +0000\n\
+                          +++ Makefile\t2018-01-02 00:00:00.000000000 +0000\n\
+                          @@ -1 +1 @@\n\
+                          -all: submodule\n\
+                          +all:\n\
+                          --- README\t2018-01-01 00:00:00.000000000 +0000\n\
+                          +++ README\t2018-01-02 00:00:00.000000000 +0000\n\
+                          @@ -2 +2 @@\n\
+                          -Run `git submodule update` first.\n\
+                          +Nothing to set up.\n")
+            .unwrap();
+        assert_eq!(patch.files.len(), 2);
+        assert_eq!(patch.files[0].old_path, Some(String::from("Makefile")));
+        assert_eq!(patch.files[0].new_path, Some(String::from("Makefile")));
+        assert_eq!(patch.files[1].old_path, Some(String::from("README")));
+        assert_eq!(patch.files[1].hunks[0].old_start, 2);
+    }
+
+    #[test]
+    fn missing_final_newlines_are_kept_track_of() {
+        let patch = read("newline",
+                         "--- a/version\n\
+                          +++ b/version\n\
+                          @@ -1,2 +1,2 @@\n \
+                          name\n\
+                          -1.0\n\
+                          \\ No newline at end of file\n\
+                          +1.1\n\
+                          \\ No newline at end of file\n")
+            .unwrap();
+        let hunk = &patch.files[0].hunks[0];
+        assert_eq!(lines(&hunk.old_lines), vec!["name\n", "1.0"]);
+        assert_eq!(lines(&hunk.new_lines), vec!["name\n", "1.1"]);
+
+        // Only the new side lost its newline
+        let patch = read("newline",
+                         "--- a/version\n\
+                          +++ b/version\n\
+                          @@ -1 +1 @@\n\
+                          -1.0\n\
+                          +1.1\n\
+                          \\ No newline at end of file\n")
+            .unwrap();
+        let hunk = &patch.files[0].hunks[0];
+        assert_eq!(lines(&hunk.old_lines), vec!["1.0\n"]);
+        assert_eq!(lines(&hunk.new_lines), vec!["1.1"]);
+        assert_eq!(apply_hunks(b"1.0\n", &patch.files[0].hunks), Ok(b"1.1".to_vec()));
+    }
+
+    #[test]
+    fn bad_patches_are_reported() {
+        let missing = ::std::env::temp_dir().join("git-submerge-test-no-such.patch");
+        let error = read_patch(missing.to_str().unwrap()).err().unwrap();
+        assert!(error.starts_with("Couldn't read "), "{}", error);
+
+        let error = read("empty", "Just a commit message\n").err().unwrap();
+        assert!(error.ends_with("doesn't contain a patch"), "{}", error);
+
+        let error = read("binary",
+                         "diff --git a/logo.png b/logo.png\n\
+                          index 1111111..2222222 100644\n\
+                          GIT binary patch\n\
+                          literal 1\n")
+            .err()
+            .unwrap();
+        assert!(error.ends_with("contains a binary patch, which isn't supported"),
+                "{}",
+                error);
+
+        let error = read("header", "--- a/f\n+++ b/f\n@@ -x +1 @@\n-a\n+b\n").err().unwrap();
+        assert!(error.ends_with(":3: malformed hunk header"), "{}", error);
+
+        let error = read("line", "--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n-a\n*b\n").err().unwrap();
+        assert!(error.ends_with(":5: unexpected line in a hunk"), "{}", error);
+
+        let error = read("short", "--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n-a\n+b\n").err().unwrap();
+        assert!(error.ends_with("the patch ends in the middle of a hunk"), "{}", error);
+    }
+
+    #[test]
+    fn hunks_apply_where_their_headers_say() {
+        let patch = "--- a/f\n+++ b/f\n@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n";
+        assert_eq!(apply(patch, "a\nb\nc\nd\ne\n"), Ok(String::from("a\nb\nC\nd\ne\n")));
+    }
+
+    #[test]
+    fn hunks_apply_at_an_offset() {
+        let patch = "--- a/f\n+++ b/f\n\
+                     @@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n\
+                     @@ -6,2 +6,2 @@\n f\n-g\n+G\n";
+        // Two lines were added at the top since the patch was made; the second hunk is found
+        // by the offset of the first one
+        assert_eq!(apply(patch, "0\n1\na\nb\nc\nd\ne\nf\ng\n"),
+                   Ok(String::from("0\n1\na\nb\nC\nd\ne\nf\nG\n")));
+        // ...or removed
+        assert_eq!(apply(patch, "b\nc\nd\ne\nf\ng\n"),
+                   Ok(String::from("b\nC\nd\ne\nf\nG\n")));
+    }
+
+    #[test]
+    fn add_only_hunks_apply_after_their_line() {
+        // As in `git diff -U0'
+        let patch = "--- a/f\n+++ b/f\n@@ -2,0 +3,2 @@\n+x\n+y\n";
+        assert_eq!(apply(patch, "a\nb\nc\n"), Ok(String::from("a\nb\nx\ny\nc\n")));
+
+        let patch = "--- /dev/null\n+++ b/f\n@@ -0,0 +1,2 @@\n+x\n+y\n";
+        assert_eq!(apply(patch, ""), Ok(String::from("x\ny\n")));
+    }
+
+    #[test]
+    fn mismatched_hunks_are_reported() {
+        let patch = "--- a/f\n+++ b/f\n\
+                     @@ -1 +1 @@\n-a\n+A\n\
+                     @@ -3 +3 @@\n-c\n+C\n";
+        assert_eq!(apply(patch, "a\nb\nc\n"), Ok(String::from("A\nb\nC\n")));
+        assert_eq!(apply(patch, "a\nb\nd\n"), Err(1));
+        assert_eq!(apply(patch, "x\nb\nc\n"), Err(0));
+    }
+
+    #[test]
+    fn renamed_new_and_deleted_files_are_applied() {
+        let test = TestRepo::new("cleanup");
+        let tree_id = tree_with(&test,
+                                &[("old.sh", "echo old\n"),
+                                  ("scripts/update.sh", "git submodule update\n"),
+                                  ("README", "hello\n")]);
+        let tree = test.repo.find_tree(tree_id).unwrap();
+        let patch = read("files",
+                         "diff --git a/old.sh b/new.sh\n\
+                          similarity index 50%\n\
+                          rename from old.sh\n\
+                          rename to new.sh\n\
+                          --- a/old.sh\n\
+                          +++ b/new.sh\n\
+                          @@ -1 +1 @@\n\
+                          -echo old\n\
+                          +echo new\n\
+                          diff --git a/scripts/update.sh b/scripts/update.sh\n\
+                          deleted file mode 100644\n\
+                          --- a/scripts/update.sh\n\
+                          +++ /dev/null\n\
+                          @@ -1 +0,0 @@\n\
+                          -git submodule update\n\
+                          diff --git a/ci/build.sh b/ci/build.sh\n\
+                          new file mode 100755\n\
+                          --- /dev/null\n\
+                          +++ b/ci/build.sh\n\
+                          @@ -0,0 +1 @@\n\
+                          +make\n")
+            .unwrap();
+
+        let new_tree_id = apply_patch(&test.repo, &tree, &patch).unwrap();
+        let new_tree = test.repo.find_tree(new_tree_id).unwrap();
+        assert_eq!(contents_of(&test, &new_tree, "old.sh"), None);
+        assert_eq!(contents_of(&test, &new_tree, "new.sh"),
+                   Some(String::from("echo new\n")));
+        assert_eq!(contents_of(&test, &new_tree, "scripts/update.sh"), None);
+        assert!(new_tree.get_path(Path::new("scripts")).is_err());
+        assert_eq!(contents_of(&test, &new_tree, "ci/build.sh"), Some(String::from("make\n")));
+        assert_eq!(new_tree.get_path(Path::new("ci/build.sh")).unwrap().filemode(),
+                   0o100755);
+        assert_eq!(contents_of(&test, &new_tree, "README"), Some(String::from("hello\n")));
+    }
+
+    #[test]
+    fn patches_that_dont_fit_the_tree_are_reported() {
+        let test = TestRepo::new("cleanup-errors");
+        let tree_id = tree_with(&test, &[("README", "hello\n")]);
+        let tree = test.repo.find_tree(tree_id).unwrap();
+
+        let patch = read("missing", "--- a/gone\n+++ b/gone\n@@ -1 +1 @@\n-a\n+b\n").unwrap();
+        assert_eq!(apply_patch(&test.repo, &tree, &patch),
+                   Err(String::from("gone doesn't exist")));
+
+        let patch = read("exists", "--- /dev/null\n+++ b/README\n@@ -0,0 +1 @@\n+hi\n").unwrap();
+        assert_eq!(apply_patch(&test.repo, &tree, &patch),
+                   Err(String::from("README already exists")));
+
+        let patch = read("mismatch", "--- a/README\n+++ b/README\n@@ -1 +1 @@\n-bye\n+hi\n")
+            .unwrap();
+        assert_eq!(apply_patch(&test.repo, &tree, &patch),
+                   Err(String::from("hunk #1 of README doesn't match")));
+    }
+}
@@ -0,0 +1,85 @@
+// Colors for the reports, to make the important bits stand out in long outputs.
+//
+// With --color=auto (the default), colors are only used when the output goes to a terminal.
+// NO_COLOR turns them off, and CLICOLOR=0 and CLICOLOR_FORCE work as described at
+// https://bixense.com/clicolors/.
+
+use std::env;
+
+pub struct Colors {
+    stdout: bool,
+    stderr: bool,
+}
+
+const STDOUT_FD: i32 = 1;
+pub const STDERR_FD: i32 = 2;
+
+impl Colors {
+    // `when` is one of "always", "never" or "auto", as accepted by --color
+    pub fn new(when: &str) -> Colors {
+        let (stdout, stderr) = match when {
+            "always" => (true, true),
+            "never" => (false, false),
+            _ => {
+                let is_set = |name| env::var(name).map(|value| !value.is_empty()).unwrap_or(false);
+                if is_set("NO_COLOR") {
+                    (false, false)
+                } else if is_set("CLICOLOR_FORCE") &&
+                          env::var("CLICOLOR_FORCE").ok() != Some(String::from("0")) {
+                    (true, true)
+                } else if env::var("CLICOLOR").ok() == Some(String::from("0")) ||
+                          env::var("TERM").ok() == Some(String::from("dumb")) {
+                    (false, false)
+                } else {
+                    (is_terminal(STDOUT_FD), is_terminal(STDERR_FD))
+                }
+            }
+        };
+
+        Colors {
+            stdout: stdout,
+            stderr: stderr,
+        }
+    }
+
+    // Warnings go to stderr
+    pub fn warning(&self, text: &str) -> String {
+        paint(text, "33", self.stderr)
+    }
+
+    // So do the dangling references
+    pub fn dangling(&self, text: &str) -> String {
+        paint(text, "31", self.stderr)
+    }
+
+    // Branches that were created or moved; reported on stdout
+    pub fn branch(&self, text: &str) -> String {
+        paint(text, "36", self.stdout)
+    }
+
+    // The line that sums up the whole run; also on stdout
+    pub fn summary(&self, text: &str) -> String {
+        paint(text, "1;32", self.stdout)
+    }
+}
+
+fn paint(text: &str, color: &str, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{}m{}\x1b[0m", color, text)
+    } else {
+        String::from(text)
+    }
+}
+
+#[cfg(unix)]
+pub fn is_terminal(fd: i32) -> bool {
+    extern "C" {
+        fn isatty(fd: i32) -> i32;
+    }
+    unsafe { isatty(fd) == 1 }
+}
+
+#[cfg(not(unix))]
+pub fn is_terminal(_: i32) -> bool {
+    false
+}
@@ -1,11 +1,31 @@
-#[cfg(not(feature = "HAS_EPRINTLN"))]
+// Everything that goes to stderr is written through write_stderr(), so that it can't get mixed up
+// with stdout when both are redirected to the same file, and doesn't end up in the middle of
+// the progress meter.
 macro_rules! eprintln {
-    ($fmt:expr) => ({
-        use std::io::Write;
-        writeln!(std::io::stderr(), $fmt).unwrap();
-    });
-    ($fmt:expr, $($arg:tt)*) => ({
-        use std::io::Write;
-        writeln!(std::io::stderr(), $fmt, $( $arg )*).unwrap();
+    ($($arg:tt)*) => ({
+        ::macros::write_stderr(&format!($($arg)*));
     });
 }
+
+#[allow(deprecated)]
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::io::Write;
+
+// Whether the last thing on stderr is an unfinished progress meter line
+#[allow(deprecated)]
+pub static PROGRESS_METER_SHOWN: AtomicBool = ATOMIC_BOOL_INIT;
+
+pub fn write_stderr(message: &str) {
+    // Whatever went to stdout before this should come before it in the file, too. Stdout is
+    // flushed a line at a time, so its lines can't be cut in two.
+    let _ = ::std::io::stdout().flush();
+    // The meter is redrawn on its next update
+    let erase = if PROGRESS_METER_SHOWN.swap(false, Ordering::SeqCst) {
+        "\r\x1b[K"
+    } else {
+        ""
+    };
+    // One write per message, so that it comes out in one piece
+    let stderr = ::std::io::stderr();
+    let _ = stderr.lock().write_all(format!("{}{}\n", erase, message).as_bytes());
+}
@@ -3,12 +3,48 @@ extern crate clap;
 extern crate git2;
 
 use git2::{Repository, Commit, Oid, Revwalk, Index, Tree};
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 #[macro_use]
 mod macros;
 
+mod checklist;
+mod cleanup;
+mod color;
+mod manpage;
+mod migrate;
+mod migration_script;
+mod output;
+mod quarantine;
+mod raw_commit;
+mod references;
+mod scan_cache;
+mod script;
+mod spill;
+mod state;
+mod version_info;
+
+#[cfg(feature = "tui")]
+mod tui;
+
+#[cfg(not(feature = "tui"))]
+mod tui {
+    use git2::Oid;
+    use std::collections::HashMap;
+
+    // Without the "tui" feature, --interactive isn't accepted, so this is never called
+    pub fn browse_plan(_: &::git2::Repository,
+                       _: &HashMap<Oid, Oid>,
+                       _: &::Options)
+                       -> Option<HashMap<Oid, Oid>> {
+        unreachable!()
+    }
+}
+
 const E_SUCCESS: i32 = 0;
 const E_NO_GIT_REPO: i32 = 1;
 const E_FOUND_DANGLING_REFERENCES: i32 = 2;
@@ -17,613 +53,7216 @@ const E_INVALID_MAPPINGS: i32 = 4;
 const E_DIRTY_WORKDIR: i32 = 5;
 const E_SUBMODULE_FETCH_FAILED: i32 = 6;
 const E_SUBMODULE_NOT_FOUND: i32 = 7;
+const E_WORKDIR_MISMATCH: i32 = 8;
+const E_TAG_COLLISION: i32 = 9;
+const E_INVALID_ENVIRONMENT: i32 = 10;
+const E_VERIFICATION_FAILED: i32 = 11;
+const E_ABORTED: i32 = 12;
+const E_INVALID_IDENTITY: i32 = 13;
+const E_CONCURRENT_ACCESS: i32 = 14;
+const E_ARCHIVE_EXISTS: i32 = 15;
+const E_MEMORY_LIMIT_EXCEEDED: i32 = 16;
+const E_NOTHING_TO_PROMOTE: i32 = 17;
+const E_EXISTING_CONTENT: i32 = 18;
+const E_SELF_REFERENCING_SUBMODULE: i32 = 19;
+const E_COMPLETED_WITH_WARNINGS: i32 = 20;
+const E_STRICT_FAILURE: i32 = 21;
+const E_UNWRITABLE_MESSAGE: i32 = 22;
+const E_PROTECTED_REFS: i32 = 23;
+const E_NOTHING_TO_DO: i32 = 24;
+const E_OUTPUT_FAILED: i32 = 25;
 
-fn main() {
-    let exit_code = real_main();
-    std::process::exit(exit_code);
+// Paths that --strip-submodule-ci leaves out of submodule's history. They only matter to the
+// submodule's own CI, and would confuse the path filters of the main repo's one.
+const SUBMODULE_CI_PATHS: &'static [&'static str] = &[".github",
+                                                      ".gitlab-ci.yml",
+                                                      ".gitlab",
+                                                      ".travis.yml",
+                                                      ".circleci",
+                                                      "appveyor.yml",
+                                                      ".appveyor.yml",
+                                                      "azure-pipelines.yml",
+                                                      ".drone.yml",
+                                                      ".cirrus.yml",
+                                                      ".buildkite",
+                                                      "bitbucket-pipelines.yml",
+                                                      "Jenkinsfile"];
+
+// Every option can also be set through an environment variable named after it, e.g.
+// GIT_SUBMERGE_DEFAULT_MAPPING for --default-mapping. Options given on the command line take
+// precedence.
+struct EnvOption {
+    name: &'static str,
+    short: Option<&'static str>,
+    // Zero for flags
+    values_count: usize,
+    // Values of repeated options are separated by whitespace
+    multiple: bool,
 }
 
-fn real_main() -> i32 {
-    let mut mappings: HashMap<Oid, Oid> = HashMap::new();
-    let (submodule_dir, default_mapping) = match parse_cli_arguments(&mut mappings) {
-        Ok((dir, oid)) => (dir, oid),
-        Err(exit_code) => return exit_code,
-    };
+const ENV_OPTIONS: &'static [EnvOption] = &[
+    EnvOption {
+        name: "mapping",
+        short: Some("m"),
+        values_count: 2,
+        multiple: true,
+    },
+    EnvOption {
+        name: "default-mapping",
+        short: Some("d"),
+        values_count: 1,
+        multiple: true,
+    },
+    EnvOption {
+        name: "override",
+        short: None,
+        values_count: 2,
+        multiple: true,
+    },
+    EnvOption {
+        name: "allow-external-mapping",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "external-repo",
+        short: None,
+        values_count: 1,
+        multiple: true,
+    },
+    EnvOption {
+        name: "fetch-refspec",
+        short: None,
+        values_count: 1,
+        multiple: true,
+    },
+    EnvOption {
+        name: "include-remote-refs",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "rev-range",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "preserve-gitlinks-before",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "tag-rename",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "merge-matching-branches",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "summary-commit",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "dot",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "interactive",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "diff-preview",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "signoff",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "committer",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "committer-date",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "lock",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "archive-old-branches",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "max-default-mappings",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "strip-submodule-ci",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "strip-path",
+        short: None,
+        values_count: 1,
+        multiple: true,
+    },
+    EnvOption {
+        name: "honor-export-ignore",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "merge-mailmap",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "url",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "exclude-branches",
+        short: None,
+        values_count: 1,
+        multiple: true,
+    },
+    EnvOption {
+        name: "include-refs",
+        short: None,
+        values_count: 1,
+        multiple: true,
+    },
+    EnvOption {
+        name: "progress-json",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "progress-fd",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "max-memory",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "auto-init",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "abbrev",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "color",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "mappings-from",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "output-refs-namespace",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "existing-content",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "date-order",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "codeowners",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "emit-script",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "output",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "no-update-worktree",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "keep-submodule-gitfile",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "map-range",
+        short: None,
+        values_count: 2,
+        multiple: true,
+    },
+    EnvOption {
+        name: "default-mapping-for",
+        short: None,
+        values_count: 2,
+        multiple: true,
+    },
+    EnvOption {
+        name: "fetch-historical-urls",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "also-fetch",
+        short: None,
+        values_count: 1,
+        multiple: true,
+    },
+    EnvOption {
+        name: "strict",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "head",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "hoist-gitignore",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "no-progress",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "quarantine",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "protected-ref",
+        short: None,
+        values_count: 1,
+        multiple: true,
+    },
+    EnvOption {
+        name: "rewrite-protected",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "migration-checklist",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "no-scan-cache",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "find-renames",
+        short: Some("M"),
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "find-copies",
+        short: Some("C"),
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "similarity-threshold",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "text",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "binary",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "emit-migration-script",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "no-reference-scan",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "report-vendored",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "cleanup-remove",
+        short: None,
+        values_count: 1,
+        multiple: true,
+    },
+    EnvOption {
+        name: "cleanup-patch",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "prune-empty",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "skip-downgrade-parents",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "tip-only",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "no-modify-refs",
+        short: None,
+        values_count: 0,
+        multiple: false,
+    },
+    EnvOption {
+        name: "result-prefix",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+    EnvOption {
+        name: "result-suffix",
+        short: None,
+        values_count: 1,
+        multiple: false,
+    },
+];
 
+#[derive(PartialEq)]
+enum Mode {
+    Merge,
+    // Re-check a merge that was done earlier, using the commit map it left behind
+    Verify,
+    // Move the results of an earlier run with --output-refs-namespace into place
+    Promote,
+    // Only rewrite the submodule's history, and leave the main one alone
+    Import,
+    // Only rewrite the main history, using the submodule's history from an earlier `import'
+    Stitch,
+    // Bring another clone over to the history rewritten by an earlier run
+    MigrateClone,
+}
 
-    let repo = match Repository::open(".") {
-        Ok(repo) => repo,
-        Err(e) => {
-            eprintln!("Couldn't find Git repo in the current directory: {}",
-                      e.message());
-            return E_NO_GIT_REPO;
-        }
-    };
+// See --codeowners
+enum CodeownersAction {
+    Apply,
+    Print,
+}
 
-    if !is_workdir_clean(&repo) {
-        eprintln!("The working directory is dirty, aborting!");
-        return E_DIRTY_WORKDIR;
-    }
+// See --existing-content
+enum ExistingContent {
+    Error,
+    PreferSubmodule,
+    PreferExisting,
+}
 
-    if !does_submodule_exist(&repo, &submodule_dir) {
-        eprintln!("Couldn't find a submodule named `{}'", submodule_dir);
-        return E_SUBMODULE_NOT_FOUND;
-    }
+struct Options {
+    mode: Mode,
+    submodule_dir: String,
+    mappings: HashMap<Oid, Oid>,
+    // For each mapping, the commits it went through before resolve_mapping_chains() reached its
+    // final target, starting with the mapped commit itself; only used to explain invalid ones
+    mapping_chains: HashMap<Oid, Vec<Oid>>,
+    // Paths of the other submodules that some mappings were given for, and which are ignored
+    other_mapping_scopes: HashSet<String>,
+    default_mapping: Option<Oid>,
+    overrides: HashMap<Oid, Oid>,
+    allow_external_mapping: bool,
+    external_repos: Vec<String>,
+    fetch_refspecs: Vec<String>,
+    include_remote_refs: bool,
+    rev_range: Option<String>,
+    preserve_gitlinks_before: Option<String>,
+    tag_rename: Option<String>,
+    merge_matching_branches: bool,
+    summary_commit: bool,
+    dot_file: Option<String>,
+    interactive: bool,
+    diff_preview: Option<String>,
+    signoff: bool,
+    // Name and email
+    committer: Option<(String, String)>,
+    // Seconds since the Unix epoch
+    committer_date: Option<i64>,
+    lock: bool,
+    archive_namespace: Option<String>,
+    max_default_mappings: Option<usize>,
+    strip_paths: Vec<String>,
+    honor_export_ignore: bool,
+    merge_mailmap: bool,
+    url: Option<String>,
+    exclude_branches: Vec<String>,
+    include_refs: Vec<String>,
+    progress: Option<Progress>,
+    // (phase, message) for each warn()
+    warnings: RefCell<Vec<(&'static str, String)>>,
+    // Whether this run wrote the commit map, i.e. actually rewrote something
+    commit_map_written: Cell<bool>,
+    memory_limit: Option<MemoryLimit>,
+    auto_init: bool,
+    abbrev: Option<usize>,
+    colors: color::Colors,
+    output_namespace: Option<String>,
+    existing_content: ExistingContent,
+    date_order: bool,
+    codeowners: Option<CodeownersAction>,
+    emit_script: Option<String>,
+    output: Option<String>,
+    no_update_worktree: bool,
+    keep_submodule_gitfile: bool,
+    // (since, until, submodule commit) from --map-range, with times in seconds since the epoch
+    date_range_mappings: Vec<(i64, i64, Oid)>,
+    // (branch glob, submodule commit) from --default-mapping-for and the mappings file
+    branch_default_mappings: Vec<(String, Oid)>,
+    fetch_historical_urls: bool,
+    also_fetch: Vec<String>,
+    strict: bool,
+    head: Option<String>,
+    hoist_gitignore: bool,
+    progress_meter: bool,
+    import_map_path: Option<String>,
+    // For `migrate-clone'; the remote is None with --no-fetch
+    migration_commit_map: Option<String>,
+    migration_remote: Option<String>,
+    import_map: Option<HashMap<Oid, Oid>>,
+    // i18n.commitEncoding, unless it's UTF-8
+    commit_encoding: Option<String>,
+    // Submodule commits that the main history has, too; see find_shared_commits()
+    shared_commits: HashSet<Oid>,
+    quarantine: bool,
+    protected_refs: Vec<String>,
+    rewrite_protected: bool,
+    migration_checklist: bool,
+    scan_cache: bool,
+    diff_settings: DiffSettings,
+    migration_script: Option<String>,
+    no_reference_scan: bool,
+    report_vendored: bool,
+    cleanup_remove: Vec<String>,
+    cleanup_patch: Option<cleanup::Patch>,
+    prune_empty: bool,
+    skip_downgrade_parents: bool,
+    tip_only: bool,
+    no_modify_refs: bool,
+    result_prefix: String,
+    result_suffix: String,
+}
 
-    match fetch_submodule_history(&repo, &submodule_dir) {
-        Ok(_) => {}
-        Err(_) => return E_SUBMODULE_FETCH_FAILED,
-    }
+// With --lock, we hold .git/index.lock while working, so that other Git commands can't modify
+// the index under us. The lock is released when this goes out of scope.
+struct IndexLock {
+    path: PathBuf,
+}
 
-    if !are_mappings_valid(&repo, &submodule_dir, &mappings, &default_mapping) {
-        return E_INVALID_MAPPINGS;
+impl IndexLock {
+    fn acquire(repo: &Repository) -> Result<IndexLock, ()> {
+        let path = repo.path().join("index.lock");
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => Ok(IndexLock { path: path }),
+            Err(e) => {
+                eprintln!("Couldn't lock the index ({}): {}\n\nIs some other Git command \
+                           running? If not, remove the file and try again.",
+                          path.display(),
+                          e);
+                Err(())
+            }
+        }
     }
+}
 
-    println!("Merging {}...", submodule_dir);
+impl Drop for IndexLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
 
-    let mut old_id_to_new = HashMap::new();
+// With --lock, we also hold the lock files that Git takes to update packed-refs, HEAD, and the
+// branches we're going to move, so that other Git commands fail to move them rather than have their
+// work overwritten. libgit2 takes the very same files when we move the refs ourselves, so these are
+// released right before that, and get_refs_snapshot() covers the rest of the way.
+struct RefLocks {
+    paths: Vec<PathBuf>,
+}
 
-    rewrite_submodule_history(&repo, &mut old_id_to_new, &submodule_dir);
+impl RefLocks {
+    fn acquire(repo: &Repository, options: &Options) -> Result<RefLocks, ()> {
+        let mut refs = vec![String::from("packed-refs"), String::from("HEAD")];
+        let mut branches: Vec<String> = get_branch_to_id_map(repo, options)
+            .into_iter()
+            .filter(|&(ref name, _)| !is_branch_excluded(name, options))
+            .map(|(name, _)| format!("refs/heads/{}", name))
+            .collect();
+        branches.sort();
+        refs.extend(branches);
 
-    match find_dangling_references_to_submodule(&repo,
-                                                &submodule_dir,
-                                                &old_id_to_new,
-                                                &mappings,
-                                                &default_mapping) {
-        Some(_) => return E_FOUND_DANGLING_REFERENCES,
-        None => {}
+        // Whatever we managed to lock is released if the rest fails
+        let mut locks = RefLocks { paths: Vec::new() };
+        for name in refs {
+            let path = repo.path().join(format!("{}.lock", name));
+            // Packed branches might not have their directory
+            let result = path.parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::OpenOptions::new().write(true).create_new(true).open(&path));
+            match result {
+                Ok(_) => locks.paths.push(path),
+                Err(e) => {
+                    eprintln!("Couldn't lock {} ({}): {}\n\nIs some other Git command running? If \
+                               not, remove the file and try again.",
+                              name,
+                              path.display(),
+                              e);
+                    return Err(());
+                }
+            }
+        }
+        Ok(locks)
     }
+}
 
-    rewrite_repo_history(&repo,
-                         &mut old_id_to_new,
-                         &mappings,
-                         &default_mapping,
-                         &submodule_dir);
-
-    // Working directories with and without submodules are pretty much
-    // the same, save for two files:
-    // - submodules have .git in their root directory;
-    // - there's .gitmodules in the root of the repo.
-    remove_dotgit_from_submodule(&submodule_dir);
-    remove_gitmodules();
-    // Git used to think of submodule's directory as a file, because it was
-    // "opaque". We have to update the index in order for Git to realise
-    // that the submodule directory is *just* a directory now.
-    update_index(&repo, &old_id_to_new);
+impl Drop for RefLocks {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
 
-    E_SUCCESS
+// Held for the whole run, so that two git-submerge processes can't interleave their object writes
+// and ref moves. Unlike IndexLock, it's always taken: it only gets in the way of git-submerge.
+struct RunLock {
+    path: PathBuf,
 }
 
-fn parse_cli_arguments(mappings: &mut HashMap<Oid, Oid>) -> Result<(String, Option<Oid>), i32> {
-    let options = clap::App::new("git-submerge")
-        .version("0.5")
-        .author(crate_authors!())
-        .about("Merge Git submodule into the main repo as if they've never been separate at all")
-        .arg(clap::Arg::with_name("SUBMODULE_DIR")
-            .help("The submodule to merge")
-            .required(true)
-            .index(1))
-        .arg(clap::Arg::with_name("mapping")
-            .value_names(&["commit id 1", "commit id 2"])
-            .help("Whenever main repo references submodule's <commit id 1>, the <commit id 2> \
-                   will be used instead")
-            .short("m")
-            .long("mapping")
-            .number_of_values(2)
-            .multiple(true))
-        .arg(clap::Arg::with_name("default-mapping")
-            .value_name("commit id")
-            .help("Whenever main repo references a commit that is neither in submodule's \
-                   history nor in mappings (see --mapping), the <commit id> will be used instead")
-            .short("d")
-            .long("default-mapping")
-            .number_of_values(1)
-            .multiple(false))
-        .get_matches();
+impl RunLock {
+    fn acquire(repo: &Repository) -> Result<RunLock, ()> {
+        let path = repo.path().join("submerge").join("lock");
+        let result = std::fs::create_dir_all(repo.path().join("submerge"))
+            .and_then(|_| std::fs::OpenOptions::new().write(true).create_new(true).open(&path));
+        match result {
+            Ok(_) => Ok(RunLock { path: path }),
+            Err(e) => {
+                eprintln!("Couldn't lock the repository ({}): {}\n\nIs another git-submerge \
+                           running on it? If not, remove the file and try again.",
+                          path.display(),
+                          e);
+                Err(())
+            }
+        }
+    }
+}
 
-    match options.values_of("mapping") {
-        None => {}
-        Some(values) => {
-            let mut i: i32 = 1;
-            let (first, second): (Vec<&str>, Vec<&str>) = values.partition(|_| {
-                i += 1;
-                i % 2 == 0
-            });
-            for (f, s) in first.iter().zip(second.iter()) {
-                let oid1 = match Oid::from_str(f) {
-                    Ok(oid) => oid,
-                    Err(_) => {
-                        eprintln!("{} is not a valid 40-character hex string", f);
-                        return Err(E_INVALID_COMMIT_ID);
-                    }
-                };
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
 
-                let oid2 = match Oid::from_str(s) {
-                    Ok(oid) => oid,
-                    Err(_) => {
-                        eprintln!("{} is not a valid 40-character hex string", s);
-                        return Err(E_INVALID_COMMIT_ID);
-                    }
-                };
+// Machine-readable progress for --progress-json and --progress-fd: lines like
+// {"phase":"repo","done":100,"total":2500,"rate":431.5}, where rate is in commits per second.
+struct Progress {
+    output: RefCell<File>,
+    // When the current phase started
+    started: Cell<std::time::Instant>,
+}
 
-                mappings.insert(oid1, oid2);
-            }
+impl Progress {
+    fn new(output: File) -> Progress {
+        Progress {
+            output: RefCell::new(output),
+            started: Cell::new(std::time::Instant::now()),
         }
     }
 
-    let default_mapping_str = options.value_of("default-mapping");
-    let default_mapping = if let Some(s) = default_mapping_str {
-        match Oid::from_str(s) {
-            Ok(oid) => Some(oid),
-            Err(_) => {
-                eprintln!("{} is not a valid 40-character hex string", s);
-                return Err(E_INVALID_COMMIT_ID);
-            }
+    fn report(&self, phase: &str, done: usize, total: usize) {
+        // Often enough to look alive, rarely enough not to slow anything down
+        if done % 100 != 0 && done != total {
+            return;
         }
-    } else {
-        None
-    };
-
-    // We can safely use unwrap() here because the argument is marked as "required" and Clap checks
-    // its presence for us.
-    Ok((String::from(options.value_of("SUBMODULE_DIR").unwrap()), default_mapping))
+        if done == 0 {
+            self.started.set(std::time::Instant::now());
+        }
+        let elapsed = self.started.get().elapsed();
+        let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let rate = if seconds > 0.0 { done as f64 / seconds } else { 0.0 };
+        // Nobody might be reading on the other end anymore; that's not a reason to stop
+        let _ = writeln!(self.output.borrow_mut(),
+                         "{{\"phase\":\"{}\",\"done\":{},\"total\":{},\"rate\":{:.1}}}",
+                         phase,
+                         done,
+                         total,
+                         rate);
+    }
 }
 
-fn is_workdir_clean(repo: &Repository) -> bool {
-    let mut statusopts = git2::StatusOptions::new();
-    statusopts.include_untracked(false);
-    statusopts.include_ignored(false);
-    statusopts.include_unmodified(false);
-    statusopts.exclude_submodules(false);
-    statusopts.recurse_untracked_dirs(false);
-    statusopts.recurse_ignored_dirs(false);
-    let statuses = repo.statuses(Some(&mut statusopts))
-        .expect("Couldn't get statuses from the repo");
-    statuses.iter().count() == 0
+fn report_progress(options: &Options, phase: &str, done: usize, total: usize) {
+    if let Some(ref progress) = options.progress {
+        progress.report(phase, done, total);
+    }
+    if options.progress_meter {
+        draw_progress_meter(phase, done, total);
+    }
 }
 
-fn does_submodule_exist(repo: &Repository, submodule_dir: &str) -> bool {
-    repo.find_submodule(submodule_dir).is_ok()
+// The human-readable counterpart of Progress, redrawn in place on stderr. Anything else written
+// to stderr erases it first; see write_stderr().
+fn draw_progress_meter(phase: &str, done: usize, total: usize) {
+    if total == 0 || (done % 100 != 0 && done != total) {
+        return;
+    }
+    let line = format!("\rRewriting {} commits: {}% ({}/{})",
+                       if phase == "submodule" { "submodule's" } else { "repo's" },
+                       done * 100 / total,
+                       done,
+                       total);
+    let finished = done == total;
+    let stderr = std::io::stderr();
+    let mut stderr = stderr.lock();
+    let _ = if finished {
+        writeln!(stderr, "{}, done.", line)
+    } else {
+        write!(stderr, "{}", line)
+    };
+    macros::PROGRESS_METER_SHOWN.store(!finished, std::sync::atomic::Ordering::SeqCst);
 }
 
-// Checks if all the values in the `mappings` exist in submodule's history
-fn are_mappings_valid(repo: &Repository,
-                      submodule_dir: &str,
-                      mappings: &HashMap<Oid, Oid>,
-                      default_mapping: &Option<Oid>)
-                      -> bool {
-    let mut commits: HashSet<Oid> = mappings.values().cloned().collect();
-    if let &Some(oid) = default_mapping {
-        commits.insert(oid);
-    };
+// With --max-memory, the rewrite loops check the resident set size every now and then. Once it gets
+// close to the limit, they move what they can to disk (see spill.rs) and carry on; if it gets over
+// the limit all the same, they stop early, and the caller has to check exceeded() and bail out.
+struct MemoryLimit {
+    megabytes: u64,
+    exceeded: Cell<bool>,
+    // Whether anything was moved to disk yet
+    spilled: Cell<bool>,
+}
 
-    let revwalk = get_submodule_revwalk(&repo, &submodule_dir);
-    for maybe_oid in revwalk {
-        match maybe_oid {
-            Ok(oid) => {
-                commits.remove(&oid);
-            }
-            Err(e) => eprintln!("Error walking the submodule's history: {:?}", e),
+impl MemoryLimit {
+    fn new(megabytes: u64) -> MemoryLimit {
+        MemoryLimit {
+            megabytes: megabytes,
+            exceeded: Cell::new(false),
+            spilled: Cell::new(false),
         }
     }
 
-    for commit in commits.iter() {
-        eprintln!("Commit {} not found in submodule's history.", commit);
+    fn check(&self) -> MemoryUsage {
+        let mut status = String::new();
+        // Only Linux has /proc/self/status; elsewhere, the limit never triggers
+        let _ = File::open("/proc/self/status").and_then(|mut f| f.read_to_string(&mut status));
+        let rss_kilobytes = status.lines()
+            .find(|line| line.starts_with("VmRSS:"))
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|kilobytes| kilobytes.parse::<u64>().ok());
+        let megabytes = rss_kilobytes.map_or(0, |kilobytes| kilobytes / 1024);
+        if megabytes > self.megabytes {
+            self.exceeded.set(true);
+        }
+        if self.exceeded.get() {
+            MemoryUsage::OverLimit
+        } else if megabytes > self.megabytes / 4 * 3 {
+            MemoryUsage::NearLimit
+        } else {
+            MemoryUsage::Fine
+        }
     }
-
-    commits.len() == 0
 }
 
-fn get_submodule_revwalk<'repo>(repo: &'repo Repository, submodule_dir: &str) -> Revwalk<'repo> {
-    let submodule = repo.find_submodule(submodule_dir)
-        .expect("Couldn't find the submodule with expected path");
-    let submodule_head = submodule.head_id()
-        .expect("Couldn't obtain submodule's HEAD");
-
-    let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
-    // "Topological" and reverse means "parents are always visited before their children".
-    // We need that in order to be sure that our old-to-new-ids map always contains everything we
-    // need it to contain.
-    revwalk.set_sorting(git2::SORT_REVERSE | git2::SORT_TOPOLOGICAL);
-    // TODO (#6): push all branches and tags, not just HEAD
-    revwalk.push(submodule_head).expect("Couldn't add submodule's HEAD to RevWalk");
+enum MemoryUsage {
+    Fine,
+    NearLimit,
+    OverLimit,
+}
 
-    revwalk
+// Reading /proc is too slow to do for every commit
+fn check_memory_usage(options: &Options, done: usize) -> MemoryUsage {
+    match options.memory_limit {
+        Some(ref limit) if done % 100 == 0 => limit.check(),
+        _ => MemoryUsage::Fine,
+    }
 }
 
-fn fetch_submodule_history(repo: &Repository, submodule_dir: &str) -> Result<(), ()> {
-    let submodule_url = String::from("./") + submodule_dir;
-    let mut remote = repo.remote_anonymous(&submodule_url)
-        .expect("Couldn't create an anonymous remote");
-    match remote.fetch(&[], None, None) {
-        Ok(_) => Ok(()),
-        Err(_) => {
-            eprintln!("Couldn't fetch submodule's history!  Have you forgot to run \
-                       `git submodule update --recursive`?");
-            Err(())
+// Moves the commit map entries of the commits that the loop is done with to disk. Returns false if
+// that didn't work out, in which case the loop carries on as it was.
+fn spill_commit_map(spill: &mut spill::Spill,
+                    old_id_to_new: &mut HashMap<Oid, Oid>,
+                    done: &[Result<Oid, git2::Error>],
+                    options: &Options)
+                    -> bool {
+    if let Some(ref limit) = options.memory_limit {
+        if !limit.spilled.get() {
+            eprintln!("Memory usage is getting close to {} MB; moving the commit map to {} and \
+                       carrying on.",
+                      limit.megabytes,
+                      spill.path().display());
+            limit.spilled.set(true);
+        }
+    }
+    // Shared commits are in both histories, and the other one might still need them
+    let ids: Vec<Oid> = done.iter()
+        .filter_map(|maybe_oid| maybe_oid.as_ref().ok())
+        .filter(|oid| !options.shared_commits.contains(oid))
+        .cloned()
+        .collect();
+    match spill.spill(old_id_to_new, &ids) {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("Couldn't move the commit map to {}: {}",
+                      spill.path().display(),
+                      e);
+            false
         }
     }
 }
 
-fn rewrite_submodule_history(repo: &Repository,
-                             old_id_to_new: &mut HashMap<Oid, Oid>,
-                             submodule_dir: &str) {
-    let revwalk = get_submodule_revwalk(&repo, &submodule_dir);
-    for maybe_oid in revwalk {
-        match maybe_oid {
-            Ok(oid) => {
-                let commit = repo.find_commit(oid)
-                    .expect(&format!("Couldn't get a commit with ID {}", oid));
-                let tree = commit.tree()
-                    .expect(&format!("Couldn't obtain the tree of a commit with ID {}", oid));
-                let mut old_index = Index::new()
-                    .expect("Couldn't create an in-memory index for commit");
-                let mut new_index = Index::new().expect("Couldn't create an in-memory index");
-                old_index.read_tree(&tree)
-                    .expect(&format!("Couldn't read the commit {} into index", oid));
+fn has_exceeded_memory_limit(options: &Options) -> bool {
+    options.memory_limit.as_ref().map_or(false, |limit| limit.exceeded.get())
+}
 
-                // Obtain the new tree, where everything from the old one is moved under
-                // a directory named after the submodule
-                for entry in old_index.iter() {
-                    let mut new_entry = entry;
+#[cfg(unix)]
+fn open_progress_fd(fd: i32) -> File {
+    use std::os::unix::io::FromRawFd;
+    // The descriptor was handed to us by whoever started us, and nobody else in this process
+    // uses it
+    unsafe { File::from_raw_fd(fd) }
+}
 
-                    let mut new_path = String::from(submodule_dir);
-                    new_path += "/";
-                    new_path += &String::from_utf8(new_entry.path)
-                        .expect("Failed to convert a path to str");
+// Without Unix, --progress-fd isn't accepted, so this is never called
+#[cfg(not(unix))]
+fn open_progress_fd(_: i32) -> File {
+    unreachable!()
+}
 
-                    new_entry.path = new_path.into_bytes();
-                    new_index.add(&new_entry).expect("Couldn't add an entry to the index");
-                }
-                let tree_id = new_index.write_tree_to(&repo)
-                    .expect("Couldn't write the index into a tree");
-                old_id_to_new.insert(tree.id(), tree_id);
-                let tree = repo.find_tree(tree_id)
-                    .expect("Couldn't retrieve the tree we just created");
+// How --diff-preview and `verify' compare trees. Thresholds are in percent; None turns the
+// detection off.
+struct DiffSettings {
+    renames: Option<u16>,
+    copies: Option<u16>,
+    text: bool,
+    binary: bool,
+}
 
-                let parents = {
-                    let mut p: Vec<Commit> = Vec::new();
-                    for parent_id in commit.parent_ids() {
-                        let new_parent_id = old_id_to_new[&parent_id];
-                        let parent = repo.find_commit(new_parent_id)
-                            .expect("Couldn't find parent commit by its id");
-                        p.push(parent);
-                    }
-                    p
-                };
+// What find_dangling_references_to_submodule() learned about the main repo's history, so that
+// rewrite_repo_history() doesn't have to walk it and look into its trees all over again
+struct HistoryScan {
+    // In the order they have to be rewritten in
+    commits: Vec<Oid>,
+    // Commit ID -> ID and filemode of whatever is at the submodule's path in that commit
+    submodule_entries: HashMap<Oid, Option<(Oid, i32)>>,
+}
 
-                let mut parents_refs: Vec<&Commit> = Vec::new();
-                for i in 0..parents.len() {
-                    parents_refs.push(&parents[i]);
-                }
-                let new_commit_id = repo.commit(None,
-                            &commit.author(),
-                            &commit.committer(),
-                            &commit.message().expect("Couldn't retrieve commit's message"),
-                            &tree,
-                            &parents_refs[..])
-                    .expect("Failed to commit");
+// New commits aren't referenced by anything until the branches are moved at the very end, so
+// a crash followed by `git gc` would throw all of them away. To prevent that, we point refs under
+// refs/submerge/in-progress/<phase>/ at the tips of the new history every now and then.
+// remove_temporary_refs() cleans them up once we're done.
+struct InProgressAnchors {
+    phase: &'static str,
+    tips: HashSet<Oid>,
+}
 
-                old_id_to_new.insert(oid, new_commit_id);
+impl InProgressAnchors {
+    fn new(phase: &'static str) -> InProgressAnchors {
+        InProgressAnchors {
+            phase: phase,
+            tips: HashSet::new(),
+        }
+    }
+
+    fn add(&mut self, repo: &Repository, new_id: Oid) {
+        let commit = repo.find_commit(new_id).expect("Couldn't find the commit we just created");
+        for parent_id in commit.parent_ids() {
+            self.tips.remove(&parent_id);
+        }
+        self.tips.insert(new_id);
+    }
+
+    fn anchor(&self, repo: &Repository) {
+        let namespace = format!("refs/submerge/in-progress/{}/", self.phase);
+        let references = repo.references_glob(&format!("{}*", namespace))
+            .expect("Couldn't obtain an iterator over references");
+        for mut reference in references.filter_map(|r| r.ok()) {
+            if !reference.target().map_or(false, |id| self.tips.contains(&id)) {
+                reference.delete().expect("Couldn't remove an outdated in-progress reference");
             }
-            Err(e) => eprintln!("Error walking the submodule's history: {:?}", e),
+        }
+        for tip in &self.tips {
+            repo.reference(&format!("{}{}", namespace, tip),
+                           *tip,
+                           true,
+                           "git-submerge: anchoring the history rewritten so far")
+                .expect("Couldn't create an in-progress reference");
         }
     }
 }
 
-fn find_dangling_references_to_submodule<'repo>(repo: &'repo Repository,
-                                                submodule_dir: &str,
-                                                old_id_to_new: &HashMap<Oid, Oid>,
-                                                mappings: &HashMap<Oid, Oid>,
-                                                default_mapping: &Option<Oid>)
-                                                -> Option<bool> {
-    let submodule_path = Path::new(submodule_dir);
-
-    let known_submodule_commits: HashSet<&Oid> = old_id_to_new.keys().collect();
-    let mut dangling_references = HashSet::new();
+// Where GitHub and GitLab look for CODEOWNERS
+const CODEOWNERS_PATHS: &'static [&'static str] = &[".github/CODEOWNERS",
+                                                    ".gitlab/CODEOWNERS",
+                                                    "CODEOWNERS",
+                                                    "docs/CODEOWNERS"];
 
-    let revwalk = get_repo_revwalk(&repo);
+// The longest path Windows accepts by default, including the terminating NUL
+const MAX_PATH: usize = 260;
 
-    for maybe_oid in revwalk {
-        match maybe_oid {
-            Ok(oid) => {
-                let commit = repo.find_commit(oid)
-                    .expect(&format!("Couldn't get a commit with ID {}", oid));
-                let tree = commit.tree()
-                    .expect(&format!("Couldn't obtain the tree of a commit with ID {}", oid));
+// How many commits to rewrite between updates of the in-progress refs
+const ANCHOR_INTERVAL: usize = 1000;
 
-                let submodule_subdir = match tree.get_path(submodule_path) {
-                    Ok(tree) => {
-                        // We're only interested in gitlinks
-                        if tree.filemode() != 0o160000 {
-                            continue;
-                        }
-                        tree
-                    },
-                    Err(e) => {
-                        if e.code() == git2::ErrorCode::NotFound &&
-                           e.class() == git2::ErrorClass::Tree {
-                            // It's okay. The tree lacks the subtree corresponding to the
-                            // submodule. In other words, the commit doesn't include the submodule.
-                            // That's totally fine. Let's  move on.
-                            continue;
-                        } else {
-                            // Unexpected error; let's report it and abort the program
-                            panic!("Error getting submodule's subdir from the tree: {:?}", e);
-                        };
-                    }
-                };
+// A main repo commit that went through rewrite_repo_history()
+struct RewrittenCommit {
+    old_id: Oid,
+    new_id: Oid,
+    // The submodule commit (from the original submodule history) whose contents replaced the
+    // gitlink. Zero if the directory was left empty, None if the commit kept its gitlink.
+    submodule_commit: Option<Oid>,
+}
 
-                // **INVARIANT**: if we got this far, current commit contains a submodule and
-                // should be rewritten
+// With --preserve-gitlinks-before, commits that should keep the submodule as it is
+enum GitlinkCutoff {
+    // The commit itself and all of its ancestors
+    Commits(HashSet<Oid>),
+    // Commits made before the given time (in seconds since the Unix epoch)
+    Date(i64),
+}
 
-                let submodule_commit_id = submodule_subdir.id();
-                if !known_submodule_commits.contains(&submodule_commit_id) &&
-                   !mappings.contains_key(&submodule_commit_id) &&
-                   default_mapping.is_none() {
-                    dangling_references.insert(submodule_commit_id);
-                }
+impl GitlinkCutoff {
+    fn from_options(repo: &Repository, options: &Options) -> Option<Result<GitlinkCutoff, ()>> {
+        options.preserve_gitlinks_before.as_ref().map(|cutoff| {
+            if let Ok(object) = repo.revparse_single(cutoff) {
+                let id = object.peel(git2::ObjectType::Commit).map_err(|_| ())?.id();
+                let mut revwalk = repo.revwalk()
+                    .expect("Couldn't obtain RevWalk object for the repo");
+                revwalk.push(id).expect("Couldn't add the cutoff commit to RevWalk");
+                let commits = revwalk.filter_map(|maybe_oid| maybe_oid.ok()).collect();
+                Ok(GitlinkCutoff::Commits(commits))
+            } else {
+                parse_date(cutoff).map(GitlinkCutoff::Date).ok_or(())
             }
-            Err(e) => eprintln!("Error walking the submodule's history: {:?}", e),
-        }
+        })
     }
 
-    if dangling_references.is_empty() {
-        None
-    } else {
-        eprintln!("The repository references the following submodule commits, but they couldn't \
-                   be found in the submodule's history:\n");
-        for id in dangling_references {
-            eprintln!("{}", id);
+    fn preserves(&self, commit: &Commit) -> bool {
+        match *self {
+            GitlinkCutoff::Commits(ref commits) => commits.contains(&commit.id()),
+            GitlinkCutoff::Date(time) => commit.committer().when().seconds() < time,
         }
-
-        eprintln!("\nYou can use --mapping and --default-mapping options to make git-submerge \
-                   replace these commits with some other, still existing, commits.");
-
-        Some(true)
     }
 }
 
-fn get_repo_revwalk<'repo>(repo: &'repo Repository) -> Revwalk<'repo> {
-    let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
-    revwalk.set_sorting(git2::SORT_REVERSE | git2::SORT_TOPOLOGICAL);
-    let head = repo.head().expect("Couldn't obtain repo's HEAD");
-    let head_id = head.target().expect("Couldn't resolve repo's HEAD to a commit ID");
-    revwalk.push(head_id).expect("Couldn't add repo's HEAD to RevWalk");
-
-    for (name, id) in get_branch_to_id_map(&repo) {
-        revwalk.push(id)
-            .expect(&format!("Couldn't push branch `{}' to RevWalk", name));
-    }
-
-    revwalk
+fn main() {
+    let exit_code = real_main();
+    std::process::exit(exit_code);
 }
 
-fn get_branch_to_id_map(repo: &Repository) -> HashMap<String, Oid> {
-    let mut result = HashMap::new();
+fn real_main() -> i32 {
+    let mut options = match parse_cli_arguments() {
+        Ok(options) => options,
+        Err(exit_code) => return exit_code,
+    };
 
-    let branches = repo.branches(Some(git2::BranchType::Local))
-        .expect("Couldn't obtain an iterator over local branches");
-    for maybe_branch in branches {
+    let repo = match Repository::open(".") {
+        Ok(repo) => repo,
+        Err(e) => {
+            eprintln!("Couldn't find Git repo in the current directory: {}",
+                      e.message());
+            return E_NO_GIT_REPO;
+        }
+    };
+
+    let _run_lock = match options.mode {
+        // Only reads the repo, so it can run alongside anything
+        Mode::Verify => None,
+        _ => {
+            match RunLock::acquire(&repo) {
+                Ok(lock) => Some(lock),
+                Err(_) => return E_CONCURRENT_ACCESS,
+            }
+        }
+    };
+
+    // Admins can protect refs in the config, so that nobody has to remember to do it
+    if let Ok(config) = repo.config() {
+        if let Ok(entries) = config.entries(Some("^submerge\\.protectedref$")) {
+            for entry in &entries {
+                if let Some(glob) = entry.ok().and_then(|entry| entry.value().map(String::from)) {
+                    options.protected_refs.push(glob);
+                }
+            }
+        }
+    }
+
+    match options.mode {
+        Mode::Verify => return verify_merge(&repo, &options),
+        Mode::Promote => return promote_results(&repo, &options),
+        Mode::MigrateClone => return migrate::migrate_clone(&repo, &options),
+        Mode::Merge | Mode::Import | Mode::Stitch => {}
+    }
+
+    let (quarantine, repo) = if options.quarantine {
+        match quarantine::Quarantine::open(&repo) {
+            Ok((quarantine, quarantined_repo)) => (Some(quarantine), quarantined_repo),
+            Err(message) => {
+                eprintln!("{}", message);
+                return E_INVALID_ENVIRONMENT;
+            }
+        }
+    } else {
+        (None, repo)
+    };
+
+    let started = std::time::SystemTime::now();
+    let mut exit_code = merge_submodule(&repo, &mut options);
+    if print_warnings_summary(&options, exit_code) && exit_code == E_SUCCESS {
+        exit_code = E_COMPLETED_WITH_WARNINGS;
+    }
+    if let Some(quarantine) = quarantine {
+        let succeeded = exit_code == E_SUCCESS || exit_code == E_COMPLETED_WITH_WARNINGS;
+        // With --output, the objects are already where they should be
+        let result = if (succeeded && options.output.is_none()) || quarantine.is_referenced() {
+            quarantine.migrate()
+        } else {
+            quarantine.discard()
+        };
+        if let Err(e) = result {
+            eprintln!("{} Couldn't clean up .git/submerge/quarantine/: {}. Run git-submerge with \
+                       --quarantine again to finish that.",
+                      options.colors.warning("Warning:"),
+                      e);
+        }
+    }
+    state::write(&repo, &options, started, options.commit_map_written.get(), exit_code);
+    exit_code
+}
+
+// Something went wrong, but not badly enough to stop, e.g. a branch couldn't be read. The run goes
+// on, but the result might be incomplete, so the warning is repeated at the end and the exit code
+// says so. Branches and revwalks are enumerated many times per run, so each warning is only
+// reported once.
+fn warn(options: &Options, phase: &'static str, message: String) {
+    let mut warnings = options.warnings.borrow_mut();
+    if warnings.iter().any(|&(seen_phase, ref seen_message)| {
+        seen_phase == phase && *seen_message == message
+    }) {
+        return;
+    }
+    eprintln!("{} {}", options.colors.warning("Warning:"), message);
+    warnings.push((phase, message));
+}
+
+// With --strict, anything that couldn't be read stops the run before the first ref is modified
+fn violates_strictness(repo: &Repository, options: &Options) -> bool {
+    if !options.strict {
+        return false;
+    }
+    // The steps that modify refs enumerate them once more; if that's going to fail, it has to fail
+    // now rather than halfway through
+    get_branch_to_id_map(repo, options);
+    if let Some(ref format) = options.tag_rename {
+        get_renamed_submodule_tags(repo, format, options);
+    }
+    !options.warnings.borrow().is_empty()
+}
+
+// Returns true if there was anything to print
+fn print_warnings_summary(options: &Options, exit_code: i32) -> bool {
+    let warnings = options.warnings.borrow();
+    if warnings.is_empty() {
+        return false;
+    }
+    let consequence = if exit_code == E_STRICT_FAILURE {
+        "nothing was changed (see --strict)"
+    } else {
+        "the result might be incomplete"
+    };
+    eprintln!("\n{} {} warning(s) came up along the way, so {}:",
+              options.colors.warning("Warning:"),
+              warnings.len(),
+              consequence);
+    for &(phase, ref message) in warnings.iter() {
+        eprintln!("    {}: {}", phase, message);
+    }
+    true
+}
+
+fn merge_submodule(repo: &Repository, options: &mut Options) -> i32 {
+    let submodule_dir = &options.submodule_dir;
+
+    if let Some(ref range) = options.rev_range {
+        if resolve_rev_range(repo, range).is_err() {
+            eprintln!("Couldn't resolve `{}' into a range of commits", range);
+            return E_INVALID_COMMIT_ID;
+        }
+    }
+
+    if let Some(name) = options.head.take() {
+        match resolve_head_ref(repo, &name) {
+            Ok(full_name) => options.head = full_name,
+            Err(_) => {
+                eprintln!("--head expects a ref that points at a commit, e.g. a branch; `{}' \
+                           isn't one",
+                          name);
+                return E_INVALID_COMMIT_ID;
+            }
+        }
+    }
+
+    options.commit_encoding = repo.config()
+        .and_then(|config| config.get_string("i18n.commitEncoding"))
+        .ok()
+        .and_then(|encoding| if is_utf8(&encoding) { None } else { Some(encoding) });
+
+    if let Some(Err(_)) = GitlinkCutoff::from_options(repo, options) {
+        eprintln!("--preserve-gitlinks-before expects a commit or a date (YYYY-MM-DD, \
+                   YYYY-MM-DDTHH:MM:SS in UTC, or @<seconds since the epoch>)");
+        return E_INVALID_COMMIT_ID;
+    }
+
+    let index_lock = if options.lock {
+        match IndexLock::acquire(repo) {
+            Ok(lock) => Some(lock),
+            Err(_) => return E_CONCURRENT_ACCESS,
+        }
+    } else {
+        None
+    };
+    let ref_locks = if options.lock {
+        match RefLocks::acquire(repo, options) {
+            Ok(locks) => Some(locks),
+            Err(_) => return E_CONCURRENT_ACCESS,
+        }
+    } else {
+        None
+    };
+    // The ref locks have to go before we move the refs, so we also check that nobody moved them
+    let refs_before = get_refs_snapshot(repo, options);
+
+    // Git doesn't follow symlinks and would just call the submodule deleted, so this has to come
+    // before the dirty worktree check
+    if let Some(symlink) = find_symlink_along_path(submodule_dir) {
+        if !options.no_update_worktree {
+            eprintln!("{} is a symbolic link, so changes to {} might end up somewhere other than \
+                       the submodule. Please replace the link with a real directory and try again.",
+                      symlink.display(),
+                      submodule_dir);
+            return E_INVALID_ENVIRONMENT;
+        }
+    }
+
+    // With --no-update-worktree or `import', nothing can be lost, and there might not even be
+    // a worktree
+    let is_import = options.mode == Mode::Import;
+    let dirty_paths = if options.no_update_worktree || is_import {
+        Vec::new()
+    } else {
+        get_dirty_paths(repo, &options.submodule_dir)
+    };
+    if !dirty_paths.is_empty() {
+        eprintln!("The working directory is dirty, aborting! These paths have changes:");
+        for &(ref path, status) in &dirty_paths {
+            eprintln!("    {} {}", get_status_letters(status), path);
+        }
+        eprintln!("Please commit or stash them (e.g. with `git stash') and try again.");
+        return E_DIRTY_WORKDIR;
+    }
+
+    if let Some(ref path) = options.output {
+        if let Err(message) = output::check_path(path) {
+            eprintln!("{}; please pick another place for --output", message);
+            return E_INVALID_ENVIRONMENT;
+        }
+    }
+
+    if let Some(ref namespace) = options.archive_namespace {
+        let mut references = repo.references_glob(&format!("{}*", namespace))
+            .expect("Couldn't obtain an iterator over references");
+        if references.next().is_some() {
+            eprintln!("{} already contains some refs; please pick another namespace to archive \
+                       the branches into",
+                      namespace);
+            return E_ARCHIVE_EXISTS;
+        }
+    }
+
+    if !does_submodule_exist(repo, submodule_dir) {
+        if is_already_merged(repo, submodule_dir) {
+            eprintln!("`{}' is already merged: it's an ordinary directory at HEAD, and there's a \
+                       commit map from an earlier run. Nothing to do.",
+                      submodule_dir);
+            return E_NOTHING_TO_DO;
+        }
+        eprintln!("Couldn't find a submodule named `{}'", submodule_dir);
+        return E_SUBMODULE_NOT_FOUND;
+    }
+    // Those for existing submodules are simply waiting for their turn, but these must be typos
+    let mut unknown_scopes: Vec<&String> = options.other_mapping_scopes
+        .iter()
+        .filter(|scope| !does_submodule_exist(repo, scope))
+        .collect();
+    unknown_scopes.sort();
+    for scope in unknown_scopes {
+        warn(options,
+             "mappings",
+             format!("Some mappings are for `{}', but there's no such submodule; they are ignored",
+                     scope));
+    }
+
+    if options.mode == Mode::Stitch {
+        match read_import_map(repo, options) {
+            Ok(map) => options.import_map = Some(map),
+            Err(_) => return E_SUBMODULE_FETCH_FAILED,
+        }
+    } else if let Err(exit_code) = fetch_submodule_objects(repo, options) {
+        return exit_code;
+    }
+
+    if !options.date_range_mappings.is_empty() {
+        let overrides = get_date_range_overrides(repo, options);
+        options.overrides.extend(overrides);
+    }
+    if !are_mappings_valid(repo, options) {
+        return E_INVALID_MAPPINGS;
+    }
+    warn_about_unanchored_mapping_targets(repo, options);
+
+    let unwritable = find_unwritable_messages(repo, options);
+    if !unwritable.is_empty() {
+        eprintln!("{} commit(s) have messages that can't be written back as they are:",
+                  unwritable.len());
+        for &(id, ref reason) in unwritable.iter().take(10) {
+            eprintln!("    {}: {}", abbreviate(repo, id, options.abbrev), reason);
+        }
+        if unwritable.len() > 10 {
+            eprintln!("    ...and {} more", unwritable.len() - 10);
+        }
+        eprintln!("Nothing was rewritten. Those messages would have to be re-encoded in UTF-8 \
+                   first.");
+        remove_temporary_refs(repo);
+        return E_UNWRITABLE_MESSAGE;
+    }
+
+    if let Some(ref commit) = options.diff_preview {
+        let exit_code = preview_commit_rewrite(repo, commit, options);
+        remove_temporary_refs(repo);
+        return exit_code;
+    }
+
+    eprintln!("{} {}...", if is_import { "Importing" } else { "Merging" }, submodule_dir);
+
+    let mut old_id_to_new = HashMap::new();
+
+    options.shared_commits = match options.import_map {
+        // `import' kept them as they were, too
+        Some(ref import_map) => {
+            import_map.iter()
+                .filter(|&(old_id, new_id)| old_id == new_id && repo.find_commit(*old_id).is_ok())
+                .map(|(old_id, _)| *old_id)
+                .collect()
+        }
+        None => find_shared_commits(repo, options),
+    };
+    if !options.shared_commits.is_empty() {
+        eprintln!("{} commit(s) of the submodule's history are in the main history as well; they \
+                   will stay as they are, and the rest of the submodule's history will build on \
+                   them.",
+                  options.shared_commits.len());
+    }
+
+    // `stitch' gets the submodule's history ready-made
+    let self_referencing = match options.import_map {
+        Some(ref import_map) => {
+            old_id_to_new = import_map.clone();
+            Vec::new()
+        }
+        None => rewrite_submodule_history(repo, &mut old_id_to_new, options),
+    };
+    if has_exceeded_memory_limit(options) {
+        return abort_on_memory_limit(repo, options);
+    }
+    if let Some(id) = self_referencing.first() {
+        eprintln!("{} commit(s) of the submodule, e.g. {}, have a gitlink at {}, the very path \
+                   the submodule is being merged into. That usually means that the main repo's \
+                   own commits ended up in the submodule's history. Please remove them from the \
+                   submodule's branches (or point --url at a clean copy of it) and try again.",
+                  self_referencing.len(),
+                  abbreviate(repo, *id, options.abbrev),
+                  submodule_dir);
+        remove_temporary_refs(repo);
+        return E_SELF_REFERENCING_SUBMODULE;
+    }
+
+    if is_import {
+        return import_submodule_history(repo, &old_id_to_new, options);
+    }
+
+    if !options.branch_default_mappings.is_empty() {
+        let overrides = get_branch_default_overrides(repo, &old_id_to_new, options);
+        options.overrides.extend(overrides);
+    }
+
+    warn_about_long_paths(repo, &old_id_to_new, options);
+
+    if options.interactive {
+        match tui::browse_plan(repo, &old_id_to_new, options) {
+            Some(mappings) => options.mappings = mappings,
+            None => {
+                remove_temporary_refs(repo);
+                return E_ABORTED;
+            }
+        }
+    }
+
+    if options.tip_only {
+        drop(ref_locks);
+        if options.lock && get_refs_snapshot(repo, options) != refs_before {
+            eprintln!("HEAD or branches were changed by someone else while we worked, aborting!");
+            return E_CONCURRENT_ACCESS;
+        }
+        if violates_strictness(repo, options) {
+            remove_temporary_refs(repo);
+            return E_STRICT_FAILURE;
+        }
+        match merge_submodule_at_tip(repo, &old_id_to_new, options) {
+            Ok(_) => {}
+            Err(exit_code) => return exit_code,
+        }
+        if !options.no_reference_scan {
+            let tip = (String::from(get_head_ref_name(options)), get_head_id(repo, options));
+            references::print(repo, &[tip], options);
+        }
+    } else {
+        let mut scan = HistoryScan {
+            commits: Vec::new(),
+            submodule_entries: HashMap::new(),
+        };
+        match find_dangling_references_to_submodule(repo, &old_id_to_new, options, &mut scan) {
+            Some(_) => {
+                remove_temporary_refs(repo);
+                return E_FOUND_DANGLING_REFERENCES;
+            }
+            None => {}
+        }
+
+        if let ExistingContent::Error = options.existing_content {
+            let conflicting = find_commits_with_existing_content(repo, &scan);
+            if !conflicting.is_empty() {
+                eprintln!("{} commit(s) come after commits with the submodule, but have files of \
+                           their own in {}:",
+                          conflicting.len(),
+                          submodule_dir);
+                for id in conflicting.iter().take(10) {
+                    eprintln!("    {}", abbreviate(repo, *id, options.abbrev));
+                }
+                if conflicting.len() > 10 {
+                    eprintln!("    ...and {} more", conflicting.len() - 10);
+                }
+                eprintln!("Use --existing-content=prefer-submodule to replace those files with \
+                           the submodule's contents, or --existing-content=prefer-existing to \
+                           keep them.");
+                remove_temporary_refs(repo);
+                return E_EXISTING_CONTENT;
+            }
+        }
+
+        let rewritten = rewrite_repo_history(repo, &mut old_id_to_new, options, scan);
+        if has_exceeded_memory_limit(options) {
+            return abort_on_memory_limit(repo, options);
+        }
+        drop(ref_locks);
+        if options.lock && get_refs_snapshot(repo, options) != refs_before {
+            eprintln!("HEAD or branches were changed by someone else while we worked, aborting! \
+                       No refs were modified.");
+            return E_CONCURRENT_ACCESS;
+        }
+        if violates_strictness(repo, options) {
+            remove_temporary_refs(repo);
+            return E_STRICT_FAILURE;
+        }
+        if !options.no_modify_refs && !options.protected_refs.is_empty() {
+            let mut refs: Vec<(String, Oid)> = get_branch_to_id_map(repo, options)
+                .into_iter()
+                .filter(|&(ref name, _)| !is_branch_excluded(name, options))
+                .map(|(name, id)| (format!("refs/heads/{}", name), id))
+                .collect();
+            refs.extend(get_included_refs(repo, options));
+            let moves: Vec<(String, Oid, Oid)> = refs.into_iter()
+                .filter_map(|(name, id)| old_id_to_new.get(&id).map(|new_id| (name, id, *new_id)))
+                .collect();
+            if !may_move_protected_refs(repo, &moves, options) {
+                remove_temporary_refs(repo);
+                return E_PROTECTED_REFS;
+            }
+        }
+        // Keep enough information around to check the result later with `git submerge verify`
+        write_commit_map(repo, &rewritten, options);
+        if let Some(ref path) = options.dot_file {
+            write_dot_graph(repo, path, &rewritten);
+        }
+        if options.migration_checklist {
+            checklist::print(repo, &old_id_to_new, options);
+        }
+        if let Some(ref path) = options.migration_script {
+            migration_script::write(repo, path, &old_id_to_new, options);
+        }
+        if !options.no_modify_refs {
+            backup_branches(repo, options);
+        }
+        if let Some(ref namespace) = options.archive_namespace {
+            archive_branches(repo, namespace, options);
+        }
+        let mut tips = move_branches(repo, &old_id_to_new, options);
+        if let Some(CodeownersAction::Print) = options.codeowners {
+            print_submodule_codeowners(repo, &old_id_to_new, options);
+        }
+        if !options.no_reference_scan {
+            tips.sort();
+            references::print(repo, &tips, options);
+        }
+        if !options.no_modify_refs || options.output_namespace.is_some() {
+            move_included_refs(repo, &old_id_to_new, options);
+        }
+    }
+
+    if let Some(ref format) = options.tag_rename {
+        import_submodule_tags(repo, &old_id_to_new, format, options);
+    }
+
+    if let Some(ref path) = options.emit_script {
+        script::write_script(repo, path, &old_id_to_new, options);
+        let references = repo.references_glob(&format!("{}*", script::NAMESPACE))
+            .expect("Couldn't obtain an iterator over references");
+        for mut reference in references.filter_map(|r| r.ok()) {
+            reference.delete().expect("Couldn't remove a temporary reference");
+        }
+    }
+
+    if let Some(ref path) = options.output {
+        // The results stay in the namespace (and the objects are kept), so they aren't lost
+        if let Err(message) = output::write_repo(repo, path, options) {
+            eprintln!("{}\nThe rewritten refs were left in {}.", message, output::NAMESPACE);
+            return E_OUTPUT_FAILED;
+        }
+        let references = repo.references_glob(&format!("{}*", output::NAMESPACE))
+            .expect("Couldn't obtain an iterator over references");
+        for mut reference in references.filter_map(|r| r.ok()) {
+            reference.delete().expect("Couldn't remove a temporary reference");
+        }
+    }
+
+    // Fetched and external commits are now part of the rewritten history, so they don't need
+    // references of their own anymore
+    remove_temporary_refs(repo);
+
+    // Nothing we rewrote is checked out, so the index and the working directory stay as they are
+    if let Some(ref name) = options.head {
+        if !is_checked_out(repo, name) {
+            if !options.no_modify_refs && !repo.is_bare() {
+                eprintln!("`{}' is not checked out, so the index and the working directory were \
+                           left as they were.",
+                          name);
+            }
+            return E_SUCCESS;
+        }
+    }
+
+    // HEAD still points at the old history, and so should the index and the working directory
+    if let (false, Some(name)) = (options.tip_only, get_excluded_head_branch(repo, options)) {
+        if !options.no_modify_refs && !repo.is_bare() {
+            eprintln!("The checked-out branch `{}' is excluded by --exclude-branches, so it, the \
+                       index and the working directory were left as they were.",
+                      name);
+        }
+        return E_SUCCESS;
+    }
+
+    if options.no_update_worktree {
+        if !options.no_modify_refs && !repo.is_bare() {
+            print_worktree_update_instructions(submodule_dir);
+        }
+        return E_SUCCESS;
+    }
+
+    // The submodule's checkout is still on disk, untouched by the rewrite. If the new HEAD's tree
+    // doesn't match it, something went wrong along the way (wrong mapping etc.), and the user
+    // should know about it before they push anything.
+    let is_consistent = is_submodule_dir_consistent(repo, submodule_dir, &old_id_to_new);
+
+    if options.no_modify_refs {
+        // HEAD still points at the old history, so the working directory should stay as it is
+        return if is_consistent { E_SUCCESS } else { E_WORKDIR_MISMATCH };
+    }
+
+    // Working directories with and without submodules are pretty much
+    // the same, save for two files:
+    // - submodules have .git in their root directory;
+    // - there's .gitmodules in the root of the repo.
+    remove_dotgit_from_submodule(repo, options);
+    remove_gitmodules();
+    // Git used to think of submodule's directory as a file, because it was
+    // "opaque". We have to update the index in order for Git to realise
+    // that the submodule directory is *just* a directory now.
+    // libgit2 takes the index lock itself when writing the index, so we have to let go of ours.
+    drop(index_lock);
+    let has_cleanup = !options.cleanup_remove.is_empty() || options.cleanup_patch.is_some();
+    let old_tree = if has_cleanup {
+        repo.index()
+            .and_then(|mut index| index.write_tree())
+            .and_then(|id| repo.find_tree(id))
+            .ok()
+    } else {
+        None
+    };
+    update_index(repo, &old_id_to_new);
+    if let Some(ref old_tree) = old_tree {
+        cleanup::update_worktree(repo, old_tree, options);
+    }
+    if options.merge_mailmap {
+        // The only file outside of the submodule's directory that we might've changed
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.force().path(".mailmap");
+        repo.checkout_head(Some(&mut checkout)).expect("Couldn't check out the merged .mailmap");
+    }
+    if let Some(CodeownersAction::Apply) = options.codeowners {
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.force();
+        for path in CODEOWNERS_PATHS {
+            checkout.path(path);
+        }
+        repo.checkout_head(Some(&mut checkout)).expect("Couldn't check out the merged CODEOWNERS");
+    }
+    if options.hoist_gitignore {
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.force().path(".gitignore");
+        repo.checkout_head(Some(&mut checkout)).expect("Couldn't check out the merged .gitignore");
+        // The submodule's own .gitignore is gone from the tree, but not from the disk
+        let gitignore_path = Path::new(submodule_dir).join(".gitignore");
+        let gitignore_fs_path = long_path(&gitignore_path);
+        let head_tree = repo.head()
+            .and_then(|head| head.peel(git2::ObjectType::Tree))
+            .expect("Couldn't obtain the tree of HEAD");
+        let head_tree = head_tree.as_tree().expect("HEAD's tree is not a tree");
+        if gitignore_fs_path.exists() && head_tree.get_path(&gitignore_path).is_err() {
+            std::fs::remove_file(&gitignore_fs_path)
+                .expect(&format!("Couldn't remove {}", gitignore_path.display()));
+        }
+    }
+
+    if !is_consistent {
+        return E_WORKDIR_MISMATCH;
+    }
+
+    E_SUCCESS
+}
+
+fn print_worktree_update_instructions(submodule_dir: &str) {
+    eprintln!("The index and the working directory were left as they were. To bring them up to \
+               date with the rewritten HEAD, run:\n    rm {}/.git && rm -f .gitmodules && git \
+               reset -q",
+              submodule_dir);
+}
+
+// Brings everything the rewrite of the submodule's history needs into the repo: the submodule's
+// own history, and whatever --url, --external-repo and friends point at
+fn fetch_submodule_objects(repo: &Repository, options: &Options) -> Result<(), i32> {
+    if options.auto_init {
+        match init_submodule_checkout(repo, options) {
+            Ok(_) => {}
+            Err(_) => return Err(E_SUBMODULE_FETCH_FAILED),
+        }
+    }
+
+    match fetch_submodule_history(repo, options) {
+        Ok(_) => {}
+        Err(_) => return Err(E_SUBMODULE_FETCH_FAILED),
+    }
+
+    if options.include_remote_refs {
+        match fetch_submodule_remote_refs(repo, &options.submodule_dir) {
+            Ok(_) => {}
+            Err(_) => return Err(E_SUBMODULE_FETCH_FAILED),
+        }
+    }
+
+    if !options.fetch_refspecs.is_empty() {
+        match fetch_submodule_refspecs(repo, options) {
+            Ok(_) => {}
+            Err(_) => return Err(E_SUBMODULE_FETCH_FAILED),
+        }
+    }
+
+    // `import' takes all of the submodule's branches along
+    if options.merge_matching_branches || options.mode == Mode::Import {
+        match fetch_submodule_branches(repo, options) {
+            Ok(_) => {}
+            Err(_) => return Err(E_SUBMODULE_FETCH_FAILED),
+        }
+    }
+
+    if let Some(ref format) = options.tag_rename {
+        match fetch_submodule_tags(repo, options) {
+            Ok(_) => {}
+            Err(_) => return Err(E_SUBMODULE_FETCH_FAILED),
+        }
+        if !are_tag_names_available(repo, format, options) {
+            return Err(E_TAG_COLLISION);
+        }
+    }
+
+    let url_history = get_submodule_url_history(repo, options);
+    if url_history.len() > 1 {
+        print_submodule_url_history(repo, &url_history, options);
+    }
+    if options.fetch_historical_urls {
+        fetch_historical_urls(repo, &url_history, options);
+    }
+
+    for url in &options.external_repos {
+        match fetch_external_repo(repo, url) {
+            Ok(_) => {}
+            Err(_) => return Err(E_SUBMODULE_FETCH_FAILED),
+        }
+    }
+
+    if !options.also_fetch.is_empty() {
+        fetch_missing_submodule_commits(repo, options);
+    }
+
+    Ok(())
+}
+
+fn abort_on_memory_limit(repo: &Repository, options: &Options) -> i32 {
+    eprintln!("Memory usage went over {} MB, aborting! No refs were modified. Consider rewriting \
+               the history in several steps with --rev-range.",
+              options.memory_limit.as_ref().map_or(0, |limit| limit.megabytes));
+    remove_temporary_refs(repo);
+    E_MEMORY_LIMIT_EXCEEDED
+}
+
+fn build_cli() -> clap::App<'static, 'static> {
+    let app = clap::App::new("git-submerge")
+        .version("0.5")
+        .author(crate_authors!())
+        .about("Merge Git submodule into the main repo as if they've never been separate at all")
+        .after_help("Options can also be set through environment variables, e.g. \
+                     GIT_SUBMERGE_DEFAULT_MAPPING for --default-mapping. Flags are enabled by \
+                     any value other than \"\", \"0\", \"false\" and \"no\". Command-line \
+                     options take precedence.")
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
+        .subcommand(clap::SubCommand::with_name("verify")
+            .about("Check that a merge done earlier (possibly by someone else) produced the \
+                    expected history, using the commit map and the backups it left in the repo")
+            .arg(clap::Arg::with_name("SUBMODULE_DIR")
+                .help("The submodule that was merged")
+                .required(true)
+                .index(1)))
+        .subcommand(clap::SubCommand::with_name("promote")
+            .about("Move the refs created by an earlier run with --output-refs-namespace into \
+                    place: branches, tags and the --include-refs refs, then HEAD, the index and \
+                    the working directory. The refs that get replaced are backed up under \
+                    refs/submerge/backup/")
+            .arg(clap::Arg::with_name("SUBMODULE_DIR")
+                .help("The submodule that was merged")
+                .required(true)
+                .index(1))
+            .arg(clap::Arg::with_name("from")
+                .help("The namespace that was passed to --output-refs-namespace")
+                .long("from")
+                .value_name("namespace")
+                .default_value("refs/submerge/result/")))
+        .subcommand(clap::SubCommand::with_name("stitch")
+            .about("Rewrite the main history using the submodule's history from an earlier \
+                    `git submerge import', possibly done elsewhere and fetched from there \
+                    (refs/submerge/imported/SUBMODULE_DIR/ and the import map). The submodule's \
+                    history isn't fetched or rewritten again. The other options go before \
+                    `stitch'")
+            .arg(clap::Arg::with_name("SUBMODULE_DIR")
+                .help("The submodule to merge")
+                .required(true)
+                .index(1))
+            .arg(clap::Arg::with_name("import-map")
+                .help("The map of the imported commits [default: \
+                       .git/submerge/SUBMODULE_DIR/import-map]")
+                .long("import-map")
+                .value_name("file")))
+        .subcommand(clap::SubCommand::with_name("migrate-clone")
+            .about("Bring this clone over to the history that was rewritten elsewhere (e.g. on \
+                    the server) and pushed: fetch it, move the local branches to it using the \
+                    commit map of that run, forget the submodule's config, and update the index \
+                    and the working directory. Branches are backed up under \
+                    refs/submerge/backup/ first")
+            .arg(clap::Arg::with_name("SUBMODULE_DIR")
+                .help("The submodule that was merged")
+                .required(true)
+                .index(1))
+            .arg(clap::Arg::with_name("commit-map")
+                .help("The commit map written by the run that did the rewrite [default: \
+                       .git/submerge/SUBMODULE_DIR/commit-map]")
+                .long("commit-map")
+                .value_name("file"))
+            .arg(clap::Arg::with_name("remote")
+                .help("The remote to fetch the rewritten history from")
+                .long("remote")
+                .value_name("name")
+                .default_value("origin"))
+            .arg(clap::Arg::with_name("no-fetch")
+                .help("Don't fetch; the rewritten history is already here")
+                .long("no-fetch")))
+        .subcommand(clap::SubCommand::with_name("import")
+            .about("Only rewrite the submodule's history so that its files are in SUBMODULE_DIR, \
+                    and leave the main history alone. The result goes to \
+                    refs/submerge/imported/SUBMODULE_DIR/ (HEAD, and the submodule's branches \
+                    under heads/), and the map of the rewritten commits to \
+                    .git/submerge/SUBMODULE_DIR/import-map. Options that shape the submodule's \
+                    history, e.g. --url or --strip-path, go before `import'")
+            .arg(clap::Arg::with_name("SUBMODULE_DIR")
+                .help("The submodule to import")
+                .required(true)
+                .index(1)))
+        .arg(clap::Arg::with_name("SUBMODULE_DIR")
+            .help("The submodule to merge")
+            .required_unless_one(&["help-man", "version-info"])
+            .index(1))
+        .arg(clap::Arg::with_name("help-man")
+            .help("Print the manual page and exit. To make `git help submerge' work, save it as \
+                   git-submerge.1 somewhere in your MANPATH, e.g. /usr/local/share/man/man1/")
+            .long("help-man"))
+        .arg(clap::Arg::with_name("version-info")
+            .help("Print the versions of git-submerge and libgit2, and the features they were \
+                   built with, and exit. Please include this in bug reports")
+            .long("version-info"))
+        .arg(clap::Arg::with_name("mapping")
+            .value_names(&["commit id 1", "commit id 2"])
+            .help("Whenever main repo references submodule's <commit id 1>, the <commit id 2> \
+                   will be used instead. If <commit id 2> is \"empty\", the submodule's directory \
+                   will be empty. <commit id 1> can be prefixed with the path of the submodule \
+                   the mapping is for, e.g. vendor/foo:<commit id 1>; mappings for other \
+                   submodules are ignored, so that the same ones can be given for each of them")
+            .short("m")
+            .long("mapping")
+            .number_of_values(2)
+            .multiple(true))
+        .arg(clap::Arg::with_name("default-mapping")
+            .value_name("commit id")
+            .help("Whenever main repo references a commit that is neither in submodule's \
+                   history nor in mappings (see --mapping), the <commit id> will be used instead. \
+                   Can also be \"empty\" (see --mapping). Can be given once more for each \
+                   submodule, prefixed with its path (e.g. vendor/foo:<commit id>), which then \
+                   takes precedence for that submodule")
+            .short("d")
+            .long("default-mapping")
+            .number_of_values(1)
+            .multiple(true))
+        .arg(clap::Arg::with_name("override")
+            .value_names(&["main commit id", "submodule commit id"])
+            .help("In main repo's <main commit id>, use <submodule commit id> instead of whatever \
+                   submodule commit it references. Like in --mapping, <main commit id> can be \
+                   prefixed with the path of the submodule")
+            .long("override")
+            .number_of_values(2)
+            .multiple(true))
+        .arg(clap::Arg::with_name("allow-external-mapping")
+            .help("Allow mapping to commits that aren't in submodule's history (e.g. the ones \
+                   from a fork; see --external-repo)")
+            .long("allow-external-mapping"))
+        .arg(clap::Arg::with_name("external-repo")
+            .value_name("url")
+            .help("Fetch branches and tags from <url>, so that they can be used with \
+                   --allow-external-mapping")
+            .long("external-repo")
+            .requires("allow-external-mapping")
+            .number_of_values(1)
+            .multiple(true))
+        .arg(clap::Arg::with_name("fetch-refspec")
+            .value_name("refspec")
+            .help("Also fetch <refspec> (e.g. refs/changes/*) from submodule's upstream, and \
+                   include these commits into submodule's history")
+            .long("fetch-refspec")
+            .number_of_values(1)
+            .multiple(true))
+        .arg(clap::Arg::with_name("include-remote-refs")
+            .help("Consider commits reachable from submodule's remote-tracking branches and \
+                   FETCH_HEAD to be part of its history, too")
+            .long("include-remote-refs"))
+        .arg(clap::Arg::with_name("rev-range")
+            .value_name("base..tip")
+            .help("Only rewrite commits in the given range, leaving <base> and its ancestors \
+                   untouched. If <tip> is omitted, all branches are rewritten")
+            .long("rev-range")
+            .number_of_values(1)
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("preserve-gitlinks-before")
+            .value_name("commit or date")
+            .help("Keep the submodule as is in the given commit and its ancestors, or in commits \
+                   made before the given date; only later commits get submodule's contents")
+            .long("preserve-gitlinks-before")
+            .number_of_values(1)
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("tag-rename")
+            .value_name("format")
+            .help("Import submodule's tags, naming them according to the format. {submodule} \
+                   is replaced by submodule's path, and {tag} by the tag's original name (e.g. \
+                   \"{submodule}/{tag}\" or \"vendor-{tag}\")")
+            .long("tag-rename")
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("merge-matching-branches")
+            .help("If the submodule has a branch with the same name as some branch of the main \
+                   repo, merge it into the rewritten main branch (the current branch is left as \
+                   it is)")
+            .long("merge-matching-branches")
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("summary-commit")
+            .help("Top each rewritten branch with an empty commit documenting the migration")
+            .long("summary-commit")
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("dot")
+            .value_name("file")
+            .help("Write a Graphviz graph of the commits where submodule history was attached, \
+                   before and after the rewrite")
+            .long("dot")
+            .number_of_values(1)
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("diff-preview")
+            .value_name("commit")
+            .help("Don't rewrite anything; just show how the tree of the given commit would \
+                   change")
+            .long("diff-preview")
+            .number_of_values(1)
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("signoff")
+            .help("Add a Signed-off-by trailer with your user.name and user.email to every commit \
+                   git-submerge creates")
+            .long("signoff"))
+        .arg(clap::Arg::with_name("committer")
+            .value_name("Name <email>")
+            .help("Use this committer identity in every commit git-submerge creates. Authors are \
+                   left as they are")
+            .long("committer")
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("committer-date")
+            .value_name("date")
+            .help("With --committer, also set the commit date (YYYY-MM-DD, YYYY-MM-DDTHH:MM:SS in \
+                   UTC, @<seconds since the epoch>, or \"now\"). By default, dates are kept")
+            .long("committer-date")
+            .requires("committer")
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("lock")
+            .help("Lock the index, packed-refs, HEAD and the branches to be moved while working, \
+                   and abort if someone else changes HEAD or branches in the meantime")
+            .long("lock"))
+        .arg(clap::Arg::with_name("archive-old-branches")
+            .value_name("namespace")
+            .help("Keep all the branches as they were before the rewrite under the given \
+                   namespace (e.g. refs/attic/), along with a manifest commit listing them")
+            .long("archive-old-branches")
+            .number_of_values(1)
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("max-default-mappings")
+            .value_name("N")
+            .help("Abort if --default-mapping would have to replace more than N distinct \
+                   submodule commits")
+            .long("max-default-mappings")
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("strip-submodule-ci")
+            .help("Leave submodule's CI configuration (.github/, .gitlab-ci.yml, .travis.yml and \
+                   the like) out of the imported history. The files stay in the working \
+                   directory, untracked")
+            .long("strip-submodule-ci"))
+        .arg(clap::Arg::with_name("strip-path")
+            .value_name("glob")
+            .help("Leave paths matching <glob> (relative to submodule's root; * doesn't match \
+                   slashes, ** does) out of the imported history")
+            .long("strip-path")
+            .number_of_values(1)
+            .multiple(true))
+        .arg(clap::Arg::with_name("honor-export-ignore")
+            .help("Leave paths marked export-ignore in submodule's .gitattributes out of the \
+                   imported history, like `git archive` would")
+            .long("honor-export-ignore"))
+        .arg(clap::Arg::with_name("merge-mailmap")
+            .help("Put a commit on top of each rewritten branch that adds the entries from \
+                   submodule's .mailmap to the one in the root of the repo, where Git can see \
+                   them")
+            .long("merge-mailmap")
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("url")
+            .value_name("url")
+            .help("Fetch submodule's history (all of its branches) from <url>, e.g. a mirror, \
+                   instead of the local checkout")
+            .long("url")
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("exclude-branches")
+            .value_name("glob")
+            .help("Leave branches matching <glob> (e.g. 'archive/*') pointing at the old \
+                   history")
+            .long("exclude-branches")
+            .number_of_values(1)
+            .multiple(true))
+        .arg(clap::Arg::with_name("include-refs")
+            .value_name("glob")
+            .help("Also rewrite the history of refs matching <glob> (e.g. 'refs/pipelines/*'), \
+                   and move them to the rewritten history along with the branches")
+            .long("include-refs")
+            .number_of_values(1)
+            .multiple(true))
+        .arg(clap::Arg::with_name("progress-json")
+            .value_name("file")
+            .help("Write progress reports to <file> as JSON, one object per line")
+            .long("progress-json")
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("max-memory")
+            .value_name("megabytes")
+            .help("Keep git-submerge's memory usage under the given size: when it gets close, move \
+                   the commit map to .git/submerge/ and carry on, and if it grows past the size \
+                   all the same, stop before modifying anything instead of getting killed halfway \
+                   through. Only works on Linux")
+            .long("max-memory")
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("auto-init")
+            .help("If the submodule isn't checked out, clone it from the URL in .gitmodules (or \
+                   the one passed to --url) and check out the commit the repo points at, like \
+                   `git submodule update --init` would")
+            .long("auto-init"))
+        .arg(clap::Arg::with_name("abbrev")
+            .value_name("N")
+            .help("Show commit IDs abbreviated to at least N hex digits, or more if needed to keep \
+                   them unique. Defaults to core.abbrev, or 7. IDs of commits that couldn't be \
+                   found are always shown in full, so that they can be copied into --mapping")
+            .long("abbrev")
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("color")
+            .value_name("WHEN")
+            .help("Highlight warnings, dangling references, branches and the summary: `always', \
+                   `never', or `auto' to only do that when writing to a terminal (default). \
+                   NO_COLOR and CLICOLOR are honored in `auto' mode")
+            .long("color")
+            .possible_values(&["always", "never", "auto"])
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("mappings-from")
+            .value_name("file")
+            .help("Read more mappings (see --mapping) from <file>, or from standard input if \
+                   <file> is \"-\". The file should contain pairs of commit IDs separated by \
+                   whitespace, e.g. one pair per line; lines starting with # are ignored. See \
+                   also --default-mapping-for")
+            .long("mappings-from")
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("output-refs-namespace")
+            .value_name("namespace")
+            .help("Put the rewritten branches, tags and --include-refs under <namespace> (e.g. \
+                   refs/submerge/result/), and leave everything else alone: branches, HEAD, the \
+                   index and the working directory. `git submerge promote' can move the results \
+                   into place later")
+            .long("output-refs-namespace")
+            .number_of_values(1)
+            .conflicts_with_all(&["tip-only", "result-prefix", "result-suffix"]))
+        .arg(clap::Arg::with_name("existing-content")
+            .value_name("policy")
+            .help("What to do with commits that have files of their own in the submodule's \
+                   directory, even though they come after commits with the submodule: `error' \
+                   lists them and stops (default), `prefer-submodule' puts the submodule's \
+                   contents from the parent commit there instead, and `prefer-existing' keeps \
+                   the files as they are")
+            .long("existing-content")
+            .possible_values(&["error", "prefer-submodule", "prefer-existing"])
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("date-order")
+            .help("Walk the histories in commit date order, like `git log --date-order' (parents \
+                   still come before their children). The rewritten commits are the same either \
+                   way; this changes the order in which they are processed and listed in the \
+                   commit map, the --dot-file graph and --interactive")
+            .long("date-order"))
+        .arg(clap::Arg::with_name("codeowners")
+            .value_name("action")
+            .help("Carry the rules from submodule's CODEOWNERS over to the one the repo uses, \
+                   with the patterns moved under the submodule's directory: `apply' puts \
+                   a commit that does that on top of each rewritten branch, `print' only prints \
+                   the rules")
+            .long("codeowners")
+            .possible_values(&["apply", "print"])
+            .number_of_values(1)
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("emit-script")
+            .value_name("file")
+            .help("Don't change any refs; write a shell script to <file> instead, which creates \
+                   the same commits with `git fast-import' and moves the refs with \
+                   `git update-ref'. That way, the changes can be reviewed and applied using \
+                   nothing but Git")
+            .long("emit-script")
+            .number_of_values(1)
+            .conflicts_with_all(&["output-refs-namespace", "tip-only", "include-remote-refs"]))
+        .arg(clap::Arg::with_name("output")
+            .value_name("path")
+            .help("Don't change the repository at all; create a new one at <path> instead, with \
+                   nothing but the rewritten branches, tags and --include-refs, the objects they \
+                   need, and the commit map. The same branch is checked out there")
+            .long("output")
+            .number_of_values(1)
+            .conflicts_with_all(&["output-refs-namespace",
+                                  "emit-script",
+                                  "tip-only",
+                                  "include-remote-refs"]))
+        .arg(clap::Arg::with_name("no-update-worktree")
+            .help("Only rewrite the refs; leave the index and the working directory (including \
+                   submodule's .git and .gitmodules) as they are, and don't check them for \
+                   uncommitted changes. Useful on servers and in scripts that have no use for \
+                   a working directory")
+            .long("no-update-worktree")
+            .conflicts_with("auto-init"))
+        .arg(clap::Arg::with_name("keep-submodule-gitfile")
+            .help("Don't remove .git from the submodule's directory at the end; you'll have to \
+                   do that yourself before Git sees the directory's contents")
+            .long("keep-submodule-gitfile"))
+        .arg(clap::Arg::with_name("map-range")
+            .value_names(&["since..until", "submodule commit id"])
+            .help("In main repo's commits made from <since> up to, but not including, <until> \
+                   (dates as in --preserve-gitlinks-before), use <submodule commit id> instead of \
+                   whatever submodule commit they reference. Can also be \"empty\" (see \
+                   --mapping). --override takes precedence")
+            .long("map-range")
+            .number_of_values(2)
+            .multiple(true))
+        .arg(clap::Arg::with_name("default-mapping-for")
+            .value_names(&["branch", "commit id"])
+            .help("Like --default-mapping, but only for commits that are on <branch> (a name or a \
+                   glob) and no other branch, e.g. to map a release branch to the submodule \
+                   release it shipped with. If several of these match a commit, the first one \
+                   wins. In --mappings-from files, write this as \"default-for <branch> <commit \
+                   id>\"")
+            .long("default-mapping-for")
+            .number_of_values(2)
+            .multiple(true))
+        .arg(clap::Arg::with_name("fetch-historical-urls")
+            .help("Also fetch submodule's history from every other URL it had in .gitmodules \
+                   over time, e.g. before the repo moved to another host, so that gitlinks made \
+                   back then can be found")
+            .long("fetch-historical-urls"))
+        .arg(clap::Arg::with_name("also-fetch")
+            .value_name("url")
+            .help("If the main repo references submodule commits that weren't fetched from the \
+                   submodule's own URL, fetch from <url> (e.g. a mirror or a fork) as well. Can be \
+                   given several times; the URLs are tried in order, until nothing is missing")
+            .long("also-fetch")
+            .number_of_values(1)
+            .multiple(true))
+        .arg(clap::Arg::with_name("strict")
+            .help("If any commit, branch or tag couldn't be read, stop before modifying any refs, \
+                   instead of carrying on with what could be read")
+            .long("strict"))
+        .arg(clap::Arg::with_name("head")
+            .value_name("ref")
+            .help("Anchor the main history on <ref> instead of HEAD, e.g. in a bare mirror whose \
+                   HEAD is unset. With --tip-only, the merge is committed onto <ref>. The index \
+                   and the working directory are only updated if <ref> is checked out")
+            .long("head")
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("hoist-gitignore")
+            .help("Put a commit on top of each rewritten branch that moves the rules from \
+                   submodule's root .gitignore into the repo's root .gitignore, with \
+                   the patterns moved under the submodule's directory")
+            .long("hoist-gitignore")
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("no-progress")
+            .help("Don't show the progress meter. It's only shown when stderr is a terminal \
+                   anyway")
+            .long("no-progress"))
+        .arg(clap::Arg::with_name("quarantine")
+            .help("Write new objects into .git/submerge/quarantine/ first, and only move them \
+                   into .git/objects once some ref points at them. Failed runs then leave \
+                   nothing behind")
+            .long("quarantine"))
+        .arg(clap::Arg::with_name("protected-ref")
+            .value_name("glob")
+            .help("Treat refs matching <glob> (e.g. refs/heads/master, or refs/heads/release/*) \
+                   as protected, i.e. ones that can't be force-pushed. If the rewrite would move \
+                   any of them somewhere that doesn't contain their current tip, it stops before \
+                   modifying refs. Can be given several times; the submerge.protectedRef config \
+                   variable adds more")
+            .long("protected-ref")
+            .number_of_values(1)
+            .multiple(true))
+        .arg(clap::Arg::with_name("rewrite-protected")
+            .help("Rewrite the refs protected by --protected-ref anyway")
+            .long("rewrite-protected"))
+        .arg(clap::Arg::with_name("migration-checklist")
+            .help("List the branches whose history changed, how far back, and which remotes \
+                   have them, as a checklist for announcing the migration to everyone who has \
+                   a clone")
+            .long("migration-checklist"))
+        .arg(clap::Arg::with_name("no-scan-cache")
+            .help("Walk the main history and look into its trees from scratch, rather than \
+                   reusing what an earlier run cached in .git/submerge/cache/")
+            .long("no-scan-cache"))
+        .arg(clap::Arg::with_name("find-renames")
+            .help("When --diff-preview and `verify' compare trees, detect renames, like \
+                   `git diff -M'. Off by default, as it takes time on big trees")
+            .short("M")
+            .long("find-renames"))
+        .arg(clap::Arg::with_name("find-copies")
+            .help("Like --find-renames, but detect copies of modified files as well, like \
+                   `git diff -C'")
+            .short("C")
+            .long("find-copies"))
+        .arg(clap::Arg::with_name("similarity-threshold")
+            .value_name("percent")
+            .help("How similar two files have to be for --find-renames and --find-copies to \
+                   pair them up; 50 by default")
+            .long("similarity-threshold")
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("text")
+            .help("When comparing trees, treat all files as text, like `git diff --text'")
+            .long("text")
+            .conflicts_with("binary"))
+        .arg(clap::Arg::with_name("binary")
+            .help("When comparing trees, treat all files as binary, without looking into them \
+                   to tell; the fastest choice for trees full of big files")
+            .long("binary"))
+        .arg(clap::Arg::with_name("emit-migration-script")
+            .value_name("file")
+            .help("Also write a shell script to <file> for everyone else who has a clone of the \
+                   repo: once the rewritten history is pushed, it fetches it, moves their local \
+                   branches over, and rebases their own commits and stashes onto it. It only \
+                   needs Git, and has the commit map built in")
+            .long("emit-migration-script")
+            .number_of_values(1)
+            .conflicts_with_all(&["tip-only", "emit-script"]))
+        .arg(clap::Arg::with_name("no-reference-scan")
+            .help("Don't look for files at the tips of the rewritten branches that still refer to \
+                   the submodule, like CI configs and scripts running `git submodule update'")
+            .long("no-reference-scan"))
+        .arg(clap::Arg::with_name("report-vendored")
+            .help("If the submodule used to be vendored as plain files, report how many of the \
+                   imported file versions were already in the repository. Takes an extra walk \
+                   over the main repo's history")
+            .long("report-vendored"))
+        .arg(clap::Arg::with_name("cleanup-remove")
+            .value_name("glob")
+            .help("Put a commit on top of each rewritten branch that removes the files and \
+                   directories matching <glob> (relative to the repo's root, e.g. \
+                   'scripts/update-submodules.sh'), i.e. the tooling that only made sense while \
+                   the submodule was one")
+            .long("cleanup-remove")
+            .number_of_values(1)
+            .multiple(true)
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("cleanup-patch")
+            .value_name("file")
+            .help("Apply the patch from <file> (as made by `git diff') in the same commit as \
+                   --cleanup-remove, e.g. to take the submodule out of the CI configs. Branches \
+                   it doesn't apply to are left without the commit")
+            .long("cleanup-patch")
+            .number_of_values(1)
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("prune-empty")
+            .help("Drop commits that become empty after the rewrite (e.g. the ones that only \
+                   touched .gitmodules)")
+            .long("prune-empty"))
+        .arg(clap::Arg::with_name("skip-downgrade-parents")
+            .help("When a commit moves the submodule back to an older commit, don't turn it into \
+                   a merge; note the downgrade in the commit message instead")
+            .long("skip-downgrade-parents"))
+        .arg(clap::Arg::with_name("tip-only")
+            .help("Don't rewrite any existing commits; instead, merge submodule's history into \
+                   HEAD and remove the submodule in a new commit on top of it")
+            .long("tip-only")
+            .conflicts_with_all(&["prune-empty", "skip-downgrade-parents"]))
+        .arg(clap::Arg::with_name("no-modify-refs")
+            .help("Leave existing branches alone, and create new ones pointing at the rewritten \
+                   history (see --result-prefix and --result-suffix)")
+            .long("no-modify-refs")
+            .conflicts_with("tip-only"))
+        .arg(clap::Arg::with_name("result-prefix")
+            .value_name("namespace")
+            .help("With --no-modify-refs, where to put the new branches [default: refs/heads/]")
+            .long("result-prefix")
+            .requires("no-modify-refs")
+            .number_of_values(1))
+        .arg(clap::Arg::with_name("result-suffix")
+            .value_name("suffix")
+            .help("With --no-modify-refs, what to append to the names of the new branches \
+                   [default: -submerged]")
+            .long("result-suffix")
+            .requires("no-modify-refs")
+            .number_of_values(1));
+
+    let app = if cfg!(unix) {
+        app.arg(clap::Arg::with_name("progress-fd")
+            .value_name("fd")
+            .help("Like --progress-json, but write to an already open file descriptor")
+            .long("progress-fd")
+            .number_of_values(1)
+            .conflicts_with("progress-json"))
+    } else {
+        app
+    };
+    if cfg!(feature = "tui") {
+        app.arg(clap::Arg::with_name("interactive")
+            .help("Browse the commits that move the submodule and pick mappings for them before \
+                   doing the rewrite")
+            .long("interactive")
+            .conflicts_with("tip-only"))
+    } else {
+        app
+    }
+}
+
+// Turns options that aren't on the actual command line into command-line arguments, taking them
+// from GIT_SUBMERGE_* variables, and failing that, from `submerge.<option>' in Git's config
+fn get_env_arguments(cli_arguments: &[std::ffi::OsString])
+                     -> Result<Vec<std::ffi::OsString>, i32> {
+    // We might not be in a repo at all; real_main() will complain about that later
+    let config = Repository::open(".")
+        .and_then(|repo| repo.config())
+        .or_else(|_| git2::Config::open_default())
+        .ok();
+    let mut arguments = Vec::new();
+    for env_option in ENV_OPTIONS {
+        if is_on_command_line(cli_arguments, env_option) {
+            continue;
+        }
+        let name = env_option.name;
+        let mut variable = format!("GIT_SUBMERGE_{}", name.to_uppercase().replace("-", "_"));
+        let value = match std::env::var(&variable) {
+            Ok(value) => value,
+            Err(std::env::VarError::NotPresent) => {
+                let value = match config {
+                    Some(ref config) => get_config_value(config, env_option)?,
+                    None => None,
+                };
+                match value {
+                    Some(value) => {
+                        variable = format!("submerge.{}", name);
+                        value
+                    }
+                    None => continue,
+                }
+            }
+            Err(std::env::VarError::NotUnicode(_)) => {
+                eprintln!("{} is not valid UTF-8", variable);
+                return Err(E_INVALID_ENVIRONMENT);
+            }
+        };
+
+        let option = format!("--{}", name);
+        if env_option.values_count == 0 {
+            match value.as_ref() {
+                "" | "0" | "false" | "no" => {}
+                _ => arguments.push(option.into()),
+            }
+        } else if env_option.multiple {
+            let values: Vec<&str> = value.split_whitespace().collect();
+            if values.len() % env_option.values_count != 0 {
+                eprintln!("{} should contain a multiple of {} values",
+                          variable,
+                          env_option.values_count);
+                return Err(E_INVALID_ENVIRONMENT);
+            }
+            for occurrence in values.chunks(env_option.values_count) {
+                arguments.push(option.clone().into());
+                arguments.extend(occurrence.iter().map(|v| v.into()));
+            }
+        } else {
+            // Values might start with a dash, so they have to be glued to the option
+            arguments.push(format!("{}={}", option, value).into());
+        }
+    }
+    Ok(arguments)
+}
+
+// The value of `submerge.<option>' in the same form as the environment variable's. Repeated
+// options can be set several times, and each of these counts.
+fn get_config_value(config: &git2::Config, env_option: &EnvOption) -> Result<Option<String>, i32> {
+    let key = format!("submerge.{}", env_option.name);
+    let value = if env_option.values_count == 0 {
+        config.get_bool(&key).map(|value| Some(String::from(if value { "true" } else { "false" })))
+    } else if env_option.multiple {
+        config.entries(Some(&format!("^{}$", key.replace(".", "\\."))))
+            .and_then(|entries| {
+                let mut values = Vec::new();
+                for entry in &entries {
+                    let entry = entry?;
+                    values.push(String::from_utf8_lossy(entry.value_bytes()).into_owned());
+                }
+                if values.is_empty() {
+                    Ok(None)
+                } else {
+                    Ok(Some(values.join(" ")))
+                }
+            })
+    } else {
+        config.get_string(&key).map(Some)
+    };
+    match value {
+        Ok(value) => Ok(value),
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => {
+            eprintln!("Couldn't read {} from Git's config: {}", key, e.message());
+            Err(E_INVALID_ENVIRONMENT)
+        }
+    }
+}
+
+// We can't ask Clap about this, because the command line alone might not pass its checks (e.g.
+// --result-suffix requires --no-modify-refs, which might be set in the environment)
+fn is_on_command_line(cli_arguments: &[std::ffi::OsString], env_option: &EnvOption) -> bool {
+    let long = format!("--{}", env_option.name);
+    let long_with_value = format!("--{}=", env_option.name);
+    for argument in cli_arguments.iter().skip(1) {
+        let argument = argument.to_string_lossy();
+        if argument == "--" {
+            break;
+        }
+        if argument == long || argument.starts_with(&long_with_value) {
+            return true;
+        }
+        if let Some(short) = env_option.short {
+            if !argument.starts_with("--") && argument.starts_with(&format!("-{}", short)) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn parse_cli_arguments() -> Result<Options, i32> {
+    let cli_arguments: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let env_arguments = get_env_arguments(&cli_arguments)?;
+
+    // The environment goes first, so that the positional argument is still the one from the
+    // command line
+    let mut arguments = vec![cli_arguments[0].clone()];
+    arguments.extend(env_arguments);
+    arguments.extend(cli_arguments[1..].iter().cloned());
+    let options = build_cli().get_matches_from(arguments);
+
+    if options.is_present("help-man") {
+        manpage::print(&build_cli().set_term_width(80));
+        return Err(E_SUCCESS);
+    }
+
+    if options.is_present("version-info") {
+        version_info::print();
+        return Err(E_SUCCESS);
+    }
+
+    let stitch = options.subcommand_matches("stitch");
+    let migrate = options.subcommand_matches("migrate-clone");
+    let (mode, submodule_dir) = match (options.subcommand_matches("verify"),
+                                       options.subcommand_matches("promote"),
+                                       options.subcommand_matches("import"),
+                                       stitch,
+                                       migrate) {
+        (Some(verify), _, _, _, _) => (Mode::Verify, verify.value_of("SUBMODULE_DIR")),
+        (None, Some(promote), _, _, _) => (Mode::Promote, promote.value_of("SUBMODULE_DIR")),
+        (None, None, Some(import), _, _) => (Mode::Import, import.value_of("SUBMODULE_DIR")),
+        (None, None, None, Some(stitch), _) => (Mode::Stitch, stitch.value_of("SUBMODULE_DIR")),
+        (None, None, None, None, Some(migrate)) => {
+            (Mode::MigrateClone, migrate.value_of("SUBMODULE_DIR"))
+        }
+        (None, None, None, None, None) => (Mode::Merge, options.value_of("SUBMODULE_DIR")),
+    };
+    // We can safely use unwrap() here because the argument is marked as "required" and Clap
+    // checks its presence for us.
+    let submodule_dir = normalize_submodule_path(submodule_dir.unwrap());
+    let mut mapping_scopes = HashSet::new();
+
+    let mut mappings = parse_oid_pairs(&options, "mapping", &submodule_dir, &mut mapping_scopes)?;
+    let mut branch_default_mappings = Vec::new();
+    let values: Vec<&str> = options.values_of("default-mapping-for")
+        .map_or(Vec::new(), |values| values.collect());
+    for pair in values.chunks(2) {
+        branch_default_mappings.push((String::from(pair[0]), parse_mapping_target(pair[1])?));
+    }
+    if let Some(source) = options.value_of("mappings-from") {
+        if source == "-" && options.is_present("interactive") {
+            eprintln!("--interactive needs standard input for itself, so --mappings-from can't \
+                       read from it");
+            return Err(E_INVALID_MAPPINGS);
+        }
+        read_mappings_from(source,
+                           &submodule_dir,
+                           &mut mappings,
+                           &mut branch_default_mappings,
+                           &mut mapping_scopes)?;
+    }
+    let mapping_chains = resolve_mapping_chains(mappings)?;
+    let mappings = mapping_chains.iter()
+        .map(|(from, chain)| (*from, *chain.last().expect("A chain of mappings is empty")))
+        .collect();
+    let overrides = parse_oid_pairs(&options, "override", &submodule_dir, &mut mapping_scopes)?;
+    let date_range_mappings = parse_date_range_mappings(&options)?;
+
+    // One for all submodules, and one for this submodule in particular, which takes precedence
+    let mut default_mappings = (None, None);
+    let values: Vec<&str> = options.values_of("default-mapping")
+        .map_or(Vec::new(), |values| values.collect());
+    for value in values {
+        let (scope, target) = split_mapping_scope(value);
+        let target = parse_mapping_target(target)?;
+        let default_mapping = match scope {
+            None => &mut default_mappings.0,
+            Some(ref scope) if *scope == submodule_dir => &mut default_mappings.1,
+            Some(scope) => {
+                mapping_scopes.insert(scope);
+                continue;
+            }
+        };
+        if default_mapping.is_some() {
+            eprintln!("--default-mapping {} is given more than once for the same submodule",
+                      value);
+            return Err(E_INVALID_MAPPINGS);
+        }
+        *default_mapping = Some(target);
+    }
+    let default_mapping = default_mappings.1.or(default_mappings.0);
+
+    let committer = match options.value_of("committer") {
+        Some(ident) => Some(parse_identity(ident)?),
+        None => None,
+    };
+    let committer_date = match options.value_of("committer-date") {
+        Some("now") => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .expect("The system clock is set before 1970");
+            Some(now.as_secs() as i64)
+        }
+        Some(date) => {
+            match parse_date(date) {
+                Some(date) => Some(date),
+                None => {
+                    eprintln!("--committer-date expects YYYY-MM-DD, YYYY-MM-DDTHH:MM:SS (in UTC), \
+                               @<seconds since the epoch> or \"now\"");
+                    return Err(E_INVALID_IDENTITY);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let max_default_mappings = match options.value_of("max-default-mappings") {
+        Some(n) => {
+            match n.parse() {
+                Ok(n) => Some(n),
+                Err(_) => {
+                    eprintln!("--max-default-mappings expects a number, got `{}'", n);
+                    return Err(E_INVALID_MAPPINGS);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let similarity_threshold = match options.value_of("similarity-threshold") {
+        Some(percent) => {
+            match percent.trim_right_matches('%').parse() {
+                Ok(percent) if percent <= 100 => percent,
+                _ => {
+                    eprintln!("--similarity-threshold expects a percentage, got `{}'", percent);
+                    return Err(E_INVALID_ENVIRONMENT);
+                }
+            }
+        }
+        None => 50,
+    };
+    let find_copies = if options.is_present("find-copies") {
+        Some(similarity_threshold)
+    } else {
+        None
+    };
+    // Just like in Git, looking for copies means looking for renames, too
+    let find_renames = if options.is_present("find-renames") {
+        Some(similarity_threshold)
+    } else {
+        find_copies
+    };
+
+    let mut strip_paths: Vec<String> = options.values_of("strip-path")
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or(Vec::new());
+    if options.is_present("strip-submodule-ci") {
+        strip_paths.extend(SUBMODULE_CI_PATHS.iter().map(|p| String::from(*p)));
+    }
+
+    let memory_limit = match options.value_of("max-memory") {
+        Some(megabytes) => {
+            match megabytes.parse() {
+                Ok(megabytes) => Some(MemoryLimit::new(megabytes)),
+                Err(_) => {
+                    eprintln!("--max-memory expects a number of megabytes, got `{}'", megabytes);
+                    return Err(E_INVALID_ENVIRONMENT);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let cleanup_patch = match options.value_of("cleanup-patch") {
+        Some(path) => {
+            match cleanup::read_patch(path) {
+                Ok(patch) => Some(patch),
+                Err(message) => {
+                    eprintln!("{}", message);
+                    return Err(E_INVALID_ENVIRONMENT);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let progress = match (options.value_of("progress-json"), options.value_of("progress-fd")) {
+        (Some(path), _) => {
+            match File::create(path) {
+                Ok(file) => Some(Progress::new(file)),
+                Err(e) => {
+                    eprintln!("Couldn't create {} for progress reports: {}", path, e);
+                    return Err(E_INVALID_ENVIRONMENT);
+                }
+            }
+        }
+        (None, Some(fd)) => {
+            match fd.parse() {
+                Ok(fd) => Some(Progress::new(open_progress_fd(fd))),
+                Err(_) => {
+                    eprintln!("--progress-fd expects a file descriptor number, got `{}'", fd);
+                    return Err(E_INVALID_ENVIRONMENT);
+                }
+            }
+        }
+        (None, None) => None,
+    };
+
+    let abbrev = match options.value_of("abbrev") {
+        Some(n) => {
+            match n.parse() {
+                Ok(n) if n >= 4 && n <= 40 => Some(n),
+                _ => {
+                    eprintln!("--abbrev expects a number between 4 and 40, got `{}'", n);
+                    return Err(E_INVALID_ENVIRONMENT);
+                }
+            }
+        }
+        None => None,
+    };
+
+    // --output-refs-namespace is --no-modify-refs that also keeps tags and --include-refs apart.
+    // `promote' takes the same namespace to move things out of it.
+    let promote = options.subcommand_matches("promote");
+    let output_namespace = match (promote,
+                                  options.is_present("emit-script"),
+                                  options.is_present("output")) {
+        (Some(promote), _, _) => promote.value_of("from"),
+        // --emit-script and --output only need the results long enough to take them elsewhere
+        (None, true, _) => Some(script::NAMESPACE),
+        (None, false, true) => Some(output::NAMESPACE),
+        (None, false, false) => options.value_of("output-refs-namespace"),
+    };
+    let output_namespace = output_namespace.map(|namespace| {
+        if namespace.ends_with('/') {
+            String::from(namespace)
+        } else {
+            String::from(namespace) + "/"
+        }
+    });
+
+    Ok(Options {
+        mode: mode,
+        submodule_dir: submodule_dir,
+        mappings: mappings,
+        mapping_chains: mapping_chains,
+        other_mapping_scopes: mapping_scopes,
+        default_mapping: default_mapping,
+        overrides: overrides,
+        allow_external_mapping: options.is_present("allow-external-mapping"),
+        external_repos: options.values_of("external-repo")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or(Vec::new()),
+        fetch_refspecs: options.values_of("fetch-refspec")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or(Vec::new()),
+        include_remote_refs: options.is_present("include-remote-refs"),
+        rev_range: options.value_of("rev-range").map(String::from),
+        preserve_gitlinks_before: options.value_of("preserve-gitlinks-before").map(String::from),
+        tag_rename: options.value_of("tag-rename").map(String::from),
+        merge_matching_branches: options.is_present("merge-matching-branches"),
+        summary_commit: options.is_present("summary-commit"),
+        dot_file: options.value_of("dot").map(String::from),
+        interactive: options.is_present("interactive"),
+        diff_preview: options.value_of("diff-preview").map(String::from),
+        signoff: options.is_present("signoff"),
+        committer: committer,
+        committer_date: committer_date,
+        lock: options.is_present("lock"),
+        archive_namespace: options.value_of("archive-old-branches").map(|namespace| {
+            if namespace.ends_with('/') {
+                String::from(namespace)
+            } else {
+                String::from(namespace) + "/"
+            }
+        }),
+        max_default_mappings: max_default_mappings,
+        strip_paths: strip_paths,
+        honor_export_ignore: options.is_present("honor-export-ignore"),
+        merge_mailmap: options.is_present("merge-mailmap"),
+        url: options.value_of("url").map(String::from),
+        exclude_branches: options.values_of("exclude-branches")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or(Vec::new()),
+        include_refs: options.values_of("include-refs")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or(Vec::new()),
+        progress: progress,
+        warnings: RefCell::new(Vec::new()),
+        commit_map_written: Cell::new(false),
+        memory_limit: memory_limit,
+        auto_init: options.is_present("auto-init"),
+        abbrev: abbrev,
+        colors: color::Colors::new(options.value_of("color").unwrap_or("auto")),
+        output_namespace: output_namespace.clone(),
+        existing_content: match options.value_of("existing-content") {
+            Some("prefer-submodule") => ExistingContent::PreferSubmodule,
+            Some("prefer-existing") => ExistingContent::PreferExisting,
+            _ => ExistingContent::Error,
+        },
+        date_order: options.is_present("date-order"),
+        codeowners: match options.value_of("codeowners") {
+            Some("apply") => Some(CodeownersAction::Apply),
+            Some("print") => Some(CodeownersAction::Print),
+            _ => None,
+        },
+        emit_script: options.value_of("emit-script").map(String::from),
+        output: options.value_of("output").map(String::from),
+        no_update_worktree: options.is_present("no-update-worktree"),
+        keep_submodule_gitfile: options.is_present("keep-submodule-gitfile"),
+        date_range_mappings: date_range_mappings,
+        branch_default_mappings: branch_default_mappings,
+        fetch_historical_urls: options.is_present("fetch-historical-urls"),
+        also_fetch: options.values_of("also-fetch")
+            .map_or(Vec::new(), |urls| urls.map(String::from).collect()),
+        strict: options.is_present("strict"),
+        head: options.value_of("head").map(String::from),
+        hoist_gitignore: options.is_present("hoist-gitignore"),
+        progress_meter: !options.is_present("no-progress") &&
+                        color::is_terminal(color::STDERR_FD),
+        import_map_path: stitch.and_then(|stitch| stitch.value_of("import-map")).map(String::from),
+        migration_commit_map: migrate.and_then(|migrate| migrate.value_of("commit-map"))
+            .map(String::from),
+        migration_remote: migrate.and_then(|migrate| if migrate.is_present("no-fetch") {
+            None
+        } else {
+            migrate.value_of("remote").map(String::from)
+        }),
+        import_map: None,
+        commit_encoding: None,
+        shared_commits: HashSet::new(),
+        // Nothing written into the repo is needed once it's all in the new one
+        quarantine: options.is_present("quarantine") || options.is_present("output"),
+        protected_refs: options.values_of("protected-ref")
+            .map_or(Vec::new(), |globs| globs.map(String::from).collect()),
+        rewrite_protected: options.is_present("rewrite-protected"),
+        migration_checklist: options.is_present("migration-checklist"),
+        scan_cache: !options.is_present("no-scan-cache"),
+        diff_settings: DiffSettings {
+            renames: find_renames,
+            copies: find_copies,
+            text: options.is_present("text"),
+            binary: options.is_present("binary"),
+        },
+        migration_script: options.value_of("emit-migration-script").map(String::from),
+        no_reference_scan: options.is_present("no-reference-scan"),
+        report_vendored: options.is_present("report-vendored"),
+        cleanup_remove: options.values_of("cleanup-remove")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or(Vec::new()),
+        cleanup_patch: cleanup_patch,
+        prune_empty: options.is_present("prune-empty"),
+        skip_downgrade_parents: options.is_present("skip-downgrade-parents"),
+        tip_only: options.is_present("tip-only"),
+        no_modify_refs: options.is_present("no-modify-refs") || output_namespace.is_some(),
+        result_prefix: match output_namespace {
+            Some(ref namespace) => namespace.clone() + "heads/",
+            None => String::from(options.value_of("result-prefix").unwrap_or("refs/heads/")),
+        },
+        result_suffix: match output_namespace {
+            Some(_) => String::new(),
+            None => String::from(options.value_of("result-suffix").unwrap_or("-submerged")),
+        },
+    })
+}
+
+// Parses --map-range into the start and the end of each range, in seconds since the epoch, and the
+// commit that the gitlinks from that range are mapped to
+fn parse_date_range_mappings(options: &clap::ArgMatches) -> Result<Vec<(i64, i64, Oid)>, i32> {
+    let values: Vec<&str> = options.values_of("map-range").map_or(Vec::new(), |v| v.collect());
+    let mut result = Vec::new();
+    for pair in values.chunks(2) {
+        let mut dates = pair[0].splitn(2, "..").map(parse_date);
+        match (dates.next(), dates.next()) {
+            (Some(Some(since)), Some(Some(until))) if since < until => {
+                result.push((since, until, parse_mapping_target(pair[1])?));
+            }
+            _ => {
+                eprintln!("--map-range expects <since>..<until>, two dates (YYYY-MM-DD, \
+                           YYYY-MM-DDTHH:MM:SS in UTC, or @<seconds since the epoch>) with the \
+                           earlier one first; got `{}'",
+                          pair[0]);
+                return Err(E_INVALID_ENVIRONMENT);
+            }
+        }
+    }
+    Ok(result)
+}
+
+// Parses the values of an option that accepts pairs of commit IDs
+fn parse_oid_pairs(options: &clap::ArgMatches,
+                   name: &str,
+                   submodule_dir: &str,
+                   other_scopes: &mut HashSet<String>)
+                   -> Result<HashMap<Oid, Oid>, i32> {
+    let mut result = HashMap::new();
+
+    match options.values_of(name) {
+        None => {}
+        Some(values) => {
+            let mut i: i32 = 1;
+            let (first, second): (Vec<&str>, Vec<&str>) = values.partition(|_| {
+                i += 1;
+                i % 2 == 0
+            });
+            for (f, s) in first.iter().zip(second.iter()) {
+                add_oid_pair(&mut result, name, f, s, submodule_dir, other_scopes)?;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+// Pairs for other submodules (see split_mapping_scope()) are checked, but left out
+fn add_oid_pair(pairs: &mut HashMap<Oid, Oid>,
+                name: &str,
+                first: &str,
+                second: &str,
+                submodule_dir: &str,
+                other_scopes: &mut HashSet<String>)
+                -> Result<(), i32> {
+    let (scope, first) = split_mapping_scope(first);
+    let oid1 = match Oid::from_str(first) {
+        Ok(oid) => oid,
+        Err(_) => {
+            eprintln!("{} is not a valid 40-character hex string", first);
+            return Err(E_INVALID_COMMIT_ID);
+        }
+    };
+
+    let oid2 = parse_mapping_target(second)?;
+
+    match scope {
+        Some(ref scope) if scope != submodule_dir => {
+            other_scopes.insert(scope.clone());
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    match pairs.insert(oid1, oid2) {
+        Some(previous) if previous != oid2 => {
+            eprintln!("--{} {} is given twice, with different values: {} and {}",
+                      name,
+                      oid1,
+                      previous,
+                      oid2);
+            Err(E_INVALID_MAPPINGS)
+        }
+        Some(_) => {
+            eprintln!("--{} {} {} is given more than once", name, oid1, oid2);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+// Adds the mappings from a file (or standard input, if `source' is "-") to the ones given on the
+// command line, so that other tools can generate them
+fn read_mappings_from(source: &str,
+                      submodule_dir: &str,
+                      mappings: &mut HashMap<Oid, Oid>,
+                      branch_default_mappings: &mut Vec<(String, Oid)>,
+                      other_scopes: &mut HashSet<String>)
+                      -> Result<(), i32> {
+    let source_name = if source == "-" { "standard input" } else { source };
+    let mut contents = String::new();
+    let read = if source == "-" {
+        std::io::stdin().read_to_string(&mut contents)
+    } else {
+        File::open(source).and_then(|mut file| file.read_to_string(&mut contents))
+    };
+    if let Err(e) = read {
+        eprintln!("Couldn't read mappings from {}: {}", source_name, e);
+        return Err(E_INVALID_MAPPINGS);
+    }
+
+    let mut words = Vec::new();
+    for line in contents.lines().filter(|line| !line.trim_left().starts_with('#')) {
+        let line_words: Vec<&str> = line.split_whitespace().collect();
+        if line_words.first() != Some(&"default-for") {
+            words.extend(line_words);
+        } else if line_words.len() == 3 {
+            branch_default_mappings.push((String::from(line_words[1]),
+                                          parse_mapping_target(line_words[2])?));
+        } else {
+            eprintln!("{}: `default-for' should be followed by a branch and a commit ID: {}",
+                      source_name,
+                      line);
+            return Err(E_INVALID_MAPPINGS);
+        }
+    }
+    if words.len() % 2 != 0 {
+        eprintln!("{} contains an odd number of commit IDs; mappings should come in pairs",
+                  source_name);
+        return Err(E_INVALID_MAPPINGS);
+    }
+    for pair in words.chunks(2) {
+        add_oid_pair(mappings, "mapping", pair[0], pair[1], submodule_dir, other_scopes)?;
+    }
+
+    Ok(())
+}
+
+// If a mapping's target is itself mapped to something else, follows the chain until it ends, so
+// that each commit can be mapped straight to its final replacement, i.e. the last commit of its
+// chain.
+//
+// A commit mapped to itself (`--mapping X X') ends the chain right there rather than forming
+// a cycle: the user means "use X as it is", which is fine if X is in the submodule's history, and
+// is explained by are_mappings_valid() if it isn't.
+fn resolve_mapping_chains(mappings: HashMap<Oid, Oid>) -> Result<HashMap<Oid, Vec<Oid>>, i32> {
+    let mut chains = HashMap::new();
+
+    for (from, to) in &mappings {
+        let mut chain = vec![*from];
+        let mut target = *to;
+        while let Some(&next) = mappings.get(&target) {
+            if next == target {
+                break;
+            }
+            if chain.contains(&target) {
+                eprintln!("Mappings form a cycle:");
+                for id in &chain {
+                    eprintln!("    {} ->", id);
+                }
+                eprintln!("    {}", target);
+                return Err(E_INVALID_MAPPINGS);
+            }
+            chain.push(target);
+            target = next;
+        }
+
+        chain.push(target);
+        chains.insert(*from, chain);
+    }
+
+    Ok(chains)
+}
+
+// The mapped commit (or the target of --default-mapping) can be prefixed with the path of the
+// submodule it's for, e.g. "vendor/foo:<commit id>", so that one set of mappings can serve all the
+// submodules of a repo, one run at a time. Commit IDs and "empty" never contain a colon.
+fn split_mapping_scope(value: &str) -> (Option<String>, &str) {
+    match value.rfind(':') {
+        Some(i) => (Some(normalize_submodule_path(&value[..i])), &value[i + 1..]),
+        None => (None, value),
+    }
+}
+
+// Splits "Name <email>" into its parts
+fn parse_identity(ident: &str) -> Result<(String, String), i32> {
+    let ident = ident.trim();
+    match ident.find('<') {
+        Some(i) if ident.ends_with('>') && i > 0 => {
+            Ok((String::from(ident[..i].trim()), String::from(&ident[i + 1..ident.len() - 1])))
+        }
+        _ => {
+            eprintln!("`{}' is not an identity of the form \"Name <email>\"", ident);
+            Err(E_INVALID_IDENTITY)
+        }
+    }
+}
+
+// Git stores paths relative to the root of the repo, with forward slashes and without any `.'
+// components, so that's what .gitmodules and the trees have to be matched against. Shell
+// completion likes to add a trailing slash, for example.
+fn normalize_submodule_path(path: &str) -> String {
+    let path = if cfg!(windows) {
+        path.replace('\\', "/")
+    } else {
+        String::from(path)
+    };
+    let components: Vec<&str> = path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+    components.join("/")
+}
+
+// Parses the ID of the commit that something is mapped to. Apart from commit IDs, the user can
+// specify "empty", which we represent with an all-zeroes ID, just like Git itself does in places
+// where there's no object.
+fn parse_mapping_target(s: &str) -> Result<Oid, i32> {
+    if s == "empty" {
+        return Ok(Oid::from_bytes(&[0; 20]).expect("Couldn't create an all-zeroes ID"));
+    }
+
+    match Oid::from_str(s) {
+        Ok(oid) => Ok(oid),
+        Err(_) => {
+            eprintln!("{} is not a valid 40-character hex string", s);
+            Err(E_INVALID_COMMIT_ID)
+        }
+    }
+}
+
+// Returns the paths that `git status --porcelain` would report as changed, ignoring untracked
+// files. Submodules are checked according to their `submodule.<name>.ignore` setting, except for
+// the one we're about to merge: its checkout will become part of the main repo, so any changes in
+// it count.
+fn get_dirty_paths(repo: &Repository, submodule_dir: &str) -> Vec<(String, git2::Status)> {
+    let mut dirty_paths = get_changed_paths(repo);
+
+    if !dirty_paths.iter().any(|&(ref path, _)| path == submodule_dir) {
+        let submodule_is_dirty = repo.find_submodule(submodule_dir)
+            .and_then(|submodule| submodule.open())
+            .map(|submodule_repo| !get_changed_paths(&submodule_repo).is_empty())
+            .unwrap_or(false);
+        if submodule_is_dirty {
+            dirty_paths.push((submodule_dir.to_string(), git2::STATUS_WT_MODIFIED));
+        }
+    }
+
+    dirty_paths
+}
+
+// Two letters in the format of `git status --short': the state of the index, then of the worktree
+fn get_status_letters(status: git2::Status) -> String {
+    if status.contains(git2::STATUS_CONFLICTED) {
+        return String::from("UU");
+    }
+
+    let index = if status.contains(git2::STATUS_INDEX_NEW) {
+        'A'
+    } else if status.contains(git2::STATUS_INDEX_MODIFIED) {
+        'M'
+    } else if status.contains(git2::STATUS_INDEX_DELETED) {
+        'D'
+    } else if status.contains(git2::STATUS_INDEX_RENAMED) {
+        'R'
+    } else if status.contains(git2::STATUS_INDEX_TYPECHANGE) {
+        'T'
+    } else {
+        ' '
+    };
+    let worktree = if status.contains(git2::STATUS_WT_MODIFIED) {
+        'M'
+    } else if status.contains(git2::STATUS_WT_DELETED) {
+        'D'
+    } else if status.contains(git2::STATUS_WT_RENAMED) {
+        'R'
+    } else if status.contains(git2::STATUS_WT_TYPECHANGE) {
+        'T'
+    } else {
+        ' '
+    };
+    format!("{}{}", index, worktree)
+}
+
+fn get_changed_paths(repo: &Repository) -> Vec<(String, git2::Status)> {
+    // Ignored and untracked files don't make the worktree dirty; everything else does
+    let changes = git2::STATUS_INDEX_NEW | git2::STATUS_INDEX_MODIFIED |
+                  git2::STATUS_INDEX_DELETED | git2::STATUS_INDEX_RENAMED |
+                  git2::STATUS_INDEX_TYPECHANGE | git2::STATUS_WT_MODIFIED |
+                  git2::STATUS_WT_DELETED | git2::STATUS_WT_TYPECHANGE |
+                  git2::STATUS_WT_RENAMED | git2::STATUS_CONFLICTED;
+
+    let mut statusopts = git2::StatusOptions::new();
+    statusopts.include_untracked(false);
+    statusopts.include_ignored(false);
+    statusopts.include_unmodified(false);
+    statusopts.exclude_submodules(false);
+    statusopts.recurse_untracked_dirs(false);
+    statusopts.recurse_ignored_dirs(false);
+    let statuses = repo.statuses(Some(&mut statusopts))
+        .expect("Couldn't get statuses from the repo");
+    statuses.iter()
+        .filter(|entry| entry.status().intersects(changes))
+        .filter_map(|entry| entry.path().map(|path| (path.to_string(), entry.status())))
+        .collect()
+}
+
+fn does_submodule_exist(repo: &Repository, submodule_dir: &str) -> bool {
+    repo.find_submodule(submodule_dir).is_ok()
+}
+
+// Running git-submerge again (e.g. from a script that doesn't know whether it already did) is
+// a no-op, and automation should be able to tell that from a failure
+fn is_already_merged(repo: &Repository, submodule_dir: &str) -> bool {
+    let is_directory_at_head = repo.head()
+        .and_then(|head| head.peel(git2::ObjectType::Tree))
+        .ok()
+        .and_then(|tree| {
+            tree.as_tree().and_then(|tree| tree.get_path(Path::new(submodule_dir)).ok())
+        })
+        .map_or(false, |entry| entry.kind() == Some(git2::ObjectType::Tree));
+    is_directory_at_head && get_commit_map_path(repo, submodule_dir).exists()
+}
+
+// We remove the submodule's .git and check files out by path, so a symlink anywhere along the way
+// would make us act on whatever it points to
+fn find_symlink_along_path(submodule_dir: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(submodule_dir).components() {
+        path.push(component.as_os_str());
+        let is_symlink = std::fs::symlink_metadata(&path)
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false);
+        if is_symlink {
+            return Some(path);
+        }
+    }
+    None
+}
+
+// Turns --map-range into overrides for every commit in the range that has a gitlink. Commits that
+// already have an --override of their own keep it.
+fn get_date_range_overrides(repo: &Repository, options: &Options) -> HashMap<Oid, Oid> {
+    let submodule_path = Path::new(&options.submodule_dir);
+    let mut counts = vec![0; options.date_range_mappings.len()];
+    let mut overrides = HashMap::new();
+    for oid in get_repo_revwalk(repo, options).filter_map(|maybe_oid| maybe_oid.ok()) {
+        if options.overrides.contains_key(&oid) {
+            continue;
+        }
+        let commit = repo.find_commit(oid)
+            .expect(&format!("Couldn't get a commit with ID {}", oid));
+        let time = commit.committer().when().seconds();
+        let range = options.date_range_mappings
+            .iter()
+            .position(|&(since, until, _)| since <= time && time < until);
+        if let Some(i) = range {
+            let has_gitlink = commit.tree()
+                .and_then(|tree| tree.get_path(submodule_path))
+                .map(|entry| entry.filemode() == 0o160000)
+                .unwrap_or(false);
+            if has_gitlink {
+                overrides.insert(oid, options.date_range_mappings[i].2);
+                counts[i] += 1;
+            }
+        }
+    }
+    for (&(since, until, _), count) in options.date_range_mappings.iter().zip(counts) {
+        eprintln!("--map-range {}..{} applies to {} commit(s).",
+                  format_date(since),
+                  format_date(until),
+                  count);
+    }
+    overrides
+}
+
+// Turns --default-mapping-for into overrides for the dangling references in commits that are only
+// on the matching branches
+fn get_branch_default_overrides(repo: &Repository,
+                                old_id_to_new: &HashMap<Oid, Oid>,
+                                options: &Options)
+                                -> HashMap<Oid, Oid> {
+    let submodule_path = Path::new(&options.submodule_dir);
+    let branches = get_branch_to_id_map(repo, options);
+    let mut overrides = HashMap::new();
+    for &(ref glob, target) in &options.branch_default_mappings {
+        let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
+        let mut matched = false;
+        for (name, id) in &branches {
+            if glob_matches(glob.as_bytes(), name.as_bytes()) {
+                revwalk.push(*id).expect(&format!("Couldn't push branch `{}' to RevWalk", name));
+                matched = true;
+            } else {
+                revwalk.hide(*id).expect(&format!("Couldn't hide branch `{}' from RevWalk", name));
+            }
+        }
+        if !matched {
+            eprintln!("{} --default-mapping-for {}: no such branch.",
+                      options.colors.warning("Warning:"),
+                      glob);
+            continue;
+        }
+
+        let mut count = 0;
+        for oid in revwalk.filter_map(|maybe_oid| maybe_oid.ok()) {
+            if options.overrides.contains_key(&oid) || overrides.contains_key(&oid) {
+                continue;
+            }
+            let gitlink_id = repo.find_commit(oid)
+                .and_then(|commit| commit.tree())
+                .and_then(|tree| tree.get_path(submodule_path))
+                .ok()
+                .and_then(|entry| if entry.filemode() == 0o160000 {
+                    Some(peel_gitlink(repo, entry.id()))
+                } else {
+                    None
+                });
+            let is_dangling = gitlink_id.map_or(false, |id| {
+                map_submodule_commit(id, old_id_to_new, &options.mappings, &None).is_none()
+            });
+            if is_dangling {
+                overrides.insert(oid, target);
+                count += 1;
+            }
+        }
+        eprintln!("--default-mapping-for {} applies to {} commit(s).", glob, count);
+    }
+    overrides
+}
+
+// Checks if all the values in the mappings and overrides exist in submodule's history, and that
+// overrides are keyed by commits that exist in the main repo
+fn are_mappings_valid(repo: &Repository, options: &Options) -> bool {
+    let mut all_valid = true;
+    for main_commit in options.overrides.keys() {
+        if repo.find_commit(*main_commit).is_err() {
+            eprintln!("Commit {} not found in the repository.", main_commit);
+            all_valid = false;
+        }
+    }
+
+    let mut commits: HashSet<Oid> = options.mappings.values().cloned().collect();
+    commits.extend(options.overrides.values().cloned());
+    if let Some(oid) = options.default_mapping {
+        commits.insert(oid);
+    };
+    commits.extend(options.branch_default_mappings.iter().map(|&(_, oid)| oid));
+    let mut commits: HashSet<Oid> = commits.into_iter().filter(|oid| !oid.is_zero()).collect();
+
+    match options.import_map {
+        // `stitch' doesn't need the submodule's original history, so it might not even be here
+        Some(ref import_map) => {
+            commits = commits.into_iter().filter(|oid| !import_map.contains_key(oid)).collect();
+        }
+        None => {
+            let revwalk = get_submodule_revwalk(&repo, options, &[]);
+            for maybe_oid in revwalk {
+                match maybe_oid {
+                    Ok(oid) => {
+                        commits.remove(&oid);
+                    }
+                    Err(e) => {
+                        warn(options,
+                             "submodule history",
+                             format!("Error walking the submodule's history: {:?}", e))
+                    }
+                }
+            }
+        }
+    }
+
+    if options.allow_external_mapping {
+        commits = commits.into_iter().filter(|oid| repo.find_commit(*oid).is_err()).collect();
+    }
+
+    for commit in commits.iter() {
+        explain_missing_mapping_target(*commit, options);
+    }
+    if !commits.is_empty() && !options.allow_external_mapping {
+        eprintln!("\nIf these commits come from some other repository, fetch them with \
+                   --external-repo and pass --allow-external-mapping.");
+    }
+
+    all_valid && commits.len() == 0
+}
+
+// The generic "not found" message doesn't help much if the user never typed that ID in the first
+// place, or typed it on both sides of a mapping
+fn explain_missing_mapping_target(commit: Oid, options: &Options) {
+    let mut chains: Vec<&Vec<Oid>> = options.mapping_chains
+        .values()
+        .filter(|chain| chain.last() == Some(&commit))
+        .collect();
+    chains.sort();
+
+    if chains.iter().any(|chain| chain[..] == [commit, commit]) {
+        eprintln!("Commit {} is mapped to itself, but it's not in submodule's history either, so \
+                   the references to it would still be dangling. Map it to a commit that is, or \
+                   to `empty'.",
+                  commit);
+        return;
+    }
+
+    eprintln!("Commit {} not found in submodule's history.", commit);
+    for chain in chains.iter().filter(|chain| chain.len() > 2) {
+        let steps: Vec<String> = chain.iter().map(|id| id.to_string()).collect();
+        eprintln!("    It's where this chain of mappings ends: {}", steps.join(" -> "));
+    }
+}
+
+// A mapping target that no branch or tag points to is only there until the next `git gc` in the
+// submodule's upstream. The rewrite will still work, but anyone trying to find that commit later
+// won't, so tell the user while they can still pick something else.
+fn warn_about_unanchored_mapping_targets(repo: &Repository, options: &Options) {
+    let submodule_repo = match repo.find_submodule(&options.submodule_dir)
+        .and_then(|submodule| submodule.open()) {
+        Ok(submodule_repo) => submodule_repo,
+        Err(_) => return,
+    };
+
+    let mut targets: HashSet<Oid> = options.mappings.values().cloned().collect();
+    targets.extend(options.overrides.values().cloned());
+    if let Some(oid) = options.default_mapping {
+        targets.insert(oid);
+    }
+    targets.extend(options.branch_default_mappings.iter().map(|&(_, oid)| oid));
+    // Commits from other repositories are out of scope here; see --allow-external-mapping
+    let mut targets: HashSet<Oid> = targets.into_iter()
+        .filter(|oid| !oid.is_zero() && submodule_repo.find_commit(*oid).is_ok())
+        .collect();
+    if targets.is_empty() {
+        return;
+    }
+
+    let mut revwalk = submodule_repo.revwalk()
+        .expect("Couldn't obtain RevWalk object for the submodule");
+    for glob in &["refs/heads/*", "refs/tags/*", "refs/remotes/*"] {
+        revwalk.push_glob(glob).expect(&format!("Couldn't add {} to RevWalk", glob));
+    }
+    for oid in revwalk.filter_map(|maybe_oid| maybe_oid.ok()) {
+        targets.remove(&oid);
+    }
+
+    // Refs fetched with --fetch-refspec and friends count, too
+    if !targets.is_empty() {
+        let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
+        revwalk.push_glob("refs/submerge/fetched/*")
+            .expect("Couldn't add fetched submodule refs to RevWalk");
+        for oid in revwalk.filter_map(|maybe_oid| maybe_oid.ok()) {
+            targets.remove(&oid);
+        }
+    }
+
+    for oid in &targets {
+        eprintln!("{} mapping target {} isn't reachable from any of submodule's branches \
+                   or tags. It might disappear from the submodule's upstream after garbage \
+                   collection; consider mapping to a commit that is on a branch.",
+                  options.colors.warning("Warning:"),
+                  abbreviate(repo, *oid, options.abbrev));
+    }
+    if !targets.is_empty() {
+        eprintln!("");
+    }
+}
+
+// `extra_tips` are the commits whose history should be walked along with submodule's own, like
+// the ones that come from forks (see --allow-external-mapping).
+fn get_submodule_revwalk<'repo>(repo: &'repo Repository,
+                                options: &Options,
+                                extra_tips: &[Oid])
+                                -> Revwalk<'repo> {
+    let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
+    // "Topological" and reverse means "parents are always visited before their children".
+    // We need that in order to be sure that our old-to-new-ids map always contains everything we
+    // need it to contain.
+    revwalk.set_sorting(get_revwalk_sorting(options));
+    // The walk starts from the commit that HEAD's tree records, not from wherever the submodule's
+    // checkout happens to be: that might be detached somewhere else, or not updated since the last
+    // pull, and then the very commit HEAD uses would look dangling. The checkout's own HEAD,
+    // branches and tags come in through refs/submerge/fetched/, see fetch_submodule_history().
+    if let Some(id) = get_head_gitlink(repo, options) {
+        if repo.find_commit(id).is_ok() {
+            revwalk.push(id).expect("Couldn't add HEAD's submodule commit to RevWalk");
+        }
+    }
+
+    for tip in extra_tips {
+        revwalk.push(*tip).expect(&format!("Couldn't add commit {} to RevWalk", tip));
+    }
+    // See fetch_submodule_refspecs()
+    revwalk.push_glob("refs/submerge/fetched/*")
+        .expect("Couldn't add fetched submodule refs to RevWalk");
+
+    revwalk
+}
+
+// The submodule commit recorded in the tree of HEAD (or --head), if there's a gitlink there
+fn get_head_gitlink(repo: &Repository, options: &Options) -> Option<Oid> {
+    let head_id = get_head_id(repo, options);
+    let entry = repo.find_commit(head_id)
+        .and_then(|commit| commit.tree())
+        .and_then(|tree| tree.get_path(Path::new(&options.submodule_dir)));
+    match entry {
+        Ok(ref entry) if entry.filemode() == 0o160000 => Some(peel_gitlink(repo, entry.id())),
+        _ => None,
+    }
+}
+
+// Does what `git submodule update --init` would: clones the submodule into .git/modules/<name>
+// with the submodule's directory as the working tree, and checks out the commit the repo's index
+// points at. Submodules that are already checked out are left alone.
+fn init_submodule_checkout(repo: &Repository, options: &Options) -> Result<(), ()> {
+    let mut submodule = repo.find_submodule(&options.submodule_dir)
+        .expect("Couldn't find the submodule with expected path");
+    if submodule.open().is_ok() {
+        return Ok(());
+    }
+
+    // Copies the URL into the repo's config, resolving it if it's relative
+    submodule.init(false).expect("Couldn't initialize the submodule");
+    let url = match options.url {
+        Some(ref url) => url.clone(),
+        None => {
+            let name = submodule.name().unwrap_or(&options.submodule_dir);
+            let key = format!("submodule.{}.url", name);
+            match repo.config().and_then(|config| config.get_string(&key)) {
+                Ok(url) => url,
+                Err(_) => {
+                    eprintln!("Couldn't figure out where to clone {} from; please use --url",
+                              options.submodule_dir);
+                    return Err(());
+                }
+            }
+        }
+    };
+    let commit_id = submodule.index_id()
+        .or(submodule.head_id())
+        .expect("Couldn't obtain the commit the submodule should be at");
+
+    eprintln!("Cloning {} into {}...", url, options.submodule_dir);
+    let workdir = repo.workdir()
+        .expect("Couldn't obtain repo's working directory")
+        .join(&options.submodule_dir);
+    let gitdir = repo.path()
+        .join("modules")
+        .join(submodule.name().unwrap_or(&options.submodule_dir));
+    let mut init_options = git2::RepositoryInitOptions::new();
+    init_options.workdir_path(&workdir).no_dotgit_dir(true).mkpath(true);
+    let submodule_repo = Repository::init_opts(&gitdir, &init_options)
+        .expect("Couldn't create a repository for the submodule");
+
+    let mut remote = submodule_repo.remote("origin", &url)
+        .expect("Couldn't add a remote to the submodule's repository");
+    if let Err(e) = remote.fetch(&[], None, None) {
+        eprintln!("Couldn't fetch from {}: {}", url, e.message());
+        return Err(());
+    }
+    if submodule_repo.find_commit(commit_id).is_err() {
+        eprintln!("None of the branches in {} contain commit {}, which the repo points at",
+                  url,
+                  commit_id);
+        return Err(());
+    }
+
+    submodule_repo.set_head_detached(commit_id)
+        .expect("Couldn't point the submodule's HEAD at the right commit");
+    submodule_repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+        .expect("Couldn't check out the submodule");
+
+    Ok(())
+}
+
+// Submodule's history comes from its local checkout, unless --url says otherwise
+fn get_submodule_fetch_url(options: &Options) -> String {
+    match options.url {
+        Some(ref url) => url.clone(),
+        None => String::from("./") + &options.submodule_dir,
+    }
+}
+
+fn fetch_submodule_history(repo: &Repository, options: &Options) -> Result<(), ()> {
+    let mut remote = repo.remote_anonymous(&get_submodule_fetch_url(options))
+        .expect("Couldn't create an anonymous remote");
+    // A mirror's HEAD might not have anything to do with the commits the main repo uses, so we
+    // take all of its branches. The checkout's HEAD might be just as far off, e.g. if it wasn't
+    // updated since the last pull, so its branches and tags come along too.
+    let refspecs: &[&str] = match options.url {
+        Some(_) => &["+refs/heads/*:refs/submerge/fetched/url/*"],
+        None => {
+            &["+HEAD:refs/submerge/fetched/checkout/HEAD",
+              "+refs/heads/*:refs/submerge/fetched/checkout/heads/*",
+              "+refs/tags/*:refs/submerge/fetched/checkout/tags/*"]
+        }
+    };
+    match remote.fetch(refspecs, None, None) {
+        Ok(_) => {
+            // We need the tag objects to peel gitlinks that point at them (see peel_gitlink()).
+            // Most repos don't have any such gitlinks, so failing here isn't fatal.
+            let _ = remote.fetch(&["+refs/tags/*:refs/submerge/gitlink-tags/*"], None, None);
+            Ok(())
+        }
+        Err(e) => {
+            match options.url {
+                Some(ref url) => eprintln!("Couldn't fetch from {}: {}", url, e.message()),
+                None => {
+                    match get_submodule_inactivity_reason(repo, &options.submodule_dir) {
+                        Some(reason) => {
+                            eprintln!("Couldn't fetch submodule's history: {} isn't checked out \
+                                       because {}. Check it out first, or use --url to fetch its \
+                                       history from elsewhere.",
+                                      options.submodule_dir,
+                                      reason)
+                        }
+                        None => {
+                            eprintln!("Couldn't fetch submodule's history!  Have you forgot to \
+                                       run `git submodule update --recursive`? (--auto-init can \
+                                       do that for you.)")
+                        }
+                    }
+                }
+            }
+            Err(())
+        }
+    }
+}
+
+// Submodules can be registered but deliberately left without a checkout. Returns the setting
+// responsible for that, if any.
+fn get_submodule_inactivity_reason(repo: &Repository, submodule_dir: &str) -> Option<String> {
+    let name = match repo.find_submodule(submodule_dir) {
+        Ok(submodule) => String::from(submodule.name().unwrap_or(submodule_dir)),
+        Err(_) => return None,
+    };
+
+    let config = repo.config().ok();
+    let active_key = format!("submodule.{}.active", name);
+    if config.as_ref().and_then(|c| c.get_bool(&active_key).ok()) == Some(false) {
+        return Some(format!("{} is false", active_key));
+    }
+
+    // The local config overrides .gitmodules
+    let update_key = format!("submodule.{}.update", name);
+    let gitmodules = repo.workdir()
+        .and_then(|workdir| git2::Config::open(&workdir.join(".gitmodules")).ok());
+    let update = config.as_ref()
+        .and_then(|c| c.get_string(&update_key).ok())
+        .or_else(|| gitmodules.and_then(|c| c.get_string(&update_key).ok()));
+    if update.map_or(false, |update| update == "none") {
+        return Some(format!("{} is set to `none'", update_key));
+    }
+
+    None
+}
+
+// Returns the URL of the repository the submodule was cloned from. The URL in .gitmodules might
+// be relative, so we prefer the one from submodule's own config. --url trumps both.
+fn get_submodule_upstream_url(repo: &Repository, options: &Options) -> Option<String> {
+    if options.url.is_some() {
+        return options.url.clone();
+    }
+    let submodule = repo.find_submodule(&options.submodule_dir)
+        .expect("Couldn't find the submodule with expected path");
+    let from_checkout = submodule.open()
+        .ok()
+        .and_then(|r| r.find_remote("origin").ok().and_then(|o| o.url().map(String::from)));
+    from_checkout.or(submodule.url().map(String::from))
+}
+
+// Fetches refs that the local checkout of the submodule usually doesn't have, like the ones code
+// review systems keep on the server. Whatever we fetch ends up in refs/submerge/fetched/, and is
+// then treated as part of submodule's history.
+fn fetch_submodule_refspecs(repo: &Repository, options: &Options) -> Result<(), ()> {
+    let url = match get_submodule_upstream_url(repo, options) {
+        Some(url) => url,
+        None => {
+            eprintln!("Couldn't figure out where the submodule was cloned from");
+            return Err(());
+        }
+    };
+
+    // We only care about the source side; the destination is always our own namespace
+    let refspecs: Vec<String> = options.fetch_refspecs
+        .iter()
+        .map(|refspec| {
+            let source = refspec.trim_left_matches('+').split(':').next().unwrap_or("");
+            let name = source.trim_left_matches("refs/");
+            format!("+{}:refs/submerge/fetched/{}", source, name)
+        })
+        .collect();
+    let refspecs: Vec<&str> = refspecs.iter().map(|s| s.as_str()).collect();
+
+    let mut remote = repo.remote_anonymous(&url).expect("Couldn't create an anonymous remote");
+    match remote.fetch(&refspecs, None, None) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("Couldn't fetch {} from {}: {}",
+                      options.fetch_refspecs.join(", "),
+                      url,
+                      e.message());
+            Err(())
+        }
+    }
+}
+
+// Fetches the commits that submodule's checkout only knows through its remote-tracking branches
+// and FETCH_HEAD. Like with fetch_submodule_refspecs(), they end up in refs/submerge/fetched/.
+fn fetch_submodule_remote_refs(repo: &Repository, submodule_dir: &str) -> Result<(), ()> {
+    let submodule_repo = repo.find_submodule(submodule_dir)
+        .and_then(|s| s.open())
+        .expect("Couldn't open the submodule's repository");
+
+    // FETCH_HEAD isn't advertised to the fetching side, so we temporarily create proper references
+    // for the commits it lists
+    let fetch_head_path = submodule_repo.path().join("FETCH_HEAD");
+    let mut fetch_head_refs = Vec::new();
+    let mut contents = String::new();
+    let read_result = std::fs::File::open(&fetch_head_path)
+        .and_then(|mut f| f.read_to_string(&mut contents));
+    if read_result.is_ok() {
+        for line in contents.lines() {
+            let id = line.split('\t').next().and_then(|s| Oid::from_str(s).ok());
+            if let Some(id) = id {
+                let name = format!("refs/submerge/fetch-head/{}", fetch_head_refs.len());
+                if submodule_repo.find_commit(id).is_ok() {
+                    submodule_repo.reference(&name, id, true, "git-submerge: anchoring FETCH_HEAD")
+                        .expect("Couldn't create a reference in the submodule");
+                    fetch_head_refs.push(name);
+                }
+            }
+        }
+    }
+
+    let submodule_url = String::from("./") + submodule_dir;
+    let mut remote = repo.remote_anonymous(&submodule_url)
+        .expect("Couldn't create an anonymous remote");
+    let result = remote.fetch(&["+refs/remotes/*:refs/submerge/fetched/remotes/*",
+                                "+refs/submerge/fetch-head/*:refs/submerge/fetched/fetch-head/*"],
+                              None,
+                              None);
+
+    for name in fetch_head_refs {
+        submodule_repo.find_reference(&name)
+            .and_then(|mut r| r.delete())
+            .expect("Couldn't remove a temporary reference from the submodule");
+    }
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("Couldn't fetch submodule's remote-tracking branches: {}", e.message());
+            Err(())
+        }
+    }
+}
+
+// Every URL the submodule had in .gitmodules, along with the first commit that used it, oldest
+// first
+fn get_submodule_url_history(repo: &Repository, options: &Options) -> Vec<(String, Oid)> {
+    let mut history: Vec<(String, Oid)> = Vec::new();
+    // .gitmodules rarely changes, so most commits share a handful of blobs
+    let mut blob_urls: HashMap<Oid, Option<String>> = HashMap::new();
+    for oid in get_repo_revwalk(repo, options).filter_map(|maybe_oid| maybe_oid.ok()) {
+        let blob_id = match repo.find_commit(oid)
+            .and_then(|commit| commit.tree())
+            .and_then(|tree| tree.get_path(Path::new(".gitmodules"))) {
+            Ok(entry) => entry.id(),
+            Err(_) => continue,
+        };
+        let url = blob_urls.entry(blob_id)
+            .or_insert_with(|| {
+                repo.find_blob(blob_id)
+                    .ok()
+                    .and_then(|blob| {
+                        parse_gitmodules_url(&String::from_utf8_lossy(blob.content()),
+                                             &options.submodule_dir)
+                    })
+            })
+            .clone();
+        if let Some(url) = url {
+            if history.iter().all(|&(ref known, _)| *known != url) {
+                history.push((url, oid));
+            }
+        }
+    }
+    history
+}
+
+// Finds the URL of the submodule at `path` in the contents of a .gitmodules file. That's the
+// plain subset of Git's config syntax that `git submodule' writes: sections, and `key = value'.
+fn parse_gitmodules_url(contents: &str, path: &str) -> Option<String> {
+    // Path and URL of the current section
+    let mut section: (Option<String>, Option<String>) = (None, None);
+    let mut result = None;
+    for line in contents.lines().map(|line| line.trim()) {
+        if line.starts_with('[') {
+            if let (Some(ref section_path), Some(ref url)) = section {
+                if section_path == path {
+                    result = Some(url.clone());
+                }
+            }
+            section = (None, None);
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().map(|value| String::from(value.trim().trim_matches('"')));
+        match &key[..] {
+            "path" => section.0 = value.map(|value| String::from(value.trim_right_matches('/'))),
+            "url" => section.1 = value,
+            _ => {}
+        }
+    }
+    if let (Some(ref section_path), Some(ref url)) = section {
+        if section_path == path {
+            result = Some(url.clone());
+        }
+    }
+    result
+}
+
+fn print_submodule_url_history(repo: &Repository, history: &[(String, Oid)], options: &Options) {
+    eprintln!("The URL of {} in .gitmodules changed over time:", options.submodule_dir);
+    for &(ref url, oid) in history {
+        let date = repo.find_commit(oid)
+            .map(|commit| format_date(commit.committer().when().seconds()))
+            .unwrap_or(String::new());
+        eprintln!("    {} {}  {}", abbreviate(repo, oid, options.abbrev), date, url);
+    }
+    if !options.fetch_historical_urls {
+        eprintln!("Gitlinks made before a move might only be found at the old URL; \
+                   --fetch-historical-urls fetches from all of them.");
+    }
+}
+
+// Gitlinks in the main repo's history that can't be found in the submodule's history as fetched so
+// far, and aren't taken care of by --mapping or --override either
+fn find_missing_submodule_commits(repo: &Repository, options: &Options) -> HashSet<Oid> {
+    let submodule_path = Path::new(&options.submodule_dir);
+    let cutoff = GitlinkCutoff::from_options(repo, options)
+        .map(|c| c.expect("Couldn't figure out --preserve-gitlinks-before"));
+    let mut missing = HashSet::new();
+    for oid in get_repo_revwalk(repo, options).filter_map(|maybe_oid| maybe_oid.ok()) {
+        if options.overrides.contains_key(&oid) {
+            continue;
+        }
+        let commit = repo.find_commit(oid)
+            .expect(&format!("Couldn't get a commit with ID {}", oid));
+        if cutoff.as_ref().map_or(false, |c| c.preserves(&commit)) {
+            continue;
+        }
+        let gitlink_id = commit.tree()
+            .and_then(|tree| tree.get_path(submodule_path))
+            .ok()
+            .and_then(|entry| if entry.filemode() == 0o160000 {
+                Some(peel_gitlink(repo, entry.id()))
+            } else {
+                None
+            });
+        if let Some(id) = gitlink_id {
+            if !options.mappings.contains_key(&id) {
+                missing.insert(id);
+            }
+        }
+    }
+
+    if !missing.is_empty() {
+        let revwalk = get_submodule_revwalk(repo, options, &[]);
+        for oid in revwalk.filter_map(|maybe_oid| maybe_oid.ok()) {
+            missing.remove(&oid);
+        }
+    }
+    missing
+}
+
+// Tries the --also-fetch URLs one by one, for as long as some submodule commits are missing. The
+// histories end up in refs/submerge/fetched/, which makes them part of submodule's history.
+fn fetch_missing_submodule_commits(repo: &Repository, options: &Options) {
+    let mut missing = find_missing_submodule_commits(repo, options);
+    for (i, url) in options.also_fetch.iter().enumerate() {
+        if missing.is_empty() {
+            break;
+        }
+        eprintln!("{} submodule commit(s) are still missing, fetching from {}...",
+                  missing.len(),
+                  url);
+        let mut remote = repo.remote_anonymous(url).expect("Couldn't create an anonymous remote");
+        let refspecs = [format!("+refs/heads/*:refs/submerge/fetched/also-{}/heads/*", i),
+                        format!("+refs/tags/*:refs/submerge/fetched/also-{}/tags/*", i)];
+        let refspecs: Vec<&str> = refspecs.iter().map(|refspec| &refspec[..]).collect();
+        match remote.fetch(&refspecs, None, None) {
+            Ok(_) => missing = find_missing_submodule_commits(repo, options),
+            Err(e) => {
+                eprintln!("{} Couldn't fetch from {}: {}",
+                          options.colors.warning("Warning:"),
+                          url,
+                          e.message())
+            }
+        }
+    }
+}
+
+// The URLs from get_submodule_url_history() that fetch_submodule_history() didn't fetch from
+fn get_historical_urls(repo: &Repository,
+                       history: &[(String, Oid)],
+                       options: &Options)
+                       -> Vec<String> {
+    let current_urls = [get_submodule_upstream_url(repo, options),
+                        repo.find_submodule(&options.submodule_dir)
+                            .ok()
+                            .and_then(|submodule| submodule.url().map(String::from))];
+    history.iter()
+        .map(|&(ref url, _)| url.clone())
+        .filter(|url| !current_urls.iter().any(|current_url| current_url.as_ref() == Some(url)))
+        .collect()
+}
+
+// The histories end up in refs/submerge/fetched/, which makes them part of submodule's history.
+// Old hosts might be long gone, so failing to fetch from them is not an error.
+fn fetch_historical_urls(repo: &Repository, history: &[(String, Oid)], options: &Options) {
+    for (i, url) in get_historical_urls(repo, history, options).iter().enumerate() {
+        // These are relative to the URL the main repo itself had back then, which we don't know
+        if url.starts_with("./") || url.starts_with("../") {
+            eprintln!("{} Skipping {}, a relative URL.", options.colors.warning("Warning:"), url);
+            continue;
+        }
+        let mut remote = repo.remote_anonymous(url).expect("Couldn't create an anonymous remote");
+        let refspecs = [format!("+refs/heads/*:refs/submerge/fetched/historical-{}/heads/*", i),
+                        format!("+refs/tags/*:refs/submerge/fetched/historical-{}/tags/*", i)];
+        let refspecs: Vec<&str> = refspecs.iter().map(|refspec| &refspec[..]).collect();
+        if let Err(e) = remote.fetch(&refspecs, None, None) {
+            eprintln!("{} Couldn't fetch from {}: {}",
+                      options.colors.warning("Warning:"),
+                      url,
+                      e.message());
+        }
+    }
+}
+
+fn fetch_external_repo(repo: &Repository, url: &str) -> Result<(), ()> {
+    let mut remote = repo.remote_anonymous(url).expect("Couldn't create an anonymous remote");
+    // The refs are only there to keep the objects around until we're done; they're removed at
+    // the end of the run
+    let refspecs = ["+refs/heads/*:refs/submerge/external/heads/*",
+                    "+refs/tags/*:refs/submerge/external/tags/*"];
+    match remote.fetch(&refspecs, None, None) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("Couldn't fetch from {}: {}", url, e.message());
+            Err(())
+        }
+    }
+}
+
+// Submodule's tags end up in refs/submerge/fetched/tags/, so the commits they point to are
+// rewritten along with the rest of submodule's history.
+fn fetch_submodule_tags(repo: &Repository, options: &Options) -> Result<(), ()> {
+    let mut remote = repo.remote_anonymous(&get_submodule_fetch_url(options))
+        .expect("Couldn't create an anonymous remote");
+    match remote.fetch(&["+refs/tags/*:refs/submerge/fetched/tags/*"], None, None) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("Couldn't fetch submodule's tags: {}", e.message());
+            Err(())
+        }
+    }
+}
+
+// Submodule's branches end up in refs/submerge/fetched/branches/, which makes them part of
+// submodule's history.
+fn fetch_submodule_branches(repo: &Repository, options: &Options) -> Result<(), ()> {
+    let mut remote = repo.remote_anonymous(&get_submodule_fetch_url(options))
+        .expect("Couldn't create an anonymous remote");
+    match remote.fetch(&["+refs/heads/*:refs/submerge/fetched/branches/*"], None, None) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("Couldn't fetch submodule's branches: {}", e.message());
+            Err(())
+        }
+    }
+}
+
+// Returns pairs of (original name, new name) for each of the fetched submodule's tags
+fn get_renamed_submodule_tags(repo: &Repository,
+                              format: &str,
+                              options: &Options)
+                              -> Vec<(String, String)> {
+    let references = repo.references_glob("refs/submerge/fetched/tags/*")
+        .expect("Couldn't obtain an iterator over references");
+    let mut tags = Vec::new();
+    for maybe_reference in references {
+        match maybe_reference {
+            Ok(reference) => {
+                let name = reference.name()
+                    .expect("Tag name is not valid UTF-8")
+                    .trim_left_matches("refs/submerge/fetched/tags/");
+                let new_name = format.replace("{submodule}", &options.submodule_dir)
+                    .replace("{tag}", name);
+                tags.push((String::from(name), new_name));
+            }
+            Err(e) => warn(options, "tags", format!("Error walking the references: {:?}", e)),
+        }
+    }
+    tags
+}
+
+// Checks that renamed submodule's tags don't clash with each other or with main repo's tags
+fn are_tag_names_available(repo: &Repository, format: &str, options: &Options) -> bool {
+    let mut result = true;
+    let mut seen: HashMap<String, String> = HashMap::new();
+    for (name, new_name) in get_renamed_submodule_tags(repo, format, options) {
+        if repo.find_reference(&format!("refs/tags/{}", new_name)).is_ok() {
+            eprintln!("Submodule's tag `{}' would be imported as `{}', but the main repo \
+                       already has a tag with that name",
+                      name,
+                      new_name);
+            result = false;
+        }
+        if let Some(other) = seen.insert(new_name.clone(), name.clone()) {
+            eprintln!("Submodule's tags `{}' and `{}' would both be imported as `{}'",
+                      other,
+                      name,
+                      new_name);
+            result = false;
+        }
+    }
+    if !result {
+        eprintln!("Please pick a different --tag-rename format.");
+    }
+    result
+}
+
+// Creates main repo's counterparts of submodule's tags, pointing to the rewritten commits.
+// Annotated tags stay annotated, with the same tagger and message.
+fn import_submodule_tags(repo: &Repository,
+                         old_id_to_new: &HashMap<Oid, Oid>,
+                         format: &str,
+                         options: &Options) {
+    let mut imported = 0;
+    for (name, new_name) in get_renamed_submodule_tags(repo, format, options) {
+        let reference = repo.find_reference(&format!("refs/submerge/fetched/tags/{}", name))
+            .expect("Couldn't find a tag we just fetched");
+        let commit_id = reference.peel(git2::ObjectType::Commit).map(|c| c.id());
+        let new_commit_id = match commit_id.ok().and_then(|id| old_id_to_new.get(&id)) {
+            Some(id) => *id,
+            None => {
+                eprintln!("Skipping submodule's tag `{}': it doesn't point to a commit", name);
+                continue;
+            }
+        };
+        let new_commit = repo.find_object(new_commit_id, Some(git2::ObjectType::Commit))
+            .expect("Couldn't find the rewritten commit");
+
+        let tag_id = reference.target().expect("Tag reference is not direct");
+        match repo.find_tag(tag_id) {
+            Ok(tag) => {
+                let tagger = match tag.tagger() {
+                    Some(tagger) => tagger,
+                    None => repo.signature().expect("Couldn't obtain user's signature"),
+                };
+                repo.tag(&new_name,
+                         &new_commit,
+                         &tagger,
+                         tag.message().unwrap_or(""),
+                         false)
+                    .expect(&format!("Couldn't create tag {}", new_name));
+            }
+            Err(_) => {
+                repo.tag_lightweight(&new_name, &new_commit, false)
+                    .expect(&format!("Couldn't create tag {}", new_name));
+            }
+        }
+        // libgit2 can only create tags in refs/tags/, so we move them afterwards
+        if let Some(ref namespace) = options.output_namespace {
+            repo.find_reference(&format!("refs/tags/{}", new_name))
+                .and_then(|mut reference| {
+                    reference.rename(&format!("{}tags/{}", namespace, new_name),
+                                     true,
+                                     "git-submerge: moving to the output namespace")
+                })
+                .expect(&format!("Couldn't move tag {} to {}", new_name, namespace));
+        }
+        imported += 1;
+    }
+    eprintln!("Imported {} of submodule's tags.", imported);
+}
+
+fn remove_temporary_refs(repo: &Repository) {
+    for glob in &["refs/submerge/fetched/*",
+                  "refs/submerge/external/*",
+                  "refs/submerge/in-progress/*",
+                  "refs/submerge/gitlink-tags/*"] {
+        let references = repo.references_glob(glob)
+            .expect("Couldn't obtain an iterator over references");
+        for maybe_reference in references {
+            match maybe_reference {
+                Ok(mut reference) => {
+                    reference.delete().expect("Couldn't remove a temporary reference");
+                }
+                Err(e) => eprintln!("Error walking the references: {:?}", e),
+            }
+        }
+    }
+}
+
+// Mapping targets are normally in submodule's history anyway, but with --allow-external-mapping
+// they might not be, and we need them rewritten all the same.
+fn get_mapping_targets_to_walk(options: &Options) -> Vec<Oid> {
+    let mut extra_tips: Vec<Oid> = Vec::new();
+    if options.allow_external_mapping {
+        extra_tips.extend(options.mappings.values().cloned());
+        extra_tips.extend(options.overrides.values().cloned());
+        extra_tips.extend(options.default_mapping);
+        extra_tips.extend(options.branch_default_mappings.iter().map(|&(_, oid)| oid));
+        extra_tips.retain(|oid| !oid.is_zero());
+    }
+    extra_tips
+}
+
+// Shows how the rewrite would change the tree of a single commit, without rewriting anything.
+// Parents aren't shown: they depend on the rest of the history.
+fn preview_commit_rewrite(repo: &Repository, rev: &str, options: &Options) -> i32 {
+    let submodule_path = Path::new(&options.submodule_dir);
+    let object = repo.revparse_single(rev).and_then(|o| o.peel(git2::ObjectType::Commit));
+    let mut commit = match object {
+        Ok(object) => {
+            repo.find_commit(object.id()).expect("Couldn't find a commit we just resolved")
+        }
+        Err(_) => {
+            eprintln!("Couldn't resolve `{}' into a commit", rev);
+            return E_INVALID_COMMIT_ID;
+        }
+    };
+    let tree = commit.tree().expect("Couldn't obtain commit's tree");
+    println!("Commit {} ({}):",
+             abbreviate(repo, commit.id(), options.abbrev),
+             commit.summary().unwrap_or(""));
+
+    let cutoff = GitlinkCutoff::from_options(repo, options)
+        .map(|c| c.expect("Couldn't figure out --preserve-gitlinks-before"));
+    if cutoff.as_ref().map_or(false, |c| c.preserves(&commit)) {
+        println!("    the tree will stay the same (--preserve-gitlinks-before)");
+        return E_SUCCESS;
+    }
+
+    let gitlink_id = match tree.get_path(submodule_path) {
+        Ok(_) if options.overrides.contains_key(&commit.id()) => options.overrides[&commit.id()],
+        Ok(ref entry) if entry.filemode() == 0o160000 => peel_gitlink(repo, entry.id()),
+        _ => {
+            println!("    the tree will stay the same: there's no submodule at {}",
+                     options.submodule_dir);
+            return E_SUCCESS;
+        }
+    };
+
+    // resolve_submodule_commit() only needs to know which commits are in submodule's history
+    let extra_tips = get_mapping_targets_to_walk(options);
+    let known_commits: HashMap<Oid, Oid> =
+        get_submodule_revwalk(repo, options, &extra_tips)
+            .filter_map(|maybe_oid| maybe_oid.ok())
+            .map(|oid| (oid, oid))
+            .collect();
+    let submodule_commit_id = match resolve_submodule_commit(gitlink_id,
+                                                             &known_commits,
+                                                             &options.mappings,
+                                                             &options.default_mapping) {
+        Some(id) => id,
+        None => {
+            eprintln!("The commit references submodule commit {}, which couldn't be found in the \
+                       submodule's history. Use --mapping or --default-mapping to replace it.",
+                      gitlink_id);
+            return E_FOUND_DANGLING_REFERENCES;
+        }
+    };
+    let subtree_id = if submodule_commit_id.is_zero() {
+        None
+    } else {
+        Some(repo.find_commit(submodule_commit_id)
+            .and_then(|c| c.tree())
+            .map(|t| t.id())
+            .expect("Couldn't obtain the tree of submodule's commit"))
+    };
+
+    let new_tree = replace_submodule_dir(repo, &tree, submodule_path, subtree_id, true);
+    let diff = diff_trees(repo, &tree, &new_tree, options);
+    for delta in diff.deltas() {
+        let mut path = delta.new_file()
+            .path()
+            .or(delta.old_file().path())
+            .map(|p| p.display().to_string())
+            .unwrap_or(String::from("<unknown path>"));
+        let status = match delta.status() {
+            git2::Delta::Added => "added",
+            git2::Delta::Deleted => "removed",
+            git2::Delta::Renamed => "renamed",
+            git2::Delta::Copied => "copied",
+            _ => "modified",
+        };
+        if let (git2::Delta::Renamed, Some(old_path)) |
+               (git2::Delta::Copied, Some(old_path)) = (delta.status(), delta.old_file().path()) {
+            path = format!("{} -> {}", old_path.display(), path);
+        }
+        if delta.old_file().id() == gitlink_id && delta.status() == git2::Delta::Deleted {
+            println!("    {:8} {} (gitlink to {})",
+                     status,
+                     path,
+                     abbreviate(repo, gitlink_id, options.abbrev));
+        } else {
+            println!("    {:8} {}", status, path);
+        }
+    }
+    if submodule_commit_id != gitlink_id {
+        if submodule_commit_id.is_zero() {
+            println!("(the submodule commit is mapped to an empty directory)");
+        } else {
+            println!("(the submodule commit is mapped to {})",
+                     abbreviate(repo, submodule_commit_id, options.abbrev));
+        }
+    }
+
+    E_SUCCESS
+}
+
+// Superprojects are sometimes split off from the submodule (or the other way round), so the two
+// histories start with the same commits. Those commits are only imported once, as they are in the
+// main history, rather than a second time under the submodule's directory.
+fn find_shared_commits(repo: &Repository, options: &Options) -> HashSet<Oid> {
+    let extra_tips = get_mapping_targets_to_walk(options);
+    let submodule_commits: HashSet<Oid> = get_submodule_revwalk(repo, options, &extra_tips)
+        .filter_map(|maybe_oid| maybe_oid.ok())
+        .collect();
+    get_repo_revwalk(repo, options)
+        .filter_map(|maybe_oid| maybe_oid.ok())
+        .filter(|oid| submodule_commits.contains(oid))
+        .collect()
+}
+
+// The tree of a commit of the (rewritten) submodule history, as it should appear in the submodule's
+// directory. Most commits have it there already; shared ones have it at the root.
+fn get_imported_subtree_id(commit: &Commit, options: &Options) -> Result<Oid, git2::Error> {
+    if options.shared_commits.contains(&commit.id()) {
+        return Ok(commit.tree_id());
+    }
+    commit.tree()
+        .and_then(|tree| tree.get_path(Path::new(&options.submodule_dir)))
+        .map(|entry| entry.id())
+}
+
+// Returns the submodule commits that have a gitlink at the submodule's own path. Such commits
+// are most likely main repo's commits that somehow got into the submodule's history, and
+// rewrite_repo_history() would mix them up with their namesakes.
+fn rewrite_submodule_history(repo: &Repository,
+                             old_id_to_new: &mut HashMap<Oid, Oid>,
+                             options: &Options)
+                             -> Vec<Oid> {
+    let submodule_dir = &options.submodule_dir;
+    let mut self_referencing = Vec::new();
+
+    let extra_tips = get_mapping_targets_to_walk(options);
+    let revwalk: Vec<_> = get_submodule_revwalk(&repo, options, &extra_tips).collect();
+    let total = revwalk.len();
+    let mut anchors = InProgressAnchors::new("submodule");
+    let vendored_blobs = if options.report_vendored {
+        get_vendored_blobs(repo, options)
+    } else {
+        HashSet::new()
+    };
+    let mut imported_blobs = HashSet::new();
+    let mut spill = spill::Spill::new(repo, &options.submodule_dir);
+    // Where the commits that haven't been spilled yet start
+    let mut spilled_up_to = 0;
+    let mut can_spill = true;
+    for (done, maybe_oid) in revwalk.iter().enumerate() {
+        report_progress(options, "submodule", done, total);
+        match check_memory_usage(options, done) {
+            MemoryUsage::OverLimit => break,
+            MemoryUsage::NearLimit if can_spill && spilled_up_to < done => {
+                can_spill = spill_commit_map(&mut spill,
+                                             old_id_to_new,
+                                             &revwalk[spilled_up_to..done],
+                                             options);
+                spilled_up_to = done;
+            }
+            _ => {}
+        }
+        if done % ANCHOR_INTERVAL == 0 {
+            anchors.anchor(repo);
+        }
+        match *maybe_oid {
+            Ok(oid) => {
+                // Shared commits are already where they belong
+                if options.shared_commits.contains(&oid) {
+                    old_id_to_new.insert(oid, oid);
+                    continue;
+                }
+
+                let commit = repo.find_commit(oid)
+                    .expect(&format!("Couldn't get a commit with ID {}", oid));
+                spill.read_back(old_id_to_new, commit.parent_ids())
+                    .expect(&format!("Couldn't read the commit map back from {}",
+                                     spill.path().display()));
+                let tree = commit.tree()
+                    .expect(&format!("Couldn't obtain the tree of a commit with ID {}", oid));
+                match tree.get_path(Path::new(submodule_dir)) {
+                    Ok(ref entry) if entry.filemode() == 0o160000 => self_referencing.push(oid),
+                    _ => {}
+                }
+                let mut old_index = Index::new()
+                    .expect("Couldn't create an in-memory index for commit");
+                let mut new_index = Index::new().expect("Couldn't create an in-memory index");
+                old_index.read_tree(&tree)
+                    .expect(&format!("Couldn't read the commit {} into index", oid));
+
+                let mut strip_paths = options.strip_paths.clone();
+                if options.honor_export_ignore {
+                    strip_paths.extend(get_export_ignore_patterns(repo, &old_index));
+                }
+
+                // Obtain the new tree, where everything from the old one is moved under
+                // a directory named after the submodule
+                for entry in old_index.iter() {
+                    let mut new_entry = entry;
+
+                    let path = String::from_utf8(new_entry.path)
+                        .expect("Failed to convert a path to str");
+                    if is_stripped(&path, &strip_paths) {
+                        continue;
+                    }
+                    if options.report_vendored && new_entry.mode != 0o160000 {
+                        imported_blobs.insert(new_entry.id);
+                    }
+                    let mut new_path = String::from(submodule_dir);
+                    new_path += "/";
+                    new_path += &path;
+
+                    new_entry.path = new_path.into_bytes();
+                    new_index.add(&new_entry).expect("Couldn't add an entry to the index");
+                }
+                let tree_id = new_index.write_tree_to(&repo)
+                    .expect("Couldn't write the index into a tree");
+                old_id_to_new.insert(tree.id(), tree_id);
+                let tree = repo.find_tree(tree_id)
+                    .expect("Couldn't retrieve the tree we just created");
+
+                let parents = {
+                    let mut p: Vec<Commit> = Vec::new();
+                    for parent_id in commit.parent_ids() {
+                        let new_parent_id = old_id_to_new[&parent_id];
+                        let parent = repo.find_commit(new_parent_id)
+                            .expect("Couldn't find parent commit by its id");
+                        p.push(parent);
+                    }
+                    p
+                };
+
+                // Commits that only touched what --strip-path and --honor-export-ignore leave
+                // out are empty now, and go the same way as in rewrite_repo_history()
+                if options.prune_empty && commit.parent_ids().count() == 1 &&
+                   adds_nothing(repo, tree_id, &parents) {
+                    let was_empty = commit.parent(0)
+                        .expect("Couldn't obtain commit's parent")
+                        .tree_id() == commit.tree_id();
+                    if !was_empty {
+                        old_id_to_new.insert(oid, parents[0].id());
+                        continue;
+                    }
+                }
+
+                let mut parents_refs: Vec<&Commit> = Vec::new();
+                for i in 0..parents.len() {
+                    parents_refs.push(&parents[i]);
+                }
+                let message = commit.message().expect("Couldn't retrieve commit's message");
+                let new_commit_id = recreate_commit(repo,
+                                                    &commit,
+                                                    message,
+                                                    &tree,
+                                                    &parents_refs[..],
+                                                    options);
+
+                old_id_to_new.insert(oid, new_commit_id);
+                anchors.add(repo, new_commit_id);
+            }
+            Err(ref e) => {
+                warn(options,
+                     "submodule history",
+                     format!("Error walking the submodule's history: {:?}", e))
+            }
+        }
+    }
+    anchors.anchor(repo);
+    report_progress(options, "submodule", total, total);
+    // The rest of the run needs all of the map, unless it's about to stop anyway
+    if !has_exceeded_memory_limit(options) {
+        spill.restore(old_id_to_new)
+            .expect(&format!("Couldn't read the commit map back from {}",
+                             spill.path().display()));
+    }
+
+    if !vendored_blobs.is_empty() {
+        let shared = imported_blobs.iter().filter(|id| vendored_blobs.contains(id)).count();
+        let summary = format!("{} of {} file version(s) from the submodule were already in the \
+                               repository, from back when {} was vendored ({}% deduplicated).",
+                              shared,
+                              imported_blobs.len(),
+                              submodule_dir,
+                              shared * 100 / std::cmp::max(imported_blobs.len(), 1));
+        println!("{}", options.colors.summary(&summary));
+    }
+
+    self_referencing
+}
+
+// Whether a commit with the given tree and parents would bring nothing of its own: it has the same
+// tree as its first parent, and the other parents are already in the first one's history
+fn adds_nothing(repo: &Repository, tree_id: Oid, parents: &[Commit]) -> bool {
+    match parents.split_first() {
+        Some((first, rest)) => {
+            first.tree_id() == tree_id &&
+            rest.iter().all(|parent| {
+                parent.id() == first.id() ||
+                repo.graph_descendant_of(first.id(), parent.id()).unwrap_or(false)
+            })
+        }
+        None => false,
+    }
+}
+
+// Blobs that the main repo's own history has under the submodule's directory, i.e. the files of
+// a submodule that used to be vendored as plain files. Objects are stored by their contents, so
+// the submodule's copies of these files, and any directories that are exactly the same as they
+// were back then, don't take up any more space; this is only used to report how much of the
+// import was deduplicated this way, see --report-vendored.
+fn get_vendored_blobs(repo: &Repository, options: &Options) -> HashSet<Oid> {
+    let submodule_path = Path::new(&options.submodule_dir);
+    let mut seen_trees = HashSet::new();
+    let mut blobs = HashSet::new();
+    for oid in get_repo_revwalk(repo, options).filter_map(|maybe_oid| maybe_oid.ok()) {
+        let subtree_id = match repo.find_commit(oid)
+            .and_then(|commit| commit.tree())
+            .and_then(|tree| tree.get_path(submodule_path)) {
+            Ok(ref entry) if entry.filemode() == 0o040000 => entry.id(),
+            _ => continue,
+        };
+        if !seen_trees.insert(subtree_id) {
+            continue;
+        }
+        let subtree = repo.find_tree(subtree_id)
+            .expect(&format!("Couldn't get a tree with ID {}", subtree_id));
+        let mut index = Index::new().expect("Couldn't create an in-memory index");
+        index.read_tree(&subtree)
+            .expect(&format!("Couldn't read the tree {} into index", subtree_id));
+        blobs.extend(index.iter()
+            .filter(|entry| entry.mode != 0o160000)
+            .map(|entry| entry.id));
+    }
+    blobs
+}
+
+// Turns export-ignore attributes from all .gitattributes files in the index into patterns for
+// is_stripped(). Like in Git, patterns without a slash match at any depth.
+fn get_export_ignore_patterns(repo: &Repository, index: &Index) -> Vec<String> {
+    let mut patterns = Vec::new();
+    for entry in index.iter() {
+        let path = String::from_utf8(entry.path).expect("Failed to convert a path to str");
+        let dir = match path.rfind('/') {
+            Some(i) if &path[i + 1..] == ".gitattributes" => String::from(&path[..i + 1]),
+            None if path == ".gitattributes" => String::new(),
+            _ => continue,
+        };
+        let blob = repo.find_blob(entry.id)
+            .expect(&format!("Couldn't get the contents of {}", path));
+        let contents = String::from_utf8_lossy(blob.content());
+
+        for line in contents.lines() {
+            let mut words = line.split_whitespace();
+            let pattern = match words.next() {
+                Some(pattern) if !pattern.starts_with('#') => pattern.trim_right_matches('/'),
+                _ => continue,
+            };
+            if !words.any(|attribute| attribute == "export-ignore") {
+                continue;
+            }
+            if pattern.contains('/') {
+                patterns.push(dir.clone() + pattern.trim_left_matches('/'));
+            } else {
+                patterns.push(dir.clone() + pattern);
+                patterns.push(dir.clone() + "**/" + pattern);
+            }
+        }
+    }
+    patterns
+}
+
+// A path is stripped if it, or any of the directories it's in, matches one of the patterns
+fn is_stripped(path: &str, patterns: &[String]) -> bool {
+    let path = path.as_bytes();
+    let prefixes = path.iter()
+        .enumerate()
+        .filter(|&(_, c)| *c == b'/')
+        .map(|(i, _)| &path[..i])
+        .chain(Some(path));
+    for prefix in prefixes {
+        if patterns.iter().any(|pattern| glob_matches(pattern.as_bytes(), prefix)) {
+            return true;
+        }
+    }
+    false
+}
+
+// `*` and `?` don't match slashes, `**` matches anything
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(&b'*') if pattern.get(1) == Some(&b'*') => {
+            (0..text.len() + 1).any(|i| glob_matches(&pattern[2..], &text[i..]))
+        }
+        Some(&b'*') => {
+            let segment_end = text.iter().position(|c| *c == b'/').unwrap_or(text.len());
+            (0..segment_end + 1).any(|i| glob_matches(&pattern[1..], &text[i..]))
+        }
+        Some(&b'?') => {
+            text.first().map_or(false, |c| *c != b'/') && glob_matches(&pattern[1..], &text[1..])
+        }
+        Some(c) => text.first() == Some(c) && glob_matches(&pattern[1..], &text[1..]),
+    }
+}
+
+fn find_dangling_references_to_submodule(repo: &Repository,
+                                         old_id_to_new: &HashMap<Oid, Oid>,
+                                         options: &Options,
+                                         scan: &mut HistoryScan)
+                                         -> Option<bool> {
+    let submodule_path = Path::new(&options.submodule_dir);
+
+    let known_submodule_commits: HashSet<&Oid> = old_id_to_new.keys().collect();
+    // Maps each dangling submodule commit to the main repo's commits that reference it, oldest first
+    let mut dangling_references: HashMap<Oid, Vec<Oid>> = HashMap::new();
+    // Dangling references that --default-mapping takes care of
+    let mut defaulted_references = HashSet::new();
+    let cutoff = GitlinkCutoff::from_options(repo, options)
+        .map(|c| c.expect("Couldn't figure out --preserve-gitlinks-before"));
+
+    // A cached walk can be replayed as is, see scan_cache.rs
+    let mut is_cached = false;
+    if options.scan_cache {
+        is_cached = scan_cache::load(repo, options, scan);
+    }
+    let revwalk: Vec<Result<Oid, git2::Error>> = if is_cached {
+        scan.commits.drain(..).map(Ok).collect()
+    } else {
+        get_repo_revwalk(&repo, options).collect()
+    };
+    let mut is_complete = true;
+
+    for maybe_oid in revwalk {
+        match maybe_oid {
+            Ok(oid) => {
+                scan.commits.push(oid);
+                let commit = repo.find_commit(oid)
+                    .expect(&format!("Couldn't get a commit with ID {}", oid));
+
+                // Preserved commits keep their gitlinks, dangling or not
+                if cutoff.as_ref().map_or(false, |c| c.preserves(&commit)) {
+                    continue;
+                }
+
+                // A commit without the submodule is totally fine, we just move on
+                let submodule_commit_id = match get_submodule_entry(repo,
+                                                                    oid,
+                                                                    submodule_path,
+                                                                    &mut scan.submodule_entries) {
+                    // We're only interested in gitlinks
+                    Some((id, 0o160000)) => id,
+                    _ => continue,
+                };
+
+                // **INVARIANT**: if we got this far, current commit contains a submodule and
+                // should be rewritten
+
+                // Overridden commits don't reference anything but what the user told us to
+                if options.overrides.contains_key(&oid) {
+                    continue;
+                }
+
+                if !known_submodule_commits.contains(&submodule_commit_id) &&
+                   !options.mappings.contains_key(&submodule_commit_id) {
+                    if options.default_mapping.is_none() {
+                        dangling_references.entry(submodule_commit_id)
+                            .or_insert_with(Vec::new)
+                            .push(oid);
+                    } else {
+                        defaulted_references.insert(submodule_commit_id);
+                    }
+                }
+            }
+            Err(e) => {
+                is_complete = false;
+                warn(options,
+                     "main history",
+                     format!("Error walking the main history: {:?}", e))
+            }
+        }
+    }
+    if options.scan_cache && is_complete {
+        scan_cache::save(repo, options, scan);
+    }
+
+    // A lot of these usually means a wrong submodule URL, or a missing fetch
+    if !defaulted_references.is_empty() {
+        eprintln!("{} submodule commit(s) couldn't be found in the submodule's history, and will \
+                   be replaced according to --default-mapping:\n",
+                  defaulted_references.len());
+        for id in &defaulted_references {
+            eprintln!("{}", options.colors.dangling(&id.to_string()));
+        }
+        eprintln!("");
+
+        if let Some(max) = options.max_default_mappings {
+            if defaulted_references.len() > max {
+                eprintln!("That's more than --max-default-mappings allows ({}), aborting! Please \
+                           check that the submodule's history was fetched correctly.",
+                          max);
+                return Some(true);
+            }
+        }
+    }
+
+    if dangling_references.is_empty() {
+        None
+    } else {
+        eprintln!("The repository references the following submodule commits, but they couldn't \
+                   be found in the submodule's history:\n");
+        // Commits that the local checkout still has were most likely rebased or amended before
+        // they got anywhere; the rest were probably force-pushed away and garbage-collected
+        let submodule_repo = repo.find_submodule(&options.submodule_dir)
+            .and_then(|submodule| submodule.open())
+            .ok();
+        let branches = get_branch_to_id_map(repo, options);
+        let mut found_locally = 0;
+        for (id, referencing_commits) in &dangling_references {
+            if submodule_repo.as_ref().map_or(false, |r| r.find_commit(*id).is_ok()) {
+                eprintln!("{}  (only in your checkout of the submodule)",
+                          options.colors.dangling(&id.to_string()));
+                found_locally += 1;
+            } else {
+                eprintln!("{}  (gone)", options.colors.dangling(&id.to_string()));
+            }
+            print_referencing_commits(repo, referencing_commits, &branches, options);
+        }
+
+        if found_locally > 0 {
+            eprintln!("\nTo keep the commits that are only in your checkout, make sure each of \
+                       them is on a branch there (e.g. `git -C {0} branch rescued <commit id>`) \
+                       and pass --url ./{0} to take submodule's history from all of the \
+                       checkout's branches.",
+                      options.submodule_dir);
+        }
+        if found_locally < dangling_references.len() {
+            eprintln!("\nCommits that are gone were probably force-pushed away upstream and then \
+                       garbage-collected. If a mirror or a fork still has them, fetch from it \
+                       with --url or --external-repo.");
+        }
+        eprintln!("\nYou can also use --mapping and --default-mapping options to make \
+                   git-submerge replace these commits with some other, still existing, commits.");
+
+        Some(true)
+    }
+}
+
+// Helps to figure out where a dangling reference came from: shows the first few commits that
+// contain it, and the branches that contain any of them
+fn print_referencing_commits(repo: &Repository,
+                             referencing_commits: &[Oid],
+                             branches: &HashMap<String, Oid>,
+                             options: &Options) {
+    const SHOWN_COMMITS: usize = 3;
+
+    for id in referencing_commits.iter().take(SHOWN_COMMITS) {
+        let mut commit = repo.find_commit(*id)
+            .expect(&format!("Couldn't get a commit with ID {}", id));
+        let date = format_date(commit.committer().when().seconds());
+        eprintln!("    referenced by {} ({}) {}",
+                  abbreviate(repo, *id, options.abbrev),
+                  date,
+                  commit.summary().unwrap_or(""));
+    }
+    if referencing_commits.len() > SHOWN_COMMITS {
+        eprintln!("    ...and {} more commit(s)", referencing_commits.len() - SHOWN_COMMITS);
+    }
+
+    let mut containing_branches: Vec<&str> = branches.iter()
+        .filter(|&(_, tip)| {
+            referencing_commits.iter().any(|id| {
+                tip == id || repo.graph_descendant_of(*tip, *id).unwrap_or(false)
+            })
+        })
+        .map(|(name, _)| name.as_str())
+        .collect();
+    containing_branches.sort();
+    if !containing_branches.is_empty() {
+        eprintln!("    on branch(es): {}", containing_branches.join(", "));
+    }
+}
+
+// Commits that have a directory at the submodule's path, yet build on commits that have
+// the submodule there (see --existing-content). These are the ones that rewrite_repo_history()
+// would have to rewrite, too, because their parents change.
+fn find_commits_with_existing_content(repo: &Repository, scan: &HistoryScan) -> Vec<Oid> {
+    let mut rewritten = HashSet::new();
+    let mut conflicting = Vec::new();
+    for oid in &scan.commits {
+        let commit = repo.find_commit(*oid)
+            .expect(&format!("Couldn't get a commit with ID {}", oid));
+        let has_rewritten_parent = commit.parent_ids().any(|id| rewritten.contains(&id));
+        // Preserved commits aren't in submodule_entries, and aren't rewritten
+        match scan.submodule_entries.get(oid).cloned().and_then(|entry| entry) {
+            Some((_, 0o160000)) => {
+                rewritten.insert(*oid);
+            }
+            Some((_, 0o040000)) if has_rewritten_parent => {
+                rewritten.insert(*oid);
+                conflicting.push(*oid);
+            }
+            Some(_) if has_rewritten_parent => {
+                rewritten.insert(*oid);
+            }
+            _ => {}
+        }
+    }
+    conflicting
+}
+
+// Parents first, and with --date-order, the oldest commits among the ones that can go next
+fn get_revwalk_sorting(options: &Options) -> git2::Sort {
+    if options.date_order {
+        git2::SORT_REVERSE | git2::SORT_TOPOLOGICAL | git2::SORT_TIME
+    } else {
+        git2::SORT_REVERSE | git2::SORT_TOPOLOGICAL
+    }
+}
+
+fn get_repo_revwalk<'repo>(repo: &'repo Repository, options: &Options) -> Revwalk<'repo> {
+    let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
+    revwalk.set_sorting(get_revwalk_sorting(options));
+
+    let range = options.rev_range.as_ref().map(|range| {
+        resolve_rev_range(repo, range).expect("Couldn't resolve the range of commits")
+    });
+    if let Some((base, _)) = range {
+        revwalk.hide(base).expect("Couldn't hide the base of the range from RevWalk");
+    }
+
+    if let Some((_, Some(tip))) = range {
+        revwalk.push(tip).expect("Couldn't add the tip of the range to RevWalk");
+    } else {
+        if get_excluded_head_branch(repo, options).is_none() {
+            let head_id = get_head_id(repo, options);
+            revwalk.push(head_id).expect("Couldn't add repo's HEAD to RevWalk");
+        }
+
+        for (name, id) in get_branch_to_id_map(repo, options) {
+            if is_branch_excluded(&name, options) {
+                continue;
+            }
+            revwalk.push(id)
+                .expect(&format!("Couldn't push branch `{}' to RevWalk", name));
+        }
+        for (name, id) in get_included_refs(repo, options) {
+            revwalk.push(id).expect(&format!("Couldn't push `{}' to RevWalk", name));
+        }
+    }
+
+    revwalk
+}
+
+// Refs matching --include-refs. Only the ones that point at commits directly are included;
+// moving annotated tags would require rewriting the tag objects, too.
+fn get_included_refs(repo: &Repository, options: &Options) -> Vec<(String, Oid)> {
+    let mut result = Vec::new();
+    for glob in &options.include_refs {
+        let references = repo.references_glob(glob)
+            .expect("Couldn't obtain an iterator over references");
+        for reference in references.filter_map(|r| r.ok()) {
+            let name = String::from(reference.name().expect("Ref name is not valid UTF-8"));
+            match reference.target() {
+                Some(id) if repo.find_commit(id).is_ok() => result.push((name, id)),
+                _ => eprintln!("Skipping {}, as it doesn't point at a commit", name),
+            }
+        }
+    }
+    result
+}
+
+// Backs the --include-refs refs up under refs/submerge/backup/<submodule dir>/, like branches, and
+// moves them to the rewritten history
+fn move_included_refs(repo: &Repository, old_id_to_new: &HashMap<Oid, Oid>, options: &Options) {
+    for (name, id) in get_included_refs(repo, options) {
+        let new_id = match old_id_to_new.get(&id) {
+            Some(new_id) => *new_id,
+            None => continue,
+        };
+        if let Some(ref namespace) = options.output_namespace {
+            repo.reference(&format!("{}{}", namespace, name.trim_left_matches("refs/")),
+                           new_id,
+                           true,
+                           "git-submerge: creating a ref for rewritten history")
+                .expect(&format!("Couldn't create a rewritten counterpart of {}", name));
+            continue;
+        }
+        repo.reference(&format!("refs/submerge/backup/{}/{}",
+                                options.submodule_dir,
+                                name.trim_left_matches("refs/")),
+                       id,
+                       true,
+                       "git-submerge: backing up the ref")
+            .expect("Couldn't back up a ref");
+        repo.reference(&name, new_id, true, "git-submerge: moving to rewritten history")
+            .expect(&format!("Couldn't move {} to rewritten history", name));
+    }
+}
+
+// Moves what a run with --output-refs-namespace left in the namespace to where it belongs:
+// <namespace>heads/* become branches, <namespace>tags/* become tags, and the rest goes back under
+// refs/. The refs that get replaced are backed up just like the merge itself would've done it, so
+// `git submerge verify' works afterwards.
+//
+// libgit2 has no ref transactions, so each ref is only moved if it's still where it was when we
+// started, and if one of them can't be moved, the ones moved before it are put back.
+fn promote_results(repo: &Repository, options: &Options) -> i32 {
+    let namespace = options.output_namespace
+        .as_ref()
+        .expect("`promote' always has a namespace to promote from");
+    let submodule_dir = &options.submodule_dir;
+
+    let dirty_paths = if options.no_update_worktree {
+        Vec::new()
+    } else {
+        get_dirty_paths(repo, submodule_dir)
+    };
+    if !dirty_paths.is_empty() {
+        eprintln!("The working directory is dirty, aborting! These paths have changes:");
+        for &(ref path, status) in &dirty_paths {
+            eprintln!("    {} {}", get_status_letters(status), path);
+        }
+        eprintln!("Please commit or stash them (e.g. with `git stash') and try again.");
+        return E_DIRTY_WORKDIR;
+    }
+
+    let index_lock = if options.lock {
+        match IndexLock::acquire(repo) {
+            Ok(lock) => Some(lock),
+            Err(_) => return E_CONCURRENT_ACCESS,
+        }
+    } else {
+        None
+    };
+
+    // Source ref, the ref it's promoted to, the ID to put there, and the ID that's there now
+    let mut promotions: Vec<(String, String, Oid, Option<Oid>)> = Vec::new();
+    let references = repo.references_glob(&format!("{}*", namespace))
+        .expect("Couldn't obtain an iterator over references");
+    for reference in references.filter_map(|r| r.ok()) {
+        let name = String::from(reference.name().expect("Ref name is not valid UTF-8"));
+        let id = match reference.target() {
+            Some(id) => id,
+            None => continue,
+        };
+        let target = format!("refs/{}", &name[namespace.len()..]);
+        let current_id = repo.refname_to_id(&target).ok();
+        promotions.push((name, target, id, current_id));
+    }
+    if promotions.is_empty() {
+        eprintln!("There's nothing to promote in {}. Was git-submerge run with \
+                   --output-refs-namespace={}?",
+                  namespace,
+                  namespace);
+        return E_NOTHING_TO_PROMOTE;
+    }
+    let moves: Vec<(String, Oid, Oid)> = promotions.iter()
+        .filter_map(|&(_, ref target, id, current_id)| {
+            current_id.map(|current_id| (target.clone(), current_id, id))
+        })
+        .collect();
+    if !may_move_protected_refs(repo, &moves, options) {
+        return E_PROTECTED_REFS;
+    }
+
+    let mut promoted = 0;
+    for &(_, ref target, id, current_id) in &promotions {
+        let result = match current_id {
+            Some(current_id) => {
+                repo.reference(&format!("refs/submerge/backup/{}/{}",
+                                        submodule_dir,
+                                        target.trim_left_matches("refs/")),
+                               current_id,
+                               true,
+                               "git-submerge: backing up the ref")
+                    .expect("Couldn't back up a ref");
+                repo.reference_matching(target,
+                                        id,
+                                        true,
+                                        current_id,
+                                        "git-submerge: promoting rewritten history")
+            }
+            None => repo.reference(target, id, false, "git-submerge: promoting rewritten history"),
+        };
+        if let Err(e) = result {
+            eprintln!("Couldn't move {}: {}\nPutting back the refs that were already moved.",
+                      target,
+                      e.message());
+            for &(_, ref target, _, current_id) in &promotions[..promoted] {
+                match current_id {
+                    Some(current_id) => {
+                        repo.reference(target, current_id, true, "git-submerge: rolling back")
+                            .expect(&format!("Couldn't put {} back", target));
+                    }
+                    None => {
+                        repo.find_reference(target)
+                            .and_then(|mut reference| reference.delete())
+                            .expect(&format!("Couldn't remove {}", target));
+                    }
+                }
+            }
+            return E_CONCURRENT_ACCESS;
+        }
+        promoted += 1;
+    }
+    for &(ref name, _, _, _) in &promotions {
+        repo.find_reference(name)
+            .and_then(|mut reference| reference.delete())
+            .expect(&format!("Couldn't remove {}", name));
+    }
+    println!("Promoted {} ref(s) from {}.", promoted, namespace);
+
+    // The working directory only has to change if HEAD is on one of the branches we just moved
+    let head_branch = repo.find_reference("HEAD")
+        .ok()
+        .and_then(|head| head.symbolic_target().map(String::from));
+    let is_head_promoted = head_branch.map_or(false, |branch| {
+        promotions.iter().any(|&(_, ref target, _, _)| *target == branch)
+    });
+    if is_head_promoted && options.no_update_worktree {
+        print_worktree_update_instructions(submodule_dir);
+        return E_SUCCESS;
+    }
+    if !is_head_promoted {
+        eprintln!("HEAD isn't on any of the promoted branches, so the working directory was left \
+                   as it is.");
+        return E_SUCCESS;
+    }
+    // The submodule was never checked out, or its repository was already moved out of the way
+    if !Path::new(submodule_dir).join(".git").exists() {
+        eprintln!("{0}/.git doesn't exist, so the index and the working directory were left as \
+                   they were. To bring them up to date with the promoted HEAD, run:\n    rm -f \
+                   .gitmodules && git reset -q && git checkout -- {0}",
+                  submodule_dir);
+        return E_SUCCESS;
+    }
+
+    // Same as at the end of a merge; see real_main()
+    let old_id_to_new = HashMap::new();
+    let is_consistent = is_submodule_dir_consistent(repo, submodule_dir, &old_id_to_new);
+    remove_dotgit_from_submodule(repo, options);
+    drop(index_lock);
+    update_index(repo, &old_id_to_new);
+    let head_tree = repo.head()
+        .and_then(|head| head.peel(git2::ObjectType::Commit))
+        .and_then(|commit| commit.peel(git2::ObjectType::Tree))
+        .expect("Couldn't obtain the tree of HEAD");
+    let head_tree = head_tree.as_tree().expect("HEAD's tree is not a tree");
+    if Path::new(".gitmodules").exists() && head_tree.get_path(Path::new(".gitmodules")).is_err() {
+        remove_gitmodules();
+    }
+    // --merge-mailmap, --codeowners and --hoist-gitignore might've changed these
+    let mut checkout = git2::build::CheckoutBuilder::new();
+    checkout.force().path(".mailmap").path(".gitignore");
+    for path in CODEOWNERS_PATHS {
+        checkout.path(path);
+    }
+    repo.checkout_head(Some(&mut checkout))
+        .expect("Couldn't check out .mailmap, .gitignore and CODEOWNERS");
+
+    if !is_consistent {
+        return E_WORKDIR_MISMATCH;
+    }
+
+    E_SUCCESS
+}
+
+fn is_branch_excluded(name: &str, options: &Options) -> bool {
+    options.exclude_branches.iter().any(|glob| glob_matches(glob.as_bytes(), name.as_bytes()))
+}
+
+// Full ref names, matched against --protected-ref and submerge.protectedRef
+fn is_ref_protected(name: &str, options: &Options) -> bool {
+    options.protected_refs.iter().any(|glob| glob_matches(glob.as_bytes(), name.as_bytes()))
+}
+
+// Takes (ref name, current ID, new ID) of the refs that are about to be moved. A protected ref
+// can still be fast-forwarded, e.g. by --tip-only, but moving it anywhere else needs
+// --rewrite-protected. Returns false, after listing the offending refs, if that's missing.
+fn may_move_protected_refs(repo: &Repository,
+                           moves: &[(String, Oid, Oid)],
+                           options: &Options)
+                           -> bool {
+    let rewritten: Vec<&str> = moves.iter()
+        .filter(|&&(ref name, old_id, new_id)| {
+            is_ref_protected(name, options) && old_id != new_id &&
+            !repo.graph_descendant_of(new_id, old_id).unwrap_or(false)
+        })
+        .map(|&(ref name, _, _)| &name[..])
+        .collect();
+    if rewritten.is_empty() {
+        return true;
+    }
+    if options.rewrite_protected {
+        eprintln!("Rewriting {} protected ref(s), as --rewrite-protected allows.",
+                  rewritten.len());
+        return true;
+    }
+
+    eprintln!("These refs are protected, and would be moved to history that doesn't contain \
+               their current tips:");
+    for name in rewritten {
+        eprintln!("    {}", name);
+    }
+    eprintln!("Remotes that forbid force-pushes wouldn't accept them. No refs were modified; use \
+               --exclude-branches to leave them be, or --rewrite-protected if that's what you \
+               want.");
+    false
+}
+
+// The name of the checked-out branch (or the one given to --head), if --exclude-branches keeps it
+// out of the rewrite
+fn get_excluded_head_branch(repo: &Repository, options: &Options) -> Option<String> {
+    let head = repo.find_reference(get_head_ref_name(options))
+        .and_then(|reference| reference.resolve())
+        .expect("Couldn't obtain repo's HEAD");
+    if !head.is_branch() {
+        return None;
+    }
+    head.shorthand()
+        .map(String::from)
+        .and_then(|name| if is_branch_excluded(&name, options) {
+            Some(name)
+        } else {
+            None
+        })
+}
+
+// The ref that anchors the main history: the one given to --head, or HEAD itself
+fn get_head_ref_name(options: &Options) -> &str {
+    options.head.as_ref().map_or("HEAD", |name| &name[..])
+}
+
+fn get_head_id(repo: &Repository, options: &Options) -> Oid {
+    repo.refname_to_id(get_head_ref_name(options))
+        .expect("Couldn't resolve repo's HEAD to a commit ID")
+}
+
+// Turns the value of --head into the full name of a ref, or None if it's HEAD after all
+fn resolve_head_ref(repo: &Repository, name: &str) -> Result<Option<String>, ()> {
+    let (_, reference) = repo.revparse_ext(name).map_err(|_| ())?;
+    let reference = reference.ok_or(())?;
+    let full_name = reference.name().map(String::from).ok_or(())?;
+    if full_name == "HEAD" {
+        return Ok(None);
+    }
+    // Annotated tags would have to be peeled, and can't be moved by --tip-only anyway
+    let is_commit = reference.target().map_or(false, |id| repo.find_commit(id).is_ok());
+    if !is_commit {
+        return Err(());
+    }
+    Ok(Some(full_name))
+}
+
+// Whether the working directory has the given ref checked out
+fn is_checked_out(repo: &Repository, full_name: &str) -> bool {
+    !repo.is_bare() && repo.head().ok().map_or(false, |head| head.name() == Some(full_name))
+}
+
+// Turns "base..tip" into the IDs of the corresponding commits. The tip is optional.
+fn resolve_rev_range(repo: &Repository, range: &str) -> Result<(Oid, Option<Oid>), ()> {
+    let mut parts = range.splitn(2, "..");
+    let base = parts.next().unwrap_or("");
+    let tip = match parts.next() {
+        Some(tip) => tip,
+        None => return Err(()),
+    };
+    if tip.starts_with('.') {
+        // "base...tip" means something else entirely
+        return Err(());
+    }
+
+    let base_id = repo.revparse_single(base)
+        .and_then(|o| o.peel(git2::ObjectType::Commit))
+        .map(|o| o.id())
+        .map_err(|_| ())?;
+    let tip_id = if tip.is_empty() {
+        None
+    } else {
+        Some(repo.revparse_single(tip)
+            .and_then(|o| o.peel(git2::ObjectType::Commit))
+            .map(|o| o.id())
+            .map_err(|_| ())?)
+    };
+
+    Ok((base_id, tip_id))
+}
+
+// Where HEAD and the branches point to
+fn get_refs_snapshot(repo: &Repository, options: &Options) -> (Option<Oid>, HashMap<String, Oid>) {
+    let head_id = repo.head().ok().and_then(|head| head.target());
+    (head_id, get_branch_to_id_map(repo, options))
+}
+
+fn get_branch_to_id_map(repo: &Repository, options: &Options) -> HashMap<String, Oid> {
+    let mut result = HashMap::new();
+
+    let branches = repo.branches(Some(git2::BranchType::Local))
+        .expect("Couldn't obtain an iterator over local branches");
+    for maybe_branch in branches {
+        match maybe_branch {
+            Ok((branch, _)) => {
+                let name = branch.name()
+                    .expect("Couldn't get branch' name")
+                    .expect("Branch name is not valid UTF-8");
+                let id = branch.get()
+                    .peel(git2::ObjectType::Commit)
+                    .expect("Couldn't convert branch into a Commit")
+                    .id();
+                result.insert(String::from(name), id);
+            }
+            Err(e) => warn(options, "branches", format!("Error walking the branches: {:?}", e)),
+        }
+    }
+
+    result
+}
+
+// A commit can only be rewritten after its parents are. The revwalk's topological order gives us
+// that, but histories with several roots (e.g. after merging an unrelated repository) are where
+// it's easiest to get wrong, so we don't take it on faith: any commit that comes before one of its
+// parents is moved after them. Commits that are already in order keep their places.
+fn order_parents_first(repo: &Repository, commits: Vec<Oid>) -> Vec<Oid> {
+    let walked: HashSet<Oid> = commits.iter().cloned().collect();
+    let mut placed = HashSet::with_capacity(commits.len());
+    let mut ordered = Vec::with_capacity(commits.len());
+    for id in commits {
+        // Depth-first, but with a stack of our own, so that long histories don't overflow it
+        let mut stack = vec![id];
+        while let Some(&top) = stack.last() {
+            if placed.contains(&top) {
+                stack.pop();
+                continue;
+            }
+            let commit = repo.find_commit(top)
+                .expect(&format!("Couldn't get a commit with ID {}", top));
+            let pending: Vec<Oid> = commit.parent_ids()
+                .filter(|parent| walked.contains(parent) && !placed.contains(parent))
+                .collect();
+            if pending.is_empty() {
+                stack.pop();
+                placed.insert(top);
+                ordered.push(top);
+            } else {
+                stack.extend(pending);
+            }
+        }
+    }
+    ordered
+}
+
+fn rewrite_repo_history(repo: &Repository,
+                        old_id_to_new: &mut HashMap<Oid, Oid>,
+                        options: &Options,
+                        scan: HistoryScan)
+                        -> Vec<RewrittenCommit> {
+    let mut rewritten = Vec::new();
+    let submodule_path = Path::new(&options.submodule_dir);
+    let mut downgrades = 0;
+    let cutoff = GitlinkCutoff::from_options(repo, options)
+        .map(|c| c.expect("Couldn't figure out --preserve-gitlinks-before"));
+    let mut preserved = HashSet::new();
+    let mut new_tree_ids = HashSet::new();
+    let mut reused_entries = 0;
+    let mut symlinked = Vec::new();
+    // Commit ID -> the original submodule commit that ended up in its rewritten tree
+    let mut submodule_states: HashMap<Oid, Oid> = HashMap::new();
+    // Most commits are parents of some other commit, so this saves us a tree lookup per parent
+    let mut submodule_entries = scan.submodule_entries;
+
+    let revwalk: Vec<Result<Oid, git2::Error>> =
+        order_parents_first(repo, scan.commits).into_iter().map(Ok).collect();
+    let total = revwalk.len();
+    let mut anchors = InProgressAnchors::new("repo");
+    let mut spill = spill::Spill::new(repo, &options.submodule_dir);
+    // Where the commits that haven't been spilled yet start
+    let mut spilled_up_to = 0;
+    let mut can_spill = true;
+    for (done, maybe_oid) in revwalk.iter().enumerate() {
+        report_progress(options, "repo", done, total);
+        match check_memory_usage(options, done) {
+            MemoryUsage::OverLimit => break,
+            MemoryUsage::NearLimit if can_spill && spilled_up_to < done => {
+                can_spill = spill_commit_map(&mut spill,
+                                             old_id_to_new,
+                                             &revwalk[spilled_up_to..done],
+                                             options);
+                spilled_up_to = done;
+                // It only saves us some tree lookups
+                submodule_entries = HashMap::new();
+            }
+            _ => {}
+        }
+        if done % ANCHOR_INTERVAL == 0 {
+            anchors.anchor(repo);
+        }
+        match *maybe_oid {
+            Ok(oid) => {
+                let commit = repo.find_commit(oid)
+                    .expect(&format!("Couldn't get a commit with ID {}", oid));
+                spill.read_back(old_id_to_new, commit.parent_ids())
+                    .expect(&format!("Couldn't read the commit map back from {}",
+                                     spill.path().display()));
+                let tree = commit.tree()
+                    .expect(&format!("Couldn't obtain the tree of a commit with ID {}", oid));
+
+                if cutoff.as_ref().map_or(false, |c| c.preserves(&commit)) {
+                    let new_commit_id = reparent_commit(repo, &commit, old_id_to_new, options);
+                    old_id_to_new.insert(oid, new_commit_id);
+                    anchors.add(repo, new_commit_id);
+                    preserved.insert(oid);
+                    rewritten.push(RewrittenCommit {
+                        old_id: oid,
+                        new_id: new_commit_id,
+                        submodule_commit: None,
+                    });
+                    continue;
+                }
+
+                let gitlink_id = match get_submodule_entry(repo,
+                                                           oid,
+                                                           submodule_path,
+                                                           &mut submodule_entries) {
+                    // We're only interested in gitlinks
+                    Some((id, 0o160000)) => id,
+                    // A symlink in place of the submodule has nothing to merge, and we shouldn't
+                    // turn it into a directory either. The tree stays as it is, only the parents
+                    // are rewritten.
+                    Some((_, 0o120000)) => {
+                        let new_commit_id = reparent_commit(repo, &commit, old_id_to_new, options);
+                        old_id_to_new.insert(oid, new_commit_id);
+                        anchors.add(repo, new_commit_id);
+                        symlinked.push(oid);
+                        rewritten.push(RewrittenCommit {
+                            old_id: oid,
+                            new_id: new_commit_id,
+                            submodule_commit: None,
+                        });
+                        continue;
+                    }
+                    // The directory has files of its own, but the parents change, so the commit
+                    // has to be rewritten one way or another
+                    Some((_, 0o040000)) if commit.parent_ids().any(|id| {
+                        old_id_to_new.get(&id).map_or(false, |new_id| *new_id != id)
+                    }) => {
+                        let state = match options.existing_content {
+                            ExistingContent::PreferSubmodule => {
+                                commit.parent_ids()
+                                    .filter_map(|id| submodule_states.get(&id).cloned())
+                                    .next()
+                            }
+                            _ => None,
+                        };
+                        let new_commit_id = match state {
+                            Some(state) => {
+                                replace_existing_content(repo,
+                                                         &commit,
+                                                         state,
+                                                         old_id_to_new,
+                                                         options)
+                            }
+                            None => reparent_commit(repo, &commit, old_id_to_new, options),
+                        };
+                        old_id_to_new.insert(oid, new_commit_id);
+                        anchors.add(repo, new_commit_id);
+                        if let Some(state) = state {
+                            submodule_states.insert(oid, state);
+                        }
+                        rewritten.push(RewrittenCommit {
+                            old_id: oid,
+                            new_id: new_commit_id,
+                            submodule_commit: state,
+                        });
+                        continue;
+                    }
+                    Some(_) => continue,
+                    None => {
+                        // It's okay. The tree lacks the subtree corresponding to the submodule.
+                        // In other words, the commit doesn't include the submodule. That's
+                        // totally fine. Let's map it into itself and move on.
+                        old_id_to_new.insert(oid, oid);
+                        continue;
+                    }
+                };
+
+                // **INVARIANT**: if we got this far, current commit contains a submodule and
+                // should be rewritten
+
+                let submodule_commit_id = match options.overrides.get(&oid) {
+                    Some(id) => *id,
+                    None => gitlink_id,
+                };
+                let original_submodule_commit_id =
+                    resolve_submodule_commit(submodule_commit_id,
+                                             old_id_to_new,
+                                             &options.mappings,
+                                             &options.default_mapping)
+                        .expect(&format!("Found a commit that isn't in mappings, \
+                                          and default-mapping is empty: {}",
+                                         submodule_commit_id));
+                let new_submodule_commit_id = if original_submodule_commit_id.is_zero() {
+                    original_submodule_commit_id
+                } else {
+                    old_id_to_new[&original_submodule_commit_id]
+                };
+                // Commits mapped to "empty" simply don't have anything at the submodule's path
+                let submodule_commit = if new_submodule_commit_id.is_zero() {
+                    None
+                } else {
+                    Some(repo.find_commit(new_submodule_commit_id)
+                        .expect(&format!("Couldn't obtain submodule's commit with ID {}",
+                                         new_submodule_commit_id)))
+                };
+                let is_shared = options.shared_commits.contains(&new_submodule_commit_id);
+                let subtree_id = submodule_commit.as_ref().map(|c| {
+                    get_imported_subtree_id(c, options)
+                        .expect("Couldn't obtain submodule's subtree ID")
+                });
+
+                let new_tree =
+                    replace_submodule_dir(&repo, &tree, &submodule_path, subtree_id, true);
+                count_tree_reuse(repo,
+                                 &new_tree,
+                                 submodule_path,
+                                 &mut new_tree_ids,
+                                 &mut reused_entries);
+
+                // In commits that used to update the submodule, add a parent pointing to
+                // appropriate commit in new submodule history
+                let mut parent_subtree_ids = HashSet::new();
+                for parent_id in commit.parent_ids() {
+                    let entry = get_submodule_entry(repo,
+                                                    parent_id,
+                                                    submodule_path,
+                                                    &mut submodule_entries);
+                    if let Some((id, 0o160000)) = entry {
+                        // Parents see the submodule the way their own rewrites did
+                        let state = options.overrides.get(&parent_id).cloned().unwrap_or(id);
+                        parent_subtree_ids.insert(state);
+                    }
+                }
+
+                // Here's a few pictures to help you understand how we figure out if current commit
+                // updated the submodule. If we draw a DAG and name submodule states, the following
+                // situations will mean that the submodule wasn't updated:
+                //
+                //     o--o--o--A--
+                //                 `,-A
+                //      o--o--o--B-
+                //
+                // or
+                //
+                //     o--o--o--A--
+                //                 `,-B
+                //      o--o--o--B-
+                //
+                // And in the following graphs the submodule was updated:
+                //
+                //     o--o--o--A--
+                //                 `,-C
+                //      o--o--o--B-
+                //
+                // or
+                //
+                //     o--o--o--o--A--B
+                //
+                // Put into words, the rule will be "the submodule state in current commit is
+                // different from states in all its parents". Or, more formally, the current state
+                // doesn't belong to the set of states in parents.
+                //
+                // With --rev-range, commits right after the base of the range are special: their
+                // parents keep the submodule as it was, so we have to connect the submodule
+                // history to them regardless.
+                // The same goes for the first commits after --preserve-gitlinks-before.
+                let is_range_boundary =
+                    commit.parent_ids().any(|id| !old_id_to_new.contains_key(&id));
+                let is_cutoff_boundary = commit.parent_ids().any(|id| preserved.contains(&id));
+                let submodule_updated: bool = is_range_boundary || is_cutoff_boundary ||
+                                              !parent_subtree_ids.contains(&submodule_commit_id);
+
+                // If the submodule was moved back to a commit that some parent already has in its
+                // history, a merge would claim to bring in changes that are already there.
+                let mut downgraded_from = None;
+                if submodule_updated && submodule_commit.is_some() {
+                    for parent_subtree_id in &parent_subtree_ids {
+                        let parent_state = map_submodule_commit(*parent_subtree_id,
+                                                                old_id_to_new,
+                                                                &options.mappings,
+                                                                &options.default_mapping);
+                        if let Some(parent_state) = parent_state {
+                            if repo.graph_descendant_of(parent_state, new_submodule_commit_id)
+                                .unwrap_or(false) {
+                                downgraded_from = Some(parent_state);
+                                break;
+                            }
+                        }
+                    }
+                }
+                if downgraded_from.is_some() {
+                    downgrades += 1;
+                }
+                let skip_submodule_parent = options.skip_downgrade_parents &&
+                                            downgraded_from.is_some();
+
+                // Rewrite the parents if the submodule was updated
+                let parents = {
+                    let mut p: Vec<Commit> = Vec::new();
+                    for parent_id in commit.parent_ids() {
+                        // Parents outside of --rev-range stay as they are
+                        let actual_parent_id = match old_id_to_new.get(&parent_id) {
+                            Some(id) => *id,
+                            None => parent_id,
+                        };
+                        let parent = repo.find_commit(actual_parent_id)
+                            .expect("Couldn't find parent commit by its id");
+                        p.push(parent);
+                    }
+
+                    // A shared commit that the main history already builds on doesn't have
+                    // to be merged in
+                    let is_ancestor = is_shared &&
+                                      repo.graph_descendant_of(oid, new_submodule_commit_id)
+                        .unwrap_or(false);
+                    if submodule_updated && !skip_submodule_parent && !is_ancestor {
+                        if let Some(submodule_commit) = submodule_commit {
+                            p.push(submodule_commit);
+                        }
+                    }
+
+                    p
+                };
+
+                // A commit that only touched .gitmodules has nothing left in it once .gitmodules
+                // is gone, and neither does one that moved the gitlink to a commit that changed
+                // nothing we import. If the user asked us to, we skip such commits and point their
+                // children at the (rewritten) parent instead. Commits that were empty to begin
+                // with are kept, though: somebody made them on purpose.
+                if options.prune_empty && commit.parent_ids().count() == 1 &&
+                   adds_nothing(repo, new_tree.id(), &parents) {
+                    let was_empty = commit.parent_ids().count() == 1 &&
+                                    commit.parent(0)
+                        .expect("Couldn't obtain commit's parent")
+                        .tree_id() == commit.tree_id();
+                    if !was_empty {
+                        old_id_to_new.insert(oid, parents[0].id());
+                        rewritten.push(RewrittenCommit {
+                            old_id: oid,
+                            new_id: parents[0].id(),
+                            submodule_commit: Some(original_submodule_commit_id),
+                        });
+                        continue;
+                    }
+                }
+
+                let mut message = String::from(commit.message()
+                    .expect("Couldn't retrieve commit's message"));
+                if skip_submodule_parent {
+                    if let Some(from) = downgraded_from {
+                        message = append_trailer(&message,
+                                                 &format!("Submodule-Downgrade: {} {} -> {}",
+                                                          options.submodule_dir,
+                                                          from,
+                                                          new_submodule_commit_id));
+                    }
+                }
+                if is_cutoff_boundary {
+                    message = append_trailer(&message,
+                                             &format!("Submodule-Inlined: {}",
+                                                      options.submodule_dir));
+                }
+
+                let mut parents_refs: Vec<&Commit> = Vec::new();
+                for i in 0..parents.len() {
+                    parents_refs.push(&parents[i]);
+                }
+                let new_commit_id = recreate_commit(repo,
+                                                    &commit,
+                                                    &message,
+                                                    &new_tree,
+                                                    &parents_refs[..],
+                                                    options);
+
+                old_id_to_new.insert(oid, new_commit_id);
+                anchors.add(repo, new_commit_id);
+                submodule_states.insert(oid, original_submodule_commit_id);
+                rewritten.push(RewrittenCommit {
+                    old_id: oid,
+                    new_id: new_commit_id,
+                    submodule_commit: Some(original_submodule_commit_id),
+                });
+            }
+            Err(ref e) => {
+                warn(options,
+                     "repo history",
+                     format!("Error walking the repo's history: {:?}", e))
+            }
+        }
+    }
+    anchors.anchor(repo);
+    report_progress(options, "repo", total, total);
+    // The rest of the run needs all of the map, unless it's about to stop anyway
+    if !has_exceeded_memory_limit(options) {
+        spill.restore(old_id_to_new)
+            .expect(&format!("Couldn't read the commit map back from {}",
+                             spill.path().display()));
+    }
+
+    let summary = format!("Created {} new tree(s) outside of {}; {} existing file(s) and \
+                           directories were reused as is.",
+                          new_tree_ids.len(),
+                          options.submodule_dir,
+                          reused_entries);
+    println!("{}", options.colors.summary(&summary));
+
+    if let Some(example) = symlinked.first() {
+        eprintln!("{} {} is a symbolic link in {} commit(s) (e.g. {}); their trees were left as \
+                   they are.",
+                  options.colors.warning("Warning:"),
+                  options.submodule_dir,
+                  symlinked.len(),
+                  abbreviate(repo, *example, options.abbrev));
+    }
+
+    if downgrades > 0 && !options.skip_downgrade_parents {
+        eprintln!("{} {} commit(s) moved the submodule back to an older commit, and were turned \
+                   into merges anyway. Use --skip-downgrade-parents to avoid that.",
+                  options.colors.warning("Warning:"),
+                  downgrades);
+    }
+
+    rewritten
+}
+
+fn get_commit_map_path(repo: &Repository, submodule_dir: &str) -> PathBuf {
+    repo.path().join("submerge").join(submodule_dir).join("commit-map")
+}
+
+fn get_import_namespace(submodule_dir: &str) -> String {
+    format!("refs/submerge/imported/{}/", submodule_dir)
+}
+
+fn get_import_map_path(repo: &Repository, submodule_dir: &str) -> PathBuf {
+    get_commit_map_path(repo, submodule_dir).with_file_name("import-map")
+}
+
+// Reads the map that `git submerge import' wrote, and checks that the commits it leads to are
+// here, too
+fn read_import_map(repo: &Repository, options: &Options) -> Result<HashMap<Oid, Oid>, ()> {
+    let path = options.import_map_path
+        .as_ref()
+        .map_or(get_import_map_path(repo, &options.submodule_dir), PathBuf::from);
+    let mut contents = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut file| file.read_to_string(&mut contents)) {
+        eprintln!("Couldn't read the import map from {}: {}. Run `git submerge import {}' first, \
+                   or point --import-map at the map it wrote.",
+                  path.display(),
+                  e,
+                  options.submodule_dir);
+        return Err(());
+    }
+
+    let mut import_map = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let ids: Vec<Oid> =
+            line.split_whitespace().filter_map(|id| Oid::from_str(id).ok()).collect();
+        if ids.len() != 2 {
+            eprintln!("{}:{}: expected two commit IDs, got `{}'", path.display(), number + 1, line);
+            return Err(());
+        }
+        import_map.insert(ids[0], ids[1]);
+    }
+
+    let missing = import_map.values().filter(|id| repo.find_object(**id, None).is_err()).count();
+    if missing > 0 {
+        eprintln!("{} of the imported commits aren't in the repository. If the import was done \
+                   elsewhere, fetch {} from there first.",
+                  missing,
+                  get_import_namespace(&options.submodule_dir));
+        return Err(());
+    }
+
+    Ok(import_map)
+}
+
+// `git submerge import': puts the rewritten submodule history under get_import_namespace(), and
+// writes down which submodule commit (and tree) became which, a line per object: old ID, then new
+// ID. That's everything the main history's rewrite needs from the submodule, so people can do that
+// part themselves, e.g. with a merge or a graft of their own, or leave it to `git submerge stitch'.
+fn import_submodule_history(repo: &Repository,
+                            old_id_to_new: &HashMap<Oid, Oid>,
+                            options: &Options)
+                            -> i32 {
+    let namespace = get_import_namespace(&options.submodule_dir);
+    let mut tips = Vec::new();
+    if let Some(id) = get_head_gitlink(repo, options) {
+        tips.push((String::from("HEAD"), id));
+    }
+    for glob in &["refs/submerge/fetched/branches/*", "refs/submerge/fetched/url/*"] {
+        let references = repo.references_glob(glob)
+            .expect("Couldn't obtain an iterator over references");
+        for reference in references.filter_map(|r| r.ok()) {
+            let name = match reference.name() {
+                Some(name) => name,
+                None => continue,
+            };
+            let prefix_len = glob.len() - 1;
+            if let Some(id) = reference.target() {
+                tips.push((format!("heads/{}", &name[prefix_len..]), id));
+            }
+        }
+    }
+
+    if violates_strictness(repo, options) {
+        remove_temporary_refs(repo);
+        return E_STRICT_FAILURE;
+    }
+
+    // Whatever an earlier import left here is replaced as a whole
+    let references = repo.references_glob(&format!("{}*", namespace))
+        .expect("Couldn't obtain an iterator over references");
+    for mut reference in references.filter_map(|r| r.ok()) {
+        reference.delete().expect("Couldn't remove a previously imported reference");
+    }
+
+    let path = get_import_map_path(repo, &options.submodule_dir);
+    std::fs::create_dir_all(path.parent().expect("Import map path has no parent directory"))
+        .expect("Couldn't create a directory for the import map");
+    let mut file = File::create(&path).expect("Couldn't create the import map");
+    let mut ids: Vec<(&Oid, &Oid)> = old_id_to_new.iter().collect();
+    ids.sort();
+    for (old_id, new_id) in ids {
+        writeln!(file, "{} {}", old_id, new_id).expect("Couldn't write to the import map");
+    }
+
+    println!("Imported {} commit(s) of {}:", old_id_to_new.len(), options.submodule_dir);
+    for (name, id) in tips {
+        let new_id = match old_id_to_new.get(&id) {
+            Some(new_id) => *new_id,
+            None => continue,
+        };
+        let full_name = namespace.clone() + &name;
+        repo.reference(&full_name,
+                       new_id,
+                       true,
+                       "git-submerge: importing submodule's history")
+            .expect(&format!("Couldn't create {}", full_name));
+        println!("    {}", options.colors.branch(&full_name));
+    }
+    println!("The commit map is in {}.", path.display());
+
+    remove_temporary_refs(repo);
+    E_SUCCESS
+}
+
+fn get_backup_namespace(submodule_dir: &str) -> String {
+    format!("refs/submerge/backup/{}/heads/", submodule_dir)
+}
+
+// The commit map has a line per rewritten commit: old ID, new ID, and the submodule commit that
+// ended up in the tree ("empty" if none did, "preserved" if the gitlink was kept).
+// Patterns from --strip-path go next to it, in "stripped-paths", so that verification knows which
+// files are missing from the imported trees on purpose. An empty "export-ignore" file means
+// --honor-export-ignore was in effect.
+fn write_commit_map(repo: &Repository, rewritten: &[RewrittenCommit], options: &Options) {
+    let path = get_commit_map_path(repo, &options.submodule_dir);
+    std::fs::create_dir_all(path.parent().expect("Commit map path has no parent directory"))
+        .expect("Couldn't create a directory for the commit map");
+
+    let export_ignore_path = path.with_file_name("export-ignore");
+    if options.honor_export_ignore {
+        File::create(&export_ignore_path).expect("Couldn't create the export-ignore marker");
+    } else {
+        let _ = std::fs::remove_file(&export_ignore_path);
+    }
+
+    let stripped_paths_path = path.with_file_name("stripped-paths");
+    if options.strip_paths.is_empty() {
+        // Might be left over from an earlier run; it's fine if there's none
+        let _ = std::fs::remove_file(&stripped_paths_path);
+    } else {
+        let mut file = File::create(&stripped_paths_path)
+            .expect("Couldn't create the list of stripped paths");
+        for pattern in &options.strip_paths {
+            writeln!(file, "{}", pattern).expect("Couldn't write to the list of stripped paths");
+        }
+    }
+
+    let mut file = File::create(&path).expect("Couldn't create the commit map");
+    for commit in rewritten {
+        let submodule_commit = match commit.submodule_commit {
+            Some(id) if id.is_zero() => String::from("empty"),
+            Some(id) => id.to_string(),
+            None => String::from("preserved"),
+        };
+        writeln!(file, "{} {} {}", commit.old_id, commit.new_id, submodule_commit)
+            .expect("Couldn't write to the commit map");
+    }
+    options.commit_map_written.set(true);
+}
+
+// Draws the neighbourhood of every commit that got submodule's history as an extra parent, old
+// history on the left and the new one on the right. Render with e.g. `dot -Tsvg`.
+fn write_dot_graph(repo: &Repository, path: &str, rewritten: &[RewrittenCommit]) {
+    let mut graph = String::from("digraph submerge {\n    rankdir=RL;\n");
+    for &(cluster, label) in &[("old", "Before"), ("new", "After")] {
+        graph += &format!("    subgraph cluster_{} {{\n        label=\"{}\";\n", cluster, label);
+        let mut nodes = HashSet::new();
+        for commit in rewritten {
+            let old_commit = repo.find_commit(commit.old_id).expect("Couldn't find old commit");
+            let new_commit = repo.find_commit(commit.new_id).expect("Couldn't find new commit");
+            let old_parents: Vec<Oid> = old_commit.parent_ids().collect();
+            let new_parents: Vec<Oid> = new_commit.parent_ids().collect();
+            if new_parents.len() <= old_parents.len() {
+                continue;
+            }
+
+            let (id, parents) = if cluster == "old" {
+                (commit.old_id, old_parents)
+            } else {
+                (commit.new_id, new_parents)
+            };
+            for node in std::iter::once(id).chain(parents.iter().cloned()) {
+                if nodes.insert(node) {
+                    let summary = repo.find_commit(node)
+                        .ok()
+                        .and_then(|mut c| c.summary().map(String::from))
+                        .unwrap_or(String::new());
+                    graph += &format!("        {}_{} [label=\"{:.7}\\n{}\"];\n",
+                                      cluster,
+                                      node,
+                                      node.to_string(),
+                                      summary.replace("\\", "\\\\").replace("\"", "\\\""));
+                }
+            }
+            for (i, parent) in parents.iter().enumerate() {
+                // Submodule's history comes in as the last parent
+                let style = if cluster == "new" && i == parents.len() - 1 {
+                    " [color=blue, style=bold]"
+                } else {
+                    ""
+                };
+                graph += &format!("        {0}_{1} -> {0}_{2}{3};\n", cluster, id, parent, style);
+            }
+        }
+        graph += "    }\n";
+    }
+    graph += "}\n";
+
+    File::create(path)
+        .and_then(|mut f| f.write_all(graph.as_bytes()))
+        .expect(&format!("Couldn't write the graph to {}", path));
+}
+
+// Remembers where the branches pointed before the rewrite, in the same fashion as
+// git-filter-branch's refs/original/
+fn backup_branches(repo: &Repository, options: &Options) {
+    let namespace = get_backup_namespace(&options.submodule_dir);
+    for (name, id) in get_branch_to_id_map(repo, options) {
+        repo.reference(&format!("{}{}", namespace, name),
+                       id,
+                       true,
+                       "git-submerge: backing up the branch")
+            .expect("Couldn't back up a branch");
+    }
+}
+
+// With --archive-old-branches, puts the pre-rewrite branches under <namespace>heads/, and creates
+// <namespace>manifest: an empty commit that lists them and has all of them as parents. Pushing
+// that one ref is enough to keep the whole old history around.
+fn archive_branches(repo: &Repository, namespace: &str, options: &Options) {
+    let mut branches: Vec<(String, Oid)> =
+        get_branch_to_id_map(repo, options).into_iter().collect();
+    branches.sort();
+
+    let mut message = format!("Branches before merging submodule '{}'\n\n",
+                              options.submodule_dir);
+    let mut parents: Vec<Commit> = Vec::new();
+    for &(ref name, id) in &branches {
+        repo.reference(&format!("{}heads/{}", namespace, name),
+                       id,
+                       false,
+                       "git-submerge: archiving the branch")
+            .expect(&format!("Couldn't archive branch {}", name));
+        message += &format!("{} {}\n", id, name);
+        if !parents.iter().any(|p| p.id() == id) {
+            parents.push(repo.find_commit(id).expect("Couldn't find branch's tip"));
+        }
+    }
+
+    let empty_tree_id = repo.treebuilder(None)
+        .and_then(|t| t.write())
+        .expect("Couldn't create an empty tree");
+    let empty_tree = repo.find_tree(empty_tree_id)
+        .expect("Couldn't read back the Tree we just wrote");
+    let signature = repo.signature().expect("Couldn't obtain user's signature from Git config");
+    let parents_refs: Vec<&Commit> = parents.iter().collect();
+    let manifest_id = create_commit(repo,
+                                    None,
+                                    &signature,
+                                    &signature,
+                                    &message,
+                                    &empty_tree,
+                                    &parents_refs[..],
+                                    options);
+    repo.reference(&format!("{}manifest", namespace),
+                   manifest_id,
+                   false,
+                   "git-submerge: archiving the branches")
+        .expect("Couldn't create the manifest ref");
+    println!("Old branches are archived under {}", namespace);
+}
+
+// Checks that the only difference between the trees are the files removed by --strip-path and
+// --honor-export-ignore
+fn is_stripped_copy(repo: &Repository,
+                    original: &Tree,
+                    stripped: Option<Oid>,
+                    patterns: &[String],
+                    honor_export_ignore: bool)
+                    -> bool {
+    let mut patterns = patterns.to_vec();
+    if honor_export_ignore {
+        let mut index = Index::new().expect("Couldn't create an in-memory index");
+        index.read_tree(original).expect("Couldn't read the tree into index");
+        patterns.extend(get_export_ignore_patterns(repo, &index));
+    }
+    if patterns.is_empty() {
+        return false;
+    }
+    let stripped = match stripped.map(|id| repo.find_tree(id)) {
+        Some(Ok(tree)) => tree,
+        _ => return false,
+    };
+    let diff = repo.diff_tree_to_tree(Some(original), Some(&stripped), None)
+        .expect("Couldn't diff the trees");
+    let all_stripped = diff.deltas().all(|delta| {
+        delta.status() == git2::Delta::Deleted &&
+        delta.old_file()
+            .path()
+            .and_then(|p| p.to_str())
+            .map_or(false, |p| is_stripped(p, &patterns))
+    });
+    all_stripped
+}
+
+// Compares trees the way --find-renames, --find-copies, --text and --binary say
+fn diff_trees<'repo>(repo: &'repo Repository,
+                     old_tree: &Tree,
+                     new_tree: &Tree,
+                     options: &Options)
+                     -> git2::Diff<'repo> {
+    let settings = &options.diff_settings;
+    let mut diffopts = git2::DiffOptions::new();
+    diffopts.force_text(settings.text);
+    diffopts.force_binary(settings.binary);
+    let mut diff = repo.diff_tree_to_tree(Some(old_tree), Some(new_tree), Some(&mut diffopts))
+        .expect("Couldn't diff the trees");
+
+    if settings.renames.is_some() {
+        let mut find_options = git2::DiffFindOptions::new();
+        if let Some(threshold) = settings.renames {
+            find_options.renames(true).rename_threshold(threshold);
+        }
+        if let Some(threshold) = settings.copies {
+            find_options.copies(true).copy_threshold(threshold);
+        }
+        diff.find_similar(Some(&mut find_options)).expect("Couldn't detect renames");
+    }
+    diff
+}
+
+// Re-checks the result of an earlier merge. In every rewritten commit, everything outside of
+// the submodule's directory should be as it was, and the directory itself should match the tree of
+// the submodule commit that the merge used.
+fn verify_merge(repo: &Repository, options: &Options) -> i32 {
+    let submodule_dir = &options.submodule_dir[..];
+    let abbrev = options.abbrev;
+    let path = get_commit_map_path(repo, submodule_dir);
+    let mut contents = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+        eprintln!("Couldn't read the commit map from {}: {}", path.display(), e);
+        return E_VERIFICATION_FAILED;
+    }
+    if let Some(state) = state::read(repo, submodule_dir) {
+        println!("Checking the results of `git submerge {}', run by git-submerge {} on {}.",
+                 state.arguments.join(" "),
+                 state.version,
+                 format_date(state.finished));
+        if state.exit_code != E_SUCCESS {
+            println!("That run ended with exit code {}.", state.exit_code);
+        }
+    }
+
+    let mut stripped_paths = String::new();
+    let _ = File::open(path.with_file_name("stripped-paths"))
+        .and_then(|mut f| f.read_to_string(&mut stripped_paths));
+    let stripped_paths: Vec<String> = stripped_paths.lines().map(String::from).collect();
+    let honor_export_ignore = path.with_file_name("export-ignore").exists();
+
+    let submodule_path = Path::new(submodule_dir);
+    let mut old_id_to_new = HashMap::new();
+    let mut problems = 0;
+    let mut unchecked = 0;
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let ids: Vec<Oid> = fields.iter().take(2).filter_map(|f| Oid::from_str(f).ok()).collect();
+        if fields.len() != 3 || ids.len() != 2 {
+            eprintln!("Malformed line in the commit map: {}", line);
+            problems += 1;
+            continue;
+        }
+        let (old_id, new_id) = (ids[0], ids[1]);
+        old_id_to_new.insert(old_id, new_id);
+
+        let (old_tree, new_tree) = match (repo.find_commit(old_id).and_then(|c| c.tree()),
+                                          repo.find_commit(new_id).and_then(|c| c.tree())) {
+            (Ok(old_tree), Ok(new_tree)) => (old_tree, new_tree),
+            _ => {
+                eprintln!("{} -> {}: couldn't find the commits", old_id, new_id);
+                problems += 1;
+                continue;
+            }
+        };
+        let rewrite = format!("{} -> {}",
+                              abbreviate(repo, old_id, abbrev),
+                              abbreviate(repo, new_id, abbrev));
+
+        if fields[2] == "preserved" {
+            if old_tree.id() != new_tree.id() {
+                eprintln!("{}: the tree was supposed to stay the same", rewrite);
+                problems += 1;
+            }
+            continue;
+        }
+
+        let diff = diff_trees(repo, &old_tree, &new_tree, options);
+        for delta in diff.deltas() {
+            // A rename has two paths, and both have to be in the submodule's directory
+            let mut paths = delta.old_file().path().into_iter().chain(delta.new_file().path());
+            let outside = paths.find(|path| {
+                !path.starts_with(submodule_path) && *path != Path::new(".gitmodules")
+            });
+            if let Some(path) = outside {
+                eprintln!("{}: {} changed", rewrite, path.display());
+                problems += 1;
+            }
+        }
+
+        let new_subtree_id = new_tree.get_path(submodule_path)
+            .ok()
+            .and_then(|entry| if entry.filemode() == 0o040000 {
+                Some(entry.id())
+            } else {
+                None
+            });
+        if fields[2] == "empty" {
+            if new_subtree_id.is_some() {
+                eprintln!("{}: {} was supposed to be empty", rewrite, submodule_dir);
+                problems += 1;
+            }
+            continue;
+        }
+        let expected_subtree = Oid::from_str(fields[2])
+            .and_then(|id| repo.find_commit(id))
+            .and_then(|c| c.tree());
+        match expected_subtree {
+            Ok(ref tree) if Some(tree.id()) == new_subtree_id => {}
+            Ok(ref tree) if is_stripped_copy(repo,
+                                             tree,
+                                             new_subtree_id,
+                                             &stripped_paths,
+                                             honor_export_ignore) => {}
+            Ok(ref tree) => {
+                eprintln!("{}: {} doesn't match submodule's commit {}",
+                          rewrite,
+                          submodule_dir,
+                          abbreviate(repo, tree.id(), abbrev));
+                problems += 1;
+            }
+            Err(_) => unchecked += 1,
+        }
+    }
+
+    // The branches should still contain the rewritten history
+    let namespace = get_backup_namespace(submodule_dir);
+    let backups = repo.references_glob(&format!("{}*", namespace))
+        .expect("Couldn't obtain an iterator over references");
+    for backup in backups.filter_map(|r| r.ok()) {
+        let name = backup.name()
+            .expect("Branch name is not valid UTF-8")
+            .trim_left_matches(&namespace[..]);
+        let new_id = match backup.target().and_then(|id| old_id_to_new.get(&id)) {
+            Some(id) => *id,
+            // The branch wasn't rewritten
+            None => continue,
+        };
+        match repo.refname_to_id(&format!("refs/heads/{}", name)) {
+            Ok(id) if id == new_id || repo.graph_descendant_of(id, new_id).unwrap_or(false) => {}
+            Ok(_) => {
+                eprintln!("Branch {} doesn't contain its rewritten tip {} anymore",
+                          name,
+                          abbreviate(repo, new_id, abbrev));
+                problems += 1;
+            }
+            Err(_) => {
+                eprintln!("Branch {} doesn't exist anymore", name);
+                problems += 1;
+            }
+        }
+    }
+
+    let summary = format!("Checked {} commit(s), found {} problem(s).",
+                          old_id_to_new.len(),
+                          problems);
+    if problems == 0 {
+        println!("{}", options.colors.summary(&summary));
+    } else {
+        println!("{}", summary);
+    }
+    if unchecked > 0 {
+        println!("{} commit(s) couldn't be compared against the submodule because its commits \
+                  are missing; fetch the submodule's history into this repo to check them, too.",
+                 unchecked);
+    }
+
+    if problems > 0 {
+        E_VERIFICATION_FAILED
+    } else {
+        E_SUCCESS
+    }
+}
+
+// Returns the rewritten branches, and where they ended up
+fn move_branches(repo: &Repository,
+                 old_id_to_new: &HashMap<Oid, Oid>,
+                 options: &Options)
+                 -> Vec<(String, Oid)> {
+    if options.no_modify_refs {
+        println!("Rewritten branches:");
+    }
+
+    let mut excluded = Vec::new();
+    let mut tips = Vec::new();
+    let branches = repo.branches(Some(git2::BranchType::Local))
+        .expect("Couldn't obtain an iterator over local branches");
+    for maybe_branch in branches {
         match maybe_branch {
             Ok((branch, _)) => {
-                let name = branch.name()
-                    .expect("Couldn't get branch' name")
-                    .expect("Branch name is not valid UTF-8");
-                let id = branch.get()
-                    .peel(git2::ObjectType::Commit)
+                let branch_is_head = branch.is_head();
+                let mut reference = branch.into_reference();
+                let name = String::from(reference.shorthand()
+                    .expect("Branch name is not valid UTF-8"));
+                if is_branch_excluded(&name, options) {
+                    excluded.push(name);
+                    continue;
+                }
+                let id = reference.peel(git2::ObjectType::Commit)
                     .expect("Couldn't convert branch into a Commit")
                     .id();
-                result.insert(String::from(name), id);
+                // Branches that are entirely outside of --rev-range stay where they are
+                let mut new_id = match old_id_to_new.get(&id) {
+                    Some(new_id) => *new_id,
+                    None => id,
+                };
+
+                if options.merge_matching_branches && !branch_is_head {
+                    new_id = merge_matching_submodule_branch(repo,
+                                                             &name,
+                                                             id,
+                                                             new_id,
+                                                             old_id_to_new,
+                                                             options);
+                }
+
+                if options.merge_mailmap && new_id != id {
+                    new_id = merge_submodule_mailmap(repo, new_id, options);
+                }
+
+                if let Some(CodeownersAction::Apply) = options.codeowners {
+                    if new_id != id {
+                        new_id = merge_submodule_codeowners(repo, new_id, options);
+                    }
+                }
+
+                if options.hoist_gitignore && new_id != id {
+                    new_id = hoist_submodule_gitignore(repo, new_id, options);
+                }
+
+                let has_cleanup = !options.cleanup_remove.is_empty() ||
+                                  options.cleanup_patch.is_some();
+                if has_cleanup && new_id != id {
+                    new_id = cleanup::add_cleanup_commit(repo, &name, new_id, options);
+                }
+
+                // Branches that weren't rewritten don't have anything to document
+                if options.summary_commit && new_id != id {
+                    new_id = add_summary_commit(repo, &name, id, new_id, options);
+                }
+
+                if options.no_modify_refs {
+                    let result_name = options.result_prefix.clone() + &name +
+                                      &options.result_suffix;
+                    repo.reference(&result_name,
+                                   new_id,
+                                   true,
+                                   "git-submerge: creating a branch for rewritten history")
+                        .expect(&format!("Couldn't create {}", result_name));
+                    println!("    {} -> {}", name, options.colors.branch(&result_name));
+                } else {
+                    reference.set_target(new_id, "git-submerge: moving to rewritten history")
+                        .expect("Couldn't move branch to rewritten history");
+                }
+                if old_id_to_new.contains_key(&id) {
+                    tips.push((name, new_id));
+                }
+            }
+            Err(e) => warn(options, "branches", format!("Error walking the branches: {:?}", e)),
+        }
+    }
+
+    if !excluded.is_empty() {
+        println!("These branches were excluded with --exclude-branches, and still point at the \
+                  history with the submodule:");
+        for name in excluded {
+            println!("    {}", name);
+        }
+    }
+    tips
+}
+
+// With --merge-matching-branches, merges submodule's branch with the given name (if there is
+// one) on top of the rewritten main branch. Returns the ID of the new branch tip.
+//
+// The submodule's directory is simply replaced by the one from the submodule's branch, so we only
+// do that if the branch is ahead of the submodule commit the main branch uses. If the two have
+// diverged, the user has to merge them by hand.
+fn merge_matching_submodule_branch(repo: &Repository,
+                                   name: &str,
+                                   old_tip_id: Oid,
+                                   new_tip_id: Oid,
+                                   old_id_to_new: &HashMap<Oid, Oid>,
+                                   options: &Options)
+                                   -> Oid {
+    let submodule_path = Path::new(&options.submodule_dir);
+
+    let old_submodule_branch_id =
+        match repo.refname_to_id(&format!("refs/submerge/fetched/branches/{}", name)) {
+            Ok(id) => id,
+            Err(_) => return new_tip_id,
+        };
+    // E.g. with `stitch', or if the commit is shared with the main repo's history
+    let submodule_branch_id = match old_id_to_new.get(&old_submodule_branch_id) {
+        Some(id) => *id,
+        None => {
+            warn(options,
+                 "branches",
+                 format!("Submodule's branch {} points at {}, which wasn't rewritten, so it \
+                          wasn't merged into the main repo's branch {}; you'll have to merge it \
+                          by hand.",
+                         name,
+                         abbreviate(repo, old_submodule_branch_id, options.abbrev),
+                         name));
+            return new_tip_id;
+        }
+    };
+
+    // The submodule state in the main branch, as it was before the rewrite
+    let old_tip = repo.find_commit(old_tip_id).expect("Couldn't find branch's tip");
+    let gitlink_id = match old_tip.tree()
+        .expect("Couldn't obtain commit's tree")
+        .get_path(submodule_path) {
+        Ok(_) if options.overrides.contains_key(&old_tip_id) => options.overrides[&old_tip_id],
+        Ok(ref entry) if entry.filemode() == 0o160000 => peel_gitlink(repo, entry.id()),
+        _ => return new_tip_id,
+    };
+    let state = match map_submodule_commit(gitlink_id,
+                                           old_id_to_new,
+                                           &options.mappings,
+                                           &options.default_mapping) {
+        Some(id) if !id.is_zero() => id,
+        _ => return new_tip_id,
+    };
+
+    if state == submodule_branch_id ||
+       repo.graph_descendant_of(state, submodule_branch_id).unwrap_or(false) {
+        // Nothing new in the submodule's branch
+        return new_tip_id;
+    }
+    if !repo.graph_descendant_of(submodule_branch_id, state).unwrap_or(false) {
+        eprintln!("Submodule's branch {} has diverged from the submodule commit used by the main \
+                   repo's branch {}; you'll have to merge them by hand.",
+                  name,
+                  name);
+        return new_tip_id;
+    }
+
+    let new_tip = repo.find_commit(new_tip_id).expect("Couldn't find rewritten branch's tip");
+    let submodule_branch = repo.find_commit(submodule_branch_id)
+        .expect("Couldn't find rewritten submodule's branch");
+    let subtree_id = get_imported_subtree_id(&submodule_branch, options)
+        .expect("Couldn't obtain submodule's subtree ID");
+    let tree = replace_submodule_dir(repo,
+                                     &new_tip.tree().expect("Couldn't obtain commit's tree"),
+                                     submodule_path,
+                                     Some(subtree_id),
+                                     false);
+
+    let signature = repo.signature().expect("Couldn't obtain user's signature from Git config");
+    create_commit(repo,
+                  None,
+                  &signature,
+                  &signature,
+                  &format!("Merge branch '{}' of submodule '{}'\n", name, options.submodule_dir),
+                  &tree,
+                  &[&new_tip, &submodule_branch],
+                  options)
+}
+
+// Git only reads .mailmap from the root of the repo, so the one that came with the submodule is
+// ignored from now on. With --merge-mailmap, we put a commit on top of the rewritten branch that
+// appends submodule's entries to the root .mailmap (unless they're already there). Returns the ID
+// of the new branch tip.
+fn merge_submodule_mailmap(repo: &Repository, new_tip_id: Oid, options: &Options) -> Oid {
+    let new_tip = repo.find_commit(new_tip_id).expect("Couldn't find rewritten branch's tip");
+    let tree = new_tip.tree().expect("Couldn't obtain commit's tree");
+    let read_blob = |path: &Path| -> Option<String> {
+        tree.get_path(path)
+            .and_then(|entry| repo.find_blob(entry.id()))
+            .map(|blob| String::from_utf8_lossy(blob.content()).into_owned())
+            .ok()
+    };
+
+    let submodule_mailmap = match read_blob(&Path::new(&options.submodule_dir).join(".mailmap")) {
+        Some(contents) => contents,
+        None => return new_tip_id,
+    };
+    let mut mailmap = read_blob(Path::new(".mailmap")).unwrap_or(String::new());
+    let existing: HashSet<String> = mailmap.lines().map(|line| String::from(line.trim())).collect();
+    let new_entries: Vec<&str> = submodule_mailmap.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !existing.contains(*line))
+        .collect();
+    if new_entries.is_empty() {
+        return new_tip_id;
+    }
+
+    if !mailmap.is_empty() && !mailmap.ends_with('\n') {
+        mailmap.push('\n');
+    }
+    mailmap += &format!("# From {}/.mailmap\n", options.submodule_dir);
+    for entry in new_entries {
+        mailmap += entry;
+        mailmap.push('\n');
+    }
+
+    let blob_id = repo.blob(mailmap.as_bytes()).expect("Couldn't write .mailmap into the repo");
+    let mut builder = repo.treebuilder(Some(&tree)).expect("Couldn't create a TreeBuilder");
+    builder.insert(".mailmap", blob_id, 0o100644).expect("Couldn't add .mailmap to the tree");
+    let tree_id = builder.write().expect("Couldn't write the tree");
+    let new_tree = repo.find_tree(tree_id).expect("Couldn't retrieve the tree we just created");
+
+    let message = format!("Merge {0}/.mailmap into .mailmap\n\nGit only reads .mailmap from the \
+                           root of the repo, so the entries from {0}/.mailmap are copied here.\n",
+                          options.submodule_dir);
+    let signature = repo.signature().expect("Couldn't obtain user's signature from Git config");
+    create_commit(repo,
+                  None,
+                  &signature,
+                  &signature,
+                  &message,
+                  &new_tree,
+                  &[&new_tip],
+                  options)
+}
+
+// Code review tools only read CODEOWNERS from the root of the repo, .github/ and the like, so
+// the rules from the submodule's one stop being enforced after the merge. This finds them in the
+// given (rewritten) tree, and returns the file they should go to (the one the repo already has,
+// if any), the one they came from, and the rules themselves, with the patterns adjusted to only
+// match inside the submodule's directory.
+fn get_submodule_codeowners(repo: &Repository,
+                            tree: &Tree,
+                            options: &Options)
+                            -> Option<(String, String, Vec<String>)> {
+    let found = CODEOWNERS_PATHS.iter()
+        .filter_map(|path| {
+            let source = format!("{}/{}", options.submodule_dir, path);
+            tree.get_path(Path::new(&source))
+                .and_then(|entry| repo.find_blob(entry.id()))
+                .map(|blob| (source, String::from_utf8_lossy(blob.content()).into_owned()))
+                .ok()
+        })
+        .next();
+
+    found.map(|(source, contents)| {
+        let target = CODEOWNERS_PATHS.iter()
+            .find(|path| tree.get_path(Path::new(path)).is_ok())
+            .map_or(&source[options.submodule_dir.len() + 1..], |path| *path);
+        let rules = contents.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| move_codeowners_rule(line, &options.submodule_dir))
+            .collect();
+        (String::from(target), source.clone(), rules)
+    })
+}
+
+// CODEOWNERS patterns work like the ones in .gitignore: a pattern with a slash at the beginning
+// or in the middle is relative to the root, others match at any depth. Section headers (GitLab's
+// "[Section]") are left as they are.
+fn move_codeowners_rule(rule: &str, submodule_dir: &str) -> String {
+    if rule.starts_with('[') || rule.starts_with("^[") {
+        return String::from(rule);
+    }
+
+    // The pattern ends at the first whitespace that isn't escaped
+    let mut escaped = false;
+    let mut end = rule.len();
+    for (i, c) in rule.char_indices() {
+        if c.is_whitespace() && !escaped {
+            end = i;
+            break;
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    let (pattern, owners) = rule.split_at(end);
+
+    let pattern = if pattern.starts_with('/') {
+        format!("/{}{}", submodule_dir, pattern)
+    } else if pattern.trim_right_matches('/').contains('/') {
+        format!("/{}/{}", submodule_dir, pattern)
+    } else if pattern == "*" {
+        format!("/{}/", submodule_dir)
+    } else {
+        format!("/{}/**/{}", submodule_dir, pattern)
+    };
+    pattern + owners
+}
+
+// With --codeowners=apply, puts a commit on top of the rewritten branch that appends submodule's
+// CODEOWNERS rules to the repo's own CODEOWNERS (unless they're already there). Rules that come
+// later take precedence, so the submodule's rules override the repo-wide ones, just like they
+// did before the merge. Returns the ID of the new branch tip.
+fn merge_submodule_codeowners(repo: &Repository, new_tip_id: Oid, options: &Options) -> Oid {
+    let new_tip = repo.find_commit(new_tip_id).expect("Couldn't find rewritten branch's tip");
+    let tree = new_tip.tree().expect("Couldn't obtain commit's tree");
+    let (target, source, rules) = match get_submodule_codeowners(repo, &tree, options) {
+        Some(found) => found,
+        None => return new_tip_id,
+    };
+
+    let mut codeowners = tree.get_path(Path::new(&target))
+        .and_then(|entry| repo.find_blob(entry.id()))
+        .map(|blob| String::from_utf8_lossy(blob.content()).into_owned())
+        .unwrap_or(String::new());
+    let existing: HashSet<String> =
+        codeowners.lines().map(|line| String::from(line.trim())).collect();
+    let new_rules: Vec<&String> = rules.iter().filter(|rule| !existing.contains(*rule)).collect();
+    if new_rules.is_empty() {
+        return new_tip_id;
+    }
+
+    if !codeowners.is_empty() && !codeowners.ends_with('\n') {
+        codeowners.push('\n');
+    }
+    codeowners += &format!("# From {}\n", source);
+    for rule in new_rules {
+        codeowners += rule;
+        codeowners.push('\n');
+    }
+
+    let blob_id = repo.blob(codeowners.as_bytes())
+        .expect("Couldn't write CODEOWNERS into the repo");
+    let tree_id = insert_blob(repo, Some(&tree), Path::new(&target), blob_id, 0o100644);
+    let new_tree = repo.find_tree(tree_id).expect("Couldn't retrieve the tree we just created");
+
+    let message = format!("Merge {} into {}\n\nCode review tools don't look for CODEOWNERS in \
+                           subdirectories, so the rules from {} are copied here, with their \
+                           patterns moved under {}/.\n",
+                          source,
+                          target,
+                          source,
+                          options.submodule_dir);
+    let signature = repo.signature().expect("Couldn't obtain user's signature from Git config");
+    create_commit(repo,
+                  None,
+                  &signature,
+                  &signature,
+                  &message,
+                  &new_tree,
+                  &[&new_tip],
+                  options)
+}
+
+// With --codeowners=print, shows the rules that --codeowners=apply would add
+fn print_submodule_codeowners(repo: &Repository,
+                              old_id_to_new: &HashMap<Oid, Oid>,
+                              options: &Options) {
+    let head_id = get_head_id(repo, options);
+    let head_id = old_id_to_new.get(&head_id).cloned().unwrap_or(head_id);
+    let tree = repo.find_commit(head_id)
+        .and_then(|commit| commit.tree())
+        .expect("Couldn't obtain the tree of HEAD");
+    match get_submodule_codeowners(repo, &tree, options) {
+        Some((target, source, rules)) => {
+            println!("Rules from {} to add to {}:", source, target);
+            for rule in rules {
+                println!("    {}", rule);
             }
-            Err(e) => eprintln!("Error walking the branches: {:?}", e),
         }
+        None => println!("{} doesn't have a CODEOWNERS file.", options.submodule_dir),
+    }
+}
+
+// Git keeps reading submodule's .gitignore after the merge, since nested .gitignore files work
+// just like the root one. Some people prefer to have all the rules in one place, though, so with
+// --hoist-gitignore we put a commit on top of the rewritten branch that appends submodule's rules
+// to the root .gitignore (unless they're already there) and removes submodule's file. Returns the
+// ID of the new branch tip.
+fn hoist_submodule_gitignore(repo: &Repository, new_tip_id: Oid, options: &Options) -> Oid {
+    let new_tip = repo.find_commit(new_tip_id).expect("Couldn't find rewritten branch's tip");
+    let tree = new_tip.tree().expect("Couldn't obtain commit's tree");
+    let source = format!("{}/.gitignore", options.submodule_dir);
+    let source_path = Path::new(&source);
+    let contents = match tree.get_path(source_path).and_then(|entry| repo.find_blob(entry.id())) {
+        Ok(blob) => String::from_utf8_lossy(blob.content()).into_owned(),
+        Err(_) => return new_tip_id,
+    };
+
+    let mut gitignore = tree.get_path(Path::new(".gitignore"))
+        .and_then(|entry| repo.find_blob(entry.id()))
+        .map(|blob| String::from_utf8_lossy(blob.content()).into_owned())
+        .unwrap_or(String::new());
+    let existing: HashSet<String> =
+        gitignore.lines().map(|line| String::from(line.trim_right())).collect();
+    // Unlike in CODEOWNERS, the order of the rules matters in both directions: a "!" rule only
+    // re-includes what the rules before it excluded. Appending them all in their original order
+    // keeps that intact.
+    let new_rules: Vec<String> = contents.lines()
+        .map(|line| line.trim_right())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| move_gitignore_rule(line, &options.submodule_dir))
+        .filter(|rule| !existing.contains(rule))
+        .collect();
+
+    if !new_rules.is_empty() {
+        if !gitignore.is_empty() && !gitignore.ends_with('\n') {
+            gitignore.push('\n');
+        }
+        gitignore += &format!("# From {}\n", source);
+        for rule in new_rules {
+            gitignore += &rule;
+            gitignore.push('\n');
+        }
+    }
+
+    let blob_id = repo.blob(gitignore.as_bytes())
+        .expect("Couldn't write .gitignore into the repo");
+    let tree_id = insert_blob(repo, Some(&tree), Path::new(".gitignore"), blob_id, 0o100644);
+    let tree = repo.find_tree(tree_id).expect("Couldn't retrieve the tree we just created");
+    let tree_id = replace_subdir(repo, Some(&tree), source_path, None, None)
+        .expect("The tree became empty after removing submodule's .gitignore");
+    let new_tree = repo.find_tree(tree_id).expect("Couldn't retrieve the tree we just created");
+
+    let message = format!("Move {} into .gitignore\n\nThe rules are the same as before, with their \
+                           patterns moved under {}/.\n",
+                          source,
+                          options.submodule_dir);
+    let signature = repo.signature().expect("Couldn't obtain user's signature from Git config");
+    create_commit(repo,
+                  None,
+                  &signature,
+                  &signature,
+                  &message,
+                  &new_tree,
+                  &[&new_tip],
+                  options)
+}
+
+// .gitignore patterns are moved the same way as CODEOWNERS ones, except that "!" has to stay in
+// front, and that a lone "*" can't become the directory itself: Git doesn't look inside ignored
+// directories, so the "!" rules after it would stop working.
+fn move_gitignore_rule(rule: &str, submodule_dir: &str) -> String {
+    let (negation, pattern) = if rule.starts_with('!') {
+        ("!", &rule[1..])
+    } else {
+        ("", rule)
+    };
+    if pattern == "*" {
+        return format!("{}/{}/**", negation, submodule_dir);
+    }
+    String::from(negation) + &move_codeowners_rule(pattern, submodule_dir)
+}
+
+// Writes a tree that is the given one with the blob put at the given path, creating directories
+// along the way if needed. Returns the ID of the new tree.
+fn insert_blob(repo: &Repository,
+               tree: Option<&Tree>,
+               path: &Path,
+               blob_id: Oid,
+               mode: i32)
+               -> Oid {
+    let mut components = path.components();
+    let first = components.next().expect("Can't insert a blob at an empty path");
+    let rest = components.as_path();
+
+    let mut builder = repo.treebuilder(tree).expect("Couldn't create a TreeBuilder");
+    if rest.as_os_str().is_empty() {
+        builder.insert(first.as_os_str(), blob_id, mode)
+            .expect("Couldn't add a blob to the tree");
+    } else {
+        let subtree = tree.and_then(|tree| tree.get_path(Path::new(first.as_os_str())).ok())
+            .and_then(|entry| repo.find_tree(entry.id()).ok());
+        let subtree_id = insert_blob(repo, subtree.as_ref(), rest, blob_id, mode);
+        builder.insert(first.as_os_str(), subtree_id, 0o040000)
+            .expect("Couldn't add a subtree to the tree");
+    }
+    builder.write().expect("Couldn't write the tree")
+}
+
+// Puts an empty commit on top of the rewritten branch, describing what happened to it. That gives
+// people a single commit to point at when announcing the migration. Returns the ID of the new
+// branch tip.
+fn add_summary_commit(repo: &Repository,
+                      name: &str,
+                      old_tip_id: Oid,
+                      new_tip_id: Oid,
+                      options: &Options)
+                      -> Oid {
+    let url = get_submodule_upstream_url(repo, options)
+        .unwrap_or(String::from("unknown"));
+    let default_mapping = match options.default_mapping {
+        Some(id) if id.is_zero() => String::from("empty"),
+        Some(id) => id.to_string(),
+        None => String::from("none"),
+    };
+
+    let message = format!("Merge submodule '{}' into the tree\n\n\
+                           The submodule was merged into branch {} by git-submerge {}.\n\n\
+                           Submodule URL: {}\n\
+                           Branch tip before the merge: {}\n\
+                           Mappings: {}\n\
+                           Default mapping: {}\n\
+                           Overrides: {}\n",
+                          options.submodule_dir,
+                          name,
+                          crate_version!(),
+                          url,
+                          old_tip_id,
+                          options.mappings.len(),
+                          default_mapping,
+                          options.overrides.len());
+
+    let new_tip = repo.find_commit(new_tip_id).expect("Couldn't find rewritten branch's tip");
+    let signature = repo.signature().expect("Couldn't obtain user's signature from Git config");
+    create_commit(repo,
+                  None,
+                  &signature,
+                  &signature,
+                  &message,
+                  &new_tip.tree().expect("Couldn't obtain commit's tree"),
+                  &[&new_tip],
+                  options)
+}
+
+// Instead of rewriting the history, puts two new commits on top of HEAD: a merge that brings in
+// the (rewritten) submodule history, replacing the gitlink with an ordinary directory, and
+// a commit that removes .gitmodules.
+fn merge_submodule_at_tip(repo: &Repository,
+                          old_id_to_new: &HashMap<Oid, Oid>,
+                          options: &Options)
+                          -> Result<(), i32> {
+    let submodule_path = Path::new(&options.submodule_dir);
+
+    let head_id = get_head_id(repo, options);
+    let head_commit = repo.find_commit(head_id)
+        .expect("Couldn't get the commit HEAD points at");
+    let head_tree = head_commit.tree().expect("Couldn't obtain commit's tree");
+
+    let gitlink_id = match head_tree.get_path(submodule_path) {
+        Ok(_) if options.overrides.contains_key(&head_id) => options.overrides[&head_id],
+        Ok(ref entry) if entry.filemode() == 0o160000 => peel_gitlink(repo, entry.id()),
+        _ => {
+            eprintln!("HEAD doesn't contain a submodule at {}", options.submodule_dir);
+            return Err(E_SUBMODULE_NOT_FOUND);
+        }
+    };
+    let submodule_commit_id = match map_submodule_commit(gitlink_id,
+                                                         old_id_to_new,
+                                                         &options.mappings,
+                                                         &options.default_mapping) {
+        Some(id) => id,
+        None => {
+            eprintln!("HEAD references submodule commit {}, but it couldn't be found in the \
+                       submodule's history.\n\nYou can use --mapping and --default-mapping \
+                       options to make git-submerge replace it with some other, still existing, \
+                       commit.",
+                      gitlink_id);
+            return Err(E_FOUND_DANGLING_REFERENCES);
+        }
+    };
+    if submodule_commit_id.is_zero() {
+        eprintln!("HEAD's submodule commit is mapped to an empty directory, so there's no history \
+                   to merge.");
+        return Err(E_INVALID_MAPPINGS);
+    }
+    let submodule_commit = repo.find_commit(submodule_commit_id)
+        .expect(&format!("Couldn't obtain submodule's commit with ID {}", submodule_commit_id));
+    let subtree_id = get_imported_subtree_id(&submodule_commit, options)
+        .expect("Couldn't obtain submodule's subtree ID");
+
+    let signature = repo.signature().expect("Couldn't obtain user's signature from Git config");
+
+    let merge_tree =
+        replace_submodule_dir(repo, &head_tree, submodule_path, Some(subtree_id), false);
+    let merge_id = create_commit(repo,
+                                 Some(get_head_ref_name(options)),
+                                 &signature,
+                                 &signature,
+                                 &format!("Merge submodule '{}' into the tree\n",
+                                          options.submodule_dir),
+                                 &merge_tree,
+                                 &[&head_commit, &submodule_commit],
+                                 options);
+
+    let merge_commit = repo.find_commit(merge_id)
+        .expect("Couldn't retrieve the commit we just created");
+    let mut treebuilder = repo.treebuilder(Some(&merge_tree))
+        .expect("Couldn't create TreeBuilder");
+    treebuilder.remove(".gitmodules")
+        .expect("Couldn't remove .gitmodules from TreeBuilder");
+    let final_tree_id = treebuilder.write()
+        .expect("Couldn't write TreeBuilder into a Tree");
+    let final_tree = repo.find_tree(final_tree_id)
+        .expect("Couldn't read back the Tree we just wrote");
+    create_commit(repo,
+                  Some(get_head_ref_name(options)),
+                  &signature,
+                  &signature,
+                  &format!("Remove .gitmodules\n\n{} is not a submodule anymore.\n",
+                           options.submodule_dir),
+                  &final_tree,
+                  &[&merge_commit],
+                  options);
+
+    Ok(())
+}
+
+// Re-creates the commit on top of rewritten parents, without changing anything else. If none of
+// the parents were rewritten, the commit is left as it is.
+fn reparent_commit(repo: &Repository,
+                   commit: &Commit,
+                   old_id_to_new: &HashMap<Oid, Oid>,
+                   options: &Options)
+                   -> Oid {
+    let parent_ids: Vec<Oid> = commit.parent_ids()
+        .map(|id| match old_id_to_new.get(&id) {
+            Some(new_id) => *new_id,
+            None => id,
+        })
+        .collect();
+    if commit.parent_ids().eq(parent_ids.iter().cloned()) {
+        return commit.id();
+    }
+
+    let parents: Vec<Commit> = parent_ids.iter()
+        .map(|id| repo.find_commit(*id).expect("Couldn't find parent commit by its id"))
+        .collect();
+    let parents_refs: Vec<&Commit> = parents.iter().collect();
+    let tree = commit.tree().expect("Couldn't obtain commit's tree");
+    recreate_commit(repo,
+                    commit,
+                    commit.message().expect("Couldn't retrieve commit's message"),
+                    &tree,
+                    &parents_refs[..],
+                    options)
+}
+
+// With --existing-content=prefer-submodule, replaces the files in the submodule's directory with
+// the contents of the given (original) submodule commit, and rewrites the parents
+fn replace_existing_content(repo: &Repository,
+                            commit: &Commit,
+                            state: Oid,
+                            old_id_to_new: &HashMap<Oid, Oid>,
+                            options: &Options)
+                            -> Oid {
+    let submodule_path = Path::new(&options.submodule_dir);
+    let subtree_id = if state.is_zero() {
+        None
+    } else {
+        Some(repo.find_commit(old_id_to_new[&state])
+            .and_then(|c| get_imported_subtree_id(&c, options))
+            .expect("Couldn't obtain submodule's subtree ID"))
+    };
+    let tree = commit.tree().expect("Couldn't obtain commit's tree");
+    let new_tree = replace_submodule_dir(repo, &tree, submodule_path, subtree_id, true);
+
+    let parents: Vec<Commit> = commit.parent_ids()
+        .map(|id| match old_id_to_new.get(&id) {
+            Some(new_id) => *new_id,
+            None => id,
+        })
+        .map(|id| repo.find_commit(id).expect("Couldn't find parent commit by its id"))
+        .collect();
+    let parents_refs: Vec<&Commit> = parents.iter().collect();
+    recreate_commit(repo,
+                    commit,
+                    commit.message().expect("Couldn't retrieve commit's message"),
+                    &new_tree,
+                    &parents_refs[..],
+                    options)
+}
+
+// For the commits git-submerge makes up itself. Like Git, we declare i18n.commitEncoding in their
+// headers, but only if the message is ASCII; anything else we could only write in UTF-8.
+fn create_commit(repo: &Repository,
+                 update_ref: Option<&str>,
+                 author: &git2::Signature,
+                 committer: &git2::Signature,
+                 message: &str,
+                 tree: &Tree,
+                 parents: &[&Commit],
+                 options: &Options)
+                 -> Oid {
+    let encoding = if is_ascii(message) {
+        options.commit_encoding.as_ref().map(|encoding| &encoding[..])
+    } else {
+        None
+    };
+    write_commit(repo,
+                 update_ref,
+                 author,
+                 committer,
+                 message,
+                 encoding,
+                 tree,
+                 parents,
+                 options)
+}
+
+// For the rewritten counterparts of existing commits, which keep the encoding and the signatures of
+// the original. find_unwritable_messages() has already made sure the message can be written in
+// that encoding.
+fn recreate_commit(repo: &Repository,
+                   original: &Commit,
+                   message: &str,
+                   tree: &Tree,
+                   parents: &[&Commit],
+                   options: &Options)
+                   -> Oid {
+    let encoding = get_message_encoding(original);
+    let encoding = encoding.as_ref().map(|encoding| &encoding[..]);
+    let keep_committer = options.committer.is_none();
+    if raw_commit::are_signatures_intact(original, keep_committer) {
+        return write_commit(repo,
+                            None,
+                            &original.author(),
+                            &original.committer(),
+                            message,
+                            encoding,
+                            tree,
+                            parents,
+                            options);
+    }
+
+    // libgit2 would mangle the signatures, so the commit is put together byte by byte instead
+    let message = add_signoff(repo, message, options);
+    let committer = if keep_committer {
+        None
+    } else {
+        Some(get_committer(&original.committer(), options))
+    };
+    raw_commit::write_commit(repo,
+                             original,
+                             committer.as_ref(),
+                             &message,
+                             encoding,
+                             tree,
+                             parents,
+                             options.quarantine)
+        .expect("Couldn't write a commit with the signatures of the original")
+}
+
+// All the commits git-submerge creates go through here (or, if libgit2 can't write their
+// signatures, through add_signoff() and get_committer()), so that options like --signoff apply to
+// each of them
+fn write_commit(repo: &Repository,
+                update_ref: Option<&str>,
+                author: &git2::Signature,
+                committer: &git2::Signature,
+                message: &str,
+                encoding: Option<&str>,
+                tree: &Tree,
+                parents: &[&Commit],
+                options: &Options)
+                -> Oid {
+    let message = add_signoff(repo, message, options);
+    let committer = get_committer(committer, options);
+    let id = repo.commit(update_ref, author, &committer, &message, tree, parents)
+        .expect("Failed to commit");
+    match encoding {
+        // Repository::commit() can't declare an encoding, but amending can. The parents stay the
+        // same, and `update_ref' already points at the commit being amended.
+        Some(encoding) => {
+            repo.find_commit(id)
+                .and_then(|commit| {
+                    commit.amend(update_ref, None, None, Some(encoding), Some(&message), None)
+                })
+                .expect("Couldn't declare the encoding of commit's message")
+        }
+        None => id,
     }
+}
 
-    result
+// The message with the sign-off that --signoff asks for
+fn add_signoff(repo: &Repository, message: &str, options: &Options) -> String {
+    let message = String::from(message);
+    if !options.signoff {
+        return message;
+    }
+    let signoff = get_signoff(repo);
+    // Just like `git commit --signoff`, don't repeat the sign-off if it's already the last one
+    if message.trim_right().lines().last() == Some(&signoff[..]) {
+        message
+    } else {
+        append_trailer(&message, &signoff)
+    }
 }
 
-fn rewrite_repo_history(repo: &Repository,
-                        old_id_to_new: &mut HashMap<Oid, Oid>,
-                        mappings: &HashMap<Oid, Oid>,
-                        default_mapping: &Option<Oid>,
-                        submodule_dir: &str) {
-    let revwalk = get_repo_revwalk(&repo);
-    let submodule_path = Path::new(submodule_dir);
+// The committer from --committer, or the given one if there's none
+fn get_committer(committer: &git2::Signature, options: &Options) -> git2::Signature<'static> {
+    match options.committer {
+        Some((ref name, ref email)) => {
+            let time = match options.committer_date {
+                Some(date) => git2::Time::new(date, 0),
+                None => committer.when(),
+            };
+            git2::Signature::new(name, email, &time)
+                .expect("Couldn't create committer's signature")
+        }
+        None => committer.to_owned(),
+    }
+}
 
-    for maybe_oid in revwalk {
-        match maybe_oid {
-            Ok(oid) => {
-                let commit = repo.find_commit(oid)
-                    .expect(&format!("Couldn't get a commit with ID {}", oid));
-                let tree = commit.tree()
-                    .expect(&format!("Couldn't obtain the tree of a commit with ID {}", oid));
+fn get_signoff(repo: &Repository) -> String {
+    let signature = repo.signature().expect("Couldn't obtain user's signature from Git config");
+    format!("Signed-off-by: {} <{}>",
+            signature.name().expect("user.name is not valid UTF-8"),
+            signature.email().expect("user.email is not valid UTF-8"))
+}
 
-                let submodule_subdir = match tree.get_path(submodule_path) {
-                    Ok(tree) => {
-                        // We're only interested in gitlinks
-                        if tree.filemode() != 0o160000 {
-                            continue;
-                        };
-                        tree
-                    },
-                    Err(e) => {
-                        if e.code() == git2::ErrorCode::NotFound &&
-                           e.class() == git2::ErrorClass::Tree {
-                            // It's okay. The tree lacks the subtree corresponding to the
-                            // submodule. In other words, the commit doesn't include the submodule.
-                            // That's totally fine. Let's map it into itself and move on.
-                            old_id_to_new.insert(oid, oid);
-                            continue;
-                        } else {
-                            // Unexpected error; let's report it and abort the program
-                            panic!("Error getting submodule's subdir from the tree: {:?}", e);
-                        };
-                    }
-                };
+// The encoding that commit's header declares its message to be in, or None if that's UTF-8 (which
+// is also what a commit without the header is in)
+fn get_message_encoding(commit: &Commit) -> Option<String> {
+    // Commit::message_encoding() returns the message instead, so we read the header ourselves
+    commit.raw_header_bytes()
+        .split(|byte| *byte == b'\n')
+        .find(|line| line.starts_with(b"encoding "))
+        .map(|line| String::from_utf8_lossy(&line[b"encoding ".len()..]).into_owned())
+        .and_then(|encoding| if is_utf8(&encoding) { None } else { Some(encoding) })
+}
 
-                // **INVARIANT**: if we got this far, current commit contains a submodule and
-                // should be rewritten
+// The names Git accepts for UTF-8
+fn is_utf8(encoding: &str) -> bool {
+    let encoding = encoding.to_lowercase();
+    encoding == "utf-8" || encoding == "utf8"
+}
 
-                let submodule_commit_id = submodule_subdir.id();
-                let mut new_submodule_commit_id = match mappings.get(&submodule_commit_id) {
-                    Some(id) => *id,
-                    None => submodule_commit_id,
-                };
-                new_submodule_commit_id = match old_id_to_new.get(&new_submodule_commit_id) {
-                    Some(id) => *id,
-                    None => {
-                        let mapped =
-                            default_mapping
-                            .expect(&format!("Found a commit that isn't in mappings, \
-                                              and default-mapping is empty: {}",
-                                              new_submodule_commit_id));
-                        old_id_to_new[&mapped]
-                    }
-                };
-                let submodule_commit = repo.find_commit(new_submodule_commit_id)
-                    .expect(&format!("Couldn't obtain submodule's commit with ID {}",
-                                     new_submodule_commit_id));
-                let subtree_id = submodule_commit.tree()
-                    .and_then(|t| t.get_path(submodule_path))
-                    .and_then(|te| Ok(te.id()))
-                    .expect("Couldn't obtain submodule's subtree ID");
+fn is_ascii(s: &str) -> bool {
+    s.bytes().all(|byte| byte < 0x80)
+}
 
-                let new_tree = replace_submodule_dir(&repo, &tree, &submodule_path, &subtree_id);
+// Commits that would be rewritten, but whose messages can't be written back in their encoding,
+// along with the reason. libgit2 only takes messages as UTF-8 strings, so a message in some other
+// encoding can only be copied byte for byte if it happens to be valid UTF-8 as well, e.g. if it's
+// all ASCII. By the same token, trailers (like --signoff's) can only be added to it if they're
+// ASCII too, and the encoding is one that spells ASCII the same way.
+fn find_unwritable_messages(repo: &Repository, options: &Options) -> Vec<(Oid, String)> {
+    let mut trailers_are_ascii = is_ascii(&options.submodule_dir);
+    if options.signoff {
+        trailers_are_ascii = trailers_are_ascii && is_ascii(&get_signoff(repo));
+    }
+    let check = |id: Oid, might_get_trailers: bool| -> Option<String> {
+        let commit = match repo.find_commit(id) {
+            Ok(commit) => commit,
+            Err(_) => return None,
+        };
+        let encoding = get_message_encoding(&commit);
+        let encoding = match (encoding, commit.message()) {
+            (None, None) => {
+                return Some(String::from("the message isn't valid UTF-8, but the commit doesn't \
+                                          declare any other encoding"))
+            }
+            (Some(encoding), None) => {
+                return Some(format!("the message is in {}, and isn't valid UTF-8", encoding))
+            }
+            (None, Some(_)) => return None,
+            (Some(encoding), Some(_)) => encoding,
+        };
+        let upper = encoding.to_uppercase();
+        let spells_ascii_differently = upper.starts_with("UTF-16") ||
+                                       upper.starts_with("UTF-32") ||
+                                       upper.starts_with("UCS-");
+        if !might_get_trailers {
+            None
+        } else if spells_ascii_differently {
+            Some(format!("the message is in {}, which trailers can't be added to", encoding))
+        } else if !trailers_are_ascii {
+            Some(format!("the message is in {}, and the trailers to add to it aren't ASCII",
+                         encoding))
+        } else {
+            None
+        }
+    };
 
-                // In commits that used to update the submodule, add a parent pointing to
-                // appropriate commit in new submodule history
-                let mut parent_subtree_ids = HashSet::new();
-                for parent in commit.parents() {
-                    let parent_tree = parent.tree().expect("Couldn't obtain parent's tree");
-                    let parent_subdir_tree_id = parent_tree.get_path(submodule_path)
-                        .and_then(|x| Ok(x.id()));
-
-                    match parent_subdir_tree_id {
-                        Ok(id) => {
-                            parent_subtree_ids.insert(id);
-                            ()
-                        }
-                        Err(e) => {
-                            if e.code() == git2::ErrorCode::NotFound &&
-                               e.class() == git2::ErrorClass::Tree {
-                                continue;
-                            } else {
-                                panic!("Error getting submodule's subdir from the tree: {:?}", e);
-                            };
-                        }
-                    }
-                }
+    let mut unwritable = Vec::new();
+    if options.import_map.is_none() {
+        let extra_tips = get_mapping_targets_to_walk(options);
+        for id in get_submodule_revwalk(repo, options, &extra_tips).filter_map(|id| id.ok()) {
+            if let Some(reason) = check(id, options.signoff) {
+                unwritable.push((id, reason));
+            }
+        }
+    }
+    // The main history gets a few trailers of its own, e.g. from --preserve-gitlinks-before
+    if options.mode != Mode::Import && !options.tip_only {
+        for id in get_repo_revwalk(repo, options).filter_map(|id| id.ok()) {
+            if let Some(reason) = check(id, true) {
+                unwritable.push((id, reason));
+            }
+        }
+    }
+    unwritable
+}
 
-                // Here's a few pictures to help you understand how we figure out if current commit
-                // updated the submodule. If we draw a DAG and name submodule states, the following
-                // situations will mean that the submodule wasn't updated:
-                //
-                //     o--o--o--A--
-                //                 `,-A
-                //      o--o--o--B-
-                //
-                // or
-                //
-                //     o--o--o--A--
-                //                 `,-B
-                //      o--o--o--B-
-                //
-                // And in the following graphs the submodule was updated:
-                //
-                //     o--o--o--A--
-                //                 `,-C
-                //      o--o--o--B-
-                //
-                // or
-                //
-                //     o--o--o--o--A--B
-                //
-                // Put into words, the rule will be "the submodule state in current commit is
-                // different from states in all its parents". Or, more formally, the current state
-                // doesn't belong to the set of states in parents.
-                let submodule_updated: bool = !parent_subtree_ids.contains(&submodule_commit_id);
+// Parses dates for --preserve-gitlinks-before: YYYY-MM-DD and YYYY-MM-DDTHH:MM:SS (both in UTC),
+// and @<seconds since the epoch>.
+fn parse_date(s: &str) -> Option<i64> {
+    if s.starts_with('@') {
+        return s[1..].parse().ok();
+    }
 
-                // Rewrite the parents if the submodule was updated
-                let parents = {
-                    let mut p: Vec<Commit> = Vec::new();
-                    for parent_id in commit.parent_ids() {
-                        let actual_parent_id = old_id_to_new[&parent_id];
-                        let parent = repo.find_commit(actual_parent_id)
-                            .expect("Couldn't find parent commit by its id");
-                        p.push(parent);
-                    }
+    let mut date_and_time = s.splitn(2, 'T');
+    let date: Vec<i64> = match date_and_time.next()
+        .map(|d| d.split('-').map(|n| n.parse()).collect()) {
+        Some(Ok(date)) => date,
+        _ => return None,
+    };
+    let time: Vec<i64> = match date_and_time.next()
+        .map(|t| t.split(':').map(|n| n.parse()).collect()) {
+        Some(Ok(time)) => time,
+        Some(Err(_)) => return None,
+        None => vec![0, 0, 0],
+    };
+    if date.len() != 3 || time.len() != 3 {
+        return None;
+    }
+    let (year, month, day) = (date[0], date[1], date[2]);
+    if month < 1 || month > 12 || day < 1 || day > 31 {
+        return None;
+    }
 
-                    if submodule_updated {
-                        p.push(submodule_commit);
-                    }
+    // Days since the epoch, as per http://howardhinnant.github.io/date_algorithms.html
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
 
-                    p
-                };
+    Some(days * 86400 + time[0] * 3600 + time[1] * 60 + time[2])
+}
 
-                let mut parents_refs: Vec<&Commit> = Vec::new();
-                for i in 0..parents.len() {
-                    parents_refs.push(&parents[i]);
-                }
-                let new_commit_id = repo.commit(None,
-                            &commit.author(),
-                            &commit.committer(),
-                            &commit.message().expect("Couldn't retrieve commit's message"),
-                            &new_tree,
-                            &parents_refs[..])
-                    .expect("Failed to commit");
+// Like `git rev-parse --short': at least `abbrev' (or core.abbrev) hex digits, and as many more as
+// it takes for the prefix to be unique in the repo
+pub fn abbreviate(repo: &Repository, id: Oid, abbrev: Option<usize>) -> String {
+    if id.is_zero() {
+        return id.to_string();
+    }
+    let id = id.to_string();
 
-                old_id_to_new.insert(oid, new_commit_id);
-            }
-            Err(e) => eprintln!("Error walking the repo's history: {:?}", e),
+    let min_len = abbrev.or_else(|| {
+            repo.config()
+                .and_then(|config| config.get_string("core.abbrev"))
+                .ok()
+                .and_then(|value| if value == "no" {
+                    Some(40)
+                } else {
+                    value.parse().ok()
+                })
+        })
+        .unwrap_or(7);
+    let min_len = std::cmp::max(4, std::cmp::min(40, min_len));
+    for len in min_len..40 {
+        match repo.revparse_single(&id[..len]) {
+            Err(ref e) if e.code() == git2::ErrorCode::Ambiguous => continue,
+            _ => return String::from(&id[..len]),
         }
     }
+    id
+}
 
-    let branches = repo.branches(Some(git2::BranchType::Local))
-        .expect("Couldn't obtain an iterator over local branches");
-    for maybe_branch in branches {
-        match maybe_branch {
-            Ok((branch, _)) => {
-                let mut reference = branch.into_reference();
-                let id = reference.peel(git2::ObjectType::Commit)
-                    .expect("Couldn't convert branch into a Commit")
-                    .id();
-                let new_id = old_id_to_new[&id];
-                reference.set_target(new_id, "git-submerge: moving to rewritten history")
-                    .expect("Couldn't move branch to rewritten history");
-            }
-            Err(e) => eprintln!("Error walking the branches: {:?}", e),
-        }
+// The inverse of parse_date(), down to the day, in UTC
+fn format_date(seconds: i64) -> String {
+    // See parse_date() for where this comes from
+    let days = if seconds >= 0 { seconds } else { seconds - 86399 } / 86400;
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Returns the ID, in rewritten submodule history, of the commit that should be used in place of
+// `id`, or None if there's no such commit (i.e. `id` is a dangling reference).
+fn map_submodule_commit(id: Oid,
+                        old_id_to_new: &HashMap<Oid, Oid>,
+                        mappings: &HashMap<Oid, Oid>,
+                        default_mapping: &Option<Oid>)
+                        -> Option<Oid> {
+    resolve_submodule_commit(id, old_id_to_new, mappings, default_mapping)
+        .map(|id| if id.is_zero() { id } else { old_id_to_new[&id] })
+}
+
+// Same as map_submodule_commit(), but returns the ID from the original submodule history
+fn resolve_submodule_commit(id: Oid,
+                            old_id_to_new: &HashMap<Oid, Oid>,
+                            mappings: &HashMap<Oid, Oid>,
+                            default_mapping: &Option<Oid>)
+                            -> Option<Oid> {
+    let id = match mappings.get(&id) {
+        Some(mapped) => *mapped,
+        None => id,
+    };
+    if id.is_zero() || old_id_to_new.contains_key(&id) {
+        return Some(id);
     }
+    default_mapping.and_then(|mapped| if mapped.is_zero() || old_id_to_new.contains_key(&mapped) {
+        Some(mapped)
+    } else {
+        None
+    })
+}
+
+// Adds a "Key: value" line to the end of the commit message, putting it into the same paragraph
+// as the trailers that are already there (if any).
+fn append_trailer(message: &str, trailer: &str) -> String {
+    let trimmed = message.trim_right();
+    let last_paragraph = trimmed.rsplit("\n\n").next().unwrap_or("");
+    let has_trailers = trimmed.contains("\n\n") &&
+                       last_paragraph.lines().all(|line| {
+        match line.find(": ") {
+            Some(pos) => pos > 0 && !line[..pos].contains(' '),
+            None => false,
+        }
+    });
+
+    let separator = if has_trailers { "\n" } else { "\n\n" };
+    String::from(trimmed) + separator + trailer + "\n"
 }
 
 fn replace_submodule_dir<'repo>(repo: &'repo Repository,
                                 tree: &Tree,
                                 submodule_path: &Path,
-                                subtree_id: &Oid)
+                                subtree_id: Option<Oid>,
+                                remove_gitmodules: bool)
                                 -> Tree<'repo> {
-    let mut treebuilder = repo.treebuilder(Some(&tree))
-        .expect("Couldn't create TreeBuilder");
-
-    treebuilder.remove(submodule_path)
-        .expect("Couldn't remove submodule path from TreeBuilder");
-    treebuilder.insert(submodule_path, *subtree_id, 0o040000)
-        .expect("Couldn't add submodule as a subdir to TreeBuilder");
-
-    treebuilder.remove(".gitmodules")
-        .expect("Couldn't remove .gitmodules from TreeBuilder");
-
-    let new_tree_id = treebuilder.write()
-        .expect("Couldn't write TreeBuilder into a Tree");
+    let also_remove = if remove_gitmodules {
+        Some(".gitmodules")
+    } else {
+        None
+    };
+    let new_tree_id = match replace_subdir(repo, Some(tree), submodule_path, subtree_id, also_remove) {
+        Some(id) => id,
+        None => {
+            repo.treebuilder(None)
+                .and_then(|treebuilder| treebuilder.write())
+                .expect("Couldn't write an empty Tree")
+        }
+    };
     let new_tree = repo.find_tree(new_tree_id)
         .expect("Couldn't read back the Tree we just wrote");
 
     new_tree
 }
 
-fn remove_dotgit_from_submodule(submodule_dir: &str) {
-    let dotgit_path = String::from(submodule_dir) + "/.git";
-    std::fs::remove_file(&dotgit_path).expect(&format!("Couldn't remove {}", dotgit_path));
+// Only the trees along the path are rebuilt; everything else is reused as is, by ID. That keeps
+// the number of new objects down to a handful per commit. Returns None if the resulting tree is
+// empty, so that directories that only contained the submodule disappear along with it.
+fn replace_subdir(repo: &Repository,
+                  tree: Option<&Tree>,
+                  path: &Path,
+                  subtree_id: Option<Oid>,
+                  also_remove: Option<&str>)
+                  -> Option<Oid> {
+    let mut components = path.components();
+    let name = components.next().expect("Submodule path is empty").as_os_str();
+    let rest = components.as_path();
+
+    let mut treebuilder = repo.treebuilder(tree).expect("Couldn't create TreeBuilder");
+    let new_id = if rest.as_os_str().is_empty() {
+        subtree_id
+    } else {
+        let child = match tree.and_then(|t| t.get_path(Path::new(name)).ok()) {
+            Some(ref entry) if entry.filemode() == 0o040000 => {
+                Some(repo.find_tree(entry.id()).expect("Couldn't find a subtree"))
+            }
+            _ => None,
+        };
+        replace_subdir(repo, child.as_ref(), rest, subtree_id, None)
+    };
+
+    if treebuilder.get(name).expect("Couldn't look up a TreeBuilder entry").is_some() {
+        treebuilder.remove(name).expect("Couldn't remove an entry from TreeBuilder");
+    }
+    if let Some(id) = new_id {
+        treebuilder.insert(name, id, 0o040000)
+            .expect("Couldn't add submodule as a subdir to TreeBuilder");
+    }
+    if let Some(name) = also_remove {
+        if treebuilder.get(name).expect("Couldn't look up a TreeBuilder entry").is_some() {
+            treebuilder.remove(name).expect("Couldn't remove an entry from TreeBuilder");
+        }
+    }
+
+    if treebuilder.is_empty() {
+        None
+    } else {
+        Some(treebuilder.write().expect("Couldn't write TreeBuilder into a Tree"))
+    }
+}
+
+// Returns the ID and the filemode of whatever is at the submodule's path in the given commit, or
+// None if there's nothing there
+fn get_submodule_entry(repo: &Repository,
+                       commit_id: Oid,
+                       submodule_path: &Path,
+                       cache: &mut HashMap<Oid, Option<(Oid, i32)>>)
+                       -> Option<(Oid, i32)> {
+    if let Some(entry) = cache.get(&commit_id) {
+        return *entry;
+    }
+
+    let tree = repo.find_commit(commit_id)
+        .and_then(|commit| commit.tree())
+        .expect(&format!("Couldn't obtain the tree of a commit with ID {}", commit_id));
+    let entry = match tree.get_path(submodule_path) {
+        Ok(ref entry) if entry.filemode() == 0o160000 => {
+            Some((peel_gitlink(repo, entry.id()), entry.filemode()))
+        }
+        Ok(entry) => Some((entry.id(), entry.filemode())),
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound &&
+                      e.class() == git2::ErrorClass::Tree => None,
+        Err(e) => panic!("Error getting submodule's subdir from the tree: {:?}", e),
+    };
+    cache.insert(commit_id, entry);
+    entry
+}
+
+// Git doesn't mind if a gitlink points at an annotated tag rather than a commit, so such gitlinks
+// do turn up sometimes. Everything else in git-submerge deals in commits, so we peel them here.
+fn peel_gitlink(repo: &Repository, id: Oid) -> Oid {
+    match repo.find_tag(id) {
+        Ok(tag) => tag.peel().map(|target| target.id()).unwrap_or(id),
+        Err(_) => id,
+    }
+}
+
+// Counts the trees that replace_submodule_dir() had to create, and the entries it could reuse
+fn count_tree_reuse(repo: &Repository,
+                    new_tree: &Tree,
+                    submodule_path: &Path,
+                    new_tree_ids: &mut HashSet<Oid>,
+                    reused_entries: &mut usize) {
+    let mut current = Some(repo.find_tree(new_tree.id()).expect("Couldn't find a tree"));
+    for component in submodule_path.components() {
+        let tree = match current {
+            Some(tree) => tree,
+            None => break,
+        };
+        new_tree_ids.insert(tree.id());
+        *reused_entries += tree.iter()
+            .filter(|entry| entry.name() != component.as_os_str().to_str())
+            .count();
+        current = match tree.get_path(Path::new(component.as_os_str())) {
+            Ok(ref entry) if entry.filemode() == 0o040000 => repo.find_tree(entry.id()).ok(),
+            _ => None,
+        };
+    }
+}
+
+// This is the very last step, and the history is already rewritten by then, so failing here
+// shouldn't fail the whole run. Submodules cloned by older versions of Git have their repository
+// right in .git rather than in .git/modules/; we move it out of the way instead of deleting it.
+fn remove_dotgit_from_submodule(repo: &Repository, options: &Options) {
+    let dotgit_path = Path::new(&options.submodule_dir).join(".git");
+    if options.keep_submodule_gitfile {
+        eprintln!("{} was left in place; remove it to make Git see the files in {}.",
+                  dotgit_path.display(),
+                  options.submodule_dir);
+        return;
+    }
+
+    let dotgit_fs_path = long_path(&dotgit_path);
+    let metadata = match std::fs::symlink_metadata(&dotgit_fs_path) {
+        Ok(metadata) => metadata,
+        // Already gone
+        Err(_) => return,
+    };
+    let result = if metadata.is_dir() {
+        let destination = repo.path()
+            .join("submerge")
+            .join(&options.submodule_dir)
+            .join("submodule.git");
+        let result = destination.parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::rename(&dotgit_fs_path, long_path(&destination)));
+        if result.is_ok() {
+            eprintln!("Moved the submodule's repository from {} to {}.",
+                      dotgit_path.display(),
+                      destination.display());
+        }
+        result
+    } else {
+        std::fs::remove_file(&dotgit_fs_path).or_else(|e| if metadata.permissions().readonly() {
+            remove_readonly_file(&dotgit_fs_path)
+        } else {
+            Err(e)
+        })
+    };
+    if let Err(e) = result {
+        eprintln!("{} Couldn't remove {}: {}. Please remove it yourself, or Git won't see the \
+                   files in {}.",
+                  options.colors.warning("Warning:"),
+                  dotgit_path.display(),
+                  e,
+                  options.submodule_dir);
+    }
+}
+
+// Files that end up deep in the repo once the submodule is merged might be out of Windows' reach:
+// unless the paths start with \\?\, it refuses to create, open or remove anything past MAX_PATH.
+// Git for Windows works around that with core.longpaths, but only if it's set. This checks the
+// files that the rewritten HEAD will have in the submodule's directory, which are the ones
+// the final checkout has to write.
+fn warn_about_long_paths(repo: &Repository, old_id_to_new: &HashMap<Oid, Oid>, options: &Options) {
+    let workdir = match repo.workdir() {
+        Some(workdir) => workdir,
+        None => return,
+    };
+    let long_paths_enabled = repo.config()
+        .and_then(|config| config.get_bool("core.longpaths"))
+        .unwrap_or(false);
+    if long_paths_enabled {
+        return;
+    }
+
+    let head_id = get_head_id(repo, options);
+    let submodule_path = Path::new(&options.submodule_dir);
+    let subtree = repo.find_commit(head_id)
+        .and_then(|commit| commit.tree())
+        .and_then(|tree| tree.get_path(submodule_path))
+        .ok()
+        .and_then(|entry| if entry.filemode() == 0o160000 {
+            Some(entry)
+        } else {
+            None
+        })
+        .map(|entry| {
+            options.overrides.get(&head_id).cloned().unwrap_or(peel_gitlink(repo, entry.id()))
+        })
+        .and_then(|id| {
+            map_submodule_commit(id, old_id_to_new, &options.mappings, &options.default_mapping)
+        })
+        .and_then(|id| repo.find_commit(id).ok())
+        .and_then(|commit| get_imported_subtree_id(&commit, options).ok())
+        .and_then(|id| repo.find_tree(id).ok());
+    let subtree = match subtree {
+        Some(subtree) => subtree,
+        None => return,
+    };
+
+    let mut long_paths = Vec::new();
+    collect_long_paths(repo, &subtree, &workdir.join(submodule_path), &mut long_paths);
+    if long_paths.is_empty() {
+        return;
+    }
+    long_paths.sort_by_key(|path| path.as_os_str().len());
+    long_paths.reverse();
+    eprintln!("{} {} file(s) in {} will have paths longer than {} characters, which Windows \
+               doesn't allow by default, e.g.:\n    {}\nOn Windows, the final checkout might \
+               fail. Run `git config core.longpaths true' to let Git for Windows handle such \
+               paths, or use --no-update-worktree and check out the result with it.",
+              options.colors.warning("Warning:"),
+              long_paths.len(),
+              options.submodule_dir,
+              MAX_PATH - 1,
+              long_paths[0].display());
+}
+
+fn collect_long_paths(repo: &Repository, tree: &Tree, dir: &Path, long_paths: &mut Vec<PathBuf>) {
+    for entry in tree.iter() {
+        let path = dir.join(String::from_utf8_lossy(entry.name_bytes()).as_ref());
+        if path.as_os_str().len() >= MAX_PATH {
+            long_paths.push(path);
+        } else if entry.filemode() == 0o040000 {
+            if let Ok(subtree) = repo.find_tree(entry.id()) {
+                collect_long_paths(repo, &subtree, &path, long_paths);
+            }
+        }
+    }
+}
+
+// Windows only accepts paths longer than MAX_PATH if they're absolute and start with \\?\ (which
+// also turns off the handling of forward slashes, "." and ".."). Everywhere else, paths are fine
+// as they are.
+#[cfg(windows)]
+fn long_path(path: &Path) -> PathBuf {
+    let absolute = std::env::current_dir().map(|dir| dir.join(path)).unwrap_or(path.to_path_buf());
+    let absolute = absolute.to_string_lossy().replace('/', "\\");
+    if absolute.len() < MAX_PATH || absolute.starts_with(r"\\?\") {
+        return path.to_path_buf();
+    }
+    PathBuf::from(format!(r"\\?\{}", absolute))
+}
+
+#[cfg(not(windows))]
+fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+// Windows doesn't let us remove read-only files
+#[cfg(windows)]
+fn remove_readonly_file(path: &Path) -> std::io::Result<()> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    permissions.set_readonly(false);
+    std::fs::set_permissions(path, permissions)?;
+    std::fs::remove_file(path)
+}
+
+#[cfg(not(windows))]
+fn remove_readonly_file(path: &Path) -> std::io::Result<()> {
+    std::fs::remove_file(path)
 }
 
 fn remove_gitmodules() {
@@ -647,8 +7286,211 @@ fn update_index(repo: &Repository, old_id_to_new: &HashMap<Oid, Oid>) {
         .expect("Couldn't obtain commit's tree");
     let mut index = repo.index()
         .expect("Couldn't obtain repo's index");
+
+    // Paths outside of the submodule didn't change, so their entries can stay as they are. That
+    // keeps the stat data, and the flags people set on purpose: assume-unchanged, skip-worktree
+    // and intent-to-add.
+    let mut old_entries: HashMap<Vec<u8>, git2::IndexEntry> =
+        index.iter().map(|entry| (entry.path.clone(), entry)).collect();
+
     index.read_tree(&tree)
         .expect("Couldn't populate the index with a tree");
+
+    let mut preserved_entries = Vec::new();
+    for entry in index.iter() {
+        if let Some(old_entry) = old_entries.remove(&entry.path) {
+            if old_entry.id == entry.id && old_entry.mode == entry.mode {
+                preserved_entries.push(old_entry);
+            }
+        }
+    }
+    // Intent-to-add entries aren't in any tree, so they have to be brought back explicitly
+    const GIT_IDXENTRY_INTENT_TO_ADD: u16 = 1 << 13;
+    preserved_entries.extend(old_entries.into_iter()
+        .map(|(_, entry)| entry)
+        .filter(|entry| entry.flags_extended & GIT_IDXENTRY_INTENT_TO_ADD != 0));
+    for entry in preserved_entries {
+        index.add(&entry).expect("Couldn't restore an index entry");
+    }
+
     index.write()
         .expect("Couldn't write the index back to the repo");
 }
+
+fn is_submodule_dir_consistent(repo: &Repository,
+                               submodule_dir: &str,
+                               old_id_to_new: &HashMap<Oid, Oid>)
+                               -> bool {
+    let head = repo.head().expect("Couldn't obtain repo's HEAD");
+    let head_id = head.target().expect("Couldn't resolve repo's HEAD to a commit ID");
+    // See update_index() for why the ID might be missing from the map
+    let head_id = match old_id_to_new.get(&head_id) {
+        Some(id) => *id,
+        None => head_id,
+    };
+    let head_tree = repo.find_commit(head_id)
+        .expect("Couldn't get the commit HEAD points at")
+        .tree()
+        .expect("Couldn't obtain commit's tree");
+    let subtree_id = match head_tree.get_path(Path::new(submodule_dir)) {
+        Ok(ref entry) if entry.filemode() == 0o040000 => entry.id(),
+        _ => {
+            eprintln!("The rewritten HEAD doesn't contain {} as a directory.", submodule_dir);
+            return false;
+        }
+    };
+
+    // As far as Git is concerned, the directory is still a submodule, so we have to look at it
+    // from the inside. Subtrees of the rewritten commits are submodule's own trees, so
+    // the submodule repo has all the objects we need.
+    let submodule_repo = Repository::open(submodule_dir)
+        .expect("Couldn't open the submodule's repository");
+    // ...unless some paths were stripped from them, in which case we have to look at the original
+    // tree instead (see rewrite_submodule_history()). The stripped files are still there in the
+    // working directory.
+    let original_subtree = submodule_repo.find_tree(subtree_id).or_else(|e| {
+        old_id_to_new.iter()
+            .find(|&(_, new_id)| {
+                repo.find_tree(*new_id)
+                    .and_then(|tree| tree.get_path(Path::new(submodule_dir)))
+                    .map(|entry| entry.id() == subtree_id)
+                    .unwrap_or(false)
+            })
+            .map_or(Err(e), |(old_id, _)| submodule_repo.find_tree(*old_id))
+    });
+    let subtree = match original_subtree {
+        Ok(tree) => tree,
+        Err(_) => {
+            eprintln!("The rewritten HEAD has {} at tree {}, which the submodule doesn't know \
+                       about.",
+                      submodule_dir,
+                      subtree_id);
+            return false;
+        }
+    };
+
+    let mut diffopts = git2::DiffOptions::new();
+    diffopts.include_untracked(false);
+    diffopts.include_ignored(false);
+    diffopts.include_typechange(true);
+    let diff = submodule_repo.diff_tree_to_workdir(Some(&subtree), Some(&mut diffopts))
+        .expect("Couldn't diff HEAD against the working directory");
+
+    if diff.deltas().len() == 0 {
+        return true;
+    }
+
+    eprintln!("The contents of {} on disk don't match the rewritten HEAD:\n", submodule_dir);
+    for delta in diff.deltas() {
+        let path = delta.new_file()
+            .path()
+            .or(delta.old_file().path())
+            .map(|p| p.display().to_string())
+            .unwrap_or(String::from("<unknown path>"));
+        eprintln!("{:?}\t{}/{}", delta.status(), submodule_dir, path);
+    }
+    eprintln!("\nThis might be caused by the mappings, or by a bug in git-submerge. Please \
+               inspect the new history carefully before publishing it.");
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{Repository, Signature, Time, Oid};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::order_parents_first;
+
+    // A fresh repo in the temporary directory, removed once the test is done with it
+    pub struct TestRepo {
+        path: PathBuf,
+        pub repo: Repository,
+    }
+
+    impl TestRepo {
+        pub fn new(name: &str) -> TestRepo {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+            let path = ::std::env::temp_dir()
+                .join(format!("git-submerge-test-{}-{}", name, nanos));
+            let repo = Repository::init(&path).unwrap();
+            TestRepo {
+                path: path,
+                repo: repo,
+            }
+        }
+
+        // An empty commit; the message tells it apart from the others
+        pub fn commit(&self, message: &str, parents: &[Oid]) -> Oid {
+            let signature = Signature::new("A U Thor", "author@example.com", &Time::new(0, 0))
+                .unwrap();
+            let tree_id = self.repo.treebuilder(None).unwrap().write().unwrap();
+            let tree = self.repo.find_tree(tree_id).unwrap();
+            let parents: Vec<_> =
+                parents.iter().map(|id| self.repo.find_commit(*id).unwrap()).collect();
+            let parents: Vec<_> = parents.iter().collect();
+            self.repo.commit(None, &signature, &signature, message, &tree, &parents).unwrap()
+        }
+    }
+
+    impl Drop for TestRepo {
+        fn drop(&mut self) {
+            let _ = ::std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn assert_parents_first(repo: &Repository, order: &[Oid]) {
+        let positions: HashMap<Oid, usize> =
+            order.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        for (i, id) in order.iter().enumerate() {
+            for parent_id in repo.find_commit(*id).unwrap().parent_ids() {
+                assert!(positions[&parent_id] < i, "{} comes before its parent {}", id, parent_id);
+            }
+        }
+    }
+
+    // Two unrelated histories, merged and then built upon:
+    //
+    //     a1--a2--
+    //             `m--c
+    //     b1--b2--
+    #[test]
+    fn order_parents_first_handles_several_roots() {
+        let test = TestRepo::new("several-roots");
+        let a1 = test.commit("a1", &[]);
+        let a2 = test.commit("a2", &[a1]);
+        let b1 = test.commit("b1", &[]);
+        let b2 = test.commit("b2", &[b1]);
+        let m = test.commit("m", &[a2, b2]);
+        let c = test.commit("c", &[m]);
+        let all = [a1, a2, b1, b2, m, c];
+
+        // As the revwalk gives them
+        let mut revwalk = test.repo.revwalk().unwrap();
+        revwalk.set_sorting(::git2::SORT_REVERSE | ::git2::SORT_TOPOLOGICAL);
+        revwalk.push(c).unwrap();
+        let walked: Vec<Oid> = revwalk.map(|id| id.unwrap()).collect();
+        let ordered = order_parents_first(&test.repo, walked.clone());
+        assert_eq!(ordered.len(), all.len());
+        for id in &all {
+            assert!(ordered.contains(id), "{} wasn't walked", id);
+        }
+        assert_parents_first(&test.repo, &ordered);
+        // Nothing to fix there, so nothing is moved
+        assert_eq!(ordered, walked);
+
+        // Children first is as wrong as it gets
+        let mut reversed = walked.clone();
+        reversed.reverse();
+        let ordered = order_parents_first(&test.repo, reversed);
+        assert_eq!(ordered.len(), all.len());
+        assert_parents_first(&test.repo, &ordered);
+
+        // Only some of them out of place: a2 and m come before their parents
+        let ordered = order_parents_first(&test.repo, vec![a2, a1, b1, m, b2, c]);
+        assert_eq!(ordered.len(), all.len());
+        assert_parents_first(&test.repo, &ordered);
+    }
+}
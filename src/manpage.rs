@@ -0,0 +1,231 @@
+// The manual page, for `git help submerge` and `man git-submerge`. The list of options is taken
+// from the --help output, so it can't go out of date; everything else is written here.
+
+use std::io::Write;
+
+const HEADER: &'static str = r#".TH GIT-SUBMERGE 1 "" "git-submerge 0.5" "Git Manual"
+.SH NAME
+git-submerge \- merge a Git submodule into the main repository, as if they had never been separate
+.SH SYNOPSIS
+.B git submerge
+[\fIOPTIONS\fR] \fISUBMODULE_DIR\fR
+.br
+.B git submerge verify
+\fISUBMODULE_DIR\fR
+.br
+.B git submerge promote
+[\fB\-\-from\fR \fINAMESPACE\fR] \fISUBMODULE_DIR\fR
+.br
+.B git submerge migrate\-clone
+[\fB\-\-commit\-map\fR \fIFILE\fR] [\fB\-\-remote\fR \fINAME\fR] [\fB\-\-no\-fetch\fR]
+\fISUBMODULE_DIR\fR
+.br
+.B git submerge
+[\fIOPTIONS\fR]
+.B import
+\fISUBMODULE_DIR\fR
+.br
+.B git submerge
+[\fIOPTIONS\fR]
+.B stitch
+[\fB\-\-import\-map\fR \fIFILE\fR] \fISUBMODULE_DIR\fR
+.SH DESCRIPTION
+Rewrites the history of the repository so that the submodule at \fISUBMODULE_DIR\fR becomes an
+ordinary directory. The submodule's own history becomes part of the repository's history: each
+commit that updated the submodule is turned into a merge of the corresponding submodule commit.
+.PP
+Branches are moved to the rewritten history; their old tips are kept under
+\fBrefs/submerge/backup/\fR. The commit map, which pairs each old commit with its rewritten
+counterpart, is written to \fB.git/submerge/\fISUBMODULE_DIR\fB/commit-map\fR, and is what
+\fBgit submerge verify\fR uses to check the result later on. Next to it, \fBstate.json\fR records
+the version of git-submerge, the options and mappings, the start and end times, and the exit status
+of the run.
+.PP
+Like any other history rewrite, this changes the IDs of the commits, so it shouldn't be done to
+published history without coordinating with everyone who uses it. Once the rewritten history is
+pushed, \fBgit submerge migrate\-clone\fR brings each of the other clones over to it: it fetches
+the new history, moves the local branches according to the commit map of the run that did the
+rewrite, removes the submodule's config, and updates the index and the working directory.
+.SH STRATEGIES
+By default, the whole history is rewritten. There are a few ways to limit that:
+.TP
+.B \-\-tip\-only
+Don't rewrite anything; merge the submodule's history on top of the current branches instead.
+.TP
+.B \-\-rev\-range
+Only rewrite the given range of commits.
+.TP
+.B \-\-preserve\-gitlinks\-before
+Leave older commits as they are, and only inline the submodule in the newer ones.
+.TP
+.B \-\-no\-modify\-refs
+Put the rewritten history into new branches (see \fB\-\-result\-prefix\fR and
+\fB\-\-result\-suffix\fR) instead of moving the existing ones.
+.TP
+.B \-\-output\-refs\-namespace
+Put all of the rewritten refs into one namespace, to be reviewed and then moved into place with
+\fBgit submerge promote\fR.
+.TP
+.B \-\-output
+Leave the repository as it is, and put the rewritten refs, with just the objects they need, into
+a new repository.
+.TP
+.B import
+Only rewrite the submodule's history, putting it under \fBrefs/submerge/imported/\fR and the map
+of its commits into \fB.git/submerge/\fISUBMODULE_DIR\fB/import\-map\fR, and leave the main
+history to be stitched together some other way.
+.TP
+.B stitch
+Rewrite the main history using the result of an earlier \fBimport\fR, which might've been done
+on another machine; fetch \fBrefs/submerge/imported/\fR from there, and copy the import map.
+.SH MAPPINGS
+A commit of the main repository might reference a submodule commit that can't be found in the
+submodule's history, usually because that history was rewritten after the fact. Such dangling
+references stop git-submerge before it changes anything, and are listed along with the commits
+that contain them.
+.PP
+\fB\-\-mapping\fR \fIOLD\fR \fINEW\fR makes git-submerge use submodule commit \fINEW\fR wherever
+the repository references \fIOLD\fR. \fB\-\-default\-mapping\fR \fINEW\fR does that for every
+dangling reference that isn't mentioned in any \fB\-\-mapping\fR. \fB\-\-override\fR replaces the
+submodule commit of one particular commit of the main repository. In all three, \fBempty\fR can be
+used in place of a commit ID; the submodule's directory is then left empty.
+.PP
+A repository with several submodules can keep the mappings for all of them in one place, and merge
+them one at a time: prefix the mapped commit (or the target of \fB\-\-default\-mapping\fR) with the
+path of its submodule, e.g. \fB\-\-mapping vendor/foo:\fIOLD\fR \fINEW\fR. Mappings for other
+submodules are ignored, and aren't checked against the history of this one.
+.PP
+If the missing commits still exist elsewhere, e.g. in a fork, \fB\-\-url\fR and
+\fB\-\-external\-repo\fR can fetch them from there instead.
+"#;
+
+const FOOTER: &'static str = r#".SH ENVIRONMENT
+Every option can also be set through an environment variable named after it, e.g.
+\fBGIT_SUBMERGE_DEFAULT_MAPPING\fR for \fB\-\-default\-mapping\fR. Flags are enabled by any value
+other than "", "0", "false" and "no". Options given on the command line take precedence.
+.PP
+Options that are set neither on the command line nor in the environment are looked up in Git's
+config as \fBsubmerge.\fIoption\fR, e.g. \fBsubmerge.default\-mapping\fR. Flags take Git's boolean
+values there, and repeated options can be set several times.
+.SH EXIT STATUS
+.TP
+.B 0
+Success.
+.TP
+.B 1
+The current directory isn't a Git repository.
+.TP
+.B 2
+The repository references submodule commits that couldn't be found; see \fBMAPPINGS\fR.
+.TP
+.B 3
+A commit ID or a range of commits couldn't be resolved.
+.TP
+.B 4
+The mappings are invalid: they reference unknown commits, contradict each other, or form a cycle.
+.TP
+.B 5
+The working directory has uncommitted changes.
+.TP
+.B 6
+The submodule's history couldn't be fetched (or, for \fBmigrate\-clone\fR, the rewritten one).
+.TP
+.B 7
+There's no submodule at \fISUBMODULE_DIR\fR.
+.TP
+.B 8
+The history was rewritten, but the submodule's directory doesn't match the rewritten HEAD.
+.TP
+.B 9
+The renamed submodule tags would overwrite existing ones.
+.TP
+.B 10
+An environment variable or an option has an invalid value.
+.TP
+.B 11
+\fBgit submerge verify\fR found problems.
+.TP
+.B 12
+The user quit \fB\-\-interactive\fR without rewriting anything.
+.TP
+.B 13
+The value of \fB\-\-committer\fR or \fB\-\-committer\-date\fR is invalid.
+.TP
+.B 14
+Another process is using the repository, e.g. another git-submerge, which holds
+\fB.git/submerge/lock\fR while it runs.
+.TP
+.B 15
+The namespace for archived branches is already in use.
+.TP
+.B 16
+git-submerge stopped because it would've used more memory than \fB\-\-max\-memory\fR allows.
+.TP
+.B 17
+\fBgit submerge promote\fR found no refs to promote.
+.TP
+.B 18
+Some commits have files of their own in \fISUBMODULE_DIR\fR after it became a submodule; see
+\fB\-\-existing\-content\fR.
+.TP
+.B 19
+The submodule's history contains commits with a gitlink at \fISUBMODULE_DIR\fR itself.
+.TP
+.B 20
+The rewrite completed, but some commits, branches or tags couldn't be read along the way, so the
+result might be incomplete. The warnings are repeated at the end of the output.
+.TP
+.B 21
+With \fB\-\-strict\fR, some commits, branches or tags couldn't be read, so no refs were modified.
+.TP
+.B 22
+Some commit messages are in an encoding other than UTF-8 and couldn't be written back as they are,
+so nothing was rewritten. The commits are listed along with the reason.
+.TP
+.B 23
+The run would've moved refs protected by \fB\-\-protected\-ref\fR or \fBsubmerge.protectedRef\fR
+to history that doesn't contain their current tips, so no refs were modified; see
+\fB\-\-rewrite\-protected\fR.
+.TP
+.B 24
+Nothing to do: the submodule is already merged, i.e. \fISUBMODULE_DIR\fR is an ordinary directory at
+HEAD and an earlier run left its commit map. Nothing was changed. Unlike 0, this means that no
+history was rewritten by this run.
+.TP
+.B 25
+The history was rewritten, but the repository at the path given to \fB\-\-output\fR couldn't be
+written. The results are left in \fBrefs/submerge/output/\fR.
+.SH SEE ALSO
+.BR git-submodule (1),
+.BR git-filter-branch (1)
+"#;
+
+pub fn print(app: &::clap::App) {
+    let mut help = Vec::new();
+    app.write_help(&mut help).expect("Couldn't format the list of options");
+    let help = String::from_utf8_lossy(&help);
+
+    let mut page = String::from(HEADER);
+    page.push_str(".SH OPTIONS\n.nf\n");
+    // The name, version and description are already in the header
+    for line in help.lines().skip_while(|line| !line.starts_with("USAGE:")) {
+        page.push_str(&escape(line));
+        page.push('\n');
+    }
+    page.push_str(".fi\n");
+    page.push_str(FOOTER);
+
+    let stdout = ::std::io::stdout();
+    stdout.lock().write_all(page.as_bytes()).expect("Couldn't write the manual page");
+}
+
+// Backslashes start escape sequences in roff, and so do dots and apostrophes at the start of
+// a line
+fn escape(line: &str) -> String {
+    let line = line.replace('\\', "\\e");
+    if line.starts_with('.') || line.starts_with('\'') {
+        String::from("\\&") + &line
+    } else {
+        line
+    }
+}
@@ -0,0 +1,255 @@
+// `git submerge migrate-clone': once the history is rewritten (on a server, or in someone else's
+// clone) and pushed, every other clone needs the last few steps of a merge, without the rewrite
+// itself: fetch the new history, move the local branches over to it using the published commit
+// map, forget the submodule's config, and bring the index and the working directory up to date.
+//
+// Only branches whose tips are in the commit map are moved. Those with commits of their own on top
+// of the old history can't be moved without rewriting these commits too, so we just say how to
+// rebase them.
+
+use git2::{Repository, Oid};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::{Options, IndexLock, E_CONCURRENT_ACCESS, E_DIRTY_WORKDIR, E_INVALID_ENVIRONMENT,
+            E_NOTHING_TO_DO, E_PROTECTED_REFS, E_SUBMODULE_FETCH_FAILED, E_SUCCESS,
+            E_WORKDIR_MISMATCH, get_backup_namespace, get_branch_to_id_map, get_commit_map_path,
+            get_dirty_paths, get_status_letters, is_submodule_dir_consistent,
+            may_move_protected_refs, remove_dotgit_from_submodule, remove_gitmodules,
+            update_index};
+
+pub fn migrate_clone(repo: &Repository, options: &Options) -> i32 {
+    let submodule_dir = &options.submodule_dir;
+
+    let dirty_paths = if options.no_update_worktree {
+        Vec::new()
+    } else {
+        get_dirty_paths(repo, submodule_dir)
+    };
+    if !dirty_paths.is_empty() {
+        eprintln!("The working directory is dirty, aborting! These paths have changes:");
+        for &(ref path, status) in &dirty_paths {
+            eprintln!("    {} {}", get_status_letters(status), path);
+        }
+        eprintln!("Please commit or stash them (e.g. with `git stash') and try again.");
+        return E_DIRTY_WORKDIR;
+    }
+
+    let old_id_to_new = match read_commit_map(repo, options) {
+        Ok(map) => map,
+        Err(message) => {
+            eprintln!("{}", message);
+            return E_INVALID_ENVIRONMENT;
+        }
+    };
+    let new_ids: HashSet<Oid> = old_id_to_new.values().cloned().collect();
+
+    if let Some(ref name) = options.migration_remote {
+        let result = repo.find_remote(name).and_then(|mut remote| remote.fetch(&[], None, None));
+        if let Err(e) = result {
+            eprintln!("Couldn't fetch the rewritten history from {}: {}", name, e.message());
+            return E_SUBMODULE_FETCH_FAILED;
+        }
+    }
+
+    let index_lock = if options.lock {
+        match IndexLock::acquire(repo) {
+            Ok(lock) => Some(lock),
+            Err(_) => return E_CONCURRENT_ACCESS,
+        }
+    } else {
+        None
+    };
+
+    let mut branches: Vec<(String, Oid)> =
+        get_branch_to_id_map(repo, options).into_iter().collect();
+    branches.sort();
+
+    // Branch, its tip, and where it goes
+    let mut moves: Vec<(String, Oid, Oid)> = Vec::new();
+    // Branch, and the newest of its commits that were rewritten
+    let mut stranded: Vec<(String, Oid)> = Vec::new();
+    let mut missing = Vec::new();
+    for (name, tip) in branches {
+        if new_ids.contains(&tip) {
+            // Already there, e.g. this is the second run
+            continue;
+        }
+        match old_id_to_new.get(&tip) {
+            Some(&new_id) if repo.find_commit(new_id).is_ok() => moves.push((name, tip, new_id)),
+            Some(&new_id) => missing.push((name, new_id)),
+            None => {
+                if let Some(base) = find_rewritten_base(repo, tip, &old_id_to_new) {
+                    stranded.push((name, base));
+                }
+            }
+        }
+    }
+
+    let ref_moves: Vec<(String, Oid, Oid)> = moves.iter()
+        .map(|&(ref name, tip, new_id)| (format!("refs/heads/{}", name), tip, new_id))
+        .collect();
+    if !may_move_protected_refs(repo, &ref_moves, options) {
+        return E_PROTECTED_REFS;
+    }
+
+    let namespace = get_backup_namespace(submodule_dir);
+    for &(ref name, tip, new_id) in &moves {
+        repo.reference(&format!("{}{}", namespace, name),
+                       tip,
+                       true,
+                       "git-submerge: backing up the branch")
+            .expect("Couldn't back up a branch");
+        repo.reference_matching(&format!("refs/heads/{}", name),
+                                new_id,
+                                true,
+                                tip,
+                                "git-submerge: moving the branch to the rewritten history")
+            .expect(&format!("Couldn't move branch {}", name));
+        println!("Moved {} to the rewritten history; the old tip is backed up as {}{}.",
+                 options.colors.branch(name),
+                 namespace,
+                 name);
+    }
+    if !missing.is_empty() {
+        eprintln!("The rewritten counterparts of these branches aren't in the repository; fetch \
+                   them (see --remote) and try again:");
+        for &(ref name, new_id) in &missing {
+            eprintln!("    {} (should be {})", name, new_id);
+        }
+    }
+    if !stranded.is_empty() {
+        eprintln!("These branches have commits that aren't in the commit map, i.e. that were made \
+                   on top of the old history. Rebase them onto the new one:");
+        for &(ref name, base) in &stranded {
+            eprintln!("    git rebase --onto {} {} {}", old_id_to_new[&base], base, name);
+        }
+    }
+
+    // The working directory only has to change if HEAD is on one of the branches we just moved
+    let head_branch = repo.find_reference("HEAD")
+        .ok()
+        .and_then(|head| head.symbolic_target().map(String::from));
+    let is_head_moved = head_branch.map_or(false, |branch| {
+        ref_moves.iter().any(|&(ref name, _, _)| *name == branch)
+    });
+    if !is_head_moved || options.no_update_worktree {
+        if moves.is_empty() && stranded.is_empty() && missing.is_empty() {
+            eprintln!("All branches are already on the rewritten history. Nothing to do.");
+            return E_NOTHING_TO_DO;
+        }
+        return E_SUCCESS;
+    }
+
+    forget_submodule_config(repo, options);
+
+    // Same as at the end of a merge, see real_main(), except that the submodule might've never
+    // been checked out in this clone
+    let is_checked_out = Path::new(submodule_dir).join(".git").exists();
+    let no_map = HashMap::new();
+    let is_consistent = !is_checked_out ||
+                        is_submodule_dir_consistent(repo, submodule_dir, &no_map);
+    remove_dotgit_from_submodule(repo, options);
+    drop(index_lock);
+    update_index(repo, &no_map);
+    if !is_checked_out {
+        let mut checkout = ::git2::build::CheckoutBuilder::new();
+        checkout.force().path(submodule_dir);
+        repo.checkout_head(Some(&mut checkout))
+            .expect(&format!("Couldn't check out {}", submodule_dir));
+    }
+    let has_gitmodules = repo.head()
+        .and_then(|head| head.peel(::git2::ObjectType::Tree))
+        .map(|tree| {
+            tree.as_tree().map_or(false, |tree| tree.get_path(Path::new(".gitmodules")).is_ok())
+        })
+        .expect("Couldn't obtain the tree of HEAD");
+    if Path::new(".gitmodules").exists() && !has_gitmodules {
+        remove_gitmodules();
+    }
+
+    if !is_consistent {
+        return E_WORKDIR_MISMATCH;
+    }
+    E_SUCCESS
+}
+
+// The map that the run which did the rewrite left in its .git/submerge/, or wherever it was
+// published; the same format either way
+fn read_commit_map(repo: &Repository, options: &Options) -> Result<HashMap<Oid, Oid>, String> {
+    let path = options.migration_commit_map
+        .as_ref()
+        .map_or(get_commit_map_path(repo, &options.submodule_dir),
+                |path| Path::new(path).to_path_buf());
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| {
+            format!("Couldn't read the commit map from {}: {}. Point --commit-map at the one \
+                     published along with the rewritten history.",
+                    path.display(),
+                    e)
+        })?;
+
+    let mut map = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let ids: Vec<Oid> =
+            line.split(' ').take(2).filter_map(|id| Oid::from_str(id).ok()).collect();
+        if ids.len() != 2 {
+            return Err(format!("{}:{}: expected two commit IDs, got `{}'",
+                               path.display(),
+                               number + 1,
+                               line));
+        }
+        map.insert(ids[0], ids[1]);
+    }
+    Ok(map)
+}
+
+// The newest commit of the branch that the rewrite knew about, i.e. where the branch's own commits
+// start. None if the branch doesn't share any history with the rewritten one.
+fn find_rewritten_base(repo: &Repository,
+                       tip: Oid,
+                       old_id_to_new: &HashMap<Oid, Oid>)
+                       -> Option<Oid> {
+    let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
+    revwalk.set_sorting(::git2::SORT_TOPOLOGICAL);
+    revwalk.push(tip).expect("Couldn't add branch's tip to RevWalk");
+    revwalk.filter_map(|id| id.ok()).find(|id| old_id_to_new.contains_key(id))
+}
+
+// `git submodule init' copied the submodule's URL into .git/config, and `git submodule update'
+// looks there first, so it would keep trying to check the submodule out
+fn forget_submodule_config(repo: &Repository, options: &Options) {
+    let name = repo.find_submodule(&options.submodule_dir)
+        .ok()
+        .and_then(|submodule| submodule.name().map(String::from))
+        .unwrap_or(options.submodule_dir.clone());
+    let prefix = format!("submodule.{}.", name);
+    let mut config = match repo.config() {
+        Ok(config) => config,
+        Err(_) => return,
+    };
+    let keys: Vec<String> = match config.entries(Some("^submodule\\.")) {
+        Ok(entries) => {
+            entries.filter_map(|entry| entry.ok().and_then(|entry| entry.name().map(String::from)))
+                .filter(|key| key.starts_with(&prefix))
+                .collect()
+        }
+        Err(_) => return,
+    };
+    for key in &keys {
+        if let Err(e) = config.remove(key) {
+            eprintln!("Couldn't remove {} from the config: {}", key, e.message());
+        }
+    }
+
+    let modules_dir = repo.path().join("modules").join(&name);
+    if modules_dir.exists() {
+        eprintln!("The submodule's own repository is still in {}; remove it once you don't need \
+                   it anymore.",
+                  modules_dir.display());
+    }
+}
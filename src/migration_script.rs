@@ -0,0 +1,174 @@
+// With --emit-migration-script, git-submerge also writes a script for everyone else who has
+// a clone of the repo. Once the rewritten history is pushed, they run it in their clones, and it
+// does what `git submerge migrate-clone' does, but with nothing but Git and a POSIX shell: it
+// fetches the new history and moves the local branches over. It also rebases the branches that
+// have commits of their own on top of the old history, and the stashes, which migrate-clone leaves
+// to the user.
+//
+// The commit map is embedded in the script, so the script is all that has to be handed out.
+
+use git2::{Repository, Oid};
+use std::collections::HashMap;
+use std::io::Write;
+
+use super::Options;
+use super::script::{make_executable, quote};
+
+const SCRIPT: &'static str = r#"#!/bin/sh
+# Moves this clone over to the history in which git-submerge merged submodule @SUBMODULE_DIR@
+# into the repository: fetches the rewritten history, moves the local branches to it, rebases the
+# ones with commits of their own onto it, and does the same to the stashes.
+#
+# Usage: run it from anywhere in the clone, once the rewritten history is pushed; give it the name
+# of the remote to fetch from if that's not "origin". It can be run again, e.g. after resolving
+# a conflict, and then only does what's left.
+set -e
+
+remote=${1:-origin}
+submodule_dir=@SUBMODULE_DIR@
+cd "$(git rev-parse --show-toplevel)"
+git_dir=$(git rev-parse --git-dir)
+
+if ! git diff --quiet --ignore-submodules HEAD -- || [ -d "$git_dir/rebase-merge" ] ||
+   [ -d "$git_dir/rebase-apply" ]; then
+    echo "There are uncommitted changes, or a rebase in progress. Commit or stash the changes, or" \
+         "finish the rebase, and run this script again." >&2
+    exit 1
+fi
+
+map=$(mktemp)
+tmp_index=$(mktemp)
+trap 'rm -f "$map" "$tmp_index"' EXIT
+# Old commit, then its rewritten counterpart
+cat > "$map" <<'END_OF_MAP'
+@MAP@
+END_OF_MAP
+
+lookup() {
+    awk -v id="$1" '$1 == id { print $2; exit }' "$map"
+}
+
+# The newest commit of $1 that was rewritten, and its counterpart
+find_base() {
+    git rev-list --topo-order "$1" |
+        awk 'NR == FNR { new[$1] = $2; next } ($1 in new) { print $1, new[$1]; exit }' "$map" -
+}
+
+git fetch "$remote"
+
+head_ref=$(git symbolic-ref -q HEAD || true)
+head=$(git rev-parse HEAD)
+new_head=$(lookup "$head")
+if [ -z "$new_head" ]; then
+    new_head=$(find_base "$head" | cut -d' ' -f2)
+fi
+
+# The working directory has to look like the rewritten history before anything can be rebased:
+# the submodule becomes an ordinary directory
+if [ -n "$new_head" ]; then
+    if [ -d "$submodule_dir/.git" ]; then
+        mkdir -p "$git_dir/submerge/$submodule_dir"
+        mv "$submodule_dir/.git" "$git_dir/submerge/$submodule_dir/submodule.git"
+    else
+        rm -f "$submodule_dir/.git"
+    fi
+    git checkout -q -f --detach "$new_head"
+    git config --remove-section @SUBMODULE_SECTION@ 2>/dev/null || true
+fi
+
+for ref in $(git for-each-ref --format='%(refname)' refs/heads/); do
+    branch=${ref#refs/heads/}
+    tip=$(git rev-parse "$ref")
+    new=$(lookup "$tip")
+    if [ -n "$new" ]; then
+        git update-ref -m "migrating to the rewritten history" "$ref" "$new" "$tip"
+        echo "Moved $branch to the rewritten history."
+        continue
+    fi
+    base=$(find_base "$ref")
+    if [ -n "$base" ]; then
+        echo "Rebasing $branch onto the rewritten history..."
+        if ! git rebase -q --onto "${base#* }" "${base% *}" "$branch"; then
+            echo "Resolve the conflicts, run \`git rebase --continue', and then run this script" \
+                 "again." >&2
+            exit 1
+        fi
+    fi
+done
+
+# A stash is a commit of the working directory, with the commit it was made on and the commit of
+# the index as its parents (and the untracked files as the third one, if they were stashed too).
+# The first two are replayed onto the rewritten history; the untracked files stay as they are.
+replay() {
+    GIT_INDEX_FILE=$tmp_index git read-tree "$3" &&
+    if ! git diff --quiet "$1" "$2" -- . ":(exclude)$submodule_dir"; then
+        git diff --binary "$1" "$2" -- . ":(exclude)$submodule_dir" |
+            GIT_INDEX_FILE=$tmp_index git apply --cached
+    fi &&
+    GIT_INDEX_FILE=$tmp_index git write-tree
+}
+stashes=$(git rev-list -g refs/stash 2>/dev/null || true)
+if [ -n "$stashes" ]; then
+    stash_list=""
+    changed=""
+    for stash in $stashes; do
+        message=$(git log -1 --format=%s "$stash")
+        base=$(git rev-parse "$stash^1")
+        new_base=$(lookup "$base")
+        if [ -n "$new_base" ] &&
+           index_tree=$(replay "$base" "$stash^2" "$new_base") &&
+           tree=$(replay "$base" "$stash" "$new_base"); then
+            index=$(git commit-tree "$index_tree" -p "$new_base" -m "index on $message")
+            untracked=$(git rev-parse -q --verify "$stash^3" || true)
+            stash=$(git commit-tree "$tree" -p "$new_base" -p "$index" \
+                    ${untracked:+-p "$untracked"} -m "$message")
+            changed=yes
+        elif [ -n "$new_base" ]; then
+            echo "Couldn't move the stash \"$message\" to the rewritten history; it's kept as it" \
+                 "is." >&2
+        fi
+        stash_list="$stash $stash_list"
+    done
+    if [ -n "$changed" ]; then
+        git update-ref -d refs/stash
+        for stash in $stash_list; do
+            git stash store -m "$(git log -1 --format=%s "$stash")" "$stash"
+        done
+        echo "Moved the stashes to the rewritten history."
+    fi
+fi
+
+if [ -n "$head_ref" ]; then
+    git checkout -q "${head_ref#refs/heads/}"
+fi
+echo "Done. The old branches can still be found in the reflog."
+"#;
+
+pub fn write(repo: &Repository,
+             path: &str,
+             old_id_to_new: &HashMap<Oid, Oid>,
+             options: &Options) {
+    let mut map: Vec<String> = old_id_to_new.iter()
+        .filter(|&(old_id, new_id)| old_id != new_id)
+        .map(|(old_id, new_id)| format!("{} {}", old_id, new_id))
+        .collect();
+    map.sort();
+
+    // Config sections are named after the submodule, which is usually, but not always, its path
+    let name = repo.find_submodule(&options.submodule_dir)
+        .ok()
+        .and_then(|submodule| submodule.name().map(String::from))
+        .unwrap_or(options.submodule_dir.clone());
+    let script = SCRIPT.replace("@SUBMODULE_DIR@", &quote(&options.submodule_dir))
+        .replace("@SUBMODULE_SECTION@", &quote(&format!("submodule.{}", name)))
+        .replace("@MAP@", &map.join("\n"));
+
+    let mut file = ::std::fs::File::create(path).expect(&format!("Couldn't create {}", path));
+    file.write_all(script.as_bytes())
+        .expect(&format!("Couldn't write the migration script to {}", path));
+    make_executable(path);
+
+    println!("Wrote a script for the other clones of the repo to {}. Hand it out once the \
+              rewritten history is pushed.",
+             path);
+}
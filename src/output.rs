@@ -0,0 +1,93 @@
+// With --output, the repo itself is left alone: the rewritten refs are pushed into a new repository
+// instead, along with the objects they need and nothing else, and the original can be thrown away
+// (or kept for reference) once the result is checked. That's the safest way to do a one-shot
+// migration, and what git-filter-repo recommends to do with a fresh clone.
+//
+// The rewrite is done into NAMESPACE, like --output-refs-namespace does, and with --quarantine, so
+// once the refs are pushed and removed, the objects are simply discarded.
+
+use git2::Repository;
+use std::path::Path;
+
+use super::{Options, get_commit_map_path};
+
+pub const NAMESPACE: &'static str = "refs/submerge/output/";
+
+// The new repo is created right where the user asked, so nothing should be there yet
+pub fn check_path(path: &str) -> Result<(), String> {
+    match Path::new(path).read_dir() {
+        Ok(mut entries) => {
+            if entries.next().is_some() {
+                Err(format!("{} already exists and is not empty", path))
+            } else {
+                Ok(())
+            }
+        }
+        Err(_) if Path::new(path).exists() => Err(format!("{} is not a directory", path)),
+        Err(_) => Ok(()),
+    }
+}
+
+pub fn write_repo(repo: &Repository, path: &str, options: &Options) -> Result<(), String> {
+    let mut refspecs = Vec::new();
+    let references = repo.references_glob(&format!("{}*", NAMESPACE))
+        .map_err(|e| format!("Couldn't list the rewritten refs: {}", e.message()))?;
+    for reference in references.filter_map(|r| r.ok()) {
+        let name = String::from(reference.name().expect("Ref name is not valid UTF-8"));
+        refspecs.push(format!("+{}:refs/{}", name, &name[NAMESPACE.len()..]));
+    }
+
+    // libgit2 can only push into bare repos, so that's what it is until the push is done
+    let git_dir = Path::new(path).join(".git");
+    let destination = git_dir.to_str().ok_or_else(|| format!("{} is not valid UTF-8", path))?;
+    Repository::init_bare(&git_dir)
+        .map_err(|e| format!("Couldn't create a repository at {}: {}", path, e.message()))?;
+    repo.remote_anonymous(destination)
+        .and_then(|mut remote| {
+            let refspecs: Vec<&str> = refspecs.iter().map(|refspec| &refspec[..]).collect();
+            remote.push(&refspecs, None)
+        })
+        .map_err(|e| format!("Couldn't push the rewritten refs into {}: {}", path, e.message()))?;
+    let output = Repository::open(&git_dir)
+        .and_then(|output| output.config())
+        .and_then(|mut config| config.set_bool("core.bare", false))
+        .and_then(|_| Repository::open(path))
+        .map_err(|e| format!("Couldn't open the repository at {}: {}", path, e.message()))?;
+
+    // The same branch as in the original is checked out, if it's there
+    let head = repo.find_reference("HEAD")
+        .ok()
+        .and_then(|head| head.symbolic_target().map(String::from));
+    match head {
+        Some(ref head) if output.find_reference(head).is_ok() => {
+            output.set_head(head)
+                .and_then(|_| {
+                    let mut checkout = ::git2::build::CheckoutBuilder::new();
+                    checkout.force();
+                    output.checkout_head(Some(&mut checkout))
+                })
+                .map_err(|e| {
+                    format!("Couldn't check out {} in {}: {}", head, path, e.message())
+                })?;
+        }
+        _ => {}
+    }
+
+    // The commit map is what collaborators need to move their work over to the new history
+    let commit_map = get_commit_map_path(repo, &options.submodule_dir);
+    let output_commit_map = get_commit_map_path(&output, &options.submodule_dir);
+    output_commit_map.parent()
+        .map_or(Ok(()), ::std::fs::create_dir_all)
+        .and_then(|_| ::std::fs::copy(&commit_map, &output_commit_map))
+        .map_err(|e| format!("Couldn't copy the commit map into {}: {}", path, e))?;
+
+    let branches = output.references_glob("refs/heads/*").map(|refs| refs.count()).unwrap_or(0);
+    let tags = output.references_glob("refs/tags/*").map(|refs| refs.count()).unwrap_or(0);
+    println!("Wrote the rewritten history into {}: {} branch(es), {} tag(s) and {} other ref(s). \
+              The repository itself wasn't changed.",
+             path,
+             branches,
+             tags,
+             refspecs.len() - branches - tags);
+    Ok(())
+}
@@ -0,0 +1,129 @@
+// With --quarantine, every object the run writes or fetches goes to .git/submerge/quarantine/
+// instead of .git/objects, much like receive-pack quarantines the objects of a push. A run that
+// fails before any ref points at them leaves .git/objects exactly as it was: the quarantine is
+// simply removed. Otherwise, the objects are moved over into .git/objects.
+//
+// libgit2 can't be told where to write objects other than through the environment variables Git
+// itself uses, so that's what we do.
+
+use git2::Repository;
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub struct Quarantine {
+    objects_dir: PathBuf,
+    main_objects_dir: PathBuf,
+    git_dir: PathBuf,
+}
+
+impl Quarantine {
+    // Returns the quarantine, and the repo opened so that it writes into it
+    pub fn open(repo: &Repository) -> Result<(Quarantine, Repository), String> {
+        let quarantine = Quarantine {
+            objects_dir: repo.path().join("submerge").join("quarantine"),
+            main_objects_dir: repo.path().join("objects"),
+            git_dir: repo.path().to_path_buf(),
+        };
+
+        // Left behind by a run that crashed, which might've already pointed some refs into it
+        if quarantine.objects_dir.exists() {
+            quarantine.migrate()
+                .map_err(|e| format!("Couldn't move the objects of an earlier run: {}", e))?;
+        }
+        ::std::fs::create_dir_all(quarantine.objects_dir.join("pack"))
+            .map_err(|e| format!("Couldn't create {}: {}", quarantine.objects_dir.display(), e))?;
+
+        // libgit2 only reads these when opening the repo, and they shouldn't stay set for anything
+        // else we might open later (e.g. the submodule)
+        let names = ["GIT_DIR", "GIT_OBJECT_DIRECTORY", "GIT_ALTERNATE_OBJECT_DIRECTORIES"];
+        let saved: Vec<(&str, Option<OsString>)> =
+            names.iter().map(|name| (*name, env::var_os(name))).collect();
+        env::set_var("GIT_DIR", &quarantine.git_dir);
+        env::set_var("GIT_OBJECT_DIRECTORY", &quarantine.objects_dir);
+        env::set_var("GIT_ALTERNATE_OBJECT_DIRECTORIES", &quarantine.main_objects_dir);
+        let result = Repository::open_from_env();
+        for (name, value) in saved {
+            match value {
+                Some(value) => env::set_var(name, value),
+                None => env::remove_var(name),
+            }
+        }
+
+        match result {
+            Ok(quarantined_repo) => Ok((quarantine, quarantined_repo)),
+            Err(e) => {
+                Err(format!("Couldn't open the repository with a quarantine: {}", e.message()))
+            }
+        }
+    }
+
+    // Whether any ref points at an object that only exists in the quarantine, so that throwing it
+    // away would break the repo
+    pub fn is_referenced(&self) -> bool {
+        let repo = match Repository::open(&self.git_dir) {
+            Ok(repo) => repo,
+            // Can't tell, so better safe than sorry
+            Err(_) => return true,
+        };
+        let references = match repo.references() {
+            Ok(references) => references,
+            Err(_) => return true,
+        };
+        for reference in references {
+            let target = reference.ok().and_then(|reference| reference.target());
+            if let Some(id) = target {
+                if repo.find_object(id, None).is_err() {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // Moves the objects into .git/objects. Packs go before their indexes, so that nobody sees
+    // an index without its pack.
+    pub fn migrate(&self) -> io::Result<()> {
+        for entry in self.objects_dir.read_dir()? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name.to_str() == Some("pack") {
+                let mut files: Vec<PathBuf> = entry.path()
+                    .read_dir()?
+                    .filter_map(|file| file.ok().map(|file| file.path()))
+                    .collect();
+                files.sort_by_key(|file| file.extension() != Some("pack".as_ref()));
+                move_files(&files, &self.main_objects_dir.join("pack"))?;
+            } else if name.len() == 2 && entry.path().is_dir() {
+                let files: Vec<PathBuf> = entry.path()
+                    .read_dir()?
+                    .filter_map(|file| file.ok().map(|file| file.path()))
+                    .collect();
+                move_files(&files, &self.main_objects_dir.join(&name))?;
+            }
+        }
+        ::std::fs::remove_dir_all(&self.objects_dir)
+    }
+
+    pub fn discard(&self) -> io::Result<()> {
+        ::std::fs::remove_dir_all(&self.objects_dir)
+    }
+}
+
+// Objects are named after their contents, so if one is already there, it's the same one
+fn move_files(files: &[PathBuf], destination: &Path) -> io::Result<()> {
+    ::std::fs::create_dir_all(destination)?;
+    for file in files {
+        let target = match file.file_name() {
+            Some(name) => destination.join(name),
+            None => continue,
+        };
+        if target.exists() {
+            ::std::fs::remove_file(file)?;
+        } else {
+            ::std::fs::rename(file, &target)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,325 @@
+// libgit2 re-parses signatures before writing them back, and some of them don't survive that: an
+// offset of "-0000" becomes "+0000", offsets it considers impossible (like "+2400") become zero,
+// timestamps are truncated to 32 bits, and whitespace around the name is trimmed. Git itself
+// writes such signatures just fine (e.g. `git commit-tree` with GIT_AUTHOR_DATE), so they do turn
+// up in real histories, and a rewrite shouldn't be the thing that loses them.
+//
+// So before a commit is recreated, we check whether libgit2 would write its signature lines back
+// as they are. If it wouldn't, we put the commit together ourselves, with the original lines copied
+// byte for byte. git2 can't write raw objects, but libgit2 can, so we call it directly.
+
+use git2::{self, Repository, Commit, Signature, Tree, Oid};
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+use std::ptr;
+
+// git2 doesn't give us libgit2's object database, so we declare what we need of it ourselves
+enum GitRepository {}
+enum GitOdb {}
+
+extern "C" {
+    fn git_repository_open(out: *mut *mut GitRepository, path: *const c_char) -> c_int;
+    fn git_repository_free(repo: *mut GitRepository);
+    fn git_repository_odb(out: *mut *mut GitOdb, repo: *mut GitRepository) -> c_int;
+    fn git_odb_open(out: *mut *mut GitOdb, objects_dir: *const c_char) -> c_int;
+    fn git_odb_write(out: *mut u8,
+                     odb: *mut GitOdb,
+                     data: *const c_void,
+                     len: usize,
+                     kind: c_int)
+                     -> c_int;
+    fn git_odb_free(odb: *mut GitOdb);
+}
+
+// From git2/types.h
+const GIT_OBJ_COMMIT: c_int = 1;
+
+// Whether libgit2 writes the author (and, unless `keep_committer` is false, the committer) of
+// `original` back just as they are
+pub fn are_signatures_intact(original: &Commit, keep_committer: bool) -> bool {
+    let is_intact = |header: &str, signature: &Signature| {
+        find_header(original, header.as_bytes()) == Some(&format_signature(header, signature)[..])
+    };
+    is_intact("author ", &original.author()) &&
+    (!keep_committer || is_intact("committer ", &original.committer()))
+}
+
+// Writes a commit with the author of `original`, copied byte for byte. So is the committer, unless
+// another one is given.
+pub fn write_commit(repo: &Repository,
+                    original: &Commit,
+                    committer: Option<&Signature>,
+                    message: &str,
+                    encoding: Option<&str>,
+                    tree: &Tree,
+                    parents: &[&Commit],
+                    quarantine: bool)
+                    -> Result<Oid, git2::Error> {
+    let mut content = format!("tree {}\n", tree.id()).into_bytes();
+    for parent in parents {
+        content.extend_from_slice(format!("parent {}\n", parent.id()).as_bytes());
+    }
+    let author = find_header(original, b"author ")
+        .map(|line| line.to_vec())
+        .unwrap_or_else(|| format_signature("author ", &original.author()));
+    content.extend_from_slice(&author);
+    content.push(b'\n');
+    let committer = match committer {
+        Some(committer) => format_signature("committer ", committer),
+        None => {
+            find_header(original, b"committer ")
+                .map(|line| line.to_vec())
+                .unwrap_or_else(|| format_signature("committer ", &original.committer()))
+        }
+    };
+    content.extend_from_slice(&committer);
+    content.push(b'\n');
+    if let Some(encoding) = encoding {
+        content.extend_from_slice(format!("encoding {}\n", encoding).as_bytes());
+    }
+    content.push(b'\n');
+    content.extend_from_slice(message.as_bytes());
+    write_object(repo, &content, quarantine)
+}
+
+// The signature line the way libgit2 writes it, without the newline
+fn format_signature(header: &str, signature: &Signature) -> Vec<u8> {
+    let when = signature.when();
+    let offset = when.offset_minutes();
+    let mut line = header.as_bytes().to_vec();
+    line.extend_from_slice(signature.name_bytes());
+    line.extend_from_slice(b" <");
+    line.extend_from_slice(signature.email_bytes());
+    line.extend_from_slice(format!("> {} {}{:02}{:02}",
+                                   when.seconds() as u32,
+                                   if offset < 0 { '-' } else { '+' },
+                                   offset.abs() / 60,
+                                   offset.abs() % 60)
+        .as_bytes());
+    line
+}
+
+// The first header line of the commit that starts with `header', e.g. b"author "
+pub fn find_header<'a>(commit: &'a Commit, header: &[u8]) -> Option<&'a [u8]> {
+    commit.raw_header_bytes().split(|byte| *byte == b'\n').find(|line| line.starts_with(header))
+}
+
+// Writes a commit with the given contents through libgit2's object database, so that it ends up
+// wherever and however libgit2 writes the rest of our objects
+fn write_object(repo: &Repository, content: &[u8], quarantine: bool) -> Result<Oid, git2::Error> {
+    let mut id = [0u8; 20];
+    unsafe {
+        let mut odb = ptr::null_mut();
+        if quarantine {
+            // With --quarantine, libgit2 writes into the quarantine, and so should we
+            let objects_dir = to_c_string(&repo.path().join("submerge").join("quarantine"))?;
+            check(git_odb_open(&mut odb, objects_dir.as_ptr()))?;
+        } else {
+            let git_dir = to_c_string(repo.path())?;
+            let mut raw_repo = ptr::null_mut();
+            check(git_repository_open(&mut raw_repo, git_dir.as_ptr()))?;
+            // The object database is reference-counted, so it outlives the repo
+            let result = git_repository_odb(&mut odb, raw_repo);
+            git_repository_free(raw_repo);
+            check(result)?;
+        }
+        let result = git_odb_write(id.as_mut_ptr(),
+                                   odb,
+                                   content.as_ptr() as *const c_void,
+                                   content.len(),
+                                   GIT_OBJ_COMMIT);
+        git_odb_free(odb);
+        check(result)?;
+    }
+    Ok(Oid::from_bytes(&id).expect("Object ID is not 20 bytes long"))
+}
+
+fn to_c_string(path: &Path) -> Result<CString, git2::Error> {
+    path.to_str()
+        .and_then(|path| CString::new(path).ok())
+        .ok_or_else(|| git2::Error::from_str(&format!("{} is not valid UTF-8", path.display())))
+}
+
+fn check(code: c_int) -> Result<(), git2::Error> {
+    if code < 0 {
+        Err(git2::Error::last_error(code)
+            .unwrap_or_else(|| git2::Error::from_str("Unknown libgit2 error")))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{Repository, Signature, Time};
+
+    use super::{are_signatures_intact, find_header, write_commit, write_object};
+    use tests::TestRepo;
+
+    // Writes a commit with the given signature lines, and checks that recreating it brings back the
+    // very same commit
+    fn check_round_trip(repo: &Repository, author: &str, committer: &str) {
+        let tree_id = repo.treebuilder(None).unwrap().write().unwrap();
+        let tree = repo.find_tree(tree_id).unwrap();
+        let content = format!("tree {}\n{}\n{}\n\nMessage\n", tree_id, author, committer);
+        let original_id = write_object(repo, content.as_bytes(), false).unwrap();
+        let original = repo.find_commit(original_id).unwrap();
+        let id = write_commit(repo, &original, None, "Message\n", None, &tree, &[], false).unwrap();
+        assert_eq!(id, original_id, "{:?} / {:?} didn't survive", author, committer);
+
+        // If we say libgit2 can be trusted with the lines, it'd better write them back as they are
+        if are_signatures_intact(&original, true) {
+            let id = repo.commit(None,
+                                 &original.author(),
+                                 &original.committer(),
+                                 "Message\n",
+                                 &tree,
+                                 &[])
+                .unwrap();
+            assert_eq!(id, original_id, "libgit2 changed {:?} / {:?}", author, committer);
+        }
+
+        // With another committer, only the author is copied
+        let signature = Signature::new("Someone Else", "else@example.com", &Time::new(0, 0))
+            .unwrap();
+        let id = write_commit(repo,
+                              &original,
+                              Some(&signature),
+                              "Message\n",
+                              None,
+                              &tree,
+                              &[],
+                              false)
+            .unwrap();
+        let commit = repo.find_commit(id).unwrap();
+        assert_eq!(find_header(&commit, b"author "), Some(author.as_bytes()));
+        let libgit2_id = repo.commit(None, &signature, &signature, "Message\n", &tree, &[])
+            .unwrap();
+        let libgit2_commit = repo.find_commit(libgit2_id).unwrap();
+        assert_eq!(find_header(&commit, b"committer "),
+                   find_header(&libgit2_commit, b"committer "));
+    }
+
+    const COMMITTER: &'static str = "committer C O Mitter <c@example.com> 1500000000 +0200";
+
+    #[test]
+    fn negative_zero_offset_survives() {
+        let test = TestRepo::new("negative-zero");
+        check_round_trip(&test.repo,
+                         "author A U Thor <a@example.com> 1500000000 -0000",
+                         COMMITTER);
+    }
+
+    #[test]
+    fn impossible_offset_survives() {
+        let test = TestRepo::new("impossible-offset");
+        check_round_trip(&test.repo,
+                         "author A U Thor <a@example.com> 1500000000 +2400",
+                         COMMITTER);
+    }
+
+    #[test]
+    fn timestamp_past_32_bits_survives() {
+        let test = TestRepo::new("64-bit-timestamp");
+        check_round_trip(&test.repo,
+                         "author A U Thor <a@example.com> 5000000000 +0000",
+                         COMMITTER);
+    }
+
+    #[test]
+    fn angle_bracket_in_name_survives() {
+        let test = TestRepo::new("angle-bracket");
+        check_round_trip(&test.repo,
+                         "author A <U> Thor <a@example.com> 1500000000 +0000",
+                         COMMITTER);
+    }
+
+    #[test]
+    fn parents_and_encoding_survive() {
+        let test = TestRepo::new("parents-and-encoding");
+        let repo = &test.repo;
+        let parent_ids = [test.commit("First", &[]), test.commit("Second", &[])];
+        let tree_id = repo.treebuilder(None).unwrap().write().unwrap();
+        let content = format!("tree {}\nparent {}\nparent {}\n\
+                               author A U Thor <a@example.com> 1500000000 -0000\n{}\n\
+                               encoding ISO-8859-1\n\nMessage\n",
+                              tree_id,
+                              parent_ids[0],
+                              parent_ids[1],
+                              COMMITTER);
+        let original_id = write_object(repo, content.as_bytes(), false).unwrap();
+        let original = repo.find_commit(original_id).unwrap();
+
+        let tree = repo.find_tree(tree_id).unwrap();
+        let parents: Vec<_> = parent_ids.iter().map(|id| repo.find_commit(*id).unwrap()).collect();
+        let parents: Vec<_> = parents.iter().collect();
+        let id = write_commit(repo,
+                              &original,
+                              None,
+                              "Message\n",
+                              Some("ISO-8859-1"),
+                              &tree,
+                              &parents,
+                              false)
+            .unwrap();
+        assert_eq!(id, original_id);
+    }
+
+    // A linear congruential generator is plenty for picking test cases, and the seed keeps them the
+    // same from run to run
+    struct Generator(u64);
+
+    impl Generator {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+
+        fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+            choices[self.below(choices.len() as u64) as usize]
+        }
+
+        fn signature(&mut self, header: &str) -> String {
+            let mut words = Vec::new();
+            for _ in 0..self.below(4) {
+                words.push(self.pick(&["A", "thor", "\u{e9}", ".", "-", "'", "<x>", "\t"]));
+            }
+            let name = match self.below(4) {
+                0 => format!(" {}  ", words.join("  ")),
+                _ => words.join(" "),
+            };
+            let timestamp = match self.below(4) {
+                0 => self.below(1 << 31),
+                1 => (1 << 32) + self.below(1 << 40),
+                2 => [0, 1, (1 << 31) - 1, 1 << 31, (1 << 32) - 1, 1 << 32][self.below(6) as usize],
+                _ => 1500000000,
+            };
+            let (hours, minutes) = match self.below(3) {
+                0 => (self.below(100), self.below(100)),
+                _ => (self.below(15), [0, 30, 45][self.below(3) as usize]),
+            };
+            format!("{} {} <{}> {} {}{:02}{:02}",
+                    header,
+                    name,
+                    self.pick(&["a@example.com", "", "a b@example.com"]),
+                    timestamp,
+                    self.pick(&["+", "-"]),
+                    hours,
+                    minutes)
+        }
+    }
+
+    // Whatever git could have written, within reason: names with odd characters or nothing at all,
+    // timestamps on either side of 32 bits, and any offset at all
+    #[test]
+    fn generated_signatures_survive() {
+        let test = TestRepo::new("generated");
+        let mut generator = Generator(977);
+        for _ in 0..300 {
+            let author = generator.signature("author");
+            let committer = generator.signature("committer");
+            check_round_trip(&test.repo, &author, &committer);
+        }
+    }
+}
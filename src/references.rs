@@ -0,0 +1,177 @@
+// Gitlinks and .gitmodules aren't the only things that know about the submodule: CI configs check
+// it out (`submodules: true', GIT_SUBMODULE_STRATEGY), build scripts run `git submodule update',
+// Makefiles and the like read .gitmodules. Once the submodule is merged, all of that is either dead
+// or broken, and the merge can't fix it, since we don't know what these files should say instead.
+// So we look through the files at the tips of the rewritten branches and list the lines that
+// mention the submodule, for the user to go through after the merge.
+//
+// Anything that mentions submodules in general counts, but the submodule's path only counts in CI
+// configs: elsewhere, it's mostly the code using what's in that directory, which is fine as it is.
+
+use git2::{Repository, Tree, Oid};
+use std::collections::{HashMap, HashSet};
+
+use super::Options;
+
+// Lines that mention submodules no matter where they're found
+const PATTERNS: &'static [&'static str] = &["git submodule",
+                                            "gitmodules",
+                                            "recurse-submodules",
+                                            "recursive-submodules",
+                                            "submodules:",
+                                            "GIT_SUBMODULE_STRATEGY"];
+
+// Files and directories that hold CI configs
+const CI_PATHS: &'static [&'static str] = &[".github/workflows/",
+                                            ".gitlab-ci.yml",
+                                            ".travis.yml",
+                                            ".circleci/",
+                                            "appveyor.yml",
+                                            ".appveyor.yml",
+                                            "azure-pipelines.yml",
+                                            "Jenkinsfile",
+                                            ".drone.yml",
+                                            "bitbucket-pipelines.yml",
+                                            ".buildkite/",
+                                            ".cirrus.yml"];
+
+// Blobs bigger than this are most likely data rather than configs or scripts
+const MAX_SIZE: usize = 1024 * 1024;
+
+// The longest part of a line that gets printed
+const MAX_LINE_LENGTH: usize = 100;
+
+// A file with lines that mention the submodule
+struct Finding {
+    path: String,
+    // Line number, and the line itself
+    lines: Vec<(usize, String)>,
+    // The branches that have the file with these contents
+    branches: Vec<String>,
+}
+
+// `tips' are the branches, and the commits they point at once the submodule is merged
+pub fn print(repo: &Repository, tips: &[(String, Oid)], options: &Options) {
+    let mut found: Vec<Finding> = Vec::new();
+    // Where the file with the given contents is in `found'
+    let mut found_index: HashMap<(String, Oid), usize> = HashMap::new();
+    let mut scanned = HashMap::new();
+    for &(ref branch, tip) in tips {
+        let tree = match repo.find_commit(tip).and_then(|commit| commit.tree()) {
+            Ok(tree) => tree,
+            Err(_) => continue,
+        };
+        let mut files = Vec::new();
+        collect_files(repo, &tree, "", options, &mut files);
+        for (path, id) in files {
+            if let Some(&index) = found_index.get(&(path.clone(), id)) {
+                found[index].branches.push(branch.clone());
+                continue;
+            }
+            // The same contents can be scanned for the path only in CI configs
+            let lines = scanned.entry((id, is_ci_config(&path)))
+                .or_insert_with(|| scan_blob(repo, id, is_ci_config(&path), options))
+                .clone();
+            if !lines.is_empty() {
+                found_index.insert((path.clone(), id), found.len());
+                found.push(Finding {
+                    path: path,
+                    lines: lines,
+                    branches: vec![branch.clone()],
+                });
+            }
+        }
+    }
+    if found.is_empty() {
+        return;
+    }
+
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    let all_branches = tips.iter().map(|&(ref name, _)| name).collect::<HashSet<_>>().len();
+    println!("These files still refer to the submodule, and probably need updating now that {} is \
+              an ordinary directory:",
+             options.submodule_dir);
+    for finding in &found {
+        if finding.branches.len() == all_branches {
+            println!("    {}", finding.path);
+        } else {
+            let branches: Vec<String> =
+                finding.branches.iter().map(|name| options.colors.branch(name)).collect();
+            println!("    {} (on {})", finding.path, branches.join(", "));
+        }
+        for &(number, ref line) in &finding.lines {
+            println!("        {}: {}", number, line);
+        }
+    }
+}
+
+// Every file in the tree, except for those in the submodule's directory, which are the
+// submodule's own
+fn collect_files(repo: &Repository,
+                 tree: &Tree,
+                 dir: &str,
+                 options: &Options,
+                 files: &mut Vec<(String, Oid)>) {
+    for entry in tree.iter() {
+        let path = format!("{}{}", dir, String::from_utf8_lossy(entry.name_bytes()));
+        match entry.filemode() {
+            0o040000 if path != options.submodule_dir => {
+                if let Ok(subtree) = repo.find_tree(entry.id()) {
+                    collect_files(repo, &subtree, &format!("{}/", path), options, files);
+                }
+            }
+            0o100644 | 0o100755 => files.push((path, entry.id())),
+            _ => {}
+        }
+    }
+}
+
+fn is_ci_config(path: &str) -> bool {
+    CI_PATHS.iter().any(|ci_path| if ci_path.ends_with('/') {
+        path.starts_with(ci_path)
+    } else {
+        path == *ci_path
+    })
+}
+
+// The numbers and the text of the lines that mention the submodule
+fn scan_blob(repo: &Repository,
+             id: Oid,
+             is_ci_config: bool,
+             options: &Options)
+             -> Vec<(usize, String)> {
+    let blob = match repo.find_blob(id) {
+        Ok(blob) => blob,
+        Err(_) => return Vec::new(),
+    };
+    let content = blob.content();
+    if content.len() > MAX_SIZE || content.contains(&0) {
+        return Vec::new();
+    }
+
+    let mut lines = Vec::new();
+    for (number, line) in String::from_utf8_lossy(content).lines().enumerate() {
+        let mentions = PATTERNS.iter().any(|pattern| line.contains(pattern)) ||
+                       (is_ci_config && mentions_path(line, &options.submodule_dir));
+        if mentions {
+            let line = line.trim();
+            let line = match line.char_indices().nth(MAX_LINE_LENGTH) {
+                Some((end, _)) => format!("{}...", &line[..end]),
+                None => String::from(line),
+            };
+            lines.push((number + 1, line));
+        }
+    }
+    lines
+}
+
+// Whether the line has the path as a word of its own, so that e.g. "lib" doesn't match "libfoo"
+fn mentions_path(line: &str, path: &str) -> bool {
+    let is_boundary = |c: Option<char>| {
+        c.map_or(true, |c| !c.is_alphanumeric() && c != '_' && c != '-')
+    };
+    line.match_indices(path).any(|(start, _)| {
+        is_boundary(line[..start].chars().next_back()) &&
+        is_boundary(line[start + path.len()..].chars().next())
+    })
+}
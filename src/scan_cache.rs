@@ -0,0 +1,146 @@
+// Before rewriting anything, find_dangling_references_to_submodule() walks the whole main history
+// and looks up the submodule's path in the tree of every commit. On big repos that takes a while,
+// and when the result is a list of dangling references, the usual next step is to add a few
+// mappings and try again -- with the very same history. So we keep what the walk found in
+// .git/submerge/cache/<submodule dir>/gitlinks, and reuse it if the walk would start from the same
+// tips with the same options.
+//
+// What's at the submodule's path in a given commit never changes, so even if the tips did move,
+// the entries of the commits that are still there save us most of the tree lookups.
+//
+// The file starts with the key, one line per tip or option, followed by a line with "---" and
+// then one line per commit, in the order they were walked:
+//
+//     <commit>                  -- not looked into (e.g. preserved by --preserve-gitlinks-before)
+//     <commit> none             -- nothing at the submodule's path
+//     <commit> <id> <mode>      -- the entry at the submodule's path, mode in octal
+
+use git2::{Repository, Oid};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use super::{HistoryScan, Options, get_branch_to_id_map, get_head_id, resolve_rev_range};
+
+// Fills `scan` with whatever the cache has. Returns true if the commits are there, too, i.e. the
+// cache is for the same tips and options, and the history doesn't have to be walked at all.
+pub fn load(repo: &Repository, options: &Options, scan: &mut HistoryScan) -> bool {
+    let file = match File::open(get_path(repo, options)) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    let mut lines = BufReader::new(file).lines();
+
+    let mut key = Vec::new();
+    loop {
+        match lines.next() {
+            Some(Ok(ref line)) if line == "---" => break,
+            Some(Ok(line)) => key.push(line),
+            // Truncated or unreadable; it'll be written anew
+            _ => return false,
+        }
+    }
+
+    let mut commits = Vec::new();
+    let mut entries = HashMap::new();
+    for line in lines {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return false,
+        };
+        let fields: Vec<&str> = line.split(' ').collect();
+        let commit = match Oid::from_str(fields[0]) {
+            Ok(commit) => commit,
+            Err(_) => return false,
+        };
+        commits.push(commit);
+        match fields.len() {
+            1 => {}
+            2 if fields[1] == "none" => {
+                entries.insert(commit, None);
+            }
+            3 => {
+                match (Oid::from_str(fields[1]), i32::from_str_radix(fields[2], 8)) {
+                    (Ok(id), Ok(mode)) => {
+                        entries.insert(commit, Some((id, mode)));
+                    }
+                    _ => return false,
+                }
+            }
+            _ => return false,
+        }
+    }
+
+    scan.submodule_entries.extend(entries);
+    if key == get_key(repo, options) {
+        scan.commits = commits;
+        true
+    } else {
+        false
+    }
+}
+
+// The cache is only there to save time, so failing to write it isn't a reason to stop
+pub fn save(repo: &Repository, options: &Options, scan: &HistoryScan) {
+    let path = get_path(repo, options);
+    let result = path.parent()
+        .map_or(Ok(()), ::std::fs::create_dir_all)
+        .and_then(|_| File::create(&path))
+        .and_then(|file| {
+            let mut file = BufWriter::new(file);
+            for line in get_key(repo, options) {
+                writeln!(file, "{}", line)?;
+            }
+            writeln!(file, "---")?;
+            for commit in &scan.commits {
+                match scan.submodule_entries.get(commit) {
+                    None => writeln!(file, "{}", commit)?,
+                    Some(&None) => writeln!(file, "{} none", commit)?,
+                    Some(&Some((id, mode))) => writeln!(file, "{} {} {:o}", commit, id, mode)?,
+                }
+            }
+            file.flush()
+        });
+    if let Err(e) = result {
+        eprintln!("Couldn't cache the submodule's entries in {}: {}", path.display(), e);
+        let _ = ::std::fs::remove_file(&path);
+    }
+}
+
+fn get_path(repo: &Repository, options: &Options) -> PathBuf {
+    repo.path().join("submerge").join("cache").join(&options.submodule_dir).join("gitlinks")
+}
+
+// Everything get_repo_revwalk() starts from, and the options that change what it does
+fn get_key(repo: &Repository, options: &Options) -> Vec<String> {
+    let mut key = vec![format!("head {}", get_head_id(repo, options)),
+                       format!("date-order {}", options.date_order)];
+    if let Some(ref range) = options.rev_range {
+        if let Ok((base, tip)) = resolve_rev_range(repo, range) {
+            key.push(format!("range {} {}",
+                             base,
+                             tip.map_or(String::from("-"), |tip| tip.to_string())));
+        }
+    }
+    let mut branches: Vec<String> = get_branch_to_id_map(repo, options)
+        .into_iter()
+        .map(|(name, id)| format!("branch {} {}", name, id))
+        .collect();
+    branches.sort();
+    key.extend(branches);
+    for glob in &options.exclude_branches {
+        key.push(format!("exclude {}", glob));
+    }
+    // Not get_included_refs(), which would complain about the refs it skips every time
+    for glob in &options.include_refs {
+        if let Ok(references) = repo.references_glob(glob) {
+            for reference in references.filter_map(|r| r.ok()) {
+                if let (Some(name), Some(id)) = (reference.name(), reference.target()) {
+                    key.push(format!("ref {} {}", name, id));
+                }
+            }
+        }
+    }
+    key
+}
@@ -0,0 +1,438 @@
+// With --emit-script, nothing is changed in the repo. Instead, git-submerge writes a shell script
+// that creates the very same commits with `git fast-import' and moves the refs with
+// `git update-ref'. The script only uses plain Git, so it can be reviewed and then run by people
+// who'd rather not let a third-party binary loose on their repo.
+//
+// Each commit is described by how its tree differs from its first parent's. Files are referred to
+// by ID if the repo already has them, or will have them once the script fetches submodule's
+// history; the rest (e.g. the .mailmap from --merge-mailmap) is included in the script verbatim.
+// Annotated tags from --import-tags are created by fast-import, too.
+
+use git2::{Repository, Oid, Commit};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+use super::raw_commit::find_header;
+use super::{Options, get_branch_to_id_map, get_historical_urls, get_included_refs,
+            get_submodule_fetch_url, get_submodule_upstream_url, get_submodule_url_history};
+
+// Where the results of the run are kept while the script is being written
+pub const NAMESPACE: &'static str = "refs/submerge/script/";
+// The script's own refs: the branch fast-import commits to, and the fetched objects
+const IMPORT_REF: &'static str = "refs/submerge/script-import";
+const FETCHED_NAMESPACE: &'static str = "refs/submerge/script-fetched/";
+
+pub fn write_script(repo: &Repository,
+                    path: &str,
+                    old_id_to_new: &HashMap<Oid, Oid>,
+                    options: &Options) {
+    // Source ref, the ref it should end up as, and its new ID
+    let mut results: Vec<(String, String, Oid)> = Vec::new();
+    let references = repo.references_glob(&format!("{}*", NAMESPACE))
+        .expect("Couldn't obtain an iterator over references");
+    for reference in references.filter_map(|r| r.ok()) {
+        let name = String::from(reference.name().expect("Ref name is not valid UTF-8"));
+        if let Some(id) = reference.target() {
+            let target = format!("refs/{}", &name[NAMESPACE.len()..]);
+            results.push((name, target, id));
+        }
+    }
+
+    let mut script = Vec::new();
+    write_header(&mut script, options);
+    write_fetches(&mut script, repo, options);
+    let commits = get_new_commits(repo, &results, options);
+    // fast-import puts the tags right where they belong, so they're not among the refs to update
+    let (tags, results): (Vec<_>, Vec<_>) = results.into_iter()
+        .partition(|&(_, _, id)| repo.find_tag(id).is_ok());
+    write_fast_import(&mut script, repo, &commits, &tags, old_id_to_new, options);
+    write_checks(&mut script, &commits, &tags, &results);
+    write_ref_updates(&mut script, repo, &results, options);
+
+    let mut file = ::std::fs::File::create(path)
+        .expect(&format!("Couldn't create {}", path));
+    file.write_all(&script).expect(&format!("Couldn't write the script to {}", path));
+    make_executable(path);
+
+    println!("Wrote a script that creates {} commit(s) and updates {} ref(s) to {}. No refs were \
+              changed.",
+             commits.len(),
+             results.len() + tags.len(),
+             path);
+}
+
+fn write_header(script: &mut Vec<u8>, options: &Options) {
+    writeln!(script,
+             "#!/bin/sh\n\
+              # Merges submodule {0} into the repo, as git-submerge did when it generated this \
+              script.\n\
+              # Run it from the root of the repo's working directory.\n\
+              set -e\n",
+             options.submodule_dir)
+        .unwrap();
+}
+
+// The script has to fetch the same histories that git-submerge did, for the commits it creates to
+// come out the same
+fn write_fetches(script: &mut Vec<u8>, repo: &Repository, options: &Options) {
+    writeln!(script, "# 1. Fetch submodule's history").unwrap();
+    writeln!(script,
+             "git fetch --no-tags {0} HEAD '+refs/heads/*:{1}heads/*' '+refs/tags/*:{1}tags/*'",
+             quote(&get_submodule_fetch_url(options)),
+             FETCHED_NAMESPACE)
+        .unwrap();
+    if !options.fetch_refspecs.is_empty() {
+        let url = get_submodule_upstream_url(repo, options)
+            .expect("Couldn't figure out where the submodule was cloned from");
+        for (i, refspec) in options.fetch_refspecs.iter().enumerate() {
+            let source = refspec.trim_left_matches('+').split(':').next().unwrap_or("");
+            writeln!(script,
+                     "git fetch --no-tags {} {}",
+                     quote(&url),
+                     quote(&format!("+{}:{}refspec-{}", source, FETCHED_NAMESPACE, i)))
+                .unwrap();
+        }
+    }
+    for (i, url) in options.external_repos.iter().enumerate() {
+        writeln!(script,
+                 "git fetch --no-tags {} '+refs/heads/*:{1}external-{2}/heads/*' \
+                  '+refs/tags/*:{1}external-{2}/tags/*'",
+                 quote(url),
+                 FETCHED_NAMESPACE,
+                 i)
+            .unwrap();
+    }
+    // git-submerge might not have needed all of them, but the extra objects do no harm
+    for (i, url) in options.also_fetch.iter().enumerate() {
+        writeln!(script,
+                 "git fetch --no-tags {} '+refs/heads/*:{1}also-{2}/heads/*' \
+                  '+refs/tags/*:{1}also-{2}/tags/*' || true",
+                 quote(url),
+                 FETCHED_NAMESPACE,
+                 i)
+            .unwrap();
+    }
+    if options.fetch_historical_urls {
+        let history = get_submodule_url_history(repo, options);
+        let urls = get_historical_urls(repo, &history, options);
+        // Old hosts might be gone by now; if a commit was only there, fast-import will say so
+        for (i, url) in urls.iter().filter(|url| !url.starts_with('.')).enumerate() {
+            writeln!(script,
+                     "git fetch --no-tags {} '+refs/heads/*:{1}historical-{2}/heads/*' \
+                      '+refs/tags/*:{1}historical-{2}/tags/*' || true",
+                     quote(url),
+                     FETCHED_NAMESPACE,
+                     i)
+                .unwrap();
+        }
+    }
+    writeln!(script).unwrap();
+}
+
+// The commits reachable from the results, but not from anything that existed before the run.
+// Parents come first.
+fn get_new_commits<'repo>(repo: &'repo Repository,
+                          results: &[(String, String, Oid)],
+                          options: &Options)
+                          -> Vec<Commit<'repo>> {
+    let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
+    revwalk.set_sorting(::git2::SORT_REVERSE | ::git2::SORT_TOPOLOGICAL);
+    for &(ref name, _, _) in results {
+        // Tags that don't point at commits are simply skipped
+        let _ = revwalk.push_ref(name);
+    }
+    if let Some(head_id) = repo.head().ok().and_then(|head| head.target()) {
+        let _ = revwalk.hide(head_id);
+    }
+    let old_refs = get_branch_to_id_map(repo, options)
+        .into_iter()
+        .chain(get_included_refs(repo, options));
+    for (_, id) in old_refs {
+        let _ = revwalk.hide(id);
+    }
+    for glob in &["refs/submerge/fetched/*",
+                  "refs/submerge/external/*",
+                  "refs/submerge/gitlink-tags/*",
+                  "refs/tags/*"] {
+        let _ = revwalk.hide_glob(glob);
+    }
+
+    revwalk.filter_map(|maybe_oid| maybe_oid.ok())
+        .map(|oid| {
+            repo.find_commit(oid).expect(&format!("Couldn't get a commit with ID {}", oid))
+        })
+        .collect()
+}
+
+fn write_fast_import(script: &mut Vec<u8>,
+                     repo: &Repository,
+                     commits: &[Commit],
+                     tags: &[(String, String, Oid)],
+                     old_id_to_new: &HashMap<Oid, Oid>,
+                     options: &Options) {
+    // Each rewritten commit's original, whose files are certainly in the repo already
+    let new_id_to_old: HashMap<Oid, Oid> = old_id_to_new.iter()
+        .filter(|&(old_id, new_id)| old_id != new_id)
+        .map(|(old_id, new_id)| (*new_id, *old_id))
+        .collect();
+    let submodule_path = Path::new(&options.submodule_dir);
+    let mut marks: HashMap<Oid, usize> = HashMap::new();
+    let refer = |marks: &HashMap<Oid, usize>, id: Oid| -> String {
+        match marks.get(&id) {
+            Some(mark) => format!(":{}", mark),
+            None => id.to_string(),
+        }
+    };
+
+    writeln!(script, "# 2. Create the rewritten commits").unwrap();
+    // Only Git 2.28 and newer take that option, so it's only there when it's needed
+    writeln!(script,
+             "git fast-import --quiet{} <<'END_OF_FAST_IMPORT'",
+             if commits.iter().any(has_odd_offset) {
+                 " --date-format=raw-permissive"
+             } else {
+                 ""
+             })
+        .unwrap();
+    for commit in commits {
+        let mark = marks.len() + 1;
+        if commit.parent_ids().count() == 0 {
+            // Otherwise fast-import would use the previous commit as the parent
+            writeln!(script, "reset {}", IMPORT_REF).unwrap();
+        }
+        writeln!(script, "commit {}\nmark :{}", IMPORT_REF, mark).unwrap();
+        // The signatures are copied as they are, see raw_commit.rs
+        for header in &[&b"author "[..], &b"committer "[..]] {
+            let line = find_header(commit, header).expect("Commit has no author or committer");
+            script.extend_from_slice(line);
+            script.push(b'\n');
+        }
+        // Without it, the commit wouldn't come out the same. fast-import only knows this command
+        // since Git 2.29; older versions stop right there rather than create a different commit.
+        if let Some(line) = find_header(commit, b"encoding ") {
+            script.extend_from_slice(line);
+            script.push(b'\n');
+        }
+        write_data(script, commit.message_raw_bytes());
+
+        let parent_ids: Vec<Oid> = commit.parent_ids().collect();
+        for (i, parent_id) in parent_ids.iter().enumerate() {
+            writeln!(script,
+                     "{} {}",
+                     if i == 0 { "from" } else { "merge" },
+                     refer(&marks, *parent_id))
+                .unwrap();
+        }
+
+        // fast-import starts off with the first parent's tree
+        let tree = commit.tree().expect("Couldn't obtain commit's tree");
+        let parent_tree = parent_ids.first()
+            .map(|id| {
+                repo.find_commit(*id)
+                    .and_then(|parent| parent.tree())
+                    .expect("Couldn't obtain the tree of commit's parent")
+            });
+        let original_tree = new_id_to_old.get(&commit.id())
+            .and_then(|id| repo.find_commit(*id).ok())
+            .and_then(|original| original.tree().ok());
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)
+            .expect("Couldn't diff the trees");
+        for delta in diff.deltas() {
+            if let ::git2::Delta::Deleted = delta.status() {
+                let path = delta.old_file().path_bytes().expect("Couldn't obtain a path");
+                write!(script, "D ").unwrap();
+                write_path(script, path);
+                continue;
+            }
+            let file = delta.new_file();
+            let path = file.path().expect("Couldn't obtain a path");
+            let mode = tree.get_path(path)
+                .expect("Couldn't find a changed file in the tree")
+                .filemode();
+            let is_known = mode == 0o160000 || path.starts_with(submodule_path) ||
+                           original_tree.as_ref()
+                .and_then(|original| original.get_path(path).ok())
+                .map_or(false, |entry| entry.id() == file.id());
+            if is_known {
+                write!(script, "M {:o} {} ", mode, file.id()).unwrap();
+                write_path(script, file.path_bytes().expect("Couldn't obtain a path"));
+            } else {
+                write!(script, "M {:o} inline ", mode).unwrap();
+                write_path(script, file.path_bytes().expect("Couldn't obtain a path"));
+                let blob = repo.find_blob(file.id()).expect("Couldn't read a blob");
+                write_data(script, blob.content());
+            }
+        }
+        writeln!(script).unwrap();
+        marks.insert(commit.id(), mark);
+    }
+    for &(_, ref target, id) in tags {
+        let tag = repo.find_tag(id).expect("Couldn't read a tag");
+        writeln!(script,
+                 "tag {}\nfrom {}",
+                 target.trim_left_matches("refs/tags/"),
+                 refer(&marks, tag.target_id()))
+            .unwrap();
+        if let Some(tagger) = tag.tagger() {
+            write_signature(script, "tagger", &tagger);
+        }
+        write_data(script, tag.message_bytes().unwrap_or(b""));
+        writeln!(script).unwrap();
+    }
+    writeln!(script, "END_OF_FAST_IMPORT\n").unwrap();
+}
+
+// Every commit includes the IDs of its parents, so if the tips came out right, everything else did,
+// too
+fn write_checks(script: &mut Vec<u8>,
+                commits: &[Commit],
+                tags: &[(String, String, Oid)],
+                results: &[(String, String, Oid)]) {
+    writeln!(script,
+             "# 3. Check that the commits came out the same as they did for git-submerge\n\
+              check() {{\n    \
+                  if ! git cat-file -e \"$1\" 2>/dev/null; then\n        \
+                      echo \"fast-import didn't create $1 as expected, aborting\" >&2\n        \
+                      exit 1\n    \
+                  fi\n\
+              }}")
+        .unwrap();
+    let mut checked = Vec::new();
+    for &(_, _, id) in tags.iter().chain(results) {
+        let is_new = tags.iter().any(|&(_, _, tag_id)| tag_id == id) ||
+                     commits.iter().any(|commit| commit.id() == id);
+        if is_new && !checked.contains(&id) {
+            writeln!(script, "check {}", id).unwrap();
+            checked.push(id);
+        }
+    }
+    writeln!(script).unwrap();
+}
+
+fn write_ref_updates(script: &mut Vec<u8>,
+                     repo: &Repository,
+                     results: &[(String, String, Oid)],
+                     options: &Options) {
+    writeln!(script,
+             "# 4. Back up the refs and move them, all at once. If somebody moved any of them in \
+              the meantime,\n\
+              # nothing is changed.")
+        .unwrap();
+    writeln!(script, "git update-ref --stdin <<'END_OF_UPDATES'").unwrap();
+    let mut head_branch_updated = false;
+    let head_branch = repo.find_reference("HEAD")
+        .ok()
+        .and_then(|head| head.symbolic_target().map(String::from));
+    for &(_, ref target, id) in results {
+        match repo.refname_to_id(target) {
+            Ok(current_id) => {
+                writeln!(script,
+                         "update refs/submerge/backup/{}/{} {}",
+                         options.submodule_dir,
+                         target.trim_left_matches("refs/"),
+                         current_id)
+                    .unwrap();
+                writeln!(script, "update {} {} {}", target, id, current_id).unwrap();
+            }
+            Err(_) => writeln!(script, "create {} {}", target, id).unwrap(),
+        }
+        if Some(target) == head_branch.as_ref() {
+            head_branch_updated = true;
+        }
+    }
+    writeln!(script, "END_OF_UPDATES\n").unwrap();
+
+    writeln!(script, "# 5. Clean up").unwrap();
+    writeln!(script,
+             "git update-ref -d {}\n\
+              git for-each-ref --format='delete %(refname)' {} | git update-ref --stdin\n",
+             IMPORT_REF,
+             FETCHED_NAMESPACE)
+        .unwrap();
+
+    if head_branch_updated {
+        writeln!(script,
+                 "echo 'Done. The working directory still has the submodule in it; to turn it \
+                  into an ordinary directory, run'\n\
+                  echo '    rm {0}/.git && rm -f .gitmodules && git reset -q'",
+                 options.submodule_dir)
+            .unwrap();
+    } else {
+        writeln!(script, "echo 'Done.'").unwrap();
+    }
+}
+
+// Whether the commit has a signature whose offset fast-import won't take by default, like "+2400"
+fn has_odd_offset(commit: &Commit) -> bool {
+    [&b"author "[..], &b"committer "[..]].iter().any(|header| {
+        find_header(commit, header)
+            .map(|line| {
+                let start = line.iter().rposition(|byte| *byte == b' ').map_or(0, |i| i + 1);
+                &line[start..]
+            })
+            .and_then(|offset| if offset.len() > 1 {
+                ::std::str::from_utf8(&offset[1..]).ok()
+            } else {
+                None
+            })
+            .and_then(|digits| digits.parse::<u32>().ok())
+            .map_or(false, |offset| offset > 1400)
+    })
+}
+
+// Tags come from libgit2, which wrote their signatures just like this in the first place
+fn write_signature(script: &mut Vec<u8>, kind: &str, signature: &::git2::Signature) {
+    let when = signature.when();
+    let offset = when.offset_minutes();
+    write!(script, "{} ", kind).unwrap();
+    script.extend_from_slice(signature.name_bytes());
+    script.extend_from_slice(b" <");
+    script.extend_from_slice(signature.email_bytes());
+    writeln!(script,
+             "> {} {}{:02}{:02}",
+             when.seconds(),
+             if offset < 0 { '-' } else { '+' },
+             offset.abs() / 60,
+             offset.abs() % 60)
+        .unwrap();
+}
+
+fn write_data(script: &mut Vec<u8>, data: &[u8]) {
+    writeln!(script, "data {}", data.len()).unwrap();
+    script.extend_from_slice(data);
+    script.push(b'\n');
+}
+
+// fast-import takes paths as they are, unless they start with a quote or contain a newline
+fn write_path(script: &mut Vec<u8>, path: &[u8]) {
+    if path.first() == Some(&b'"') || path.contains(&b'\n') {
+        script.push(b'"');
+        for byte in path {
+            match *byte {
+                b'"' => script.extend_from_slice(b"\\\""),
+                b'\\' => script.extend_from_slice(b"\\\\"),
+                b'\n' => script.extend_from_slice(b"\\n"),
+                byte => script.push(byte),
+            }
+        }
+        script.push(b'"');
+    } else {
+        script.extend_from_slice(path);
+    }
+    script.push(b'\n');
+}
+
+// Single quotes keep everything as it is, except for single quotes themselves
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[cfg(unix)]
+pub fn make_executable(path: &str) {
+    use std::os::unix::fs::PermissionsExt;
+    let _ = ::std::fs::set_permissions(path, ::std::fs::Permissions::from_mode(0o755));
+}
+
+#[cfg(not(unix))]
+pub fn make_executable(_: &str) {}
@@ -0,0 +1,274 @@
+// With --max-memory, the rewrite loops keep an eye on how much memory we use. Once that gets close
+// to the limit, the commit map entries of the commits rewritten so far are moved out to
+// .git/submerge/<submodule dir>/spill/, and the rewrite carries on. A commit only needs the entries
+// of its parents, and those are read back as we get to it. Once the loop is over, everything is put
+// back into the map, since the steps that follow go through all of it.
+//
+// Each spill is a file of its own, with 40-byte records: the old ID, then the new one. They are
+// sorted by the old ID, so that lookups can bisect the file. Every lookup goes through all the
+// files, so once there are too many of them, they are merged into one.
+
+use git2::{Repository, Oid};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use super::get_commit_map_path;
+
+const RECORD_SIZE: usize = 40;
+const MAX_FILES: usize = 8;
+
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    records: u64,
+}
+
+pub struct Spill {
+    dir: PathBuf,
+    // Oldest first
+    files: Vec<SpillFile>,
+    // Entries that were read back; they are on disk already, so the next spill simply drops them
+    read_back: Vec<Oid>,
+    next_name: usize,
+}
+
+impl Spill {
+    pub fn new(repo: &Repository, submodule_dir: &str) -> Spill {
+        let dir = get_commit_map_path(repo, submodule_dir).with_file_name("spill");
+        // Left over from a run that didn't finish
+        let _ = fs::remove_dir_all(&dir);
+        Spill {
+            dir: dir,
+            files: Vec::new(),
+            read_back: Vec::new(),
+            next_name: 0,
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    // Moves the entries for `ids` out of the map. Returns how many there were.
+    pub fn spill(&mut self, map: &mut HashMap<Oid, Oid>, ids: &[Oid]) -> io::Result<usize> {
+        let mut records: Vec<(Oid, Oid)> =
+            ids.iter().filter_map(|id| map.get(id).map(|new_id| (*id, *new_id))).collect();
+        records.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+        records.dedup();
+
+        if !records.is_empty() {
+            fs::create_dir_all(&self.dir)?;
+            let path = self.dir.join(self.next_name.to_string());
+            self.next_name += 1;
+            {
+                let mut writer = BufWriter::new(File::create(&path)?);
+                for &(old_id, new_id) in &records {
+                    writer.write_all(old_id.as_bytes())?;
+                    writer.write_all(new_id.as_bytes())?;
+                }
+                writer.flush()?;
+            }
+            self.files.push(SpillFile {
+                file: File::open(&path)?,
+                path: path,
+                records: records.len() as u64,
+            });
+        }
+
+        for &(old_id, _) in &records {
+            map.remove(&old_id);
+        }
+        for id in self.read_back.drain(..) {
+            map.remove(&id);
+        }
+        if self.files.len() > MAX_FILES {
+            self.merge()?;
+        }
+        Ok(records.len())
+    }
+
+    // Puts the spilled entries for `ids` back into the map
+    pub fn read_back<I>(&mut self, map: &mut HashMap<Oid, Oid>, ids: I) -> io::Result<()>
+        where I: Iterator<Item = Oid>
+    {
+        if self.files.is_empty() {
+            return Ok(());
+        }
+        for id in ids {
+            if map.contains_key(&id) {
+                continue;
+            }
+            if let Some(new_id) = self.find(id)? {
+                map.insert(id, new_id);
+                self.read_back.push(id);
+            }
+        }
+        Ok(())
+    }
+
+    // Puts all the spilled entries back into the map, and removes the files
+    pub fn restore(&mut self, map: &mut HashMap<Oid, Oid>) -> io::Result<()> {
+        for spill_file in &self.files {
+            let mut reader = BufReader::new(File::open(&spill_file.path)?);
+            for _ in 0..spill_file.records {
+                let (old_id, new_id) = read_record(&mut reader)?;
+                map.insert(old_id, new_id);
+            }
+        }
+        self.files.clear();
+        self.read_back.clear();
+        fs::remove_dir_all(&self.dir).or_else(|e| if e.kind() == io::ErrorKind::NotFound {
+            Ok(())
+        } else {
+            Err(e)
+        })
+    }
+
+    fn find(&mut self, id: Oid) -> io::Result<Option<Oid>> {
+        let mut record = [0u8; RECORD_SIZE];
+        for spill_file in &mut self.files {
+            let (mut low, mut high) = (0, spill_file.records);
+            while low < high {
+                let middle = low + (high - low) / 2;
+                spill_file.file.seek(SeekFrom::Start(middle * RECORD_SIZE as u64))?;
+                spill_file.file.read_exact(&mut record)?;
+                match record[..20].cmp(id.as_bytes()) {
+                    Ordering::Less => low = middle + 1,
+                    Ordering::Greater => high = middle,
+                    Ordering::Equal => {
+                        return Ok(Some(Oid::from_bytes(&record[20..])
+                            .expect("Spilled ID is not 20 bytes long")));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Merges all the files into one, the way merge sort does
+    fn merge(&mut self) -> io::Result<()> {
+        let mut readers = Vec::new();
+        let mut heads = Vec::new();
+        for spill_file in &self.files {
+            let mut reader = BufReader::new(File::open(&spill_file.path)?);
+            heads.push(if spill_file.records > 0 {
+                Some(read_record(&mut reader)?)
+            } else {
+                None
+            });
+            readers.push((reader, spill_file.records));
+        }
+
+        let path = self.dir.join(self.next_name.to_string());
+        self.next_name += 1;
+        let mut records = 0;
+        {
+            let mut writer = BufWriter::new(File::create(&path)?);
+            loop {
+                let mut smallest: Option<usize> = None;
+                for (i, head) in heads.iter().enumerate() {
+                    if let Some((old_id, _)) = *head {
+                        let is_smaller = match smallest {
+                            Some(j) => {
+                                let other = heads[j].expect("Smallest record is gone").0;
+                                old_id.as_bytes() < other.as_bytes()
+                            }
+                            None => true,
+                        };
+                        if is_smaller {
+                            smallest = Some(i);
+                        }
+                    }
+                }
+                let i = match smallest {
+                    Some(i) => i,
+                    None => break,
+                };
+
+                let (old_id, new_id) = heads[i].expect("Smallest record is gone");
+                writer.write_all(old_id.as_bytes())?;
+                writer.write_all(new_id.as_bytes())?;
+                records += 1;
+
+                readers[i].1 -= 1;
+                heads[i] = if readers[i].1 > 0 {
+                    Some(read_record(&mut readers[i].0)?)
+                } else {
+                    None
+                };
+            }
+            writer.flush()?;
+        }
+
+        for spill_file in self.files.drain(..) {
+            fs::remove_file(&spill_file.path)?;
+        }
+        self.files.push(SpillFile {
+            file: File::open(&path)?,
+            path: path,
+            records: records,
+        });
+        Ok(())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn read_record<R: Read>(reader: &mut R) -> io::Result<(Oid, Oid)> {
+    let mut record = [0u8; RECORD_SIZE];
+    reader.read_exact(&mut record)?;
+    Ok((Oid::from_bytes(&record[..20]).expect("Spilled ID is not 20 bytes long"),
+        Oid::from_bytes(&record[20..]).expect("Spilled ID is not 20 bytes long")))
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::Oid;
+    use std::collections::HashMap;
+
+    use super::{MAX_FILES, Spill};
+    use tests::TestRepo;
+
+    fn id(seed: u8, n: u8) -> Oid {
+        let mut bytes = [seed; 20];
+        bytes[0] = n.wrapping_mul(37);
+        bytes[19] = n;
+        Oid::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn spilled_entries_come_back() {
+        let test = TestRepo::new("spill");
+        let original: HashMap<Oid, Oid> = (0..60).map(|n| (id(1, n), id(2, n))).collect();
+        let mut map = original.clone();
+        let mut spill = Spill::new(&test.repo, "sub");
+
+        // Enough spills for the files to get merged along the way
+        let old_ids: Vec<Oid> = (0..60).map(|n| id(1, n)).collect();
+        for batch in old_ids.chunks(5) {
+            assert_eq!(spill.spill(&mut map, batch).unwrap(), batch.len());
+            assert!(spill.files.len() <= MAX_FILES);
+        }
+        assert!(map.is_empty());
+
+        let wanted = vec![id(1, 0), id(1, 31), id(1, 59), id(3, 0)];
+        spill.read_back(&mut map, wanted.into_iter()).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[&id(1, 31)], id(2, 31));
+
+        // Entries that were read back are dropped by the next spill
+        assert_eq!(spill.spill(&mut map, &[]).unwrap(), 0);
+        assert!(map.is_empty());
+
+        spill.restore(&mut map).unwrap();
+        assert_eq!(map, original);
+        assert!(!spill.path().exists());
+    }
+}
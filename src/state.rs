@@ -0,0 +1,190 @@
+// A record of the latest run, kept in .git/submerge/<submodule dir>/state.json next to the commit
+// map: which version of git-submerge did what, with which options and mappings, when, and how it
+// ended. It's meant for people (and scripts) wondering what happened to the repo, and `git submerge
+// verify` reads it to tell which run it's checking.
+//
+// The file is JSON, with every top-level key on a line of its own. We don't need a full JSON
+// parser to read back the few fields we're interested in, so there isn't one.
+
+use git2::Repository;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Options, get_commit_map_path};
+
+pub struct State {
+    pub version: String,
+    pub arguments: Vec<String>,
+    pub finished: i64,
+    pub exit_code: i32,
+}
+
+pub fn write(repo: &Repository,
+             options: &Options,
+             started: SystemTime,
+             commit_map_written: bool,
+             exit_code: i32) {
+    // Runs that didn't rewrite anything didn't do anything to the repo either, and shouldn't replace
+    // the record of the one that did. That includes --diff-preview, and `import', which leaves the
+    // rewriting to `stitch'.
+    if !commit_map_written {
+        return;
+    }
+
+    let arguments: Vec<String> = ::std::env::args().skip(1).map(|arg| quote(&arg)).collect();
+    let mut environment: Vec<String> = ::std::env::vars()
+        .filter(|&(ref name, _)| name.starts_with("GIT_SUBMERGE_"))
+        .map(|(name, value)| format!("{}: {}", quote(&name), quote(&value)))
+        .collect();
+    environment.sort();
+    let mut mappings: Vec<String> = options.mappings
+        .iter()
+        .map(|(old, new)| format!("{}: {}", quote(&old.to_string()), quote(&format_id(*new))))
+        .collect();
+    mappings.sort();
+    let default_mapping = options.default_mapping
+        .map_or(String::from("null"), |id| quote(&format_id(id)));
+    let outcome = match exit_code {
+        super::E_SUCCESS => "success",
+        super::E_COMPLETED_WITH_WARNINGS => "success with warnings",
+        _ => "failure",
+    };
+
+    let contents = format!("{{\n  \"version\": {},\n  \"submodule_dir\": {},\n  \
+                            \"arguments\": [{}],\n  \"environment\": {{{}}},\n  \
+                            \"mappings\": {{{}}},\n  \"default_mapping\": {},\n  \
+                            \"started\": {},\n  \"finished\": {},\n  \"exit_code\": {},\n  \
+                            \"outcome\": {}\n}}\n",
+                           quote(env!("CARGO_PKG_VERSION")),
+                           quote(&options.submodule_dir),
+                           arguments.join(", "),
+                           environment.join(", "),
+                           mappings.join(", "),
+                           default_mapping,
+                           seconds_since_epoch(started),
+                           seconds_since_epoch(SystemTime::now()),
+                           exit_code,
+                           quote(outcome));
+
+    // The run is over by now, so this is no reason to fail it
+    let path = get_state_path(repo, &options.submodule_dir);
+    let result = path.parent()
+        .map_or(Ok(()), ::std::fs::create_dir_all)
+        .and_then(|_| File::create(&path))
+        .and_then(|mut file| file.write_all(contents.as_bytes()));
+    if let Err(e) = result {
+        eprintln!("Couldn't record the run in {}: {}", path.display(), e);
+    }
+}
+
+pub fn read(repo: &Repository, submodule_dir: &str) -> Option<State> {
+    let mut contents = String::new();
+    if File::open(get_state_path(repo, submodule_dir))
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .is_err() {
+        return None;
+    }
+
+    let field = |name: &str| -> Option<String> {
+        let prefix = format!("  {}: ", quote(name));
+        contents.lines()
+            .find(|line| line.starts_with(&prefix))
+            .map(|line| String::from(line[prefix.len()..].trim_right_matches(',')))
+    };
+    let version = field("version")
+        .and_then(|value| parse_strings(&value))
+        .map(|mut strings| strings.remove(0));
+    let arguments = field("arguments").and_then(|value| parse_strings(&value));
+    let finished = field("finished").and_then(|value| value.parse().ok());
+    let exit_code = field("exit_code").and_then(|value| value.parse().ok());
+    match (version, arguments, finished, exit_code) {
+        (Some(version), Some(arguments), Some(finished), Some(exit_code)) => {
+            Some(State {
+                version: version,
+                arguments: arguments,
+                finished: finished,
+                exit_code: exit_code,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn seconds_since_epoch(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).expect("The system clock is set before 1970").as_secs() as i64
+}
+
+fn get_state_path(repo: &Repository, submodule_dir: &str) -> PathBuf {
+    get_commit_map_path(repo, submodule_dir).with_file_name("state.json")
+}
+
+fn format_id(id: ::git2::Oid) -> String {
+    if id.is_zero() {
+        String::from("empty")
+    } else {
+        id.to_string()
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut result = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+// Reads a string, or an array of strings, as written by quote(). Returns None on anything else.
+fn parse_strings(value: &str) -> Option<Vec<String>> {
+    let value = value.trim();
+    let is_array = value.starts_with('[');
+    let mut chars = value.chars().skip(if is_array { 1 } else { 0 }).peekable();
+    let mut strings = Vec::new();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace() || *c == ',') {
+            chars.next();
+        }
+        match chars.next() {
+            Some('"') => {}
+            Some(']') if is_array => return Some(strings),
+            None if !is_array && strings.len() == 1 => return Some(strings),
+            _ => return None,
+        }
+        let mut string = String::new();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => {
+                    match chars.next() {
+                        Some('n') => string.push('\n'),
+                        Some('t') => string.push('\t'),
+                        Some('u') => {
+                            let code: String = chars.by_ref().take(4).collect();
+                            match u32::from_str_radix(&code, 16)
+                                .ok()
+                                .and_then(::std::char::from_u32) {
+                                Some(c) => string.push(c),
+                                None => return None,
+                            }
+                        }
+                        Some(c) => string.push(c),
+                        None => return None,
+                    }
+                }
+                Some(c) => string.push(c),
+                None => return None,
+            }
+        }
+        strings.push(string);
+    }
+}
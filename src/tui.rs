@@ -0,0 +1,229 @@
+// An interactive browser for the rewrite plan. It shows every commit where the main repo moves
+// the submodule, lets the user look into each of them and pick mappings for the dangling ones, and
+// only then lets the rewrite happen.
+//
+// It's a plain line-oriented prompt, so it works in any terminal without extra dependencies.
+
+use git2::{Repository, Oid};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use super::{Options, abbreviate, get_repo_revwalk, is_ref_protected, map_submodule_commit,
+            parse_mapping_target, peel_gitlink};
+
+// A commit where the submodule moved from one commit to another
+struct Transition {
+    commit: Oid,
+    summary: String,
+    from: Option<Oid>,
+    to: Oid,
+}
+
+const HELP: &'static str = "Commands:
+    l                  list the commits that move the submodule
+    s <n>              show the n-th of those commits
+    m <n> <target>     use <target> (a submodule commit ID, or \"empty\") in place of the n-th
+                       commit's submodule commit
+    x                  execute the rewrite
+    q                  quit without rewriting anything
+    h                  show this help";
+
+// Returns the mappings to use for the rewrite, or None if the user decided not to do it
+pub fn browse_plan(repo: &Repository,
+                   old_id_to_new: &HashMap<Oid, Oid>,
+                   options: &Options)
+                   -> Option<HashMap<Oid, Oid>> {
+    let transitions = find_transitions(repo, options);
+    let mut mappings = options.mappings.clone();
+
+    println!("{} commit(s) move the submodule.\n\n{}\n", transitions.len(), HELP);
+    list_transitions(repo, &transitions, old_id_to_new, &mappings, options);
+    list_protected_refs(repo, &transitions, options);
+
+    let stdin = ::std::io::stdin();
+    loop {
+        print!("submerge> ");
+        ::std::io::stdout().flush().expect("Couldn't flush stdout");
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            // End of input means the same as "q"
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let transition = words.get(1)
+            .and_then(|n| n.parse::<usize>().ok())
+            .and_then(|n| if n > 0 { transitions.get(n - 1) } else { None });
+
+        match (words.first().cloned(), transition, words.len()) {
+            (None, _, _) => {}
+            (Some("l"), _, 1) => {
+                list_transitions(repo, &transitions, old_id_to_new, &mappings, options)
+            }
+            (Some("s"), Some(transition), 2) => show_transition(repo, transition, options),
+            (Some("m"), Some(transition), 3) => {
+                if let Ok(target) = parse_mapping_target(words[2]) {
+                    if target.is_zero() || old_id_to_new.contains_key(&target) {
+                        mappings.insert(transition.to, target);
+                    } else {
+                        println!("{} is not in the submodule's history", target);
+                    }
+                }
+            }
+            (Some("x"), _, 1) => return Some(mappings),
+            (Some("q"), _, 1) => return None,
+            (Some("s"), None, _) |
+            (Some("m"), None, _) => println!("No such commit; use `l' to list them"),
+            _ => println!("{}", HELP),
+        }
+    }
+}
+
+fn find_transitions(repo: &Repository, options: &Options) -> Vec<Transition> {
+    let submodule_path = Path::new(&options.submodule_dir);
+    let get_state = |commit: &::git2::Commit| -> Option<Oid> {
+        if let Some(id) = options.overrides.get(&commit.id()) {
+            return Some(*id);
+        }
+        commit.tree()
+            .ok()
+            .and_then(|tree| tree.get_path(submodule_path).ok())
+            .and_then(|entry| if entry.filemode() == 0o160000 {
+                Some(peel_gitlink(repo, entry.id()))
+            } else {
+                None
+            })
+    };
+
+    let mut transitions = Vec::new();
+    for oid in get_repo_revwalk(repo, options).filter_map(|maybe_oid| maybe_oid.ok()) {
+        let mut commit = repo.find_commit(oid)
+            .expect(&format!("Couldn't get a commit with ID {}", oid));
+        let state = match get_state(&commit) {
+            Some(state) => state,
+            None => continue,
+        };
+        let parent_states: Vec<Option<Oid>> = commit.parents().map(|p| get_state(&p)).collect();
+        if parent_states.contains(&Some(state)) {
+            continue;
+        }
+
+        transitions.push(Transition {
+            commit: oid,
+            summary: String::from(commit.summary().unwrap_or("")),
+            from: parent_states.first().cloned().and_then(|s| s),
+            to: state,
+        });
+    }
+    transitions
+}
+
+fn list_transitions(repo: &Repository,
+                    transitions: &[Transition],
+                    old_id_to_new: &HashMap<Oid, Oid>,
+                    mappings: &HashMap<Oid, Oid>,
+                    options: &Options) {
+    for (i, transition) in transitions.iter().enumerate() {
+        let from = transition.from.map(|id| abbreviate(repo, id, options.abbrev));
+        let target = match map_submodule_commit(transition.to,
+                                                old_id_to_new,
+                                                mappings,
+                                                &options.default_mapping) {
+            None => String::from("  DANGLING"),
+            Some(_) => {
+                match mappings.get(&transition.to) {
+                    Some(id) if id.is_zero() => String::from("  mapped to empty"),
+                    Some(id) => format!("  mapped to {}", abbreviate(repo, *id, options.abbrev)),
+                    None => String::new(),
+                }
+            }
+        };
+        println!("{:4}  {}  {} -> {}{}  {}",
+                 i + 1,
+                 abbreviate(repo, transition.commit, options.abbrev),
+                 from.unwrap_or(String::from("none")),
+                 abbreviate(repo, transition.to, options.abbrev),
+                 target,
+                 transition.summary);
+    }
+}
+
+// Protected refs (see --protected-ref) that contain any of the transitions, and are thus going to
+// be rewritten, unless they're excluded from the run
+fn list_protected_refs(repo: &Repository, transitions: &[Transition], options: &Options) {
+    if options.protected_refs.is_empty() || options.no_modify_refs {
+        return;
+    }
+    let references = match repo.references() {
+        Ok(references) => references,
+        Err(_) => return,
+    };
+    let mut protected = Vec::new();
+    for reference in references.filter_map(|r| r.ok()) {
+        let name = match reference.name() {
+            Some(name) if is_ref_protected(name, options) => String::from(name),
+            _ => continue,
+        };
+        let id = match reference.peel(::git2::ObjectType::Commit) {
+            Ok(commit) => commit.id(),
+            Err(_) => continue,
+        };
+        if transitions.iter().any(|transition| {
+            transition.commit == id ||
+            repo.graph_descendant_of(id, transition.commit).unwrap_or(false)
+        }) {
+            protected.push(name);
+        }
+    }
+    if protected.is_empty() {
+        return;
+    }
+
+    println!("\nThese protected refs would be rewritten{}:",
+             if options.rewrite_protected {
+                 ", as --rewrite-protected allows"
+             } else {
+                 "; without --rewrite-protected, the run will stop before moving them"
+             });
+    for name in protected {
+        println!("    {}", name);
+    }
+}
+
+fn show_transition(repo: &Repository, transition: &Transition, options: &Options) {
+    let commit = repo.find_commit(transition.commit)
+        .expect(&format!("Couldn't get a commit with ID {}", transition.commit));
+    let author = commit.author();
+    println!("commit {}\nAuthor: {} <{}>\n\n{}",
+             transition.commit,
+             author.name().unwrap_or(""),
+             author.email().unwrap_or(""),
+             commit.message().unwrap_or(""));
+
+    if repo.find_commit(transition.to).is_err() {
+        println!("The submodule commit {} is not in the submodule's history.", transition.to);
+        return;
+    }
+
+    // Submodule commits that this transition brings in
+    let mut revwalk = repo.revwalk().expect("Couldn't obtain RevWalk object for the repo");
+    revwalk.push(transition.to).expect("Couldn't add submodule's commit to RevWalk");
+    if let Some(from) = transition.from {
+        // Might fail if the old commit is dangling; we'll just show more commits then
+        let _ = revwalk.hide(from);
+    }
+    let ids: Vec<Oid> = revwalk.filter_map(|maybe_oid| maybe_oid.ok()).collect();
+    println!("Submodule commits brought in: {}", ids.len());
+    for id in ids.iter().take(20) {
+        if let Ok(mut submodule_commit) = repo.find_commit(*id) {
+            println!("    {} {}",
+                     abbreviate(repo, *id, options.abbrev),
+                     submodule_commit.summary().unwrap_or(""));
+        }
+    }
+    if ids.len() > 20 {
+        println!("    ...");
+    }
+}
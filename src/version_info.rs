@@ -0,0 +1,50 @@
+// What --version-info prints. Fetching and authentication depend a lot on how libgit2 was built
+// (with or without HTTPS and SSH, and which TLS library it uses), so bug reports should include
+// this.
+
+use std::os::raw::c_int;
+
+// Neither git2 nor libgit2-sys expose these, but they're in the libgit2 we link against anyway
+extern "C" {
+    fn git_libgit2_version(major: *mut c_int, minor: *mut c_int, rev: *mut c_int);
+    fn git_libgit2_features() -> c_int;
+}
+
+// From git2/common.h
+const GIT_FEATURE_THREADS: c_int = 1 << 0;
+const GIT_FEATURE_HTTPS: c_int = 1 << 1;
+const GIT_FEATURE_SSH: c_int = 1 << 2;
+const GIT_FEATURE_NSEC: c_int = 1 << 3;
+
+pub fn print() {
+    let (mut major, mut minor, mut rev) = (0, 0, 0);
+    let features = unsafe {
+        git_libgit2_version(&mut major, &mut minor, &mut rev);
+        git_libgit2_features()
+    };
+    let has = |feature: c_int| if features & feature != 0 { "yes" } else { "no" };
+
+    println!("git-submerge {}", crate_version!());
+    println!("rustc: {}", env!("SUBMERGE_RUSTC_VERSION"));
+    println!("target: {}", env!("SUBMERGE_TARGET"));
+    println!("crate features: {}",
+             if cfg!(feature = "tui") { "tui" } else { "none" });
+    println!("libgit2: {}.{}.{}", major, minor, rev);
+    println!("    threads: {}", has(GIT_FEATURE_THREADS));
+    println!("    HTTPS: {}{}",
+             has(GIT_FEATURE_HTTPS),
+             if features & GIT_FEATURE_HTTPS != 0 { tls_backend() } else { "" });
+    println!("    SSH: {}", has(GIT_FEATURE_SSH));
+    println!("    nanosecond timestamps: {}", has(GIT_FEATURE_NSEC));
+}
+
+// libgit2 picks the TLS library by platform
+fn tls_backend() -> &'static str {
+    if cfg!(windows) {
+        " (WinHTTP)"
+    } else if cfg!(any(target_os = "macos", target_os = "ios")) {
+        " (SecureTransport)"
+    } else {
+        " (OpenSSL)"
+    }
+}